@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use fishnet::api::AcquireResponseBody;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(body) = serde_json::from_slice::<AcquireResponseBody>(data) {
+        let _ = body.work.multipv();
+        let _ = body.work.node_limit().map(|nodes| nodes.scaled(1.5));
+        let _ = format!("{:?}", body);
+    }
+});
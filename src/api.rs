@@ -1,29 +1,152 @@
+use std::cmp::{max, min};
+use std::collections::VecDeque;
+use std::error::Error;
 use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
 use std::str::FromStr;
 use arrayvec::ArrayString;
-use reqwest::StatusCode;
-use tokio::time;
+use bytes::{Bytes, BytesMut};
+use bytes::buf::BufMutExt as _;
+use reqwest::{ResponseBuilderExt as _, StatusCode};
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_ENCODING, CONTENT_TYPE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, RETRY_AFTER, USER_AGENT};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use tokio::time::{self, Instant};
 use tokio::sync::{mpsc, oneshot};
-use serde::{Deserialize, Serialize};
-use serde_with::{serde_as, NoneAsEmptyString, DurationSeconds, DisplayFromStr, SpaceSeparator, StringWithSeparator};
-use serde_repr::Deserialize_repr as DeserializeRepr;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_with::{serde_as, DeserializeAs, SerializeAs, NoneAsEmptyString, DurationMilliSeconds, DurationSeconds, DisplayFromStr, SpaceSeparator, StringWithSeparator};
+use serde_repr::{Deserialize_repr as DeserializeRepr, Serialize_repr as SerializeRepr};
 use shakmaty::fen::Fen;
 use shakmaty::uci::Uci;
 use shakmaty::variants::Variant;
 use tokio_compat_02::FutureExt as _;
 use crate::assets::EvalFlavor;
-use crate::configure::{Endpoint, Key, KeyError};
+use crate::bandwidth::{Bandwidth, HEADER_OVERHEAD};
+use crate::chaos;
+use crate::configure::{redacted_url, ClientOpt, Endpoint, Key, KeyError, Route};
 use crate::logger::Logger;
-use crate::util::{NevermindExt as _, RandomizedBackoff};
+use crate::pinning;
+use crate::spool::Spool;
+use crate::trace::{ApiTrace, Exchange};
+use crate::util::{NevermindExt as _, RandomizedBackoff, Secret};
+use crate::webhook::{Event, WebhookStub};
 
-pub fn channel(endpoint: Endpoint, key: Option<Key>, logger: Logger) -> (ApiStub, ApiActor) {
+/// Certificate or key files that can not be used to build a client.
+#[derive(Debug)]
+pub enum ClientError {
+    Read(PathBuf, io::Error),
+    Invalid(PathBuf, &'static str),
+    Build(reqwest::Error),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Read(path, err) => write!(f, "could not read {}: {}", path.display(), err),
+            ClientError::Invalid(path, what) => write!(f, "{}: {}", path.display(), what),
+            ClientError::Build(err) => write!(f, "could not build client: {}", err),
+        }
+    }
+}
+
+impl Error for ClientError {}
+
+pub(crate) fn read_pem(path: &Path) -> Result<Vec<u8>, ClientError> {
+    fs::read(path).map_err(|err| ClientError::Read(path.to_owned(), err))
+}
+
+pub fn http_client(opt: &ClientOpt) -> Result<reqwest::Client, ClientError> {
+    let mut builder = client_builder(opt);
+    if let Some(ref cacert) = opt.cacert {
+        let pem = read_pem(cacert)?;
+        builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem).map_err(|_| ClientError::Invalid(cacert.clone(), "invalid ca certificate"))?);
+    }
+    if let (Some(ref client_cert), Some(ref client_key)) = (&opt.client_cert, &opt.client_key) {
+        // The identity is read from a single PEM buffer with both the
+        // private key and the certificate chain.
+        let mut pem = read_pem(client_key)?;
+        pem.push(b'\n');
+        pem.extend(read_pem(client_cert)?);
+        builder = builder.identity(reqwest::Identity::from_pem(&pem).map_err(|_| ClientError::Invalid(client_cert.clone(), "invalid client certificate or key"))?);
+    }
+
+    builder.build().map_err(ClientError::Build)
+}
+
+/// Client for requests to the endpoint. Same as `http_client`, unless
+/// certificates are pinned. Pins do not apply to other hosts, like GitHub
+/// for updates.
+pub fn api_client(opt: &ClientOpt) -> Result<reqwest::Client, ClientError> {
+    if opt.pin_sha256.is_empty() {
+        return http_client(opt);
+    }
+    client_builder(opt)
+        .use_preconfigured_tls(pinning::tls_config(opt)?)
+        .build()
+        .map_err(ClientError::Build)
+}
+
+fn client_builder(opt: &ClientOpt) -> reqwest::ClientBuilder {
+    let mut builder = reqwest::Client::builder()
+        .user_agent(concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")))
+        .timeout(Duration::from_secs(30))
+        .pool_idle_timeout(opt.pool_idle_timeout.unwrap_or(Duration::from_secs(25)))
+        .tcp_keepalive(Duration::from_secs(60));
+
+    if let Some(pool_max_idle) = opt.pool_max_idle {
+        builder = builder.pool_max_idle_per_host(pool_max_idle);
+    }
+    if opt.http2_prior_knowledge {
+        builder = builder.http2_prior_knowledge();
+    }
+    if let Some(proxy) = opt.proxy() {
+        builder = builder.proxy(reqwest::Proxy::all(proxy.url.as_str()).expect("valid proxy"));
+    }
+    builder
+}
+
+/// How the API actor talks to the endpoint, besides where and with which
+/// keys.
+pub struct ApiConfig {
+    pub client: reqwest::Client,
+    pub timeouts: Timeouts,
+    pub spool: Option<Spool>,
+    pub trace: Option<ApiTrace>,
+    pub dry_run: bool,
+    pub webhook: WebhookStub,
+    pub bandwidth: Arc<Bandwidth>,
+}
+
+impl ApiConfig {
+    /// Default timeouts, no spool, no trace, no webhook and bandwidth that
+    /// is not persisted.
+    pub fn new(client: reqwest::Client) -> ApiConfig {
+        ApiConfig {
+            client,
+            timeouts: Timeouts::default(),
+            spool: None,
+            trace: None,
+            dry_run: false,
+            webhook: WebhookStub::default(),
+            bandwidth: Arc::new(Bandwidth::new(None, None)),
+        }
+    }
+}
+
+pub fn channel(endpoint: Endpoint, keys: Vec<Key>, config: ApiConfig, logger: Logger) -> (ApiStub, ApiActor) {
     let (tx, rx) = mpsc::unbounded_channel();
-    (ApiStub::new(tx), ApiActor::new(rx, endpoint, key, logger))
+    let bandwidth = config.bandwidth.clone();
+    let actor = ApiActor::new(rx, endpoint, keys, config, logger.module("api"));
+    let stub = ApiStub::new(tx, bandwidth, actor.latencies.clone(), actor.breaker.clone());
+    (stub, actor)
 }
 
-pub fn spawn(endpoint: Endpoint, key: Option<Key>, logger: Logger) -> ApiStub {
-    let (stub, actor) = channel(endpoint, key, logger);
+pub fn spawn(endpoint: Endpoint, keys: Vec<Key>, client: reqwest::Client, logger: Logger) -> ApiStub {
+    let (stub, actor) = channel(endpoint, keys, ApiConfig::new(client), logger);
     tokio::spawn(async move {
         actor.run().await;
     });
@@ -34,7 +157,7 @@ pub fn spawn(endpoint: Endpoint, key: Option<Key>, logger: Logger) -> ApiStub {
 enum ApiMessage {
     CheckKey {
         key: Key,
-        callback: oneshot::Sender<Result<Key, KeyError>>,
+        callback: oneshot::Sender<Result<KeyInfo, KeyError>>,
     },
     Status {
         callback: oneshot::Sender<AnalysisStatus>,
@@ -49,28 +172,80 @@ enum ApiMessage {
     SubmitAnalysis {
         batch_id: BatchId,
         flavor: EvalFlavor,
+        engine: Option<EngineInfo>,
+        nodes_multiplier: Option<f64>,
         analysis: Vec<Option<AnalysisPart>>,
+        gone: Option<oneshot::Sender<()>>,
     },
     SubmitMove {
         batch_id: BatchId,
         best_move: Option<Uci>,
         callback: oneshot::Sender<Acquired>,
-    }
+    },
+    CloudEval {
+        fen: String,
+        callback: oneshot::Sender<CloudEval>,
+    },
 }
 
 #[derive(Debug, Deserialize)]
-struct StatusResponseBody {
-    analysis: AnalysisStatus,
+pub struct CloudEval {
+    pub depth: u32,
+    pub knodes: u64,
+    pub pvs: Vec<CloudPv>,
 }
 
+#[serde_as]
+#[derive(Debug, Deserialize)]
+pub struct CloudPv {
+    #[serde_as(as = "StringWithSeparator::<SpaceSeparator, Uci>")]
+    pub moves: Vec<Uci>,
+    #[serde(default)]
+    pub cp: Option<i64>,
+    #[serde(default)]
+    pub mate: Option<i64>,
+}
+
+/// Details about the account that is credited for a key.
 #[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyInfo {
+    #[serde(default)]
+    pub user_id: Option<String>,
+    #[serde(default)]
+    pub provider: Option<String>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+impl fmt::Display for KeyInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.user_id, &self.provider) {
+            (Some(user_id), Some(provider)) => write!(f, "{} ({})", user_id, provider)?,
+            (Some(user_id), None) => f.write_str(user_id)?,
+            (None, Some(provider)) => f.write_str(provider)?,
+            (None, None) => f.write_str("unknown account")?,
+        }
+        if !self.scopes.is_empty() {
+            write!(f, ", scopes: {}", self.scopes.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct StatusResponseBody {
+    pub analysis: AnalysisStatus,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
 pub struct AnalysisStatus {
     pub user: QueueStatus,
     pub system: QueueStatus,
 }
 
 #[serde_as]
-#[derive(Debug, Default, Deserialize)]
+#[derive(Debug, Default, Clone, Deserialize)]
 pub struct QueueStatus {
     pub acquired: i64,
     pub queued: i64,
@@ -88,7 +263,7 @@ pub struct VoidRequestBody {
 struct Fishnet {
     version: &'static str,
     python: &'static str,
-    apikey: String,
+    apikey: Secret<String>,
 }
 
 impl Fishnet {
@@ -96,25 +271,42 @@ impl Fishnet {
         Fishnet {
             version: env!("CARGO_PKG_VERSION"),
             python: "-",
-            apikey: key.map_or("".to_owned(), |k| k.0),
+            apikey: key.map_or_else(Secret::default, |k| Secret::new(k.expose().to_owned())),
         }
     }
 }
 
+/// Engine that produced an analysis, as reported by the engine process.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EngineInfo {
+    pub name: String,
+    /// Name of the NNUE network file, which includes a hash of its
+    /// contents.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nnue: Option<String>,
+}
+
 #[derive(Debug, Serialize)]
 struct Stockfish {
-    name: &'static str,
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nnue: Option<String>,
     options: StockfishOptions,
     #[serde(skip_serializing_if = "Option::is_none")]
     flavor: Option<EvalFlavor>,
+    /// Node limits were scaled locally.
+    #[serde(rename = "nodesMultiplier", skip_serializing_if = "Option::is_none")]
+    nodes_multiplier: Option<f64>,
 }
 
 impl Stockfish {
     fn without_flavor() -> Stockfish {
         Stockfish {
-            name: "Stockfish 12+",
+            name: "Stockfish 12+".to_owned(),
+            nnue: None,
             options: StockfishOptions::default(),
             flavor: None,
+            nodes_multiplier: None,
         }
     }
 
@@ -124,6 +316,17 @@ impl Stockfish {
             ..Stockfish::without_flavor()
         }
     }
+
+    fn with_engine(flavor: EvalFlavor, engine: Option<EngineInfo>) -> Stockfish {
+        match engine {
+            Some(engine) => Stockfish {
+                name: engine.name,
+                nnue: engine.nnue,
+                ..Stockfish::with_flavor(flavor)
+            },
+            None => Stockfish::with_flavor(flavor),
+        }
+    }
 }
 
 #[serde_as]
@@ -147,10 +350,21 @@ impl Default for StockfishOptions {
 #[derive(Debug, Serialize)]
 pub struct AcquireQuery {
     pub slow: bool,
+    /// Comma separated variants that the engines support and that are
+    /// wanted, if not all.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variants: Option<String>,
+    /// Only analysis or only move work, if not both.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub only: Option<String>,
+    /// Seconds that the server may hold the request until work is
+    /// available.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wait: Option<u64>,
 }
 
 #[serde_as]
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(tag = "type")]
 pub enum Work {
     #[serde(rename = "analysis")]
@@ -159,6 +373,15 @@ pub enum Work {
         id: BatchId,
         #[serde(default)]
         nodes: Option<NodeLimit>,
+        #[serde(default)]
+        multipv: Option<u32>,
+        /// Time budget per position, taking precedence over nodes.
+        #[serde_as(as = "Option<DurationMilliSeconds>")]
+        #[serde(default)]
+        movetime: Option<Duration>,
+        /// Stop searching at this depth, even if budget remains.
+        #[serde(default)]
+        depth: Option<u32>,
     },
     #[serde(rename = "move")]
     Move {
@@ -188,6 +411,20 @@ impl Work {
             Work::Move { .. } => None,
         }
     }
+
+    pub fn movetime(&self) -> Option<Duration> {
+        match *self {
+            Work::Analysis { movetime, .. } => movetime,
+            Work::Move { .. } => None,
+        }
+    }
+
+    pub fn multipv(&self) -> u32 {
+        match *self {
+            Work::Analysis { multipv, .. } => max(1, multipv.unwrap_or(1)),
+            Work::Move { .. } => 1,
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
@@ -207,7 +444,7 @@ impl fmt::Display for BatchId {
     }
 }
 
-#[derive(Debug, Copy, Clone, Deserialize)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct NodeLimit {
     classical: u64,
     nnue: u64,
@@ -218,12 +455,26 @@ pub fn nnue_to_classical(nodes: u64) -> u64 {
 }
 
 impl NodeLimit {
+    pub fn from_nnue(nnue: u64) -> NodeLimit {
+        NodeLimit {
+            nnue,
+            classical: nnue_to_classical(nnue),
+        }
+    }
+
     pub fn get(&self, flavor: EvalFlavor) -> u64 {
         match flavor {
             EvalFlavor::Classical => self.classical,
             EvalFlavor::Nnue => self.nnue,
         }
     }
+
+    pub fn scaled(self, factor: f64) -> NodeLimit {
+        NodeLimit {
+            classical: max(1, (self.classical as f64 * factor) as u64),
+            nnue: max(1, (self.nnue as f64 * factor) as u64),
+        }
+    }
 }
 
 impl Default for NodeLimit {
@@ -236,7 +487,7 @@ impl Default for NodeLimit {
     }
 }
 
-#[derive(DeserializeRepr, Debug, Copy, Clone)]
+#[derive(SerializeRepr, DeserializeRepr, Debug, Copy, Clone)]
 #[repr(u32)]
 pub enum SkillLevel {
     One = 1,
@@ -290,7 +541,7 @@ impl SkillLevel {
 }
 
 #[serde_as]
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Clock {
     pub wtime: Centis,
     pub btime: Centis,
@@ -298,7 +549,7 @@ pub struct Clock {
     pub inc: Duration,
 }
 
-#[derive(Debug, Copy, Clone, Deserialize)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct Centis(u32);
 
 impl From<Centis> for Duration {
@@ -322,9 +573,12 @@ pub struct AcquireResponseBody {
     pub moves: Vec<Uci>,
     #[serde(rename = "skipPositions", default)]
     pub skip_positions: Vec<usize>,
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    #[serde(default)]
+    pub timeout: Option<Duration>,
 }
 
-#[derive(Debug, Deserialize, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Hash)]
 pub enum LichessVariant {
     #[serde(rename = "antichess")]
     Antichess,
@@ -349,6 +603,35 @@ pub enum LichessVariant {
 }
 
 impl LichessVariant {
+    pub const ALL: [LichessVariant; 10] = [
+        LichessVariant::Antichess,
+        LichessVariant::Atomic,
+        LichessVariant::Chess960,
+        LichessVariant::Crazyhouse,
+        LichessVariant::FromPosition,
+        LichessVariant::Horde,
+        LichessVariant::KingOfTheHill,
+        LichessVariant::RacingKings,
+        LichessVariant::Standard,
+        LichessVariant::ThreeCheck,
+    ];
+
+    /// Name in the API.
+    pub fn key(self) -> &'static str {
+        match self {
+            LichessVariant::Antichess => "antichess",
+            LichessVariant::Atomic => "atomic",
+            LichessVariant::Chess960 => "chess960",
+            LichessVariant::Crazyhouse => "crazyhouse",
+            LichessVariant::FromPosition => "fromPosition",
+            LichessVariant::Horde => "horde",
+            LichessVariant::KingOfTheHill => "kingOfTheHill",
+            LichessVariant::RacingKings => "racingKings",
+            LichessVariant::Standard => "standard",
+            LichessVariant::ThreeCheck => "threeCheck",
+        }
+    }
+
     pub fn short_name(self) -> Option<&'static str> {
         Some(match self {
             LichessVariant::Antichess => "anti",
@@ -392,13 +675,30 @@ pub enum Acquired {
     Accepted(AcquireResponseBody),
     NoContent,
     BadRequest,
+    RateLimited(Duration),
+}
+
+#[derive(Debug)]
+enum Submitted {
+    Accepted,
+    /// The server no longer needs the batch.
+    Gone,
 }
 
+/// Analysis request body without credentials, for the spool. The current
+/// key is added when submitting.
 #[derive(Debug, Serialize)]
-struct AnalysisRequestBody {
+struct SpooledAnalysis<'a> {
+    stockfish: &'a Stockfish,
+    analysis: &'a [Option<AnalysisPart>],
+}
+
+/// Request body with the credentials of the current key added.
+#[derive(Debug, Serialize)]
+struct Authenticated<'a, T> {
     fishnet: Fishnet,
-    stockfish: Stockfish,
-    analysis: Vec<Option<AnalysisPart>>,
+    #[serde(flatten)]
+    body: &'a T,
 }
 
 #[derive(Debug, Serialize)]
@@ -424,19 +724,68 @@ pub enum AnalysisPart {
         skipped: bool,
     },
     Complete {
-        #[serde_as(as = "StringWithSeparator::<SpaceSeparator, Uci>")]
+        #[serde_as(as = "UciLine")]
         #[serde(skip_serializing_if = "Vec::is_empty")]
         pv: Vec<Uci>,
         depth: u32,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        seldepth: Option<u32>,
         nodes: u64,
-        score: Score,
+        score: BoundedScore,
         time: u64,
         #[serde(skip_serializing_if = "Option::is_none")]
         nps: Option<u32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        hashfull: Option<u32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tbhits: Option<u64>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        secondary: Vec<PvLine>,
+        wdl: Wdl,
     },
 }
 
-#[derive(Debug, Serialize, Copy, Clone)]
+/// Alternative line when analysing with MultiPV.
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PvLine {
+    pub score: Score,
+    #[serde_as(as = "UciLine")]
+    pub pv: Vec<Uci>,
+}
+
+/// Space separated moves, like `StringWithSeparator<SpaceSeparator, Uci>`,
+/// but written straight to the serializer, without building a string for
+/// each line.
+struct UciLine;
+
+struct DisplayUciLine<'a>(&'a [Uci]);
+
+impl fmt::Display for DisplayUciLine<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, m) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{}", m)?;
+        }
+        Ok(())
+    }
+}
+
+impl SerializeAs<Vec<Uci>> for UciLine {
+    fn serialize_as<S: Serializer>(moves: &Vec<Uci>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&DisplayUciLine(moves))
+    }
+}
+
+impl<'de> DeserializeAs<'de, Vec<Uci>> for UciLine {
+    fn deserialize_as<D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Uci>, D::Error> {
+        StringWithSeparator::<SpaceSeparator, Uci>::deserialize_as(deserializer)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone)]
 pub enum Score {
     #[serde(rename = "cp")]
     Cp(i64),
@@ -444,23 +793,259 @@ pub enum Score {
     Mate(i64),
 }
 
+/// Whether the engine only found a bound for the score, because it had to
+/// stop while researching.
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Bound {
+    Lower,
+    Upper,
+}
+
+/// Score with its bound, if it is not exact.
+#[derive(Debug, Serialize)]
+pub struct BoundedScore {
+    #[serde(flatten)]
+    pub score: Score,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bound: Option<Bound>,
+}
+
+/// Win, draw and loss probabilities in permille, from the point of view of
+/// the side to move, like the score.
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
+pub struct Wdl {
+    pub win: u32,
+    pub draw: u32,
+    pub loss: u32,
+}
+
+impl Wdl {
+    /// Estimates the probabilities for engines that do not report them,
+    /// with the model of Stockfish 15.1. The model was fitted to standard
+    /// chess, but is a reasonable guess for variants.
+    pub fn from_score(score: Score, ply: u32) -> Wdl {
+        match score {
+            Score::Mate(mate) if mate > 0 => Wdl { win: 1000, draw: 0, loss: 0 },
+            Score::Mate(_) => Wdl { win: 0, draw: 0, loss: 1000 },
+            Score::Cp(cp) => {
+                let win = win_rate(cp, ply);
+                let loss = win_rate(-cp, ply);
+                Wdl {
+                    win,
+                    draw: 1000 - win - loss,
+                    loss,
+                }
+            }
+        }
+    }
+}
+
+fn win_rate(cp: i64, ply: u32) -> u32 {
+    // Parameters of the logistic curve depend on the game phase.
+    let m = f64::from(min(ply, 240)) / 64.0;
+    let a = ((-1.062_497_02 * m + 7.420_169_37) * m + 0.894_256_29) * m + 348.603_561_74;
+    let b = ((-5.331_221_90 * m + 39.578_315_33) * m - 90.844_737_71) * m + 123.406_207_48;
+    // In internal units, where 328 correspond to 100 centipawns.
+    let x = (cp as f64 * 3.28).clamp(-4000.0, 4000.0);
+    (0.5 + 1000.0 / (1.0 + ((a - x) / b).exp())) as u32
+}
+
 #[derive(Debug, Serialize)]
 struct SubmitQuery {
     slow: bool,
     stop: bool,
 }
 
+/// Timeouts for the different kinds of requests. The client default applies
+/// to everything else.
+#[derive(Debug, Copy, Clone)]
+pub struct Timeouts {
+    pub acquire: Duration,
+    pub submit: Duration,
+    pub abort: Duration,
+    pub status: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            acquire: Duration::from_secs(30),
+            submit: Duration::from_secs(30),
+            abort: Duration::from_secs(30),
+            status: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn new(opt: &ClientOpt) -> Timeouts {
+        let default = Timeouts::default();
+        Timeouts {
+            acquire: opt.acquire_timeout.unwrap_or(default.acquire),
+            submit: opt.submit_timeout.unwrap_or(default.submit),
+            abort: opt.abort_timeout.unwrap_or(default.abort),
+            status: opt.status_timeout.unwrap_or(default.status),
+        }
+    }
+}
+
+/// Minimum time between requests for the queue status. Until then, the last
+/// status is reused, so that large fleets do not ask before every acquire.
+const STATUS_MIN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Last queue status, with the validators for asking whether it changed.
+struct CachedStatus {
+    status: AnalysisStatus,
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+    fetched_at: Instant,
+}
+
+/// Consecutive server errors or timeouts, after which the endpoint is
+/// considered unhealthy.
+const BREAKER_THRESHOLD: u32 = 5;
+
+/// Delay between attempts while the endpoint is unhealthy.
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// Stops hammering the endpoint after consecutive failures, until a request
+/// goes through again.
+#[derive(Debug, Default)]
+struct CircuitBreaker {
+    failures: u32,
+    unhealthy_since: Option<Instant>,
+}
+
+/// Outcome of the most recent request of a kind, for diagnostics.
+#[derive(Debug, Clone)]
+pub struct Latency {
+    pub kind: &'static str,
+    pub duration: Duration,
+    /// `None` if the request failed without a response.
+    pub status: Option<StatusCode>,
+    pub at: Instant,
+}
+
+/// Round trip times of requests of a kind that got a response, over the
+/// most recent `ROUND_TRIP_WINDOW` requests.
+#[derive(Debug, Clone)]
+pub struct RoundTrips {
+    pub kind: &'static str,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    /// Since startup.
+    pub count: u64,
+    /// Since startup.
+    pub sum: Duration,
+}
+
+const ROUND_TRIP_WINDOW: usize = 100;
+
+#[derive(Debug)]
+struct KindLatencies {
+    latest: Latency,
+    window: VecDeque<Duration>,
+    count: u64,
+    sum: Duration,
+}
+
+impl KindLatencies {
+    fn round_trips(&self) -> Option<RoundTrips> {
+        let mut sorted: Vec<Duration> = self.window.iter().copied().collect();
+        if sorted.is_empty() {
+            return None;
+        }
+        sorted.sort();
+        let percentile = |p: usize| sorted[(sorted.len() - 1) * p / 100];
+        Some(RoundTrips {
+            kind: self.latest.kind,
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            count: self.count,
+            sum: self.sum,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct Latencies {
+    kinds: std::sync::Mutex<Vec<KindLatencies>>,
+}
+
+impl Latencies {
+    fn record(&self, kind: &'static str, duration: Duration, status: Option<StatusCode>) {
+        let latest = Latency { kind, duration, status, at: Instant::now() };
+        let mut kinds = self.kinds.lock().expect("api latencies");
+        let entry = match kinds.iter().position(|k| k.latest.kind == kind) {
+            Some(i) => &mut kinds[i],
+            None => {
+                kinds.push(KindLatencies {
+                    latest: latest.clone(),
+                    window: VecDeque::with_capacity(ROUND_TRIP_WINDOW),
+                    count: 0,
+                    sum: Duration::default(),
+                });
+                kinds.last_mut().expect("just pushed")
+            }
+        };
+        entry.latest = latest;
+        if status.is_some() {
+            if entry.window.len() >= ROUND_TRIP_WINDOW {
+                entry.window.pop_front();
+            }
+            entry.window.push_back(duration);
+            entry.count += 1;
+            entry.sum += duration;
+        }
+    }
+
+    fn snapshot(&self) -> Vec<Latency> {
+        self.kinds.lock().expect("api latencies").iter().map(|k| k.latest.clone()).collect()
+    }
+
+    fn round_trips(&self) -> Vec<RoundTrips> {
+        self.kinds.lock().expect("api latencies").iter().filter_map(KindLatencies::round_trips).collect()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ApiStub {
     tx: mpsc::UnboundedSender<ApiMessage>,
+    bandwidth: Arc<Bandwidth>,
+    latencies: Arc<Latencies>,
+    breaker: Arc<std::sync::Mutex<CircuitBreaker>>,
 }
 
 impl ApiStub {
-    fn new(tx: mpsc::UnboundedSender<ApiMessage>) -> ApiStub {
-        ApiStub { tx }
+    fn new(tx: mpsc::UnboundedSender<ApiMessage>, bandwidth: Arc<Bandwidth>, latencies: Arc<Latencies>, breaker: Arc<std::sync::Mutex<CircuitBreaker>>) -> ApiStub {
+        ApiStub { tx, bandwidth, latencies, breaker }
+    }
+
+    /// Since when requests to the endpoint keep failing, if they do.
+    pub fn unhealthy_since(&self) -> Option<Instant> {
+        self.breaker.lock().expect("circuit breaker").unhealthy_since
+    }
+
+    pub fn bandwidth(&self) -> &Bandwidth {
+        &self.bandwidth
+    }
+
+    pub fn latencies(&self) -> Vec<Latency> {
+        self.latencies.snapshot()
+    }
+
+    pub fn round_trips(&self) -> Vec<RoundTrips> {
+        self.latencies.round_trips()
     }
 
     pub async fn check_key(&mut self, key: Key) -> Option<Result<Key, KeyError>> {
+        self.key_info(key.clone()).await.map(|res| res.map(|_| key))
+    }
+
+    pub async fn key_info(&mut self, key: Key) -> Option<Result<KeyInfo, KeyError>> {
         let (req, res) = oneshot::channel();
         self.tx.send(ApiMessage::CheckKey {
             key,
@@ -477,6 +1062,15 @@ impl ApiStub {
         res.await.ok()
     }
 
+    pub async fn cloud_eval(&mut self, fen: String) -> Option<CloudEval> {
+        let (req, res) = oneshot::channel();
+        self.tx.send(ApiMessage::CloudEval {
+            fen,
+            callback: req,
+        }).expect("api actor alive");
+        res.await.ok()
+    }
+
     pub fn abort(&mut self, batch_id: BatchId) {
         self.tx.send(ApiMessage::Abort { batch_id }).expect("api actor alive");
     }
@@ -490,14 +1084,32 @@ impl ApiStub {
         res.await.ok()
     }
 
-    pub fn submit_analysis(&mut self, batch_id: BatchId, flavor: EvalFlavor, analysis: Vec<Option<AnalysisPart>>) {
+    pub fn submit_analysis(&mut self, batch_id: BatchId, flavor: EvalFlavor, engine: Option<EngineInfo>, nodes_multiplier: Option<f64>, analysis: Vec<Option<AnalysisPart>>) {
         self.tx.send(ApiMessage::SubmitAnalysis {
             batch_id,
             flavor,
+            engine,
+            nodes_multiplier,
             analysis,
+            gone: None,
         }).expect("api actor alive");
     }
 
+    /// Reports partial analysis. Resolves successfully if the server no
+    /// longer needs the batch.
+    pub fn submit_progress(&mut self, batch_id: BatchId, flavor: EvalFlavor, engine: Option<EngineInfo>, nodes_multiplier: Option<f64>, analysis: Vec<Option<AnalysisPart>>) -> oneshot::Receiver<()> {
+        let (req, res) = oneshot::channel();
+        self.tx.send(ApiMessage::SubmitAnalysis {
+            batch_id,
+            flavor,
+            engine,
+            nodes_multiplier,
+            analysis,
+            gone: Some(req),
+        }).expect("api actor alive");
+        res
+    }
+
     pub async fn submit_move_and_acquire(&mut self, batch_id: BatchId, best_move: Option<Uci>) -> Option<Acquired> {
         let (req, res) = oneshot::channel();
         self.tx.send(ApiMessage::SubmitMove {
@@ -512,47 +1124,311 @@ impl ApiStub {
 pub struct ApiActor {
     rx: mpsc::UnboundedReceiver<ApiMessage>,
     endpoint: Endpoint,
-    key: Option<Key>,
+    keys: Vec<Key>,
+    key_index: usize,
     client: reqwest::Client,
     error_backoff: RandomizedBackoff,
+    compress: bool,
+    spool: Option<Spool>,
+    spool_backoff: RandomizedBackoff,
+    next_flush: Option<Instant>,
+    trace: Option<ApiTrace>,
+    /// Log results and give back batches instead of submitting.
+    dry_run: bool,
+    webhook: WebhookStub,
+    bandwidth: Arc<Bandwidth>,
+    latencies: Arc<Latencies>,
+    timeouts: Timeouts,
+    breaker: Arc<std::sync::Mutex<CircuitBreaker>>,
+    /// Reused for analysis submissions, which can be large.
+    buf: BytesMut,
+    status_cache: Option<CachedStatus>,
+    /// Identify this node in every request.
+    instance_headers: HeaderMap,
     logger: Logger,
 }
 
+/// Server-directed delay for a 429 or 503 response. A 429 without
+/// `Retry-After` still means the client should slow down, so it falls back to
+/// one minute. Only the delay-seconds form of the header is supported.
+fn rate_limit(res: &reqwest::Response) -> Option<Duration> {
+    let retry_after = res.headers()
+        .get(RETRY_AFTER)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.trim().parse().ok())
+        .map(|secs: u64| Duration::from_secs(secs).min(Duration::from_secs(60 * 60)));
+
+    match res.status() {
+        StatusCode::TOO_MANY_REQUESTS => Some(retry_after.unwrap_or_else(|| Duration::from_secs(60))),
+        _ => retry_after,
+    }
+}
+
+/// Whether a failed submission may succeed later, as opposed to being
+/// rejected for good (for example, because the batch expired).
+fn is_retryable(err: &reqwest::Error) -> bool {
+    err.status().is_none_or(|s| s.is_server_error() || s == StatusCode::TOO_MANY_REQUESTS)
+}
+
+/// Error message with the request URL masked, so that a key in the path
+/// never ends up in logs.
+fn redacted_error(err: &reqwest::Error) -> String {
+    let msg = err.to_string();
+    match err.url() {
+        Some(url) => msg.replace(url.as_str(), &redacted_url(url)),
+        None => msg,
+    }
+}
+
+fn is_key_rejection(status: Option<StatusCode>) -> bool {
+    matches!(status, Some(StatusCode::UNAUTHORIZED) | Some(StatusCode::FORBIDDEN) | Some(StatusCode::TOO_MANY_REQUESTS))
+}
+
+/// Whether the server may have rejected a compressed body because it does
+/// not decode it. Such servers rarely answer 415, but usually fail to parse
+/// the JSON and answer 400.
+fn may_reject_encoding(status: StatusCode) -> bool {
+    status.is_client_error() && status != StatusCode::GONE && !is_key_rejection(Some(status))
+}
+
+/// Serializes a request body into a buffer that is reused across requests.
+/// The allocation is reclaimed once the previous body has been sent and
+/// dropped.
+fn json_body<T: Serialize>(buf: &mut BytesMut, body: &T, gzip: bool) -> Bytes {
+    buf.clear();
+    if gzip {
+        let mut encoder = GzEncoder::new(buf.writer(), Compression::default());
+        serde_json::to_writer(&mut encoder, body).expect("serialize request body");
+        encoder.finish().expect("gzip in memory");
+    } else {
+        serde_json::to_writer(buf.writer(), body).expect("serialize request body");
+    }
+    buf.split().freeze()
+}
+
+/// Response with a body that was already read.
+fn rebuilt_response(status: StatusCode, version: http::Version, headers: HeaderMap, url: reqwest::Url, body: Bytes) -> reqwest::Response {
+    let mut rebuilt = http::Response::builder().url(url).body(body).expect("response parts");
+    *rebuilt.status_mut() = status;
+    *rebuilt.version_mut() = version;
+    *rebuilt.headers_mut() = headers;
+    reqwest::Response::from(rebuilt)
+}
+
 impl ApiActor {
-    fn new(rx: mpsc::UnboundedReceiver<ApiMessage>, endpoint: Endpoint, key: Option<Key>, logger: Logger) -> ApiActor {
+    fn new(rx: mpsc::UnboundedReceiver<ApiMessage>, endpoint: Endpoint, keys: Vec<Key>, config: ApiConfig, logger: Logger) -> ApiActor {
         ApiActor {
             rx,
             endpoint,
-            key,
-            client: reqwest::Client::builder()
-                .user_agent(concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")))
-                .timeout(Duration::from_secs(30))
-                .pool_idle_timeout(Duration::from_secs(25))
-                .build().expect("client"),
+            keys,
+            key_index: 0,
+            client: config.client,
             error_backoff: RandomizedBackoff::default(),
+            compress: true,
+            next_flush: config.spool.as_ref().map(|_| Instant::now()),
+            spool: config.spool,
+            spool_backoff: RandomizedBackoff::default(),
+            trace: config.trace,
+            dry_run: config.dry_run,
+            webhook: config.webhook,
+            bandwidth: config.bandwidth,
+            latencies: Arc::default(),
+            timeouts: config.timeouts,
+            breaker: Arc::default(),
+            buf: BytesMut::new(),
+            status_cache: None,
+            instance_headers: HeaderMap::new(),
             logger,
         }
     }
 
+    /// Adds the instance id and the label of this node to the user agent
+    /// and headers of all requests, so that server admins can attribute
+    /// traffic.
+    pub fn set_instance(&mut self, id: Option<&str>, label: Option<&str>) {
+        let details: Vec<&str> = label.into_iter().chain(id).collect();
+        if !details.is_empty() {
+            let user_agent = format!("{}/{} ({})", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), details.join("; "));
+            self.instance_headers.insert(USER_AGENT, HeaderValue::from_str(&user_agent).expect("valid user agent"));
+        }
+        if let Some(id) = id {
+            self.instance_headers.insert("x-fishnet-instance", HeaderValue::from_str(id).expect("valid instance id"));
+        }
+        if let Some(label) = label {
+            self.instance_headers.insert("x-fishnet-label", HeaderValue::from_str(label).expect("valid instance label"));
+        }
+    }
+
+    /// Sends a request, accounting for the bandwidth used and recording the
+    /// latency for requests of the given kind.
+    async fn send(&self, kind: &'static str, req: reqwest::RequestBuilder) -> reqwest::Result<reqwest::Response> {
+        let req = req.headers(self.instance_headers.clone()).build()?;
+        let body = req.body().and_then(|b| b.as_bytes()).map_or(0, |b| b.len() as u64);
+        self.bandwidth.record_sent(HEADER_OVERHEAD + req.url().as_str().len() as u64 + body);
+        let started_at = Instant::now();
+        if chaos::strike() {
+            time::sleep(chaos::delay()).await;
+        }
+        let res = if chaos::strike() {
+            let mut res = http::Response::new("chaos: simulated server error");
+            *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+            Ok(reqwest::Response::from(res))
+        } else if let Some(ref trace) = self.trace {
+            self.execute_traced(trace, req, started_at).await
+        } else {
+            self.client.execute(req).await
+        };
+        self.latencies.record(kind, started_at.elapsed(), res.as_ref().ok().map(|res| res.status()));
+        self.record_health(match res {
+            Ok(ref res) => !res.status().is_server_error(),
+            Err(ref err) => !err.is_timeout() && !err.is_connect(),
+        });
+        let res = res?;
+        // Buffer the body to count what was actually received. Chunked
+        // responses have no content length.
+        let status = res.status();
+        let version = res.version();
+        let headers = res.headers().clone();
+        let url = res.url().clone();
+        let body = res.bytes().await?;
+        self.bandwidth.record_received(HEADER_OVERHEAD + body.len() as u64);
+        Ok(rebuilt_response(status, version, headers, url, body))
+    }
+
+    fn record_health(&self, ok: bool) {
+        let mut breaker = self.breaker.lock().expect("circuit breaker");
+        if ok {
+            if let Some(since) = breaker.unhealthy_since.take() {
+                self.logger.info(&format!("API healthy again after {}s.", since.elapsed().as_secs()));
+            }
+            breaker.failures = 0;
+        } else {
+            breaker.failures += 1;
+            if breaker.failures >= BREAKER_THRESHOLD && breaker.unhealthy_since.is_none() {
+                breaker.unhealthy_since = Some(Instant::now());
+                self.logger.error(&format!("API unhealthy after {} consecutive failures. Trying again every {:?}.", breaker.failures, BREAKER_COOLDOWN));
+            }
+        }
+    }
+
+    fn unhealthy_since(&self) -> Option<Instant> {
+        self.breaker.lock().expect("circuit breaker").unhealthy_since
+    }
+
+    /// Executes a request and writes it to the trace, together with the
+    /// response. The response body is buffered for that.
+    async fn execute_traced(&self, trace: &ApiTrace, req: reqwest::Request, started_at: Instant) -> reqwest::Result<reqwest::Response> {
+        let method = req.method().clone();
+        let url = req.url().clone();
+        let request = req.body().and_then(|b| b.as_bytes()).map(<[u8]>::to_vec);
+        let res = self.client.execute(req).await;
+        let res = match res {
+            Ok(res) => res,
+            Err(err) => {
+                trace.record(&Exchange {
+                    method: &method,
+                    url: &url,
+                    request: request.as_deref(),
+                    response: Err(redacted_error(&err)),
+                    latency: started_at.elapsed(),
+                }, &self.keys);
+                return Err(err);
+            }
+        };
+        let status = res.status();
+        let version = res.version();
+        let headers = res.headers().clone();
+        let body = res.bytes().await;
+        trace.record(&Exchange {
+            method: &method,
+            url: &url,
+            request: request.as_deref(),
+            response: match body {
+                Ok(ref body) => Ok((status, body)),
+                Err(ref err) => Err(redacted_error(err)),
+            },
+            latency: started_at.elapsed(),
+        }, &self.keys);
+        Ok(rebuilt_response(status, version, headers, url, body?))
+    }
+
+    fn key(&self) -> Option<Key> {
+        self.keys.get(self.key_index).cloned()
+    }
+
+    fn rotate_key(&mut self) -> bool {
+        if self.keys.len() < 2 {
+            return false;
+        }
+        let previous = self.keys[self.key_index].redacted();
+        self.key_index = (self.key_index + 1) % self.keys.len();
+        self.logger.warn(&format!("Key {} rejected or rate limited. Switching to key {}.", previous, self.keys[self.key_index].redacted()));
+        true
+    }
+
+    /// Whether to send a request again with the next key, because the
+    /// current one was rejected or rate limited. Each key is tried at most
+    /// once per request, starting with `tried` = 1.
+    fn retry_with_next_key(&mut self, status: StatusCode, tried: &mut usize) -> bool {
+        if !is_key_rejection(Some(status)) || *tried >= self.keys.len() {
+            return false;
+        }
+        if matches!(status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN) {
+            if let Some(key) = self.key() {
+                self.webhook.notify(Event::KeyRejected {
+                    key: key.redacted(),
+                });
+            }
+        }
+        *tried += 1;
+        self.rotate_key()
+    }
+
     pub async fn run(mut self) {
         self.logger.debug("Api actor started");
-        while let Some(msg) = self.rx.recv().await {
-            self.handle_mesage(msg).compat().await;
+        if let Some(key) = self.key() {
+            self.logger.debug(&format!("Using key {}", key.redacted()));
+        }
+        loop {
+            let next_flush = self.next_flush;
+            tokio::select! {
+                msg = self.rx.recv() => {
+                    match msg {
+                        Some(msg) => self.handle_mesage(msg).compat().await,
+                        None => break,
+                    }
+                }
+                _ = time::sleep_until(next_flush.unwrap_or_else(Instant::now)), if next_flush.is_some() => {
+                    self.flush_spool().compat().await;
+                }
+            }
         }
         self.logger.debug("Api actor exited");
     }
 
     async fn handle_mesage(&mut self, msg: ApiMessage) {
         if let Err(err) = self.handle_message_inner(msg).await {
+            if matches!(err.status(), Some(StatusCode::UNAUTHORIZED) | Some(StatusCode::FORBIDDEN)) {
+                if let Some(key) = self.key() {
+                    self.webhook.notify(Event::KeyRejected {
+                        key: key.redacted(),
+                    });
+                }
+            }
+
             if err.status().map_or(false, |s| s.is_success()) {
                 self.error_backoff.reset();
             } else if err.status() == Some(StatusCode::TOO_MANY_REQUESTS) {
                 let backoff = Duration::from_secs(60) + self.error_backoff.next();
                 self.logger.error(&format!("Too many requests. Suspending requests for {:?}.", backoff));
                 time::sleep(backoff).await;
+            } else if let Some(since) = self.unhealthy_since() {
+                // Already reported. Do not flood the log.
+                self.logger.debug(&format!("{}. API unhealthy since {}s ago. Retrying in {:?}.", redacted_error(&err), since.elapsed().as_secs(), BREAKER_COOLDOWN));
+                time::sleep(BREAKER_COOLDOWN).await;
             } else {
                 let backoff = self.error_backoff.next();
-                self.logger.error(&format!("{}. Backing off {:?}.", err, backoff));
+                self.logger.error(&format!("{}. Backing off {:?}.", redacted_error(&err), backoff));
                 time::sleep(backoff).await;
             }
         } else {
@@ -560,13 +1436,136 @@ impl ApiActor {
         }
     }
 
+    /// Resubmits spooled analysis until the spool is empty or the endpoint
+    /// turns out to be still unreachable.
+    async fn flush_spool(&mut self) {
+        self.next_flush = None;
+        let spool = match self.spool {
+            Some(ref spool) => spool.clone(),
+            None => return,
+        };
+
+        let batch_ids = match spool.batch_ids() {
+            Ok(batch_ids) => batch_ids,
+            Err(err) => {
+                self.logger.error(&format!("Failed to read spool: {}", err));
+                return;
+            }
+        };
+
+        for batch_id in batch_ids {
+            let body = match spool.load(batch_id) {
+                Ok(body) => body,
+                Err(err) => {
+                    self.logger.warn(&format!("Dropping unreadable spooled analysis for {}: {}", batch_id, err));
+                    spool.remove(batch_id).nevermind("spooled analysis already gone");
+                    continue;
+                }
+            };
+
+            match self.submit_analysis(batch_id, &body).await {
+                Ok(Submitted::Accepted) => {
+                    self.logger.info(&format!("Submitted spooled analysis for {}", batch_id));
+                    spool.remove(batch_id).nevermind("spooled analysis already gone");
+                }
+                Ok(Submitted::Gone) => {
+                    self.logger.warn(&format!("Dropping spooled analysis for {}: No longer needed", batch_id));
+                    spool.remove(batch_id).nevermind("spooled analysis already gone");
+                }
+                Err(err) if is_retryable(&err) => {
+                    let backoff = self.spool_backoff.next();
+                    self.logger.debug(&format!("Spooled analysis for {} still not submitted: {}. Retrying in {:?}.", batch_id, redacted_error(&err), backoff));
+                    self.next_flush = Some(Instant::now() + backoff);
+                    return;
+                }
+                Err(err) => {
+                    self.logger.warn(&format!("Dropping spooled analysis for {}: {}", batch_id, redacted_error(&err)));
+                    spool.remove(batch_id).nevermind("spooled analysis already gone");
+                }
+            }
+        }
+
+        self.spool_backoff.reset();
+    }
+
+    /// Submits analysis with the current key, or the next ones if it is
+    /// rejected.
+    async fn submit_analysis<T: Serialize>(&mut self, batch_id: BatchId, analysis: &T) -> reqwest::Result<Submitted> {
+        let mut tried = 1;
+        let res = loop {
+            let res = self.post_analysis(batch_id, &Authenticated {
+                fishnet: Fishnet::authenticated(self.key()),
+                body: analysis,
+            }).await?;
+            if !self.retry_with_next_key(res.status(), &mut tried) {
+                break res;
+            }
+        };
+        if res.status() == StatusCode::GONE {
+            return Ok(Submitted::Gone);
+        }
+
+        let res = res.error_for_status()?;
+        if res.status() != StatusCode::NO_CONTENT {
+            self.logger.warn(&format!("Unexpected status for submitting analysis: {}", res.status()));
+        }
+        Ok(Submitted::Accepted)
+    }
+
+    async fn post_analysis<T: Serialize>(&mut self, batch_id: BatchId, body: &T) -> reqwest::Result<reqwest::Response> {
+        let url = self.endpoint.route_with(Route::Analysis, &batch_id.to_string());
+        let query = SubmitQuery {
+            stop: true,
+            slow: false,
+        };
+
+        let mut res = None;
+        let mut rejected = None;
+        if self.compress {
+            let compressed = json_body(&mut self.buf, body, true);
+            let compressed = self.send("analysis", self.client.post(url.clone()).timeout(self.timeouts.submit).query(&query)
+                .header(CONTENT_TYPE, "application/json")
+                .header(CONTENT_ENCODING, "gzip")
+                .body(compressed)).await?;
+            if may_reject_encoding(compressed.status()) {
+                self.logger.debug(&format!("Compressed analysis for {} rejected with {}. Retrying uncompressed.", batch_id, compressed.status()));
+                rejected = Some(compressed.status());
+            } else {
+                res = Some(compressed);
+            }
+        }
+        Ok(match res {
+            Some(res) => res,
+            None => {
+                let uncompressed = json_body(&mut self.buf, body, false);
+                let res = self.send("analysis", self.client.post(url.clone()).timeout(self.timeouts.submit).query(&query)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(uncompressed)).await?;
+                if let Some(status) = rejected {
+                    // Otherwise the analysis itself was the problem.
+                    if !may_reject_encoding(res.status()) {
+                        self.logger.warn(&format!("Fishnet server does not accept compressed analysis ({}). Submitting uncompressed from now on.", status));
+                        self.compress = false;
+                    }
+                }
+                res
+            }
+        })
+    }
+
     async fn abort(&mut self, batch_id: BatchId) -> reqwest::Result<()> {
-        let url = format!("{}/abort/{}", self.endpoint, batch_id);
+        let url = self.endpoint.route_with(Route::Abort, &batch_id.to_string());
         self.logger.warn(&format!("Aborting batch {}.", batch_id));
-        let res = self.client.post(&url).json(&VoidRequestBody {
-            fishnet: Fishnet::authenticated(self.key.clone()),
-            stockfish: Stockfish::without_flavor(),
-        }).send().await?;
+        let mut tried = 1;
+        let res = loop {
+            let res = self.send("abort", self.client.post(url.clone()).timeout(self.timeouts.abort).json(&VoidRequestBody {
+                fishnet: Fishnet::authenticated(self.key()),
+                stockfish: Stockfish::without_flavor(),
+            })).await?;
+            if !self.retry_with_next_key(res.status(), &mut tried) {
+                break res;
+            }
+        };
 
         if res.status() == StatusCode::NOT_FOUND {
             self.logger.warn(&format!("Fishnet server does not support abort (404 for {}).", batch_id));
@@ -579,11 +1578,15 @@ impl ApiActor {
     async fn handle_message_inner(&mut self, msg: ApiMessage) -> reqwest::Result<()> {
         match msg {
             ApiMessage::CheckKey { key, callback } => {
-                let url = format!("{}/key/{}", self.endpoint, key.0);
-                let res = self.client.get(&url).send().await?;
+                let url = self.endpoint.route_with(Route::Key, key.expose());
+                let res = self.send("key", self.client.get(url.clone()).timeout(self.timeouts.status)).await?;
                 match res.status() {
                     StatusCode::NOT_FOUND => callback.send(Err(KeyError::AccessDenied)).nevermind("callback dropped"),
-                    StatusCode::OK => callback.send(Ok(key)).nevermind("callback dropped"),
+                    StatusCode::OK => {
+                        // Older servers do not send any details.
+                        let info = serde_json::from_slice(&res.bytes().await?).unwrap_or_default();
+                        callback.send(Ok(info)).nevermind("callback dropped");
+                    }
                     status => {
                         self.logger.warn(&format!("Unexpected status while checking key: {}", status));
                         res.error_for_status()?;
@@ -591,10 +1594,41 @@ impl ApiActor {
                 }
             }
             ApiMessage::Status { callback } => {
-                let url = format!("{}/status", self.endpoint);
-                let res = self.client.get(&url).send().await?;
+                if let Some(ref cached) = self.status_cache {
+                    if cached.fetched_at.elapsed() < STATUS_MIN_INTERVAL {
+                        callback.send(cached.status.clone()).nevermind("callback dropped");
+                        return Ok(());
+                    }
+                }
+                let url = self.endpoint.route(Route::Status);
+                let mut req = self.client.get(url.clone()).timeout(self.timeouts.status);
+                if let Some(ref cached) = self.status_cache {
+                    if let Some(ref etag) = cached.etag {
+                        req = req.header(IF_NONE_MATCH, etag.clone());
+                    }
+                    if let Some(ref last_modified) = cached.last_modified {
+                        req = req.header(IF_MODIFIED_SINCE, last_modified.clone());
+                    }
+                }
+                let res = self.send("status", req).await?;
                 match res.status() {
-                    StatusCode::OK => callback.send(res.json::<StatusResponseBody>().await?.analysis).nevermind("callback dropped"),
+                    StatusCode::NOT_MODIFIED if self.status_cache.is_some() => {
+                        let cached = self.status_cache.as_mut().expect("cached status");
+                        cached.fetched_at = Instant::now();
+                        callback.send(cached.status.clone()).nevermind("callback dropped");
+                    }
+                    StatusCode::OK => {
+                        let etag = res.headers().get(ETAG).cloned();
+                        let last_modified = res.headers().get(LAST_MODIFIED).cloned();
+                        let status = res.json::<StatusResponseBody>().await?.analysis;
+                        self.status_cache = Some(CachedStatus {
+                            status: status.clone(),
+                            etag,
+                            last_modified,
+                            fetched_at: Instant::now(),
+                        });
+                        callback.send(status).nevermind("callback dropped");
+                    }
                     StatusCode::NOT_FOUND => (),
                     status => {
                         self.logger.warn(&format!("Unexpected status for queue status: {}", status));
@@ -605,16 +1639,35 @@ impl ApiActor {
             ApiMessage::Abort { batch_id } => {
                 self.abort(batch_id).await?;
             }
+            ApiMessage::CloudEval { fen, callback } => {
+                let url = self.endpoint.route(Route::CloudEval);
+                let res = self.send("cloud-eval", self.client.get(url).timeout(self.timeouts.status).query(&[("fen", fen.as_str())])).await?;
+                match res.status() {
+                    StatusCode::OK => callback.send(res.json::<CloudEval>().await?).nevermind("callback dropped"),
+                    StatusCode::NOT_FOUND => (),
+                    status => self.logger.debug(&format!("Unexpected status for cloud eval: {}", status)),
+                }
+            }
             ApiMessage::Acquire { callback, query } => {
-                let url = format!("{}/acquire", self.endpoint);
-                let res = self.client.post(&url).query(&query).json(&VoidRequestBody {
-                    fishnet: Fishnet::authenticated(self.key.clone()),
-                    stockfish: Stockfish::without_flavor(),
-                }).send().await?;
+                let url = self.endpoint.route(Route::Acquire);
+                let timeout = self.timeouts.acquire + Duration::from_secs(query.wait.unwrap_or(0));
+                let mut tried = 1;
+                let res = loop {
+                    let res = self.send("acquire", self.client.post(url.clone()).timeout(timeout).query(&query).json(&VoidRequestBody {
+                        fishnet: Fishnet::authenticated(self.key()),
+                        stockfish: Stockfish::without_flavor(),
+                    })).await?;
+                    if !self.retry_with_next_key(res.status(), &mut tried) {
+                        break res;
+                    }
+                };
 
                 match res.status() {
                     StatusCode::NO_CONTENT => callback.send(Acquired::NoContent).nevermind("callback dropped"),
                     StatusCode::BAD_REQUEST => callback.send(Acquired::BadRequest).nevermind("callback dropped"),
+                    StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE if rate_limit(&res).is_some() => {
+                        callback.send(Acquired::RateLimited(rate_limit(&res).unwrap())).nevermind("callback dropped");
+                    }
                     StatusCode::OK | StatusCode::ACCEPTED => {
                         if let Err(Acquired::Accepted(res)) = callback.send(Acquired::Accepted(res.json().await?)) {
                             self.logger.error("Acquired a batch, but callback dropped. Aborting.");
@@ -627,32 +1680,78 @@ impl ApiActor {
                     }
                 }
             }
-            ApiMessage::SubmitAnalysis { batch_id, flavor, analysis } => {
-                let url = format!("{}/analysis/{}", self.endpoint, batch_id);
-                let res = self.client.post(&url).query(&SubmitQuery {
-                    stop: true,
-                    slow: false,
-                }).json(&AnalysisRequestBody {
-                    fishnet: Fishnet::authenticated(self.key.clone()),
-                    stockfish: Stockfish::with_flavor(flavor),
-                    analysis,
-                }).send().await?.error_for_status()?;
-
-                if res.status() != StatusCode::NO_CONTENT {
-                    self.logger.warn(&format!("Unexpected status for submitting analysis: {}", res.status()));
+            ApiMessage::SubmitAnalysis { batch_id, flavor, engine, nodes_multiplier, analysis, gone } => {
+                let complete = analysis.iter().all(Option::is_some);
+                let stockfish = Stockfish {
+                    nodes_multiplier,
+                    ..Stockfish::with_engine(flavor, engine)
+                };
+                let body = SpooledAnalysis {
+                    stockfish: &stockfish,
+                    analysis: &analysis,
+                };
+                if self.dry_run {
+                    // Progress reports would only be noise.
+                    if complete {
+                        let payload = serde_json::to_string(&body).expect("serialize analysis");
+                        self.logger.info(&format!("Dry run: Not submitting analysis for {}: {}", batch_id, payload));
+                        self.abort(batch_id).await?;
+                    }
+                    return Ok(());
+                }
+
+                match self.submit_analysis(batch_id, &body).await {
+                    Ok(Submitted::Accepted) => (),
+                    Ok(Submitted::Gone) => {
+                        self.logger.debug(&format!("Batch {} is gone", batch_id));
+                        if let Some(gone) = gone {
+                            gone.send(()).nevermind("batch already finished");
+                        }
+                    }
+                    Err(err) => {
+                        // Progress reports are superseded anyway, but completed
+                        // analysis is worth keeping.
+                        if let Some(ref spool) = self.spool {
+                            if complete && is_retryable(&err) {
+                                match spool.store(batch_id, &body) {
+                                    Ok(()) => {
+                                        self.logger.warn(&format!("Spooled analysis for {} to submit later.", batch_id));
+                                        self.next_flush = self.next_flush.or_else(|| Some(Instant::now() + self.spool_backoff.next()));
+                                    }
+                                    Err(io_err) => self.logger.error(&format!("Failed to spool analysis for {}: {}", batch_id, io_err)),
+                                }
+                            }
+                        }
+                        return Err(err);
+                    }
                 }
             }
             ApiMessage::SubmitMove { batch_id, best_move, callback } => {
-                let url = format!("{}/move/{}", self.endpoint, batch_id);
-                let res = self.client.post(&url).json(&MoveRequestBody {
-                    fishnet: Fishnet::authenticated(self.key.clone()),
-                    m: BestMove {
-                        best_move: best_move.clone(),
-                    },
-                }).send().await?;
+                if self.dry_run {
+                    self.logger.info(&format!("Dry run: Not submitting move {} for {}", best_move.unwrap_or(Uci::Null), batch_id));
+                    self.abort(batch_id).await?;
+                    callback.send(Acquired::NoContent).nevermind("callback dropped");
+                    return Ok(());
+                }
+                let url = self.endpoint.route_with(Route::Move, &batch_id.to_string());
+                let mut tried = 1;
+                let res = loop {
+                    let res = self.send("move", self.client.post(url.clone()).timeout(self.timeouts.submit).json(&MoveRequestBody {
+                        fishnet: Fishnet::authenticated(self.key()),
+                        m: BestMove {
+                            best_move: best_move.clone(),
+                        },
+                    })).await?;
+                    if !self.retry_with_next_key(res.status(), &mut tried) {
+                        break res;
+                    }
+                };
 
                 match res.status() {
                     StatusCode::NO_CONTENT => callback.send(Acquired::NoContent).nevermind("callback dropped"),
+                    StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE if rate_limit(&res).is_some() => {
+                        callback.send(Acquired::RateLimited(rate_limit(&res).unwrap())).nevermind("callback dropped");
+                    }
                     StatusCode::OK | StatusCode::ACCEPTED => {
                         if let Err(Acquired::Accepted(res)) = callback.send(Acquired::Accepted(res.json().await?)) {
                             self.logger.error("Acquired a batch while submitting move, but callback dropped. Aborting.");
@@ -672,3 +1771,175 @@ impl ApiActor {
         Ok(())
     }
 }
+
+/// Stands in for the API actor in tests. Responds to requests from an
+/// `ApiStub` with scripted acquire responses, and reports every request.
+#[cfg(test)]
+pub mod mock {
+    use std::collections::VecDeque;
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+    use shakmaty::uci::Uci;
+    use crate::bandwidth::Bandwidth;
+    use super::{Acquired, AcquireQuery, AnalysisPart, AnalysisStatus, ApiMessage, ApiStub, BatchId, KeyInfo};
+
+    /// Request received by the mock, without callbacks.
+    #[derive(Debug)]
+    pub enum Call {
+        CheckKey,
+        Status,
+        Abort(BatchId),
+        Acquire(AcquireQuery),
+        SubmitAnalysis {
+            batch_id: BatchId,
+            analysis: Vec<Option<AnalysisPart>>,
+            /// Progress report rather than the complete analysis.
+            partial: bool,
+        },
+        SubmitMove {
+            batch_id: BatchId,
+            best_move: Option<Uci>,
+        },
+        CloudEval(String),
+    }
+
+    pub struct MockApi {
+        rx: mpsc::UnboundedReceiver<ApiMessage>,
+        acquire: VecDeque<Acquired>,
+    }
+
+    pub fn channel() -> (ApiStub, MockApi) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let stub = ApiStub::new(tx, Arc::new(Bandwidth::new(None, None)), Arc::default(), Arc::default());
+        (stub, MockApi {
+            rx,
+            acquire: VecDeque::new(),
+        })
+    }
+
+    impl MockApi {
+        /// Scripts the response to the next acquire (or move submission).
+        /// Once the script runs out, there is no more work.
+        pub fn acquire(&mut self, acquired: Acquired) {
+            self.acquire.push_back(acquired);
+        }
+
+        /// Serves requests in the background, until all stubs are dropped.
+        /// Requests are reported in the order they were received.
+        pub fn spawn(self) -> mpsc::UnboundedReceiver<Call> {
+            let (tx, rx) = mpsc::unbounded_channel();
+            tokio::spawn(self.run(tx));
+            rx
+        }
+
+        async fn run(mut self, calls: mpsc::UnboundedSender<Call>) {
+            while let Some(msg) = self.rx.recv().await {
+                let call = match msg {
+                    ApiMessage::CheckKey { callback, .. } => {
+                        callback.send(Ok(KeyInfo::default())).ok();
+                        Call::CheckKey
+                    }
+                    ApiMessage::Status { callback } => {
+                        callback.send(AnalysisStatus::default()).ok();
+                        Call::Status
+                    }
+                    ApiMessage::Abort { batch_id } => Call::Abort(batch_id),
+                    ApiMessage::Acquire { query, callback } => {
+                        callback.send(self.acquire.pop_front().unwrap_or(Acquired::NoContent)).ok();
+                        Call::Acquire(query)
+                    }
+                    ApiMessage::SubmitAnalysis { batch_id, analysis, gone, .. } => Call::SubmitAnalysis {
+                        batch_id,
+                        analysis,
+                        partial: gone.is_some(),
+                    },
+                    ApiMessage::SubmitMove { batch_id, best_move, callback } => {
+                        callback.send(self.acquire.pop_front().unwrap_or(Acquired::NoContent)).ok();
+                        Call::SubmitMove { batch_id, best_move }
+                    }
+                    ApiMessage::CloudEval { fen, .. } => Call::CloudEval(fen),
+                };
+                calls.send(call).ok();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read as _;
+    use flate2::read::GzDecoder;
+    use serde_json::Value;
+    use tokio::io::{AsyncBufReadExt as _, AsyncReadExt as _, AsyncWriteExt as _, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+    use crate::configure::Verbose;
+    use crate::sim::batch_id;
+    use super::*;
+
+    /// Reads a request and returns its path, without query, and the key in
+    /// its body.
+    async fn read_request(stream: &mut BufReader<TcpStream>) -> io::Result<(String, String)> {
+        let mut request_line = String::new();
+        stream.read_line(&mut request_line).await?;
+        let target = request_line.split_whitespace().nth(1).unwrap_or("");
+        let path = target.split('?').next().unwrap_or("").to_owned();
+        let (mut content_length, mut gzip) = (0, false);
+        loop {
+            let mut header = String::new();
+            if stream.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+                break;
+            }
+            let (name, value) = header.split_once(':').unwrap_or((&header, ""));
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().expect("content length");
+            } else if name.eq_ignore_ascii_case("content-encoding") {
+                gzip = value.trim() == "gzip";
+            }
+        }
+        let mut body = vec![0; content_length];
+        stream.read_exact(&mut body).await?;
+        if gzip {
+            let mut decoded = Vec::new();
+            GzDecoder::new(&body[..]).read_to_end(&mut decoded)?;
+            body = decoded;
+        }
+        let body: Value = serde_json::from_slice(&body).expect("json body");
+        Ok((path, body["fishnet"]["apikey"].as_str().unwrap_or("").to_owned()))
+    }
+
+    /// Serves a fishnet endpoint that rejects `rejected` and accepts all
+    /// other keys. Reports the path and the key of each request.
+    async fn serve_rejecting(rejected: &'static str) -> (Endpoint, mpsc::UnboundedReceiver<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let endpoint = format!("http://{}/fishnet", listener.local_addr().expect("local addr")).parse().expect("endpoint");
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let mut stream = BufReader::new(stream);
+                let (path, key) = read_request(&mut stream).await.expect("request");
+                let status = if key == rejected { "403 Forbidden" } else { "204 No Content" };
+                tx.send((path, key)).ok();
+                let stream = stream.get_mut();
+                stream.write_all(format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).as_bytes()).await.expect("response");
+                stream.shutdown().await.ok();
+            }
+        });
+        (endpoint, rx)
+    }
+
+    #[tokio::test]
+    async fn test_analysis_submitted_with_next_key() {
+        let (endpoint, mut requests) = serve_rejecting("keyA").await;
+        let keys = vec!["keyA".parse().expect("key"), "keyB".parse().expect("key")];
+        let (mut api, actor) = channel(endpoint, keys, ApiConfig::new(reqwest::Client::new()), Logger::new(Verbose::default(), false));
+        tokio::spawn(actor.run());
+
+        let id = batch_id("abcdefgh");
+        api.submit_analysis(id, EvalFlavor::Nnue, None, None, vec![Some(AnalysisPart::Skipped { skipped: true })]);
+
+        let (path, key) = requests.recv().await.expect("first attempt");
+        assert_eq!((path.as_str(), key.as_str()), ("/fishnet/analysis/abcdefgh", "keyA"));
+        let (path, key) = requests.recv().await.expect("retry with next key");
+        assert_eq!((path.as_str(), key.as_str()), ("/fishnet/analysis/abcdefgh", "keyB"));
+    }
+}
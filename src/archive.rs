@@ -0,0 +1,76 @@
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write as _};
+use std::path::PathBuf;
+use std::time::SystemTime;
+use serde::Serialize;
+use serde_with::{serde_as, DisplayFromStr};
+use url::Url;
+use crate::api::{AnalysisPart, BatchId, LichessVariant};
+
+/// Default size at which the archive is rotated.
+pub const DEFAULT_MAX_SIZE: u64 = 100_000_000;
+
+/// Entry of the archive, one JSON object per line.
+#[serde_as]
+#[derive(Serialize)]
+struct Entry<'a> {
+    #[serde_as(as = "DisplayFromStr")]
+    id: BatchId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<&'a str>,
+    variant: LichessVariant,
+    /// Seconds since the Unix epoch.
+    finished_at: u64,
+    analysis: &'a [Option<AnalysisPart>],
+}
+
+/// Local record of submitted analysis. Once the file grows beyond the
+/// maximum size, it is renamed with the suffix `.1`, replacing the previous
+/// one, so that at most twice the maximum size is kept.
+pub struct Archive {
+    path: PathBuf,
+    max_size: u64,
+    file: File,
+    size: u64,
+}
+
+impl Archive {
+    pub fn open(path: PathBuf, max_size: u64) -> io::Result<Archive> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Archive {
+            path,
+            max_size,
+            file,
+            size,
+        })
+    }
+
+    pub fn record(&mut self, id: BatchId, url: Option<&Url>, variant: LichessVariant, analysis: &[Option<AnalysisPart>]) -> io::Result<()> {
+        let mut line = serde_json::to_vec(&Entry {
+            id,
+            url: url.map(Url::as_str),
+            variant,
+            finished_at: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+            analysis,
+        })?;
+        line.push(b'\n');
+
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(&line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let mut rotated = OsString::from(self.path.as_os_str());
+        rotated.push(".1");
+        fs::rename(&self.path, rotated)?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
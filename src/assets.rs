@@ -1,11 +1,17 @@
 use std::fmt;
-use std::io;
+use std::io::{self, Write};
 use std::fs::{File, OpenOptions};
 use std::path::{Path, PathBuf};
-use serde::Serialize;
+use std::process::{Command, Stdio};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
 use bitflags::bitflags;
 use tempfile::TempDir;
 use xz::read::XzDecoder;
+use crate::logger::Logger;
+
+/// Extraction is retried once if the written file does not match.
+const EXTRACT_ATTEMPTS: usize = 2;
 
 struct Asset {
     name: &'static str,
@@ -21,6 +27,7 @@ impl Asset {
         OpenOptions::new()
             .create(true)
             .write(true)
+            .truncate(true)
             .mode(0o700)
             .open(path)
     }
@@ -34,22 +41,78 @@ impl Asset {
         OpenOptions::new()
             .create(true)
             .write(true)
+            .truncate(true)
             .open(path)
     }
 
     fn create(&self, base: &Path) -> io::Result<PathBuf> {
         let path = base.join(self.name);
-        let mut file = if self.executable {
-            self.open_executable_file(&path)
+        let mut attempt = 1;
+        loop {
+            let expected = self.extract(&path)?;
+            match self.verify(&path, &expected) {
+                Ok(()) => return Ok(path),
+                Err(err) if attempt >= EXTRACT_ATTEMPTS => return Err(err),
+                Err(_) => attempt += 1,
+            }
+        }
+    }
+
+    /// Writes the decompressed asset and returns the SHA-256 of what should
+    /// have been written.
+    fn extract(&self, path: &Path) -> io::Result<[u8; 32]> {
+        let file = if self.executable {
+            self.open_executable_file(path)
         } else {
-            self.open_file(&path)
+            self.open_file(path)
         }?;
 
+        let mut writer = HashingWriter {
+            inner: file,
+            hasher: Sha256::new(),
+        };
         let mut decoder = XzDecoder::new(self.data);
-        io::copy(&mut decoder, &mut file)?;
+        io::copy(&mut decoder, &mut writer)?;
+
+        writer.inner.sync_all()?;
+        Ok(writer.hasher.finalize().into())
+    }
+
+    /// Reads back the extracted file, so that partial writes (for example
+    /// on a full disk) are caught before the engine is launched.
+    fn verify(&self, path: &Path, expected: &[u8; 32]) -> io::Result<()> {
+        let mut hasher = Sha256::new();
+        io::copy(&mut File::open(path)?, &mut hasher)?;
+        let actual: [u8; 32] = hasher.finalize().into();
+        if actual != *expected {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: checksum mismatch after extraction", self.name)));
+        }
+
+        // Networks are named after the prefix of their SHA-256.
+        if let Some(prefix) = self.name.strip_prefix("nn-").and_then(|n| n.strip_suffix(".nnue")) {
+            if !hex::encode(expected).starts_with(prefix) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: embedded network does not match its name", self.name)));
+            }
+        }
 
-        file.sync_all()?;
-        Ok(path)
+        Ok(())
+    }
+}
+
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
     }
 }
 
@@ -74,6 +137,7 @@ bitflags! {
         const AVX2   = 1 << 5;
         const BMI2   = 1 << 6;
         const INTEL  = 1 << 7; // amd supports bmi2, but pext is too slow
+        const AVX512 = 1 << 8; // no bundled build yet, detected for diagnostics
 
         const SF_SSE2         = Cpu::SSE2.bits;
         const SF_SSSE3        = Cpu::SF_SSE2.bits | Cpu::SSE.bits | Cpu::SSE2.bits | Cpu::SSSE3.bits;
@@ -94,6 +158,7 @@ impl Cpu {
         cpu.set(Cpu::SSE41, is_x86_feature_detected!("sse4.1"));
         cpu.set(Cpu::AVX2, is_x86_feature_detected!("avx2"));
         cpu.set(Cpu::BMI2, is_x86_feature_detected!("bmi2"));
+        cpu.set(Cpu::AVX512, is_x86_feature_detected!("avx512f") && is_x86_feature_detected!("avx512bw"));
 
         cpu.set(Cpu::INTEL, match raw_cpuid::CpuId::new().get_vendor_info() {
             Some(vendor) => vendor.as_string() == "GenuineIntel",
@@ -340,7 +405,7 @@ const STOCKFISH_MV: &[Asset] = &[
     },
 ];
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum EngineFlavor {
     Official,
     MultiVariant,
@@ -394,17 +459,120 @@ pub struct Assets {
 }
 
 impl Assets {
-    pub fn prepare(cpu: Cpu) -> io::Result<Assets> {
+    pub fn prepare(cpu: Cpu, logger: &Logger) -> io::Result<Assets> {
         let dir = tempfile::Builder::new().prefix("fishnet-").tempdir()?;
-        let sf = STOCKFISH.iter().find(|a| cpu.contains(a.needs)).expect("compatible stockfish");
+        let nnue = NNUE.create(dir.path())?.to_str().expect("nnue path printable").to_owned();
+        let (sf_name, official) = select(STOCKFISH, cpu, dir.path(), &nnue, logger)?;
+        let (_, multi_variant) = select(STOCKFISH_MV, cpu, dir.path(), &nnue, logger)?;
         Ok(Assets {
-            nnue: NNUE.create(dir.path())?.to_str().expect("nnue path printable").to_owned(),
-            sf_name: sf.name,
+            nnue,
+            sf_name,
             stockfish: ByEngineFlavor {
-                official: sf.create(dir.path())?,
-                multi_variant: STOCKFISH_MV.iter().find(|a| cpu.contains(a.needs)).expect("compatible stockfish").create(dir.path())?,
+                official,
+                multi_variant,
             },
             dir,
         })
     }
+
+    /// Benchmarks running one engine process per core against fewer
+    /// processes with several threads each, and returns the number of
+    /// threads per process with the highest total throughput. Layouts
+    /// with more threads have to be noticeably faster, to not switch on
+    /// noise.
+    pub fn threads_per_worker(&self, cores: usize, logger: &Logger) -> usize {
+        let mut best: Option<(usize, u64)> = None;
+        let mut threads = 1;
+        while threads <= cores {
+            match throughput(&self.stockfish.official, &self.nnue, cores / threads, threads) {
+                Ok(nps) => {
+                    logger.info(&format!("Benchmark: {} x {} threads: {} knps", cores / threads, threads, nps / 1000));
+                    let better = match best {
+                        Some((_, best_nps)) => nps > best_nps + best_nps / 20,
+                        None => true,
+                    };
+                    if better {
+                        best = Some((threads, nps));
+                    }
+                }
+                Err(err) => logger.warn(&format!("Benchmark with {} threads per worker failed: {}", threads, err)),
+            }
+            threads *= 2;
+        }
+        best.map_or(1, |(threads, _)| threads)
+    }
+}
+
+/// Picks the fastest build that the CPU claims to support and that survives
+/// a short bench. Virtual machines sometimes advertise instruction sets that
+/// they do not actually implement, so fall back one tier at a time.
+fn select(candidates: &'static [Asset], cpu: Cpu, base: &Path, nnue: &str, logger: &Logger) -> io::Result<(&'static str, PathBuf)> {
+    let mut compatible = candidates.iter().filter(|a| cpu.contains(a.needs)).peekable();
+    while let Some(asset) = compatible.next() {
+        let path = asset.create(base)?;
+        if compatible.peek().is_none() {
+            // Last resort. Do not bother benching.
+            return Ok((asset.name, path));
+        }
+        match bench(&path, nnue) {
+            Ok(()) => return Ok((asset.name, path)),
+            Err(err) => logger.warn(&format!("{} failed sanity bench ({}). Falling back to next build.", asset.name, err)),
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::NotFound, "no compatible stockfish build"))
+}
+
+fn bench(exe: &Path, nnue: &str) -> io::Result<()> {
+    let mut child = Command::new(exe)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    {
+        let mut stdin = child.stdin.take().expect("pipe stdin");
+        // Ignore write errors. The exit status tells what happened.
+        let _ = write!(stdin, "setoption name EvalFile value {}\nbench 16 1 5 current depth\nquit\n", nnue);
+    }
+
+    let status = child.wait()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(status.to_string()))
+    }
+}
+
+/// Total nodes per second of several engine processes searching the
+/// starting position at the same time.
+fn throughput(exe: &Path, nnue: &str, processes: usize, threads: usize) -> io::Result<u64> {
+    let mut children = Vec::with_capacity(processes);
+    for _ in 0..processes {
+        children.push(Command::new(exe)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?);
+    }
+
+    for child in &mut children {
+        let mut stdin = child.stdin.take().expect("pipe stdin");
+        // Ignore write errors. The exit status tells what happened.
+        let _ = write!(stdin, "setoption name EvalFile value {}\nbench 16 {} 2000 current movetime\nquit\n", nnue, threads);
+    }
+
+    let mut total = 0;
+    for child in children {
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(output.status.to_string()));
+        }
+        let nps = String::from_utf8_lossy(&output.stderr)
+            .lines()
+            .filter_map(|line| line.strip_prefix("Nodes/second"))
+            .find_map(|rest| rest.trim_start_matches(&[' ', ':'][..]).trim().parse::<u64>().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing nodes/second in bench output"))?;
+        total += nps;
+    }
+    Ok(total)
 }
@@ -0,0 +1,168 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+/// Rough size of the request line and headers of a request or response,
+/// which are not seen by the client.
+pub const HEADER_OVERHEAD: u64 = 300;
+
+/// Bytes exchanged with the endpoint and for updates, shared by the API actor
+/// and the updater. Bodies are counted exactly, headers are estimated.
+#[derive(Debug)]
+pub struct Bandwidth {
+    sent: AtomicU64,
+    received: AtomicU64,
+    cap: Option<u64>,
+    month: Mutex<MonthlyUsage>,
+    file: Option<PathBuf>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct MonthlyUsage {
+    month: Month,
+    bytes: u64,
+}
+
+impl Bandwidth {
+    /// Usage of the current month is persisted in `file`, so that the cap
+    /// also holds across restarts.
+    pub fn new(cap: Option<u64>, file: Option<PathBuf>) -> Bandwidth {
+        let month = Month::now();
+        let usage = file.as_ref()
+            .and_then(|file| fs::read_to_string(file).ok())
+            .and_then(|contents| MonthlyUsage::parse(&contents))
+            .filter(|usage| usage.month == month)
+            .unwrap_or(MonthlyUsage { month, bytes: 0 });
+        Bandwidth {
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            cap,
+            month: Mutex::new(usage),
+            file,
+        }
+    }
+
+    pub fn record_sent(&self, bytes: u64) {
+        self.sent.fetch_add(bytes, Ordering::Relaxed);
+        self.record_monthly(bytes);
+    }
+
+    pub fn record_received(&self, bytes: u64) {
+        self.received.fetch_add(bytes, Ordering::Relaxed);
+        self.record_monthly(bytes);
+    }
+
+    fn record_monthly(&self, bytes: u64) {
+        let mut usage = self.month.lock().expect("bandwidth month");
+        let month = Month::now();
+        if usage.month != month {
+            *usage = MonthlyUsage { month, bytes: 0 };
+        }
+        usage.bytes += bytes;
+    }
+
+    /// Bytes sent since startup.
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// Bytes received since startup.
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    /// Bytes sent and received in the current calendar month (UTC).
+    pub fn this_month(&self) -> u64 {
+        let usage = self.month.lock().expect("bandwidth month");
+        if usage.month == Month::now() { usage.bytes } else { 0 }
+    }
+
+    pub fn cap(&self) -> Option<u64> {
+        self.cap
+    }
+
+    pub fn cap_exceeded(&self) -> bool {
+        self.cap.is_some_and(|cap| self.this_month() >= cap)
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        match self.file {
+            Some(ref file) => {
+                let usage = *self.month.lock().expect("bandwidth month");
+                fs::write(file, format!("{} {}\n", usage.month, usage.bytes))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl MonthlyUsage {
+    fn parse(s: &str) -> Option<MonthlyUsage> {
+        let mut parts = s.split_whitespace();
+        let mut month = parts.next()?.splitn(2, '-');
+        Some(MonthlyUsage {
+            month: Month {
+                year: month.next()?.parse().ok()?,
+                month: month.next()?.parse().ok()?,
+            },
+            bytes: parts.next()?.parse().ok()?,
+        })
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+struct Month {
+    year: i64,
+    month: u32,
+}
+
+impl Month {
+    fn now() -> Month {
+        let days = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() / (60 * 60 * 24)) as i64;
+
+        // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        Month {
+            year: yoe + era * 400 + if month <= 2 { 1 } else { 0 },
+            month: month as u32,
+        }
+    }
+}
+
+impl fmt::Display for Month {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{:02}", self.year, self.month)
+    }
+}
+
+/// Formats byte counts like 1.5 GB.
+pub struct Bytes(pub u64);
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let units = ["B", "KB", "MB", "GB", "TB"];
+        let mut value = self.0 as f64;
+        let mut unit = 0;
+        while value >= 1000.0 && unit + 1 < units.len() {
+            value /= 1000.0;
+            unit += 1;
+        }
+        if unit == 0 {
+            write!(f, "{} B", self.0)
+        } else {
+            write!(f, "{:.1} {}", value, units[unit])
+        }
+    }
+}
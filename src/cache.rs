@@ -0,0 +1,51 @@
+use lru::LruCache;
+use crate::api::LichessVariant;
+use crate::ipc::PositionResponse;
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct EvalKey {
+    pub variant: LichessVariant,
+    pub epd: String,
+    pub nodes: u64,
+    pub multipv: u32,
+    pub depth: Option<u32>,
+}
+
+/// Recent analysis results, so that positions that recur across games
+/// (openings, simple endgames) are not analysed again.
+pub struct EvalCache {
+    entries: LruCache<EvalKey, PositionResponse>,
+    hits: u64,
+    misses: u64,
+}
+
+impl EvalCache {
+    pub fn new(capacity: usize) -> EvalCache {
+        EvalCache {
+            entries: LruCache::new(capacity),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn get(&mut self, key: &EvalKey) -> Option<PositionResponse> {
+        match self.entries.get(key) {
+            Some(res) => {
+                self.hits += 1;
+                Some(res.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: EvalKey, res: PositionResponse) {
+        self.entries.put(key, res);
+    }
+
+    pub fn hit_rate(&self) -> f64 {
+        self.hits as f64 / (self.hits + self.misses).max(1) as f64
+    }
+}
@@ -0,0 +1,43 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use rand::Rng;
+use rand::seq::SliceRandom;
+
+/// Probability of injecting each fault, as the bits of an `f64`. Zero when
+/// chaos mode is off.
+static PROBABILITY: AtomicU64 = AtomicU64::new(0);
+
+/// Lines the engine might send if something went very wrong.
+const GARBLED_UCI: &[&str] = &[
+    "",
+    "info",
+    "info depth",
+    "info depth x seldepth -1",
+    "info score cp",
+    "info score mate x pv",
+    "info multipv 0 score cp 10",
+    "info nodes -1 nps 0 time",
+    "info pv e2e9",
+    "\u{fffd}\u{fffd}",
+];
+
+/// Turns on fault injection for developers, with probability `p` for each
+/// opportunity.
+pub fn enable(p: f64) {
+    PROBABILITY.store(p.clamp(0.0, 1.0).to_bits(), Ordering::Relaxed);
+}
+
+/// Decides whether to inject a fault now.
+pub fn strike() -> bool {
+    let p = f64::from_bits(PROBABILITY.load(Ordering::Relaxed));
+    p > 0.0 && rand::thread_rng().gen_bool(p)
+}
+
+/// Artificial delay for a slow response.
+pub fn delay() -> Duration {
+    Duration::from_millis(rand::thread_rng().gen_range(0, 10_000))
+}
+
+pub fn garbled_uci_line() -> String {
+    GARBLED_UCI.choose(&mut rand::thread_rng()).copied().unwrap_or_default().to_owned()
+}
@@ -0,0 +1,628 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr, SpaceSeparator, StringWithSeparator};
+use shakmaty::fen::Fen;
+use shakmaty::uci::Uci;
+use tokio::io::{AsyncBufReadExt as _, AsyncRead, AsyncReadExt as _, AsyncWriteExt as _, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time;
+use crate::api::{Bound, EngineInfo, LichessVariant, PvLine, Score, Wdl, Work};
+use crate::assets::EngineFlavor;
+use crate::ipc::{Moves, Origin, Position, PositionFailed, PositionId, PositionResponse, Pull};
+use crate::logger::Logger;
+use crate::queue::QueueStub;
+use crate::util::{Cancellation, NevermindExt as _, RandomizedBackoff, Secret};
+
+/// Workers send a ping at least this often.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Connections without any message for this long are considered dead.
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Workers must authenticate within this time after connecting.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest hello accepted from a worker that is not yet authenticated.
+const MAX_HELLO_LEN: usize = 4 * 1024;
+
+/// Longest message accepted from an authenticated peer. Positions and results
+/// of long games fit easily.
+const MAX_LINE_LEN: usize = 1024 * 1024;
+
+/// Messages from worker to coordinator, one JSON object per line.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WorkerMessage {
+    Hello {
+        secret: Secret<String>,
+        cores: usize,
+    },
+    Pull {
+        slot: usize,
+        #[serde(default)]
+        result: Option<WireResult>,
+    },
+    Ping,
+}
+
+/// Messages from coordinator to worker, one JSON object per line.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CoordinatorMessage {
+    Welcome,
+    Position {
+        slot: usize,
+        position: WirePosition,
+    },
+    Pong,
+}
+
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WirePosition {
+    pub token: u64,
+    pub work: Work,
+    pub flavor: EngineFlavor,
+    pub variant: LichessVariant,
+    pub chess960: bool,
+    #[serde_as(as = "DisplayFromStr")]
+    pub fen: Fen,
+    #[serde_as(as = "StringWithSeparator::<SpaceSeparator, Uci>")]
+    pub moves: Vec<Uci>,
+    /// Number of earlier attempts that failed due to the engine.
+    #[serde(default)]
+    pub attempts: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WireResult {
+    pub token: u64,
+    /// `None` if the worker failed to analyse the position.
+    #[serde(default)]
+    pub analysis: Option<WireAnalysis>,
+    /// Whether the failed position should be analysed again, instead of
+    /// giving up on the batch.
+    #[serde(default)]
+    pub retry: bool,
+}
+
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WireAnalysis {
+    pub score: Score,
+    #[serde(default)]
+    pub bound: Option<Bound>,
+    /// Missing from older workers.
+    #[serde(default)]
+    pub wdl: Option<Wdl>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub best_move: Option<Uci>,
+    #[serde_as(as = "StringWithSeparator::<SpaceSeparator, Uci>")]
+    pub pv: Vec<Uci>,
+    pub depth: u32,
+    #[serde(default)]
+    pub seldepth: Option<u32>,
+    pub nodes: u64,
+    pub time_ms: u64,
+    #[serde(default)]
+    pub nps: Option<u32>,
+    #[serde(default)]
+    pub hashfull: Option<u32>,
+    #[serde(default)]
+    pub tbhits: Option<u64>,
+    #[serde(default)]
+    pub secondary: Vec<PvLine>,
+    #[serde(default)]
+    pub engine: Option<EngineInfo>,
+}
+
+pub fn secret_matches(expected: &str, given: &str) -> bool {
+    // Constant time for equal lengths.
+    expected.len() == given.len() && expected.bytes().zip(given.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+pub async fn coordinate(addr: SocketAddr, secret: Secret<String>, queue: QueueStub, logger: Logger) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    logger.info(&format!("Coordinating workers on {}", addr));
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let secret = secret.clone();
+        let queue = queue.clone();
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            match handle_worker(stream, &secret, queue, &logger).await {
+                Ok(()) => logger.info(&format!("Worker {} disconnected", peer)),
+                Err(err) => logger.warn(&format!("Worker {} disconnected: {}", peer, err)),
+            }
+        });
+    }
+}
+
+async fn write_message<W, T>(writer: &mut W, msg: &T) -> io::Result<()>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let mut line = serde_json::to_vec(msg).expect("serialize cluster message");
+    line.push(b'\n');
+    writer.write_all(&line).await
+}
+
+fn invalid_data<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+/// Reads one message per line, without buffering unbounded lines. Partial
+/// lines are kept when the read is cancelled, so that it can be used in
+/// `select!`.
+struct Lines<R> {
+    reader: BufReader<R>,
+    buf: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> Lines<R> {
+    fn new(reader: R) -> Lines<R> {
+        Lines {
+            reader: BufReader::new(reader),
+            buf: Vec::new(),
+        }
+    }
+
+    async fn next_line(&mut self, max_len: usize) -> io::Result<Option<String>> {
+        let limit = (max_len + 1).saturating_sub(self.buf.len()) as u64;
+        (&mut self.reader).take(limit).read_until(b'\n', &mut self.buf).await?;
+        if self.buf.last() == Some(&b'\n') {
+            self.buf.pop();
+            if self.buf.last() == Some(&b'\r') {
+                self.buf.pop();
+            }
+        } else if self.buf.len() > max_len {
+            return Err(invalid_data("line too long"));
+        } else if self.buf.is_empty() {
+            return Ok(None);
+        }
+        String::from_utf8(mem::take(&mut self.buf)).map(Some).map_err(invalid_data)
+    }
+}
+
+/// Positions handed out to a worker, by token.
+type Outstanding = Arc<Mutex<(u64, HashMap<u64, Position>)>>;
+
+async fn handle_worker(stream: TcpStream, secret: &Secret<String>, mut queue: QueueStub, logger: &Logger) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = Lines::new(reader);
+
+    // Handshake.
+    let cores = match time::timeout(HELLO_TIMEOUT, lines.next_line(MAX_HELLO_LEN)).await {
+        Ok(Ok(Some(line))) => match serde_json::from_str(&line).map_err(invalid_data)? {
+            WorkerMessage::Hello { secret: given, cores } if secret_matches(secret.expose(), given.expose()) => cores,
+            WorkerMessage::Hello { .. } => return Err(io::Error::new(io::ErrorKind::PermissionDenied, "wrong secret")),
+            _ => return Err(invalid_data("expected hello")),
+        },
+        Ok(Ok(None)) => return Ok(()),
+        Ok(Err(err)) => return Err(err),
+        Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "no hello")),
+    };
+    write_message(&mut writer, &CoordinatorMessage::Welcome).await?;
+    logger.info(&format!("Worker with {} cores connected", cores));
+
+    let outstanding: Outstanding = Arc::new(Mutex::new((0, HashMap::new())));
+    let (out_tx, mut out_rx) = mpsc::channel::<CoordinatorMessage>(cores.max(1) * 2);
+
+    let mut last_seen = Instant::now();
+    let res = loop {
+        tokio::select! {
+            line = time::timeout_at(time::Instant::from_std(last_seen + HEARTBEAT_TIMEOUT), lines.next_line(MAX_LINE_LEN)) => {
+                last_seen = Instant::now();
+                let line = match line {
+                    Ok(Ok(Some(line))) => line,
+                    Ok(Ok(None)) => break Ok(()),
+                    Ok(Err(err)) => break Err(err),
+                    Err(_) => break Err(io::Error::new(io::ErrorKind::TimedOut, "heartbeat timed out")),
+                };
+                match serde_json::from_str(&line) {
+                    Ok(WorkerMessage::Ping) => {
+                        out_tx.send(CoordinatorMessage::Pong).await.nevermind("writer alive");
+                    }
+                    Ok(WorkerMessage::Pull { slot, result }) => {
+                        let response = result.and_then(|result| {
+                            let position = outstanding.lock().expect("outstanding").1.remove(&result.token)?;
+                            Some(match result.analysis {
+                                Some(analysis) => Ok(PositionResponse {
+                                    wdl: analysis.wdl.unwrap_or_else(|| Wdl::from_score(analysis.score, position.ply())),
+                                    work: position.work,
+                                    position_id: position.position_id,
+                                    url: position.url,
+                                    score: analysis.score,
+                                    bound: analysis.bound,
+                                    best_move: analysis.best_move,
+                                    pv: analysis.pv,
+                                    depth: analysis.depth,
+                                    nodes: analysis.nodes,
+                                    time: Duration::from_millis(analysis.time_ms),
+                                    nps: analysis.nps,
+                                    seldepth: analysis.seldepth,
+                                    hashfull: analysis.hashfull,
+                                    tbhits: analysis.tbhits,
+                                    secondary: analysis.secondary,
+                                    engine: analysis.engine.map(Arc::new),
+                                    origin: Origin::Engine,
+                                }),
+                                None => Err(PositionFailed {
+                                    batch_id: position.work.id(),
+                                    position_id: position.position_id,
+                                    retry: if result.retry {
+                                        Some(Position {
+                                            attempts: position.attempts + 1,
+                                            ..position
+                                        })
+                                    } else {
+                                        None
+                                    },
+                                }),
+                            })
+                        });
+
+                        let (callback, waiter) = oneshot::channel();
+                        queue.pull(Pull { worker: None, response, callback }).await;
+
+                        let out_tx = out_tx.clone();
+                        let outstanding = outstanding.clone();
+                        tokio::spawn(async move {
+                            // Dropping the waiter before a position was sent
+                            // leaves it in the queue.
+                            let position = tokio::select! {
+                                _ = out_tx.closed() => return,
+                                res = waiter => match res {
+                                    Ok(position) => position,
+                                    Err(_) => return,
+                                },
+                            };
+                            let wire = {
+                                let mut outstanding = outstanding.lock().expect("outstanding");
+                                outstanding.0 += 1;
+                                let token = outstanding.0;
+                                outstanding.1.insert(token, position.clone());
+                                WirePosition {
+                                    token,
+                                    work: position.work,
+                                    flavor: position.flavor,
+                                    variant: position.variant,
+                                    chess960: position.chess960,
+                                    fen: position.fen,
+                                    moves: position.moves.to_vec(),
+                                    attempts: position.attempts,
+                                }
+                            };
+                            out_tx.send(CoordinatorMessage::Position { slot, position: wire }).await.nevermind("worker gone");
+                        });
+                    }
+                    Ok(WorkerMessage::Hello { .. }) => break Err(invalid_data("unexpected hello")),
+                    Err(err) => break Err(invalid_data(err)),
+                }
+            }
+            Some(msg) = out_rx.recv() => {
+                if let Err(err) = write_message(&mut writer, &msg).await {
+                    break Err(err);
+                }
+            }
+        }
+    };
+
+    // Positions that never came back count as failed.
+    drop(out_rx);
+    let lost: Vec<Position> = outstanding.lock().expect("outstanding").1.drain().map(|(_, p)| p).collect();
+    for position in lost {
+        let (callback, _) = oneshot::channel();
+        queue.pull(Pull {
+            worker: None,
+            response: Some(Err(PositionFailed {
+                batch_id: position.work.id(),
+                position_id: position.position_id,
+                retry: None,
+            })),
+            callback,
+        }).await;
+    }
+
+    res
+}
+
+pub fn channel(coordinator: String, secret: Secret<String>, cores: usize, logger: Logger) -> (ClusterStub, ClusterActor) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    (ClusterStub { tx }, ClusterActor::new(rx, coordinator, secret, cores, logger.module("cluster")))
+}
+
+#[derive(Clone)]
+pub struct ClusterStub {
+    tx: mpsc::UnboundedSender<Pull>,
+}
+
+impl ClusterStub {
+    pub fn pull(&mut self, pull: Pull) {
+        self.tx.send(pull).nevermind("cluster actor dropped");
+    }
+}
+
+pub struct ClusterActor {
+    rx: mpsc::UnboundedReceiver<Pull>,
+    coordinator: String,
+    secret: Secret<String>,
+    cores: usize,
+    /// Engine workers waiting for a position, by slot.
+    waiting: HashMap<usize, oneshot::Sender<Position>>,
+    next_slot: usize,
+    /// Positions received on the current connection, by token.
+    tokens: HashSet<u64>,
+    backoff: RandomizedBackoff,
+    logger: Logger,
+}
+
+impl ClusterActor {
+    fn new(rx: mpsc::UnboundedReceiver<Pull>, coordinator: String, secret: Secret<String>, cores: usize, logger: Logger) -> ClusterActor {
+        ClusterActor {
+            rx,
+            coordinator,
+            secret,
+            cores,
+            waiting: HashMap::new(),
+            next_slot: 0,
+            tokens: HashSet::new(),
+            backoff: RandomizedBackoff::default(),
+            logger,
+        }
+    }
+
+    pub async fn run(mut self) {
+        self.logger.debug("Cluster actor started");
+
+        loop {
+            let res = match TcpStream::connect(self.coordinator.as_str()).await {
+                Ok(stream) => self.session(stream).await,
+                Err(err) => Err(err),
+            };
+
+            match res {
+                Ok(()) => break,
+                Err(err) => {
+                    // Tokens are only valid for a single connection.
+                    self.tokens.clear();
+
+                    let backoff = self.backoff.next();
+                    self.logger.warn(&format!("Connection to coordinator {} failed: {}. Reconnecting in {:?}", self.coordinator, err, backoff));
+
+                    // Keep collecting requests from engine workers.
+                    let sleep = time::sleep(backoff);
+                    tokio::pin!(sleep);
+                    loop {
+                        tokio::select! {
+                            pull = self.rx.recv() => match pull {
+                                Some(pull) => {
+                                    self.handle_pull(pull);
+                                }
+                                None => return,
+                            },
+                            _ = &mut sleep => break,
+                        }
+                    }
+                }
+            }
+        }
+
+        self.logger.debug("Cluster actor exited");
+    }
+
+    /// Runs a single connection. Returns `Ok(())` when shutting down.
+    async fn session(&mut self, stream: TcpStream) -> io::Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = Lines::new(reader);
+
+        // Handshake.
+        write_message(&mut writer, &WorkerMessage::Hello {
+            secret: self.secret.clone(),
+            cores: self.cores,
+        }).await?;
+        match time::timeout(HEARTBEAT_TIMEOUT, lines.next_line(MAX_LINE_LEN)).await {
+            Ok(Ok(Some(line))) => match serde_json::from_str(&line).map_err(invalid_data)? {
+                CoordinatorMessage::Welcome => (),
+                _ => return Err(invalid_data("expected welcome")),
+            },
+            Ok(Ok(None)) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "rejected by coordinator")),
+            Ok(Err(err)) => return Err(err),
+            Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "no welcome")),
+        }
+        self.logger.info(&format!("Connected to coordinator {}", self.coordinator));
+        self.backoff.reset();
+
+        // Request work for engine workers that were waiting while
+        // disconnected.
+        self.waiting.retain(|_, callback| !callback.is_closed());
+        let mut slots: Vec<usize> = self.waiting.keys().copied().collect();
+        slots.sort_unstable();
+        for slot in slots {
+            write_message(&mut writer, &WorkerMessage::Pull { slot, result: None }).await?;
+        }
+
+        let mut last_seen = Instant::now();
+        let mut ping = time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            tokio::select! {
+                pull = self.rx.recv() => match pull {
+                    Some(pull) => {
+                        let msg = self.handle_pull(pull);
+                        write_message(&mut writer, &msg).await?;
+                    }
+                    None => return Ok(()),
+                },
+                line = time::timeout_at(time::Instant::from_std(last_seen + HEARTBEAT_TIMEOUT), lines.next_line(MAX_LINE_LEN)) => {
+                    last_seen = Instant::now();
+                    match line {
+                        Ok(Ok(Some(line))) => match serde_json::from_str(&line).map_err(invalid_data)? {
+                            CoordinatorMessage::Position { slot, position } => self.handle_position(slot, position),
+                            CoordinatorMessage::Pong => (),
+                            CoordinatorMessage::Welcome => return Err(invalid_data("unexpected welcome")),
+                        },
+                        Ok(Ok(None)) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "closed by coordinator")),
+                        Ok(Err(err)) => return Err(err),
+                        Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, "heartbeat timed out")),
+                    }
+                }
+                _ = ping.tick() => {
+                    write_message(&mut writer, &WorkerMessage::Ping).await?;
+                }
+            }
+        }
+    }
+
+    fn handle_pull(&mut self, pull: Pull) -> WorkerMessage {
+        let (response, callback) = pull.split();
+
+        // Results for positions from a previous connection are dropped. The
+        // coordinator already gave them back.
+        let result = response.and_then(|res| match res {
+            Ok(res) => {
+                let token = res.position_id.0 as u64;
+                self.tokens.take(&token)?;
+                Some(WireResult {
+                    token,
+                    analysis: Some(WireAnalysis {
+                        score: res.score,
+                        bound: res.bound,
+                        wdl: Some(res.wdl),
+                        best_move: res.best_move,
+                        pv: res.pv,
+                        depth: res.depth,
+                        nodes: res.nodes,
+                        time_ms: res.time.as_millis() as u64,
+                        nps: res.nps,
+                        seldepth: res.seldepth,
+                        hashfull: res.hashfull,
+                        tbhits: res.tbhits,
+                        secondary: res.secondary,
+                        engine: res.engine.as_deref().cloned(),
+                    }),
+                    retry: false,
+                })
+            }
+            Err(failed) => {
+                let token = failed.position_id.0 as u64;
+                self.tokens.take(&token)?;
+                Some(WireResult {
+                    token,
+                    analysis: None,
+                    retry: failed.retry.is_some(),
+                })
+            }
+        });
+
+        let slot = self.next_slot;
+        self.next_slot += 1;
+        self.waiting.insert(slot, callback);
+        WorkerMessage::Pull { slot, result }
+    }
+
+    fn handle_position(&mut self, slot: usize, wire: WirePosition) {
+        if let Some(callback) = self.waiting.remove(&slot) {
+            let token = wire.token;
+            // The token stands in for the position id, so that the result
+            // can be matched up again.
+            if callback.send(Position {
+                work: wire.work,
+                position_id: PositionId(token as usize),
+                flavor: wire.flavor,
+                url: None,
+                variant: wire.variant,
+                chess960: wire.chess960,
+                fen: wire.fen,
+                moves: Moves::from(wire.moves),
+                attempts: wire.attempts,
+                cancellation: Cancellation::default(),
+            }).is_ok() {
+                self.tokens.insert(token);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::configure::Verbose;
+    use crate::sim::batch_id;
+    use super::*;
+
+    fn wire_position(token: u64) -> WirePosition {
+        WirePosition {
+            token,
+            work: Work::Analysis {
+                id: batch_id("aaaaaaaa"),
+                nodes: None,
+                multipv: None,
+                movetime: None,
+                depth: None,
+            },
+            flavor: EngineFlavor::Official,
+            variant: LichessVariant::Standard,
+            chess960: false,
+            fen: Default::default(),
+            moves: Vec::new(),
+            attempts: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lines_bounded() {
+        let mut data = b"hello\r\nworld\n".to_vec();
+        data.extend_from_slice(&[b'x'; 20]);
+        data.push(b'\n');
+        let mut lines = Lines::new(&data[..]);
+        assert_eq!(lines.next_line(10).await.expect("line").as_deref(), Some("hello"));
+        assert_eq!(lines.next_line(10).await.expect("line").as_deref(), Some("world"));
+        assert_eq!(lines.next_line(10).await.expect_err("too long").kind(), io::ErrorKind::InvalidData);
+
+        let mut lines = Lines::new(&b"last"[..]);
+        assert_eq!(lines.next_line(10).await.expect("line").as_deref(), Some("last"));
+        assert_eq!(lines.next_line(10).await.expect("eof"), None);
+    }
+
+    #[tokio::test]
+    async fn test_failure_reported_for_failed_position() {
+        let (_stub, mut actor) = channel("localhost:9670".to_owned(), Secret::new("secret".to_owned()), 2, Logger::new(Verbose::default(), false));
+
+        // Two positions of the same batch.
+        let mut positions = Vec::new();
+        for token in 1..=2 {
+            let (callback, waiter) = oneshot::channel();
+            let slot = match actor.handle_pull(Pull { worker: None, response: None, callback }) {
+                WorkerMessage::Pull { slot, result: None } => slot,
+                msg => panic!("unexpected message: {:?}", msg),
+            };
+            actor.handle_position(slot, wire_position(token));
+            positions.push(waiter.await.expect("position"));
+        }
+
+        let failed = positions.pop().expect("second position");
+        let (callback, _waiter) = oneshot::channel();
+        match actor.handle_pull(Pull {
+            worker: None,
+            response: Some(Err(PositionFailed {
+                batch_id: failed.work.id(),
+                position_id: failed.position_id,
+                retry: Some(failed),
+            })),
+            callback,
+        }) {
+            WorkerMessage::Pull { result: Some(WireResult { token, analysis: None, retry }), .. } => {
+                assert_eq!(token, 2);
+                assert!(retry);
+            }
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::panic;
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use backtrace::Backtrace;
+use reqwest::header::CONTENT_TYPE;
+use tokio::time;
+use tokio_compat_02::FutureExt as _;
+use url::Url;
+use crate::api::{self, ApiConfig, BatchId, Timeouts};
+use crate::bandwidth::Bandwidth;
+use crate::configure::{redacted_url, ClientOpt, Endpoint, Key};
+use crate::logger::Logger;
+
+/// Time allowed for giving back batches and uploading the report, before
+/// exiting anyway.
+const CLEANUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Everything needed to clean up after a panic. The runtime may be gone by
+/// then, so requests are made from a fresh one.
+pub struct CrashContext {
+    pub endpoint: Endpoint,
+    pub keys: Vec<Key>,
+    pub client: ClientOpt,
+    pub bandwidth: Arc<Bandwidth>,
+    /// Written to the report, so it must not contain keys or secrets.
+    pub config: String,
+    pub dir: PathBuf,
+    pub upload: Option<Url>,
+    pub logger: Logger,
+}
+
+/// Keeps the list of pending batches up to date for the panic hook.
+#[derive(Clone)]
+pub struct CrashGuard {
+    pending: Arc<Mutex<Vec<BatchId>>>,
+}
+
+impl CrashGuard {
+    pub fn set_pending(&self, batch_ids: Vec<BatchId>) {
+        *self.pending.lock().expect("crash guard") = batch_ids;
+    }
+}
+
+/// Installs a panic hook that gives back pending batches, saves bandwidth
+/// usage, writes a crash report and then exits.
+pub fn install(ctx: CrashContext) -> CrashGuard {
+    let guard = CrashGuard {
+        pending: Arc::new(Mutex::new(Vec::new())),
+    };
+    let pending = guard.pending.clone();
+    let handled = AtomicBool::new(false);
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        if handled.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // Do not wait for the lock, in case the panic happened while
+        // holding it.
+        let pending = pending.try_lock().map(|p| p.clone()).unwrap_or_default();
+        handle_panic(&ctx, &info.to_string(), pending);
+        process::exit(101);
+    }));
+    guard
+}
+
+fn handle_panic(ctx: &CrashContext, message: &str, pending: Vec<BatchId>) {
+    let report = report(ctx, message, &pending);
+    let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let path = ctx.dir.join(format!("fishnet-crash-{}.txt", timestamp));
+    match fs::write(&path, &report) {
+        Ok(()) => eprintln!("Crash report written to {}", path.display()),
+        Err(err) => eprintln!("Failed to write crash report to {}: {}", path.display(), err),
+    }
+
+    if let Err(err) = ctx.bandwidth.save() {
+        eprintln!("Failed to save bandwidth usage: {}", err);
+    }
+
+    let endpoint = ctx.endpoint.clone();
+    let keys = ctx.keys.clone();
+    let client = ctx.client.clone();
+    let timeouts = Timeouts::new(&ctx.client);
+    let bandwidth = ctx.bandwidth.clone();
+    let upload = ctx.upload.clone();
+    let logger = ctx.logger.detached();
+    let cleanup = thread::spawn(move || {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().expect("cleanup runtime");
+        rt.block_on(async move {
+            let (instance_id, instance_label) = (client.instance_id.clone(), client.instance_label.clone());
+            // Worked at startup, unless the files changed since.
+            let (api_client, client) = match api::api_client(&client).and_then(|api_client| Ok((api_client, api::http_client(&client)?))) {
+                Ok(clients) => clients,
+                Err(err) => {
+                    eprintln!("Failed to clean up after crash: {}", err);
+                    return;
+                }
+            };
+            let cleanup = async {
+                if !pending.is_empty() {
+                    let (mut api, mut api_actor) = api::channel(endpoint, keys, ApiConfig {
+                        timeouts,
+                        bandwidth,
+                        ..ApiConfig::new(api_client)
+                    }, logger);
+                    api_actor.set_instance(instance_id.as_deref(), instance_label.as_deref());
+                    for batch_id in pending {
+                        api.abort(batch_id);
+                    }
+                    drop(api);
+                    api_actor.run().await;
+                }
+                if let Some(url) = upload {
+                    let res = client.post(url.clone()).header(CONTENT_TYPE, "text/plain").body(report).send().compat().await;
+                    match res.and_then(|res| res.error_for_status()) {
+                        Ok(_) => eprintln!("Crash report uploaded to {}", redacted_url(&url)),
+                        Err(_) => eprintln!("Failed to upload crash report to {}", redacted_url(&url)),
+                    }
+                }
+            };
+            if time::timeout(CLEANUP_TIMEOUT, cleanup).await.is_err() {
+                eprintln!("Timed out cleaning up after crash");
+            }
+        });
+    });
+    cleanup.join().ok();
+}
+
+fn report(ctx: &CrashContext, message: &str, pending: &[BatchId]) -> String {
+    let mut report = String::new();
+    writeln!(report, "fishnet/{} crashed: {}", env!("CARGO_PKG_VERSION"), message).ok();
+    writeln!(report).ok();
+    writeln!(report, "## Configuration").ok();
+    writeln!(report, "{}", ctx.config.trim_end()).ok();
+    writeln!(report, "Pending batches: {}", pending.len()).ok();
+    writeln!(report).ok();
+    writeln!(report, "## Backtrace").ok();
+    writeln!(report, "{:?}", Backtrace::new()).ok();
+    writeln!(report, "## Recent log").ok();
+    for line in ctx.logger.try_recent_lines().unwrap_or_default() {
+        writeln!(report, "{}", line).ok();
+    }
+    report
+}
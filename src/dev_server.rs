@@ -0,0 +1,325 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read as _};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use flate2::read::GzDecoder;
+use rand::Rng as _;
+use rand::seq::SliceRandom as _;
+use serde_json::{json, Value};
+use shakmaty::{Chess, Position as _};
+use shakmaty::fen::Fen;
+use shakmaty::uci::Uci;
+use tokio::io::{AsyncBufReadExt as _, AsyncReadExt as _, AsyncWriteExt as _, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use crate::api::{BatchId, NodeLimit};
+use crate::configure::{DevServerOpt, Workload};
+use crate::logger::Logger;
+
+/// Largest accepted request body. Analysis of a long game with MultiPV can
+/// get big.
+const MAX_BODY: usize = 4 * 1024 * 1024;
+
+/// Acquired batches that are neither submitted nor aborted after this long
+/// are handed out again, like lila does.
+const REASSIGN_AFTER: Duration = Duration::from_secs(10 * 60);
+
+struct Batch {
+    id: BatchId,
+    analysis: bool,
+    /// Body of the acquire response.
+    body: Value,
+    /// Number of positions, for checking submitted analysis.
+    positions: usize,
+    acquired_at: Option<Instant>,
+}
+
+/// Generates work and keeps track of it, like lila's fishnet module.
+struct DevServer {
+    opt: DevServerOpt,
+    created: u64,
+    completed: u64,
+    aborted: u64,
+    queue: VecDeque<Batch>,
+    acquired: HashMap<BatchId, Batch>,
+    logger: Logger,
+}
+
+enum Response {
+    Json(&'static str, Value),
+    Text(&'static str, String),
+    NoContent,
+}
+
+pub async fn serve(addr: SocketAddr, opt: DevServerOpt, logger: Logger) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    logger.info(&format!("Serving {} work on http://{}/fishnet (use as --endpoint, any key is accepted)",
+                         match opt.dev_workload.unwrap_or_default() {
+                             Workload::Analysis => "analysis",
+                             Workload::Move => "move",
+                             Workload::Mixed => "analysis and move",
+                         }, addr));
+    let server = Arc::new(Mutex::new(DevServer {
+        opt,
+        created: 0,
+        completed: 0,
+        aborted: 0,
+        queue: VecDeque::new(),
+        acquired: HashMap::new(),
+        logger: logger.clone(),
+    }));
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let server = server.clone();
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_connection(stream, server).await {
+                logger.debug(&format!("Connection from {} failed: {}", peer, err));
+            }
+        });
+    }
+}
+
+async fn handle_connection(stream: TcpStream, server: Arc<Mutex<DevServer>>) -> io::Result<()> {
+    let mut stream = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await?;
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or("").to_owned(), parts.next().unwrap_or(""));
+    let mut target = target.splitn(2, '?');
+    let (path, query) = (target.next().unwrap_or("").to_owned(), target.next().unwrap_or("").to_owned());
+
+    let mut content_length = 0;
+    let mut gzip = false;
+    loop {
+        let mut header = String::new();
+        if stream.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+            break;
+        }
+        let mut parts = header.splitn(2, ':');
+        if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(usize::MAX);
+            } else if name.trim().eq_ignore_ascii_case("content-encoding") {
+                gzip = value.trim().eq_ignore_ascii_case("gzip");
+            }
+        }
+    }
+
+    let response = if content_length > MAX_BODY {
+        Response::Text("413 Payload Too Large", "request body too large\n".to_owned())
+    } else {
+        let mut body = vec![0; content_length];
+        stream.read_exact(&mut body).await?;
+        if gzip {
+            let mut decoded = Vec::new();
+            GzDecoder::new(&body[..]).read_to_end(&mut decoded)?;
+            body = decoded;
+        }
+        let stop = query.split('&').any(|p| p == "stop=true");
+        let mut server = server.lock().expect("dev server");
+        server.handle(&method, &path, stop, &body)
+    };
+
+    let (status, content_type, body) = match response {
+        Response::Json(status, value) => (status, "application/json", value.to_string()),
+        Response::Text(status, text) => (status, "text/plain", text),
+        Response::NoContent => ("204 No Content", "text/plain", String::new()),
+    };
+    let stream = stream.get_mut();
+    stream.write_all(format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, content_type, body.len(), body).as_bytes()).await?;
+    stream.shutdown().await
+}
+
+impl DevServer {
+    fn handle(&mut self, method: &str, path: &str, stop: bool, body: &[u8]) -> Response {
+        // Only the end of the path matters, so that any prefix like
+        // /fishnet works.
+        let mut segments = path.trim_end_matches('/').rsplit('/');
+        let last = segments.next().unwrap_or("");
+        let parent = segments.next().unwrap_or("");
+        let body = match serde_json::from_slice::<Value>(body) {
+            Ok(body) => body,
+            Err(_) if body.is_empty() => Value::Null,
+            Err(err) => return Response::Text("400 Bad Request", format!("invalid json: {}\n", err)),
+        };
+        match (method, parent, last) {
+            ("GET", "key", _) => Response::Json("200 OK", json!({
+                "userId": "dev",
+                "provider": "fishnet dev-server",
+                "scopes": ["analysis", "move"],
+            })),
+            ("GET", _, "status") => Response::Json("200 OK", self.status()),
+            ("POST", _, "acquire") => self.acquire(),
+            ("POST", "analysis", id) => self.submit_analysis(id, &body),
+            ("POST", "move", id) => self.submit_move(id, &body, stop),
+            ("POST", "abort", id) => self.abort(id),
+            _ => Response::Text("404 Not Found", "not found\n".to_owned()),
+        }
+    }
+
+    fn status(&self) -> Value {
+        let queued = self.queue.len() + if self.exhausted() { 0 } else { 1 };
+        json!({
+            "analysis": {
+                "user": {
+                    "acquired": self.acquired.len(),
+                    "queued": queued,
+                    "oldest": 0,
+                },
+                "system": {
+                    "acquired": 0,
+                    "queued": 0,
+                    "oldest": 0,
+                },
+            },
+        })
+    }
+
+    fn exhausted(&self) -> bool {
+        self.opt.dev_batches.is_some_and(|limit| self.created >= limit)
+    }
+
+    fn acquire(&mut self) -> Response {
+        let now = Instant::now();
+        let expired: Vec<BatchId> = self.acquired.values()
+            .filter(|b| b.acquired_at.is_some_and(|at| now.duration_since(at) >= REASSIGN_AFTER))
+            .map(|b| b.id)
+            .collect();
+        for id in expired {
+            self.logger.warn(&format!("Batch {} was not submitted in {:?}. Handing it out again.", id, REASSIGN_AFTER));
+            self.queue.extend(self.acquired.remove(&id));
+        }
+
+        let mut batch = match self.queue.pop_front().or_else(|| self.generate()) {
+            Some(batch) => batch,
+            None => return Response::NoContent,
+        };
+        batch.acquired_at = Some(now);
+        let body = batch.body.clone();
+        self.logger.info(&format!("Batch {} acquired ({} positions)", batch.id, batch.positions));
+        self.acquired.insert(batch.id, batch);
+        Response::Json("202 Accepted", body)
+    }
+
+    fn generate(&mut self) -> Option<Batch> {
+        if self.exhausted() {
+            return None;
+        }
+        self.created += 1;
+        let id: BatchId = format!("dev{:05}", self.created).parse().expect("batch id");
+
+        let mut rng = rand::thread_rng();
+        let analysis = match self.opt.dev_workload.unwrap_or_default() {
+            Workload::Analysis => true,
+            Workload::Move => false,
+            Workload::Mixed => rng.gen(),
+        };
+        let moves = random_game(self.opt.dev_plies.unwrap_or(40));
+        let position: Fen = Default::default();
+        let work = if analysis {
+            json!({
+                "type": "analysis",
+                "id": id.to_string(),
+                "nodes": NodeLimit::default(),
+            })
+        } else {
+            json!({
+                "type": "move",
+                "id": id.to_string(),
+                "level": rng.gen_range(1, 9),
+            })
+        };
+        Some(Batch {
+            id,
+            analysis,
+            positions: if analysis { moves.len() + 1 } else { 1 },
+            body: json!({
+                "work": work,
+                "game_id": "",
+                "position": position.to_string(),
+                "variant": "standard",
+                "moves": moves.iter().map(|m| m.to_string()).collect::<Vec<_>>().join(" "),
+            }),
+            acquired_at: None,
+        })
+    }
+
+    fn take_acquired(&mut self, id: &str, analysis: bool) -> Result<Batch, Response> {
+        let batch = id.parse().ok()
+            .and_then(|id: BatchId| self.acquired.remove(&id))
+            .ok_or_else(|| Response::Text("410 Gone", "batch not acquired\n".to_owned()))?;
+        if batch.analysis != analysis {
+            let kind = if batch.analysis { "analysis" } else { "move" };
+            self.acquired.insert(batch.id, batch);
+            return Err(Response::Text("400 Bad Request", format!("expected {} submission\n", kind)));
+        }
+        Ok(batch)
+    }
+
+    fn submit_analysis(&mut self, id: &str, body: &Value) -> Response {
+        let batch = match self.take_acquired(id, true) {
+            Ok(batch) => batch,
+            Err(res) => return res,
+        };
+        let parts = body["analysis"].as_array().map_or(&[][..], |parts| &parts[..]);
+        if parts.len() != batch.positions {
+            self.logger.warn(&format!("Batch {}: Expected {} positions, got {}", batch.id, batch.positions, parts.len()));
+            self.acquired.insert(batch.id, batch);
+            return Response::Text("400 Bad Request", "wrong number of positions\n".to_owned());
+        }
+        let done = parts.iter().filter(|p| !p.is_null()).count();
+        if done < batch.positions {
+            self.logger.debug(&format!("Batch {}: Progress {}/{}", batch.id, done, batch.positions));
+            self.acquired.insert(batch.id, batch);
+            return Response::NoContent;
+        }
+        self.completed += 1;
+        self.logger.info(&format!("Batch {} analysed in {:?} ({} completed, {} aborted)", batch.id, batch.acquired_at.map(|at| at.elapsed()).unwrap_or_default(), self.completed, self.aborted));
+        Response::NoContent
+    }
+
+    fn submit_move(&mut self, id: &str, body: &Value, stop: bool) -> Response {
+        let batch = match self.take_acquired(id, false) {
+            Ok(batch) => batch,
+            Err(res) => return res,
+        };
+        self.completed += 1;
+        self.logger.info(&format!("Batch {} played {} ({} completed, {} aborted)", batch.id, body["move"]["bestmove"].as_str().unwrap_or("(none)"), self.completed, self.aborted));
+        if stop {
+            Response::NoContent
+        } else {
+            self.acquire()
+        }
+    }
+
+    fn abort(&mut self, id: &str) -> Response {
+        match id.parse().ok().and_then(|id: BatchId| self.acquired.remove(&id)) {
+            Some(mut batch) => {
+                self.aborted += 1;
+                self.logger.info(&format!("Batch {} aborted. Handing it out again.", batch.id));
+                batch.acquired_at = None;
+                self.queue.push_front(batch);
+                Response::NoContent
+            }
+            None => Response::Text("404 Not Found", "batch not acquired\n".to_owned()),
+        }
+    }
+}
+
+/// Random legal moves from the starting position, until the game ends.
+fn random_game(plies: usize) -> Vec<Uci> {
+    let mut rng = rand::thread_rng();
+    let mut pos: Chess = Default::default();
+    let mut moves = Vec::new();
+    while moves.len() < plies {
+        let m = match pos.legal_moves().choose(&mut rng) {
+            Some(m) => m.clone(),
+            None => break,
+        };
+        moves.push(Uci::from_standard(&m));
+        pos.play_unchecked(&m);
+    }
+    moves
+}
@@ -0,0 +1,144 @@
+//! Queue status pushed by the server as server-sent events, instead of
+//! asking for it before every acquire.
+
+use std::mem;
+use std::sync::Arc;
+use std::time::Duration;
+use reqwest::StatusCode;
+use tokio::sync::watch;
+use tokio::time;
+use tokio_compat_02::FutureExt as _;
+use crate::api::{AnalysisStatus, StatusResponseBody};
+use crate::bandwidth::{Bandwidth, HEADER_OVERHEAD};
+use crate::configure::{Endpoint, Route};
+use crate::logger::Logger;
+use crate::util::RandomizedBackoff;
+
+/// The server is expected to send at least a comment this often, so that
+/// dead connections are noticed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Subscriptions are renewed after this long anyway.
+const MAX_SUBSCRIPTION: Duration = Duration::from_secs(60 * 60);
+
+enum Subscription {
+    /// The server does not stream the queue status.
+    Unsupported,
+    /// The server ended the stream.
+    Ended,
+}
+
+/// Subscribes to the queue status. The receiver holds the latest status,
+/// or `None` while not connected, and stops changing if the server does not
+/// support the stream.
+pub fn spawn(endpoint: Endpoint, client: reqwest::Client, bandwidth: Arc<Bandwidth>, logger: Logger) -> watch::Receiver<Option<AnalysisStatus>> {
+    let (tx, rx) = watch::channel(None);
+    let logger = logger.module("events");
+    tokio::spawn(async move {
+        let mut backoff = RandomizedBackoff::default();
+        loop {
+            match subscribe(&endpoint, &client, &bandwidth, &tx, &mut backoff).compat().await {
+                Ok(Subscription::Unsupported) => {
+                    logger.info("Server does not stream the queue status. Asking before each acquire instead.");
+                    break;
+                }
+                Ok(Subscription::Ended) => (),
+                Err(err) => logger.debug(&format!("Queue status stream interrupted: {}", err)),
+            }
+            // Stale until subscribed again.
+            if tx.send(None).is_err() {
+                break;
+            }
+            tokio::select! {
+                _ = tx.closed() => break,
+                _ = time::sleep(backoff.next()) => (),
+            }
+        }
+    });
+    rx
+}
+
+async fn subscribe(endpoint: &Endpoint, client: &reqwest::Client, bandwidth: &Bandwidth, tx: &watch::Sender<Option<AnalysisStatus>>, backoff: &mut RandomizedBackoff) -> reqwest::Result<Subscription> {
+    bandwidth.record_sent(HEADER_OVERHEAD);
+    let res = client.get(endpoint.route(Route::StatusEvents))
+        .header("Accept", "text/event-stream")
+        .timeout(MAX_SUBSCRIPTION)
+        .send().await?;
+    bandwidth.record_received(HEADER_OVERHEAD);
+    if res.status() == StatusCode::NOT_FOUND {
+        return Ok(Subscription::Unsupported);
+    }
+    let mut res = res.error_for_status()?;
+    backoff.reset();
+
+    let mut parser = EventParser::default();
+    loop {
+        let chunk = tokio::select! {
+            _ = tx.closed() => return Ok(Subscription::Ended),
+            _ = time::sleep(IDLE_TIMEOUT) => return Ok(Subscription::Ended),
+            chunk = res.chunk() => chunk?,
+        };
+        let chunk = match chunk {
+            Some(chunk) => chunk,
+            None => return Ok(Subscription::Ended),
+        };
+        bandwidth.record_received(chunk.len() as u64);
+        for data in parser.feed(&chunk) {
+            // Ignore events that are not understood, for example from newer
+            // servers.
+            if let Ok(body) = serde_json::from_str::<StatusResponseBody>(&data) {
+                if tx.send(Some(body.analysis)).is_err() {
+                    return Ok(Subscription::Ended);
+                }
+            }
+        }
+    }
+}
+
+/// Splits a `text/event-stream` into the data of each event. Other fields
+/// and comments are ignored.
+#[derive(Default)]
+struct EventParser {
+    line: Vec<u8>,
+    data: Option<String>,
+}
+
+impl EventParser {
+    fn feed(&mut self, chunk: &[u8]) -> Vec<String> {
+        let mut events = Vec::new();
+        for &byte in chunk {
+            if byte != b'\n' {
+                self.line.push(byte);
+                continue;
+            }
+            let line = mem::take(&mut self.line);
+            let line = String::from_utf8_lossy(line.strip_suffix(b"\r").unwrap_or(&line));
+            if line.is_empty() {
+                events.extend(self.data.take());
+            } else if let Some(value) = line.strip_prefix("data:") {
+                let value = value.strip_prefix(' ').unwrap_or(value);
+                match self.data {
+                    Some(ref mut data) => {
+                        data.push('\n');
+                        data.push_str(value);
+                    }
+                    None => self.data = Some(value.to_owned()),
+                }
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_parser() {
+        let mut parser = EventParser::default();
+        assert!(parser.feed(b": keep-alive\n\nevent: status\ndata: {\"a\"").is_empty());
+        assert_eq!(parser.feed(b":1}\r\n\r\ndata: x\ndata:y\n"), vec!["{\"a\":1}".to_owned()]);
+        assert_eq!(parser.feed(b"\n"), vec!["x\ny".to_owned()]);
+    }
+}
@@ -0,0 +1,137 @@
+//! Fairy-Stockfish as the engine for variants, instead of the bundled
+//! multi-variant Stockfish. It is downloaded from the official releases on
+//! first use, and kept for later runs. Like fishnet updates, the download is
+//! only accepted with a detached signature (suffix `.sig`) by the release
+//! signing key, and the engine is probed in the engine sandbox.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use shakmaty::variants::Variant;
+use tokio::io::AsyncWriteExt as _;
+use tokio::process::Command;
+use tokio_compat_02::FutureExt as _;
+use crate::api::LichessVariant;
+use crate::assets::Cpu;
+use crate::bandwidth::Bandwidth;
+use crate::logger::Logger;
+use crate::sandbox;
+use crate::stockfish::uci_variant;
+use crate::update::{self, UpdateError};
+
+const LATEST_RELEASE: &str = "https://api.github.com/repos/fairy-stockfish/Fairy-Stockfish/releases/latest";
+
+#[derive(Debug)]
+pub struct FairyStockfish {
+    pub path: PathBuf,
+    pub version: String,
+    /// Variants that the engine supports.
+    pub variants: Vec<LichessVariant>,
+}
+
+/// Release assets that run on this CPU, fastest first.
+fn candidates(cpu: Cpu) -> Vec<String> {
+    let mut builds = Vec::new();
+    if cfg!(target_arch = "x86_64") && (cfg!(target_os = "linux") || cfg!(target_os = "windows")) {
+        if cpu.contains(Cpu::SF_BMI2) {
+            builds.push("x86-64-bmi2");
+        }
+        if cpu.contains(Cpu::SF_SSE41_POPCNT) {
+            builds.push("x86-64-modern");
+        }
+        builds.push("x86-64");
+    }
+    builds.into_iter().map(|build| format!("fairy-stockfish_{}{}", build, env::consts::EXE_SUFFIX)).collect()
+}
+
+/// Downloads the latest release into `dir`, unless already there. Falls back
+/// to an earlier download if the release can not be fetched.
+pub async fn prepare(dir: &Path, cpu: Cpu, client: &reqwest::Client, bandwidth: &Bandwidth, sandbox: bool, logger: &Logger) -> Result<FairyStockfish, UpdateError> {
+    let (version, path) = download_latest(dir, cpu, client, bandwidth, logger).compat().await?;
+    let variants = supported_variants(&path, sandbox).await?;
+    Ok(FairyStockfish {
+        path,
+        version,
+        variants,
+    })
+}
+
+async fn download_latest(dir: &Path, cpu: Cpu, client: &reqwest::Client, bandwidth: &Bandwidth, logger: &Logger) -> Result<(String, PathBuf), UpdateError> {
+    let candidates = candidates(cpu);
+    Ok(match update::latest_release(client, bandwidth, LATEST_RELEASE).await {
+        Ok(release) => {
+            // The tag becomes a directory name.
+            if release.tag_name.is_empty() || release.tag_name.contains(&['/', '\\'][..]) || release.tag_name.contains("..") {
+                return Err(UpdateError::BadTag(release.tag_name));
+            }
+            let asset = candidates.iter()
+                .find_map(|name| release.assets.iter().find(|a| a.name == *name))
+                .ok_or_else(|| UpdateError::NoAsset(candidates.first().cloned().unwrap_or_else(|| "for this platform".to_owned())))?;
+            let path = dir.join(&release.tag_name).join(&asset.name);
+            if !path.exists() {
+                let sig_name = format!("{}.sig", asset.name);
+                let sig_asset = release.assets.iter().find(|a| a.name == sig_name).ok_or_else(|| UpdateError::NoSignature(asset.name.clone()))?;
+                logger.fishnet_info(&format!("Downloading {} {} ...", asset.name, release.tag_name));
+                let data = update::download(client, bandwidth, asset, logger).await?;
+                let signature = update::download(client, bandwidth, sig_asset, logger).await?;
+                update::verify(&asset.name, &data, &signature)?;
+                logger.debug(&format!("Verified signature of {}", asset.name));
+                fs::create_dir_all(dir.join(&release.tag_name))?;
+                // Only complete downloads get the final name.
+                let partial = path.with_extension("part");
+                fs::write(&partial, &data)?;
+                update::set_executable(&partial)?;
+                fs::rename(&partial, &path)?;
+            }
+            (release.tag_name, path)
+        }
+        Err(err) => match previous_download(dir, &candidates) {
+            Some(found) => {
+                logger.warn(&format!("Failed to check for the latest Fairy-Stockfish: {}. Using {}.", err, found.1.display()));
+                found
+            }
+            None => return Err(err),
+        },
+    })
+}
+
+/// Finds a build from an earlier run, for example when offline.
+fn previous_download(dir: &Path, candidates: &[String]) -> Option<(String, PathBuf)> {
+    let mut versions: Vec<PathBuf> = fs::read_dir(dir).ok()?.filter_map(|e| e.ok()).map(|e| e.path()).collect();
+    versions.sort();
+    versions.iter().rev().find_map(|version| {
+        candidates.iter().map(|name| version.join(name)).find(|path| path.is_file()).map(|path| {
+            (version.file_name().map(|v| v.to_string_lossy().into_owned()).unwrap_or_default(), path)
+        })
+    })
+}
+
+/// Starts the engine once, to make sure that it really is Fairy-Stockfish,
+/// and reads which variants it supports.
+async fn supported_variants(exe: &Path, sandbox: bool) -> Result<Vec<LichessVariant>, UpdateError> {
+    let mut child = sandbox::sandbox(Command::new(exe)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true), sandbox).spawn()?;
+
+    {
+        let mut stdin = child.stdin.take().expect("pipe stdin");
+        // Ignore write errors. The output tells what happened.
+        let _ = stdin.write_all(b"uci\nquit\n").await;
+    }
+
+    let output = child.wait_with_output().await?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    if !output.lines().any(|line| line.starts_with("id name Fairy-Stockfish")) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} is not Fairy-Stockfish", exe.display())).into());
+    }
+    let uci_variants: Vec<&str> = output.lines()
+        .find(|line| line.starts_with("option name UCI_Variant "))
+        .map_or(Vec::new(), |line| {
+            line.split_whitespace().collect::<Vec<_>>().windows(2).filter(|w| w[0] == "var").map(|w| w[1]).collect()
+        });
+    Ok(LichessVariant::ALL.iter().copied().filter(|&v| uci_variants.contains(&uci_variant(Variant::from(v)))).collect())
+}
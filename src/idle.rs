@@ -0,0 +1,142 @@
+use std::time::{Duration, Instant};
+use crate::logger::Logger;
+
+/// Check for user activity at most this often.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Time since the last keyboard or mouse input of the user. Returns `None`
+/// if it can not be determined, for example without a graphical session.
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn idle_time() -> Option<Duration> {
+    use std::process::Command;
+    use std::time::SystemTime;
+
+    // X11, if xprintidle is installed.
+    if let Ok(output) = Command::new("xprintidle").output() {
+        if let Ok(ms) = String::from_utf8_lossy(&output.stdout).trim().parse() {
+            return Some(Duration::from_millis(ms));
+        }
+    }
+
+    // Session state from logind, as set by most desktop environments
+    // (also on Wayland). A locked session counts as idle.
+    let session = std::env::var("XDG_SESSION_ID").unwrap_or_else(|_| "auto".to_owned());
+    let output = Command::new("loginctl")
+        .args(["show-session", &session, "--property=IdleHint", "--property=IdleSinceHint", "--property=LockedHint"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let output = String::from_utf8_lossy(&output.stdout);
+    let property = |name: &str| output.lines().find_map(|line| line.strip_prefix(name)?.strip_prefix('='));
+    if property("IdleHint") != Some("yes") && property("LockedHint") != Some("yes") {
+        return Some(Duration::default());
+    }
+    let since = property("IdleSinceHint").and_then(|us| us.parse().ok()).unwrap_or(0);
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).ok()?;
+    Some(now.checked_sub(Duration::from_micros(since)).unwrap_or_default())
+}
+
+#[cfg(target_os = "macos")]
+pub fn idle_time() -> Option<Duration> {
+    // The HID system tracks the time since the last input event in
+    // nanoseconds, for example: "HIDIdleTime" = 4837392458
+    let output = std::process::Command::new("ioreg").args(&["-c", "IOHIDSystem", "-d", "4"]).output().ok()?;
+    String::from_utf8_lossy(&output.stdout).lines()
+        .find_map(|line| line.split("\"HIDIdleTime\" = ").nth(1)?.trim().parse().ok())
+        .map(Duration::from_nanos)
+}
+
+#[cfg(windows)]
+pub fn idle_time() -> Option<Duration> {
+    // https://docs.microsoft.com/en-us/windows/win32/api/winuser/nf-winuser-getlastinputinfo
+    #[repr(C)]
+    struct LastInputInfo {
+        cb_size: u32,
+        dw_time: u32,
+    }
+
+    #[link(name = "user32")]
+    extern "system" {
+        fn GetLastInputInfo(plii: *mut LastInputInfo) -> i32;
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetTickCount() -> u32;
+    }
+
+    let mut info = LastInputInfo {
+        cb_size: std::mem::size_of::<LastInputInfo>() as u32,
+        dw_time: 0,
+    };
+    // Safety: The struct matches LASTINPUTINFO, with its size filled in.
+    if unsafe { GetLastInputInfo(&mut info) } == 0 {
+        return None;
+    }
+    // Tick counts wrap around after 49.7 days.
+    Some(Duration::from_millis(u64::from(unsafe { GetTickCount() }.wrapping_sub(info.dw_time))))
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn idle_time() -> Option<Duration> {
+    None
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Activity {
+    Idle,
+    Active,
+    /// The user just came back after an idle period.
+    Returned,
+}
+
+/// Decides whether to contribute, based on how long the user has been idle.
+pub struct IdlePause {
+    min_idle: Duration,
+    checked_at: Option<Instant>,
+    paused: bool,
+    warned: bool,
+    logger: Logger,
+}
+
+impl IdlePause {
+    pub fn new(min_idle: Duration, logger: Logger) -> IdlePause {
+        IdlePause {
+            min_idle,
+            checked_at: None,
+            paused: true,
+            warned: false,
+            logger,
+        }
+    }
+
+    pub async fn activity(&mut self) -> Activity {
+        if self.checked_at.is_some_and(|t| t.elapsed() < CHECK_INTERVAL) {
+            return if self.paused { Activity::Active } else { Activity::Idle };
+        }
+        self.checked_at = Some(Instant::now());
+
+        let idle = tokio::task::spawn_blocking(idle_time).await.ok().flatten();
+        if idle.is_none() && !self.warned {
+            self.warned = true;
+            self.logger.warn("Can not determine how long the user has been idle. Not contributing with --only-when-idle.");
+        }
+        let paused = idle.is_none_or(|idle| idle < self.min_idle);
+
+        let activity = if paused && !self.paused {
+            self.logger.info("User is back. Pausing until idle again.");
+            Activity::Returned
+        } else if paused {
+            Activity::Active
+        } else {
+            if self.paused {
+                self.logger.info(&format!("User idle for {:?}. Contributing.", idle.unwrap_or_default()));
+            }
+            Activity::Idle
+        };
+        self.paused = paused;
+        activity
+    }
+}
@@ -0,0 +1,179 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write as _};
+use std::path::{Path, PathBuf};
+use std::process;
+use fs2::FileExt as _;
+
+/// Another fishnet instance running on this machine.
+#[derive(Debug)]
+pub struct OtherInstance {
+    pub pid: u32,
+    pub cores: usize,
+}
+
+/// Registration of this instance, so that instances started later can see
+/// it. Each instance holds a shared lock on its own file, which the
+/// operating system releases when the process dies, however that happens.
+/// Files that are not locked are left over from instances that are gone.
+pub struct InstanceLock {
+    _file: File,
+    path: PathBuf,
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+/// One directory per user. A directory shared with other users could be
+/// created by them first, or prepared with symlinks in place of lock files.
+#[cfg(unix)]
+fn dir() -> io::Result<PathBuf> {
+    use std::os::unix::fs::{DirBuilderExt as _, MetadataExt as _, PermissionsExt as _};
+    let uid = unsafe { libc::getuid() };
+    let dir = env::temp_dir().join(format!("fishnet-instances-{}", uid));
+    match fs::DirBuilder::new().mode(0o700).create(&dir) {
+        Err(err) if err.kind() != io::ErrorKind::AlreadyExists => return Err(err),
+        _ => (),
+    }
+    let meta = fs::symlink_metadata(&dir)?;
+    if !meta.is_dir() || meta.uid() != uid || meta.permissions().mode() & 0o077 != 0 {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("{} is not a private directory", dir.display())));
+    }
+    Ok(dir)
+}
+
+#[cfg(not(unix))]
+fn dir() -> io::Result<PathBuf> {
+    // The temporary directory is already per user.
+    let dir = env::temp_dir().join("fishnet-instances");
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+#[cfg(unix)]
+fn create(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt as _;
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .read(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+}
+
+#[cfg(not(unix))]
+fn create(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).write(true).truncate(true).read(true).open(path)
+}
+
+/// Registers this instance and returns the others that are still running.
+/// Only instances of the same user are visible.
+pub fn register(cores: usize) -> io::Result<(InstanceLock, Vec<OtherInstance>)> {
+    let dir = dir()?;
+
+    let mut others = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "lock") {
+            continue;
+        }
+        let pid = match path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok()) {
+            Some(pid) if pid != process::id() => pid,
+            _ => continue,
+        };
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        if file.try_lock_exclusive().is_ok() {
+            // Stale.
+            drop(file);
+            fs::remove_file(&path).ok();
+            continue;
+        }
+        if let Some(cores) = fs::read_to_string(&path).ok().and_then(|s| s.trim().parse().ok()) {
+            others.push(OtherInstance { pid, cores });
+        }
+    }
+
+    // Prepare under another name, so that the lock file never shows up
+    // unlocked, where it would look stale to instances starting at the same
+    // time.
+    let tmp = dir.join(format!("{}.tmp", process::id()));
+    let mut file = create(&tmp)?;
+    // Write first. On Windows, locks are mandatory and even the holder of
+    // a shared lock can no longer write.
+    writeln!(file, "{}", cores)?;
+    file.lock_shared()?;
+    let path = dir.join(format!("{}.lock", process::id()));
+    if let Err(err) = fs::rename(&tmp, &path) {
+        fs::remove_file(&tmp).ok();
+        return Err(err);
+    }
+    Ok((InstanceLock { _file: file, path }, others))
+}
+
+/// Random identifier of this installation, created on first use and kept in
+/// `path`, so that traffic can be attributed to a node across restarts.
+pub fn instance_id(path: &Path) -> io::Result<String> {
+    match fs::read_to_string(path) {
+        Ok(id) if is_uuid(id.trim()) => return Ok(id.trim().to_owned()),
+        Ok(_) => (),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err),
+    }
+    let id = random_uuid();
+    fs::write(path, format!("{}\n", id))?;
+    Ok(id)
+}
+
+/// Version 4 UUID.
+fn random_uuid() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+fn is_uuid(s: &str) -> bool {
+    s.len() == 36 && s.char_indices().all(|(i, c)| match i {
+        8 | 13 | 18 | 23 => c == '-',
+        _ => c.is_ascii_hexdigit(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_random_uuid() {
+        let id = random_uuid();
+        assert!(is_uuid(&id), "{}", id);
+        assert_eq!(id.as_bytes()[14], b'4');
+        assert_ne!(id, random_uuid());
+    }
+
+    #[test]
+    fn test_register() {
+        let (instance, others) = register(3).expect("register");
+        assert!(others.iter().all(|o| o.pid != process::id()));
+        assert_eq!(fs::read_to_string(&instance.path).expect("lock file").trim(), "3");
+        assert!(!instance.path.with_extension("tmp").exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt as _;
+            let dir = instance.path.parent().expect("dir");
+            assert_eq!(fs::metadata(dir).expect("dir").permissions().mode() & 0o777, 0o700);
+        }
+        let path = instance.path.clone();
+        drop(instance);
+        assert!(!path.exists());
+    }
+}
@@ -1,10 +1,15 @@
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
 use url::Url;
 use std::time::Duration;
 use shakmaty::fen::Fen;
 use shakmaty::uci::Uci;
+use shakmaty::{Color, Setup as _};
 use tokio::sync::oneshot;
-use crate::api::{Score, LichessVariant, Work, BatchId};
+use crate::api::{Bound, EngineInfo, Score, LichessVariant, PvLine, Wdl, Work, BatchId};
 use crate::assets::EngineFlavor;
+use crate::util::Cancellation;
 
 /// Uniquely identifies a position within a batch.
 #[derive(Debug, Copy, Clone)]
@@ -20,7 +25,62 @@ pub struct Position {
     pub variant: LichessVariant,
     pub chess960: bool,
     pub fen: Fen,
-    pub moves: Vec<Uci>,
+    pub moves: Moves,
+
+    /// Number of earlier attempts that failed due to the engine.
+    pub attempts: u32,
+
+    /// Cancelled when the batch is no longer needed.
+    pub cancellation: Cancellation,
+}
+
+impl Position {
+    /// Number of half-moves since the start of the game.
+    pub fn ply(&self) -> u32 {
+        (self.fen.fullmoves().get() - 1) * 2 + u32::from(self.fen.turn() == Color::Black) + self.moves.len() as u32
+    }
+}
+
+/// Moves leading to a position. All positions of a batch share the moves of
+/// the game, each looking at a prefix, so that cloning is cheap.
+#[derive(Clone, Default)]
+pub struct Moves {
+    game: Arc<[Uci]>,
+    len: usize,
+}
+
+impl Moves {
+    /// The first `len` moves.
+    pub fn prefix(&self, len: usize) -> Moves {
+        assert!(len <= self.game.len());
+        Moves {
+            game: self.game.clone(),
+            len,
+        }
+    }
+}
+
+impl From<Vec<Uci>> for Moves {
+    fn from(moves: Vec<Uci>) -> Moves {
+        Moves {
+            len: moves.len(),
+            game: moves.into(),
+        }
+    }
+}
+
+impl Deref for Moves {
+    type Target = [Uci];
+
+    fn deref(&self) -> &[Uci] {
+        &self.game[..self.len]
+    }
+}
+
+impl fmt::Debug for Moves {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -30,21 +90,50 @@ pub struct PositionResponse {
     pub url: Option<Url>,
 
     pub score: Score,
+    /// Set if the score is not exact.
+    pub bound: Option<Bound>,
+    pub wdl: Wdl,
     pub best_move: Option<Uci>,
     pub pv: Vec<Uci>,
     pub depth: u32,
+    /// Selective depth, if reported by the engine.
+    pub seldepth: Option<u32>,
     pub nodes: u64,
     pub time: Duration,
     pub nps: Option<u32>,
+    /// Hash table usage in permille, if reported by the engine.
+    pub hashfull: Option<u32>,
+    /// Tablebase hits, if reported by the engine.
+    pub tbhits: Option<u64>,
+    /// Further lines, second best first, when analysing with MultiPV.
+    pub secondary: Vec<PvLine>,
+    pub engine: Option<Arc<EngineInfo>>,
+    pub origin: Origin,
+}
+
+/// Where a `PositionResponse` came from. Only engine results count towards
+/// the performance statistics.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Origin {
+    Engine,
+    Reused,
+    CloudEval,
+    /// Forced move or game over, worked out without an engine.
+    Trivial,
 }
 
 #[derive(Debug)]
 pub struct PositionFailed {
     pub batch_id: BatchId,
+    pub position_id: PositionId,
+    /// Position to analyse again, instead of giving up on the batch.
+    pub retry: Option<Position>,
 }
 
 #[derive(Debug)]
 pub struct Pull {
+    /// Local engine worker that sent this pull, if any.
+    pub worker: Option<usize>,
     pub response: Option<Result<PositionResponse, PositionFailed>>,
     pub callback: oneshot::Sender<Position>,
 }
@@ -0,0 +1,60 @@
+use std::fmt;
+use crate::configure::{Endpoint, Key};
+
+/// Service name for entries in the platform keyring. Entries are named
+/// after the endpoint host, so that keys for development servers do not
+/// replace the key for lichess.org.
+#[cfg(feature = "keyring")]
+const SERVICE: &str = "fishnet";
+
+#[derive(Debug)]
+pub enum KeyStoreError {
+    #[cfg(not(feature = "keyring"))]
+    Unsupported,
+    #[cfg(feature = "keyring")]
+    InvalidKey,
+    #[cfg(feature = "keyring")]
+    Keyring(keyring::KeyringError),
+}
+
+impl fmt::Display for KeyStoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(not(feature = "keyring"))]
+            KeyStoreError::Unsupported => f.write_str("built without keyring support"),
+            #[cfg(feature = "keyring")]
+            KeyStoreError::InvalidKey => f.write_str("invalid key in keyring"),
+            #[cfg(feature = "keyring")]
+            KeyStoreError::Keyring(err) => fmt::Display::fmt(err, f),
+        }
+    }
+}
+
+#[cfg(feature = "keyring")]
+fn entry(endpoint: &Endpoint) -> keyring::Keyring<'_> {
+    keyring::Keyring::new(SERVICE, endpoint.url.host_str().unwrap_or("localhost"))
+}
+
+#[cfg(feature = "keyring")]
+pub fn load(endpoint: &Endpoint) -> Result<Option<Key>, KeyStoreError> {
+    match entry(endpoint).get_password() {
+        Ok(key) => key.parse().map(Some).map_err(|_| KeyStoreError::InvalidKey),
+        Err(keyring::KeyringError::NoPasswordFound) => Ok(None),
+        Err(err) => Err(KeyStoreError::Keyring(err)),
+    }
+}
+
+#[cfg(feature = "keyring")]
+pub fn store(endpoint: &Endpoint, key: &Key) -> Result<(), KeyStoreError> {
+    entry(endpoint).set_password(key.expose()).map_err(KeyStoreError::Keyring)
+}
+
+#[cfg(not(feature = "keyring"))]
+pub fn load(_endpoint: &Endpoint) -> Result<Option<Key>, KeyStoreError> {
+    Err(KeyStoreError::Unsupported)
+}
+
+#[cfg(not(feature = "keyring"))]
+pub fn store(_endpoint: &Endpoint, _key: &Key) -> Result<(), KeyStoreError> {
+    Err(KeyStoreError::Unsupported)
+}
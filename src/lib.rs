@@ -0,0 +1,38 @@
+//! The client, as a library for the binary in main.rs and for the fuzz
+//! targets.
+
+pub mod configure;
+pub mod assets;
+pub mod systemd;
+pub mod api;
+pub mod ipc;
+pub mod queue;
+pub mod util;
+pub mod stockfish;
+pub mod sandbox;
+pub mod logger;
+pub mod update;
+pub mod cache;
+pub mod serve;
+pub mod cluster;
+pub mod metrics;
+pub mod spool;
+pub mod archive;
+pub mod tui;
+pub mod web;
+pub mod webhook;
+pub mod bandwidth;
+pub mod keystore;
+pub mod power;
+pub mod idle;
+pub mod crash;
+pub mod instances;
+pub mod preemption;
+pub mod trace;
+pub mod chaos;
+pub mod dev_server;
+pub mod fairy;
+pub mod events;
+pub mod pinning;
+#[cfg(test)]
+mod sim;
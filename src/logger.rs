@@ -1,36 +1,184 @@
+use std::collections::VecDeque;
 use std::sync::{Arc, Mutex};
 use std::fmt;
 use std::io;
+use std::str::FromStr;
 use std::io::Write as _;
 use std::cmp::{min, max};
+use std::time::{Duration, Instant};
 use atty::Stream;
 use url::Url;
 use crate::api::BatchId;
 use crate::ipc::{PositionId, Position, PositionResponse};
 use crate::configure::Verbose;
 
+/// Recent log lines to keep for the dashboards.
+const RECENT_LINES: usize = 200;
+
+/// Identical errors within this interval are coalesced into a summary.
+const REPEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Level, String> {
+        Ok(match s {
+            "error" => Level::Error,
+            "warn" => Level::Warn,
+            "info" => Level::Info,
+            "debug" => Level::Debug,
+            "trace" => Level::Trace,
+            _ => return Err(format!("invalid log level: {} (expected error, warn, info, debug or trace)", s)),
+        })
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        })
+    }
+}
+
+/// Log levels per module, like `queue=debug,api=info,uci=trace`. A bare
+/// level applies to all other modules.
+#[derive(Debug, Default, Clone)]
+pub struct LogFilter {
+    default: Option<Level>,
+    modules: Vec<(String, Level)>,
+}
+
+impl LogFilter {
+    fn level(&self, module: Option<&str>) -> Option<Level> {
+        module
+            .and_then(|module| self.modules.iter().find(|(m, _)| m == module))
+            .map(|&(_, level)| level)
+            .or(self.default)
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogFilter, String> {
+        let mut filter = LogFilter::default();
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.find('=') {
+                Some(eq) => filter.modules.push((directive[..eq].trim().to_owned(), directive[eq + 1..].trim().parse()?)),
+                None => filter.default = Some(directive.parse()?),
+            }
+        }
+        Ok(filter)
+    }
+}
+
+impl fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let directives: Vec<String> = self.default.iter().map(Level::to_string)
+            .chain(self.modules.iter().map(|(module, level)| format!("{}={}", module, level)))
+            .collect();
+        f.write_str(&directives.join(","))
+    }
+}
+
 #[derive(Clone)]
 pub struct Logger {
     verbose: Verbose,
+    level: Level,
     stderr: bool,
     atty: bool,
+    /// Prefixed to each line, to tell nodes apart in aggregated logs.
+    label: Option<String>,
     state: Arc<Mutex<LoggerState>>,
 }
 
 impl Logger {
     pub fn new(verbose: Verbose, stderr: bool) -> Logger {
         Logger {
-            verbose,
+            level: Logger::level_for(&verbose, None),
             stderr,
-            atty: atty::is(Stream::Stdout),
-            state: Arc::new(Mutex::new(LoggerState {
-                progress_line: 0,
-            })),
+            atty: atty::is(Stream::Stdout) && !verbose.no_status_bar,
+            label: None,
+            state: Arc::new(Mutex::new(LoggerState::new())),
+            verbose,
+        }
+    }
+
+    pub fn with_label(self, label: Option<String>) -> Logger {
+        Logger {
+            label,
+            ..self
         }
     }
 
+    fn level_for(verbose: &Verbose, module: Option<&str>) -> Level {
+        verbose.log_filter.as_ref().and_then(|f| f.level(module)).unwrap_or(match verbose.level {
+            _ if verbose.quiet => Level::Warn,
+            0 => Level::Info,
+            1 => Level::Debug,
+            _ => Level::Trace,
+        })
+    }
+
+    /// A logger for a subsystem, with its own level from `--log-filter`.
+    /// Shares the output and recent lines with this one.
+    pub fn module(&self, module: &'static str) -> Logger {
+        Logger {
+            level: Logger::level_for(&self.verbose, Some(module)),
+            ..self.clone()
+        }
+    }
+
+    pub fn enabled(&self, level: Level) -> bool {
+        level <= self.level
+    }
+
     fn println(&self, line: &str) {
         let mut state = self.state.lock().expect("logger state");
+        if state.repeated.as_ref().is_some_and(|r| r.since.elapsed() >= REPEAT_INTERVAL) {
+            self.flush_repeated(&mut state);
+        }
+        self.write_line(&mut state, line);
+    }
+
+    fn flush_repeated(&self, state: &mut LoggerState) {
+        if let Some(repeated) = state.repeated.take() {
+            if repeated.count > 0 {
+                self.write_line(state, &format!("E: Last message repeated {} times", repeated.count));
+            }
+        }
+    }
+
+    fn write_line(&self, state: &mut LoggerState, line: &str) {
+        let labelled;
+        let line = match self.label {
+            Some(ref label) => {
+                labelled = format!("[{}] {}", label, line);
+                &labelled
+            }
+            None => line,
+        };
+        if state.recent.len() >= RECENT_LINES {
+            state.recent.pop_front();
+        }
+        state.recent.push_back(line.to_owned());
+        if state.captured {
+            return;
+        }
         state.line_feed();
 
         if self.stderr {
@@ -40,42 +188,120 @@ impl Logger {
         }
     }
 
+    /// Stops printing log lines, while the dashboard owns the terminal.
+    /// They are still kept in `recent_lines()`.
+    pub fn capture(&self) {
+        let mut state = self.state.lock().expect("logger state");
+        state.line_feed();
+        state.captured = true;
+    }
+
+    /// Prints log lines normally again.
+    pub fn release(&self) {
+        let mut state = self.state.lock().expect("logger state");
+        state.captured = false;
+    }
+
+    pub fn recent_lines(&self) -> Vec<String> {
+        let state = self.state.lock().expect("logger state");
+        state.recent.iter().cloned().collect()
+    }
+
+    /// Like `recent_lines()`, but gives up instead of waiting for the lock,
+    /// for use in a panic hook.
+    pub fn try_recent_lines(&self) -> Option<Vec<String>> {
+        let state = self.state.try_lock().ok()?;
+        Some(state.recent.iter().cloned().collect())
+    }
+
+    /// A logger with the same settings, but its own state, that keeps
+    /// working even if this one is stuck.
+    pub fn detached(&self) -> Logger {
+        Logger {
+            state: Arc::new(Mutex::new(LoggerState::new())),
+            ..self.clone()
+        }
+    }
+
     pub fn clear_echo(&self) {
         let mut state = self.state.lock().expect("logger state");
         state.line_feed();
     }
 
     pub fn headline(&self, title: &str) {
-        self.println(&format!("\n### {}\n", title));
+        if self.enabled(Level::Info) {
+            self.println(&format!("\n### {}\n", title));
+        }
+    }
+
+    pub fn trace(&self, line: &str) {
+        if self.enabled(Level::Trace) {
+            self.println(&format!("T: {}", line));
+        }
     }
 
     pub fn debug(&self, line: &str) {
-        if self.verbose.level > 0 {
+        if self.enabled(Level::Debug) {
             self.println(&format!("D: {}", line));
         }
     }
 
     pub fn info(&self, line: &str) {
+        if self.enabled(Level::Info) {
+            self.println(line);
+        }
+    }
+
+    /// Like `info`, but also logged in quiet mode.
+    pub fn notice(&self, line: &str) {
         self.println(line);
     }
 
     pub fn fishnet_info(&self, line: &str) {
-        self.println(&format!("><> {}", line));
+        if self.enabled(Level::Info) {
+            self.println(&format!("><> {}", line));
+        }
     }
 
     pub fn warn(&self, line: &str) {
-        self.println(&format!("W: {}", line));
+        if self.enabled(Level::Warn) {
+            self.println(&format!("W: {}", line));
+        }
     }
 
+    /// Logs an error. Repetitions of the same error are counted, but only
+    /// summarized once the error changes or after `REPEAT_INTERVAL`.
     pub fn error(&self, line: &str) {
-        self.println(&format!("E: {}", line));
+        let line = format!("E: {}", line);
+        let mut state = self.state.lock().expect("logger state");
+        state.errors += 1;
+        if let Some(ref mut repeated) = state.repeated {
+            if repeated.line == line && repeated.since.elapsed() < REPEAT_INTERVAL {
+                repeated.count += 1;
+                return;
+            }
+        }
+        self.flush_repeated(&mut state);
+        state.repeated = Some(Repeated {
+            line: line.clone(),
+            count: 0,
+            since: Instant::now(),
+        });
+        self.write_line(&mut state, &line);
+    }
+
+    /// Errors logged since startup, including coalesced repetitions.
+    pub fn error_count(&self) -> u64 {
+        self.state.lock().expect("logger state").errors
     }
 
     pub fn progress<P>(&self, queue: QueueStatusBar, progress: P)
         where P: Into<ProgressAt>,
     {
         let line = format!("{} {} cores, {} queued, latest: {}", queue, queue.cores, queue.pending, progress.into());
-        if self.atty {
+        if self.verbose.quiet || self.state.lock().expect("logger state").captured {
+            // Nothing to do.
+        } else if self.atty {
             let mut state = self.state.lock().expect("logger state");
             print!("\r{}{}", line, " ".repeat(state.progress_line.saturating_sub(line.len())));
             io::stdout().flush().expect("flush stdout");
@@ -132,9 +358,29 @@ impl From<&PositionResponse> for ProgressAt {
 
 struct LoggerState {
     pub progress_line: usize,
+    pub recent: VecDeque<String>,
+    pub captured: bool,
+    pub repeated: Option<Repeated>,
+    pub errors: u64,
+}
+
+struct Repeated {
+    line: String,
+    count: u64,
+    since: Instant,
 }
 
 impl LoggerState {
+    fn new() -> LoggerState {
+        LoggerState {
+            progress_line: 0,
+            recent: VecDeque::new(),
+            captured: false,
+            repeated: None,
+            errors: 0,
+        }
+    }
+
     fn line_feed(&mut self) {
         if self.progress_line > 0 {
             self.progress_line = 0;
@@ -1,58 +1,120 @@
-mod configure;
-mod assets;
-mod systemd;
-mod api;
-mod ipc;
-mod queue;
-mod util;
-mod stockfish;
-mod logger;
-
+use std::cmp::{max, min};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
-use std::error::Error;
 use std::thread;
 use std::path::PathBuf;
+use std::net::SocketAddr;
 use std::env;
-use atty::Stream;
+use std::num::NonZeroUsize;
+use std::process;
 use tokio::time;
 use tokio::signal;
 use tokio::sync::{mpsc, oneshot};
-use crate::configure::{Opt, Command, Cores};
-use crate::assets::{Assets, Cpu, ByEngineFlavor, EngineFlavor};
-use crate::ipc::{Pull, Position};
-use crate::stockfish::StockfishInit;
-use crate::logger::{Logger, ProgressAt};
-use crate::util::RandomizedBackoff;
+use tokio::task::JoinHandle;
+use fishnet::{api, chaos, cluster, configure, crash, dev_server, events, fairy, instances, metrics, preemption, queue, sandbox, serve, stockfish, systemd, update, webhook};
+use fishnet::api::{ApiConfig, ApiStub, Timeouts, Work};
+use fishnet::configure::{Opt, Command, Cores, EngineOpt, Key, PreemptionAction, ThreadsPerWorker, VariantEngine};
+use fishnet::assets::{Assets, Cpu, ByEngineFlavor, EngineFlavor};
+use fishnet::bandwidth::{Bandwidth, Bytes};
+use fishnet::crash::CrashContext;
+use fishnet::ipc::{Pull, Position, PositionFailed};
+use fishnet::stockfish::StockfishInit;
+use fishnet::logger::{Logger, ProgressAt};
+use fishnet::metrics::Health;
+use fishnet::spool::Spool;
+use fishnet::trace::ApiTrace;
+use fishnet::tui::Tui;
+use fishnet::util::RandomizedBackoff;
+use fishnet::webhook::{Event, WebhookStub};
+use fishnet::update::UpdateStatus;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
     let opt = configure::parse_and_configure().await;
-    let logger = Logger::new(opt.verbose, opt.command.map_or(false, Command::is_systemd));
+    let logger = Logger::new(opt.verbose.clone(), opt.command.is_some_and(Command::is_systemd)).with_label(opt.client.instance_label.clone());
+    let (client, api_client) = match api::http_client(&opt.client).and_then(|client| Ok((client, api::api_client(&opt.client)?))) {
+        Ok(clients) => clients,
+        Err(err) => {
+            logger.error(&format!("Invalid TLS configuration: {}", err));
+            process::exit(Exit::Misconfigured as i32);
+        }
+    };
+    let bandwidth = Arc::new(Bandwidth::new(opt.client.bandwidth_cap, opt.client.bandwidth_cap.map(|_| opt.conf.with_file_name("fishnet-bandwidth"))));
+
+    if let Some(p) = opt.chaos {
+        chaos::enable(p);
+        logger.warn(&format!("Chaos mode: Injecting faults with probability {}. Do not use in production.", p));
+    }
 
     if opt.auto_update {
         let current_exe = env::current_exe().expect("current exe");
-        match auto_update(!opt.command.map_or(false, Command::is_systemd), &logger) {
+        match update::auto_update(!opt.command.is_some_and(Command::is_systemd), &client, &bandwidth, &logger).await {
             Err(err) => logger.error(&format!("Failed to update: {}", err)),
-            Ok(self_update::Status::UpToDate(version)) => {
+            Ok(UpdateStatus::UpToDate(version)) => {
                 logger.fishnet_info(&format!("Fishnet {} is up to date", version));
             }
-            Ok(self_update::Status::Updated(version)) => {
+            Ok(UpdateStatus::Updated(version)) => {
                 logger.fishnet_info(&format!("Fishnet updated to {}", version));
+                save_bandwidth(&bandwidth, &logger);
                 restart_process(current_exe, &logger);
             }
         }
     }
 
-    match opt.command {
-        Some(Command::Run) | None => run(opt, &logger).await,
-        Some(Command::Systemd) => systemd::systemd_system(opt),
-        Some(Command::SystemdUser) => systemd::systemd_user(opt),
-        Some(Command::Configure) => (),
-        Some(Command::License) => license(&logger),
+    let exit = match opt.command {
+        Some(Command::Run) | Some(Command::Serve) | None => run(opt, client, api_client, bandwidth.clone(), &logger).await,
+        Some(Command::Worker) => run_worker(opt, &logger).await,
+        Some(Command::Systemd) => {
+            systemd::systemd_system(opt);
+            Exit::Drained
+        }
+        Some(Command::SystemdUser) => {
+            systemd::systemd_user(opt);
+            Exit::Drained
+        }
+        Some(Command::Configure) => Exit::Drained,
+        Some(Command::License) => {
+            license(&logger);
+            Exit::Drained
+        }
+        Some(Command::KeyInfo) => key_info(opt, api_client, &logger).await,
+        Some(Command::DevServer) => run_dev_server(opt, &logger).await,
+    };
+
+    // Final usage, including the shutdown and any update.
+    save_bandwidth(&bandwidth, &logger);
+    if exit != Exit::Drained {
+        process::exit(exit as i32);
     }
 }
 
+/// Persists the usage of this month, so that the cap holds across restarts.
+fn save_bandwidth(bandwidth: &Bandwidth, logger: &Logger) {
+    if let Err(err) = bandwidth.save() {
+        logger.warn(&format!("Failed to save bandwidth usage: {}", err));
+    }
+}
+
+/// Exit status of the process. These are stable and documented, so that
+/// service managers can react to each condition.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Exit {
+    /// Finished all pending batches before stopping, or nothing to do.
+    Drained = 0,
+    /// The configuration is invalid, like invalid command line arguments.
+    Misconfigured = 1,
+    /// Stopped early. Pending batches were given back to the server.
+    Aborted = 2,
+    /// The server requires a newer client.
+    UpdateRequired = 3,
+    /// The server rejected the key.
+    KeyInvalid = 4,
+    /// The engine could not be started.
+    EngineFailed = 5,
+    /// There was no work for a while, with --scale-down-exit.
+    NoWork = 6,
+}
+
 fn license(logger: &Logger) {
     logger.headline("LICENSE.txt");
     println!("{}", include_str!("../LICENSE.txt"));
@@ -60,6 +122,43 @@ fn license(logger: &Logger) {
     print!("{}", include_str!("../COPYING.txt"));
 }
 
+async fn key_info(opt: Opt, api_client: reqwest::Client, logger: &Logger) -> Exit {
+    let keys = match opt.keys() {
+        Ok(keys) => keys,
+        Err(err) => {
+            logger.error(&format!("Failed to read key file: {}", err));
+            return Exit::Misconfigured;
+        }
+    };
+    if keys.is_empty() {
+        logger.error("No key configured");
+        return Exit::KeyInvalid;
+    }
+
+    let mut api = api::spawn(opt.endpoint(), Vec::new(), api_client, logger.clone());
+    let mut exit = Exit::Drained;
+    for key in keys {
+        if !log_key_info(&mut api, key, logger).await {
+            exit = Exit::KeyInvalid;
+        }
+    }
+    exit
+}
+
+/// Returns `false` if the key was rejected.
+async fn log_key_info(api: &mut ApiStub, key: Key, logger: &Logger) -> bool {
+    let redacted = key.redacted();
+    match api.key_info(key).await {
+        Some(Ok(info)) => logger.info(&format!("Key {}: {}", redacted, info)),
+        Some(Err(err)) => {
+            logger.error(&format!("Key {}: {}", redacted, err));
+            return false;
+        }
+        None => logger.warn(&format!("Key {}: Could not check", redacted)),
+    }
+    true
+}
+
 #[cfg(unix)]
 fn restart_process(current_exe: PathBuf, logger: &Logger) {
     use std::os::unix::process::CommandExt as _;
@@ -78,28 +177,42 @@ fn restart_process(current_exe: PathBuf, logger: &Logger) {
     todo!("Restart on Windows");
 }
 
-fn auto_update(verbose: bool, logger: &Logger) -> Result<self_update::Status, Box<dyn Error>> {
-    if verbose {
-        logger.headline("Updating ...");
-    }
-    logger.fishnet_info("Checking for updates (--auto-update) ...");
-    Ok(self_update::backends::github::Update::configure()
-        .repo_owner("niklasf")
-        .repo_name("fishnet")
-        .bin_name("fishnet")
-        .show_output(verbose)
-        .show_download_progress(atty::is(Stream::Stdout) && verbose)
-        .current_version(env!("CARGO_PKG_VERSION"))
-        .no_confirm(true)
-        .build()?
-        .update()?)
-}
-
-async fn run(opt: Opt, logger: &Logger) {
+async fn run(mut opt: Opt, client: reqwest::Client, api_client: reqwest::Client, bandwidth: Arc<Bandwidth>, logger: &Logger) -> Exit {
     logger.headline("Checking configuration ...");
 
+    let serve = opt.command == Some(Command::Serve);
+
     let endpoint = opt.endpoint();
-    logger.info(&format!("Endpoint: {}", endpoint));
+    logger.info(&format!("Endpoint: {}", endpoint.redacted()));
+    if let Some(ref id) = opt.client.instance_id {
+        match opt.client.instance_label {
+            Some(ref label) => logger.info(&format!("Instance: {} ({})", id, label)),
+            None => logger.info(&format!("Instance: {}", id)),
+        }
+    }
+
+    if let Some(latency) = opt.engine.fake_engine {
+        if !endpoint.is_development() {
+            logger.error("--fake-engine would submit bogus analysis. It is only allowed with a development --endpoint.");
+            return Exit::Misconfigured;
+        }
+        logger.warn(&format!("Fake engine: Responding with synthetic analysis after {}ms. For load testing only.", latency));
+    }
+
+    let keys = match opt.keys() {
+        Ok(keys) => keys,
+        Err(err) => {
+            logger.error(&format!("Failed to read key file: {}", err));
+            return Exit::Misconfigured;
+        }
+    };
+    if keys.len() > 1 {
+        logger.info(&format!("Keys: {} (rotating)", keys.len()));
+    }
+
+    if let Some(proxy) = opt.client.proxy() {
+        logger.info(&format!("Proxy: {}", proxy));
+    }
 
     logger.info(&format!("Join queue if: user backlog >= {:?} or system backlog >= {:?}",
                          Duration::from(opt.backlog.user.unwrap_or_default()),
@@ -108,11 +221,59 @@ async fn run(opt: Opt, logger: &Logger) {
     let cpu = Cpu::detect();
     logger.info(&format!("CPU features: {:?}", cpu));
 
-    let assets = Assets::prepare(cpu).expect("prepared bundled stockfish");
+    let mut assets = match Assets::prepare(cpu, logger) {
+        Ok(assets) => assets,
+        Err(err) => {
+            logger.error(&format!("Failed to prepare bundled stockfish: {}", err));
+            return Exit::EngineFailed;
+        }
+    };
     logger.info(&format!("Engine: {} (for GPLv3, run: {} license)", assets.sf_name, env::args().next().unwrap_or_else(|| "./fishnet".to_owned())));
 
+    // Replace the bundled engine for variants. Only advertise the variants
+    // that the replacement supports.
+    let variants = match opt.engine.variant_engine.unwrap_or_default() {
+        VariantEngine::Bundled => None,
+        VariantEngine::FairyStockfish => {
+            let dir = opt.conf.parent().map(PathBuf::from).unwrap_or_default().join("fairy-stockfish");
+            match fairy::prepare(&dir, cpu, &client, &bandwidth, !opt.engine.no_engine_sandbox, logger).await {
+                Ok(fairy) => {
+                    logger.info(&format!("Variant engine: Fairy-Stockfish {} ({})", fairy.version, fairy.path.display()));
+                    assets.stockfish.multi_variant = fairy.path;
+                    Some(fairy.variants)
+                }
+                Err(err) => {
+                    logger.warn(&format!("Failed to prepare Fairy-Stockfish: {}. Using the bundled engine for variants.", err));
+                    None
+                }
+            }
+        }
+    };
+
     let cores = usize::from(opt.cores.unwrap_or(Cores::Auto));
     logger.info(&format!("Cores: {}", cores));
+    let workers = engine_layout(cores, &assets, &mut opt.engine, logger);
+
+    // Several instances on the same machine compete for cores, and each
+    // is slower than it should be.
+    let _instance = match instances::register(cores) {
+        Ok((instance, others)) => {
+            let total = cores + others.iter().map(|o| o.cores).sum::<usize>();
+            if !others.is_empty() && total > num_cpus::get() {
+                let pids = others.iter().map(|o| o.pid.to_string()).collect::<Vec<_>>().join(", ");
+                if opt.exclusive {
+                    logger.error(&format!("Other fishnet instances (pid {}) are running. Together, they would use {} cores, but only {} are available. Stopping.", pids, total, num_cpus::get()));
+                    return Exit::Misconfigured;
+                }
+                logger.warn(&format!("Other fishnet instances (pid {}) are running. Together, they use {} cores, but only {} are available. Performance will suffer.", pids, total, num_cpus::get()));
+            }
+            Some(instance)
+        }
+        Err(err) => {
+            logger.debug(&format!("Failed to check for other instances: {}", err));
+            None
+        }
+    };
 
     // Install handler for SIGTERM.
     #[cfg(unix)]
@@ -126,179 +287,205 @@ async fn run(opt: Opt, logger: &Logger) {
     #[cfg(windows)]
     let mut sig_int = signal::windows::ctrl_c().expect("install handler for ctrl+c");
 
+    // Install handler for SIGQUIT, to dump the state.
+    #[cfg(unix)]
+    let mut sig_quit = signal::unix::signal(signal::unix::SignalKind::quit()).expect("install handler for sigquit");
+    #[cfg(windows)]
+    let mut sig_quit = NoSignal;
+
     // To wait for workers and API actor before shutdown.
     let mut join_handles = Vec::new();
 
+    // Local clients do not need a key.
+    let health = Arc::new(Health::new(workers));
+    if serve {
+        health.key_accepted();
+    }
+
+    // Notify operators about problems.
+    let webhook = webhook::spawn(opt.webhook.clone(), opt.webhook_format.unwrap_or_default(), client.clone(), logger.clone());
+
     // Spawn API actor.
+    if opt.client.dry_run {
+        logger.warn("Dry run: Analysis is only logged, and batches are given back to the server.");
+    }
     let api = {
-        let (api, api_actor) = api::channel(endpoint.clone(), opt.key, logger.clone());
+        let spool = opt.client.spool_dir.clone().map(|dir| {
+            logger.info(&format!("Spool: {}", dir.display()));
+            Spool::new(dir).expect("create spool directory")
+        });
+        let trace = opt.client.trace_api.as_ref().map(|path| {
+            logger.info(&format!("Tracing API requests to {}", path.display()));
+            ApiTrace::open(path).expect("open api trace file")
+        });
+        let (api, mut api_actor) = api::channel(endpoint.clone(), keys.clone(), ApiConfig {
+            client: api_client.clone(),
+            timeouts: Timeouts::new(&opt.client),
+            spool,
+            trace,
+            dry_run: opt.client.dry_run,
+            webhook: webhook.clone(),
+            bandwidth: bandwidth.clone(),
+        }, logger.clone());
+        api_actor.set_instance(opt.client.instance_id.as_deref(), opt.client.instance_label.as_deref());
         join_handles.push(tokio::spawn(async move {
             api_actor.run().await;
         }));
         api
     };
 
+    // Give back batches and write a report if something goes badly wrong.
+    let crash = crash::install(CrashContext {
+        endpoint: endpoint.clone(),
+        keys: keys.clone(),
+        client: opt.client.clone(),
+        bandwidth: bandwidth.clone(),
+        config: format!("Command: {:?}\nTarget: {}-{}\nInstance: {}\nEndpoint: {}\nKeys: {}\nCores: {}\nMax batches: {}\n",
+                        opt.command, env::consts::OS, env::consts::ARCH, opt.client.instance_id.as_deref().unwrap_or("-"), endpoint.redacted(), keys.len(), cores,
+                        opt.backlog.max_batches.unwrap_or(1)),
+        dir: opt.conf.parent().map(PathBuf::from).unwrap_or_default(),
+        upload: opt.crash_report_url.clone(),
+        logger: logger.clone(),
+    });
+
+    // Show which accounts are credited.
+    if !serve && !keys.is_empty() {
+        let mut api = api.clone();
+        let mut accepted = false;
+        for key in keys {
+            accepted |= log_key_info(&mut api, key, logger).await;
+        }
+        if !accepted {
+            logger.error("All keys were rejected. Stopping.");
+            return Exit::KeyInvalid;
+        }
+    }
+
     logger.headline("Running (press Ctrl + C to stop) ...");
 
     // Spawn queue actor.
     let mut queue = {
-        let (queue, queue_actor) = queue::channel(endpoint, opt.backlog, cores, api, logger.clone());
+        let status_events = if opt.backlog.status_events && !serve {
+            Some(events::spawn(endpoint.clone(), api_client.clone(), bandwidth.clone(), logger.clone()))
+        } else {
+            None
+        };
+        let (queue, mut queue_actor) = queue::channel(endpoint, opt.backlog, workers, !serve, health.clone(), api, logger.clone());
+        if let Some(ref variants) = variants {
+            queue_actor.set_variants(variants);
+        }
+        if let Some(status_events) = status_events {
+            queue_actor.set_status_events(status_events);
+        }
         join_handles.push(tokio::spawn(async move {
             queue_actor.run().await;
         }));
         queue
     };
 
-    // Spawn workers. Workers handle engine processes and send their results
-    // to tx, thereby requesting more work.
-    let mut rx = {
-        let assets = Arc::new(assets);
-        let (tx, rx) = mpsc::channel::<Pull>(cores);
-        for i in 0..cores {
-            let logger = logger.clone();
-            let assets = assets.clone();
-            let tx = tx.clone();
-            join_handles.push(tokio::spawn(async move {
-                logger.debug(&format!("Started worker {}.", i));
-
-                let mut job: Option<Position> = None;
-                let mut engine = ByEngineFlavor {
-                    official: None,
-                    multi_variant: None,
-                };
-                let mut engine_backoff = RandomizedBackoff::default();
-
-                loop {
-                    let response = if let Some(job) = job.take() {
-                        // Ensure engine process is ready.
-                        let flavor = job.flavor;
-                        let context = ProgressAt::from(&job);
-                        let (mut sf, join_handle) = if let Some((sf, join_handle)) = engine.get_mut(flavor).take() {
-                            (sf, join_handle)
-                        } else {
-                            // Backoff before starting engine.
-                            let backoff = engine_backoff.next();
-                            if backoff >= Duration::from_secs(5) {
-                                logger.info(&format!("Waiting {:?} before attempting to start engine", backoff));
-                            } else {
-                                logger.debug(&format!("Waiting {:?} before attempting to start engine", backoff));
-                            }
-                            tokio::select! {
-                                _ = tx.closed() => break,
-                                _ = time::sleep(engine_backoff.next()) => (),
-                            }
-
-                            // Start engine and spawn actor.
-                            let (sf, sf_actor) = stockfish::channel(assets.stockfish.get(flavor).clone(), StockfishInit {
-                                nnue: assets.nnue.clone(),
-                            }, logger.clone());
-                            let join_handle = tokio::spawn(async move {
-                                sf_actor.run().await;
-                            });
-                            (sf, join_handle)
-                        };
-
-                        // Heuristic for timeout, based on fixed communication
-                        // cost and nodes.
-                        let nodes = job.work.node_limit().unwrap_or_default().get(flavor.eval_flavor());
-                        let timeout = Duration::from_secs(4 + nodes / 250_000);
-
-                        // Analyse or play.
-                        tokio::select! {
-                            _ = tx.closed() => {
-                                logger.debug(&format!("Worker {} shutting down engine early", i));
-                                drop(sf);
-                                join_handle.await.expect("join");
-                                break;
-                            }
-                            _ = time::sleep(timeout) => {
-                                logger.warn(&format!("Engine timed out in worker {}. If this happens frequently it is better to stop and defer to clients with better hardware. Context: {}", i, context));
-                                drop(sf);
-                                join_handle.await.expect("join");
-                                break;
-                            }
-                            res = sf.go(job) => {
-                                match res {
-                                    Ok(res) => {
-                                        *engine.get_mut(flavor) = Some((sf, join_handle));
-                                        engine_backoff.reset();
-                                        Some(Ok(res))
-                                    }
-                                    Err(failed) => {
-                                        drop(sf);
-                                        logger.warn(&format!("Worker {} waiting for engine to shut down after error. Context: {}", i, context));
-                                        join_handle.await.expect("join");
-                                        Some(Err(failed))
-                                    },
-                                }
-                            }
-                        }
-                    } else {
-                        None
-                    };
-
-                    let (callback, waiter) = oneshot::channel();
+    // Accept work from local clients.
+    if serve {
+        let addr = opt.listen.unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], 9670)));
+        let queue = queue.clone();
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve::serve(addr, queue, logger.clone()).await {
+                logger.error(&format!("Failed to serve local clients on {}: {}", addr, err));
+            }
+        });
+    }
 
-                    if tx.send(Pull { response, callback }).await.is_err() {
-                        logger.debug(&format!("Worker {} was about to send result, but shutting down", i));
-                        break;
-                    }
+    // Expose metrics and probes.
+    if let Some(addr) = opt.metrics_listen.clone() {
+        let socket_mode = opt.metrics_socket_mode.unwrap_or(0o660);
+        let health = health.clone();
+        let queue = queue.clone();
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            if let Err(err) = metrics::listen(&addr, socket_mode, health, queue, logger.clone()).await {
+                logger.error(&format!("Failed to serve metrics on {}: {}", addr, err));
+            }
+        });
+    }
 
-                    tokio::select! {
-                        _ = tx.closed() => break,
-                        res = waiter => {
-                            match res {
-                                Ok(next_job) => job = Some(next_job),
-                                Err(_) => break,
-                            }
-                        }
+    // Hand out positions to cluster workers, in addition to local cores.
+    if let Some(addr) = opt.cluster.coordinator_listen {
+        match opt.cluster.cluster_secret.clone() {
+            Some(secret) => {
+                let queue = queue.clone();
+                let logger = logger.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = cluster::coordinate(addr, secret, queue, logger.clone()).await {
+                        logger.error(&format!("Failed to coordinate workers on {}: {}", addr, err));
                     }
-                }
-
-                if let Some((sf, join_handle)) = engine.get_mut(EngineFlavor::Official).take() {
-                    logger.debug(&format!("Worker {} waiting for standard engine to shut down", i));
-                    drop(sf);
-                    join_handle.await.expect("join");
-                }
+                });
+            }
+            None => logger.error("Not coordinating workers: --cluster-secret is required"),
+        }
+    }
 
-                if let Some((sf, join_handle)) = engine.get_mut(EngineFlavor::MultiVariant).take() {
-                    logger.debug(&format!("Worker {} waiting for multi-variant engine to shut down", i));
-                    drop(sf);
-                    join_handle.await.expect("join");
-                }
+    // Spawn workers. Workers handle engine processes and send their results
+    // to tx, thereby requesting more work.
+    let mut rx = spawn_workers(workers, assets, opt.engine.clone(), health.clone(), webhook.clone(), logger, &mut join_handles);
 
-                logger.debug(&format!("Stopped worker {}", i));
-                drop(tx);
-            }));
+    // Replace the status bar with the dashboard.
+    let (tui, mut tui_interrupt) = if opt.tui && atty::is(atty::Stream::Stdout) {
+        match Tui::spawn(workers, queue.clone(), health.clone(), logger.clone()) {
+            Ok((tui, interrupt)) => (Some(tui), Some(interrupt)),
+            Err(err) => {
+                logger.warn(&format!("Failed to start dashboard: {}", err));
+                (None, None)
+            }
+        }
+    } else {
+        if opt.tui {
+            logger.warn("Not showing dashboard: not attached to a terminal");
         }
-        rx
+        (None, None)
     };
 
+    // Watch for the spot or preemptible instance being reclaimed.
+    let mut preempted = opt.preemption_notice.map(|cloud| {
+        logger.info(&format!("Watching {} metadata for preemption notices", cloud));
+        preemption::spawn(cloud, logger.clone())
+    });
+
     let restart = Arc::new(std::sync::Mutex::new(None));
     let mut up_to_date = Instant::now();
     let mut summarized = Instant::now();
+    let mut expired = Instant::now();
+    let mut progressed = (Instant::now(), 0);
     let mut shutdown_soon = false;
+    let mut exit = None;
+    let deadline = opt.run_for.map(|run_for| Instant::now() + run_for);
+    let mut scaled_down = false;
 
     loop {
+        health.tick();
+
+        // Engines that never started will not start on their own.
+        if exit.is_none() && health.has_engine_failed() {
+            logger.error("Engine failed to start. Stopping.");
+            exit = Some(Exit::EngineFailed);
+            shutdown_soon = true;
+            rx.close();
+        }
+
         // Check for updates from time to time.
         let now = Instant::now();
         if opt.auto_update && !shutdown_soon && now.duration_since(up_to_date) >= Duration::from_secs(60 * 60 * 5) {
             up_to_date = now;
-            let logger = logger.clone();
-            let inner_restart = restart.clone();
-            tokio::task::spawn_blocking(move || {
-                let current_exe = env::current_exe().expect("current exe");
-                match auto_update(false, &logger) {
-                    Err(err) => logger.error(&format!("Failed to update in the background: {}", err)),
-                    Ok(self_update::Status::UpToDate(version)) => {
-                        logger.fishnet_info(&format!("Fishnet {} is up to date", version));
-                    }
-                    Ok(self_update::Status::Updated(version)) => {
-                        logger.fishnet_info(&format!("Fishnet updated to {}. Will restart soon", version));
-                        *inner_restart.lock().expect("restart mutex") = Some(current_exe);
-                    }
+            let current_exe = env::current_exe().expect("current exe");
+            match update::auto_update(false, &client, &bandwidth, logger).await {
+                Err(err) => logger.error(&format!("Failed to update in the background: {}", err)),
+                Ok(UpdateStatus::UpToDate(version)) => {
+                    logger.fishnet_info(&format!("Fishnet {} is up to date", version));
+                }
+                Ok(UpdateStatus::Updated(version)) => {
+                    logger.fishnet_info(&format!("Fishnet updated to {}. Will restart soon", version));
+                    *restart.lock().expect("restart mutex") = Some(current_exe);
                 }
-            }).await.expect("spawn blocking update");
+            }
 
             if restart.lock().expect("restart mutex").is_some() {
                 shutdown_soon = true;
@@ -306,20 +493,92 @@ async fn run(opt: Opt, logger: &Logger) {
             }
         }
 
+        // Ran for long enough.
+        if !shutdown_soon && deadline.is_some_and(|deadline| now >= deadline) {
+            logger.headline("Time is up. Stopping after finishing pending batches ...");
+            shutdown_soon = true;
+            queue.shutdown_soon().await;
+        }
+
+        // Let autoscalers know when there is no work.
+        if let Some(scale_down_after) = opt.scale_down_after {
+            match queue.no_work_since().await {
+                Some(since) if !scaled_down && since.elapsed() >= scale_down_after => {
+                    scaled_down = true;
+                    logger.info(&format!("No work for {}s. Ready to scale down", since.elapsed().as_secs()));
+                    webhook.notify(Event::NoWork {
+                        duration: since.elapsed(),
+                    });
+                    if opt.scale_down_exit && !shutdown_soon {
+                        logger.headline("Stopping, because there is no work ...");
+                        exit = Some(Exit::NoWork);
+                        shutdown_soon = true;
+                        queue.shutdown_soon().await;
+                    }
+                }
+                Some(_) => (),
+                None => scaled_down = false,
+            }
+        }
+
+        // Give back batches that are stuck. Not on every completed
+        // position, to keep the queue lock free for workers.
+        if now.duration_since(expired) >= Duration::from_secs(1) {
+            expired = now;
+            queue.expire().await;
+            crash.set_pending(queue.pending_batches().await);
+        }
+
         // Print summary from time to time.
         if now.duration_since(summarized) >= Duration::from_secs(120) {
             summarized = now;
             let stats = queue.stats().await;
-            logger.fishnet_info(&format!("fishnet/{}: {} (nnue), {} batches, {} positions, {} total nodes",
+            logger.fishnet_info(&format!("fishnet/{}: {} (nnue), {} batches, {} positions, {} total nodes, {} sent, {} received",
                                          env!("CARGO_PKG_VERSION"),
                                          stats.nnue_nps,
-                                         stats.total_batches, stats.total_positions, stats.total_nodes));
+                                         stats.total_batches, stats.total_positions, stats.total_nodes,
+                                         Bytes(stats.bytes_sent), Bytes(stats.bytes_received)));
+            if !stats.round_trips.is_empty() {
+                logger.info(&format!("API round trips (p50/p90/p99): {}", stats.round_trips.iter().map(|r| {
+                    format!("{} {}/{}/{}ms", r.kind, r.p50.as_millis(), r.p90.as_millis(), r.p99.as_millis())
+                }).collect::<Vec<_>>().join(", ")));
+            }
+            if let Some(since) = queue.api_unhealthy_since() {
+                logger.warn(&format!("API unhealthy since {}s ago", since.elapsed().as_secs()));
+            }
+            if let Some(cap) = bandwidth.cap() {
+                logger.info(&format!("Bandwidth this month: {} of {}", Bytes(bandwidth.this_month()), Bytes(cap)));
+            }
+            save_bandwidth(&bandwidth, logger);
+            for (i, worker) in stats.workers.iter().enumerate() {
+                logger.debug(&format!("Worker {}: {}, {} positions, {} errors", i, worker.nps, worker.positions, worker.errors));
+            }
+            for (i, nps, median) in queue.slow_workers().await {
+                logger.warn(&format!("Worker {} is persistently slow ({} knps, median {} knps). Check for throttling or a bad core", i, nps / 1000, median / 1000));
+            }
+            if stats.total_positions != progressed.1 {
+                progressed = (now, stats.total_positions);
+            } else if !serve && now.duration_since(progressed.0) >= Duration::from_secs(60 * 60) {
+                webhook.notify(Event::Idle {
+                    duration: now.duration_since(progressed.0),
+                });
+            }
         }
 
         // Main loop. Handles signals, forwards worker results from rx to the
         // queue and responds with more work.
         tokio::select! {
-            res = sig_int.recv() => {
+            res = async {
+                // The dashboard puts the terminal into raw mode, so ^C
+                // arrives as a key press.
+                match tui_interrupt {
+                    Some(ref mut tui_interrupt) => tokio::select! {
+                        res = sig_int.recv() => res,
+                        Some(()) = tui_interrupt.recv() => Some(()),
+                    },
+                    None => sig_int.recv().await,
+                }
+            } => {
                 res.expect("sigint handler installed");
                 if shutdown_soon {
                     logger.clear_echo();
@@ -338,6 +597,34 @@ async fn run(opt: Opt, logger: &Logger) {
                 shutdown_soon = true;
                 rx.close();
             }
+            Some(()) = async {
+                match preempted {
+                    Some(ref mut preempted) => preempted.recv().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                preempted = None;
+                match opt.preemption_action.unwrap_or_default() {
+                    PreemptionAction::Abort => {
+                        logger.headline("Instance is about to be reclaimed. Reporting progress and giving back batches ...");
+                        queue.report_progress().await;
+                        shutdown_soon = true;
+                        rx.close();
+                    }
+                    PreemptionAction::Drain => {
+                        logger.headline("Instance is about to be reclaimed. Stopping soon ...");
+                        queue.shutdown_soon().await;
+                        shutdown_soon = true;
+                    }
+                }
+            }
+            res = sig_quit.recv() => {
+                res.expect("sigquit handler installed");
+                logger.headline("State dump");
+                for line in metrics::state_dump(&health, &queue).await.lines() {
+                    logger.notice(line);
+                }
+            }
             res = rx.recv() => {
                 if let Some(res) = res {
                     queue.pull(res).await;
@@ -346,21 +633,414 @@ async fn run(opt: Opt, logger: &Logger) {
                     break;
                 }
             }
-            _ = time::sleep(Duration::from_secs(120)) => (),
+            _ = time::sleep(match deadline {
+                Some(deadline) if !shutdown_soon => deadline.saturating_duration_since(Instant::now()).min(Duration::from_secs(120)),
+                _ => Duration::from_secs(120),
+            }) => (),
         }
     }
 
+    if let Some(tui) = tui {
+        tui.stop().await;
+    }
+
     // Shutdown queue to abort remaining jobs.
-    queue.shutdown().await;
+    let update_required = queue.update_required().await;
+    let given_back = queue.shutdown().await;
 
     // Wait for all workers.
     for join_handle in join_handles.into_iter() {
         join_handle.await.expect("join");
     }
 
+    // Server rejected this client version.
+    if update_required && restart.lock().expect("restart mutex").is_none() {
+        webhook.notify(Event::UpdateRequired);
+        if opt.auto_update {
+            let current_exe = env::current_exe().expect("current exe");
+            match update::auto_update(false, &client, &bandwidth, logger).await {
+                Err(err) => logger.error(&format!("Failed to update: {}", err)),
+                Ok(UpdateStatus::UpToDate(version)) => {
+                    logger.error(&format!("Fishnet {} is the latest release, but the server requires an update. Please report this", version));
+                }
+                Ok(UpdateStatus::Updated(version)) => {
+                    logger.fishnet_info(&format!("Fishnet updated to {}", version));
+                    *restart.lock().expect("restart mutex") = Some(current_exe);
+                }
+            }
+        } else {
+            logger.error("Server requires a newer fishnet. Update manually or run with --auto-update");
+        }
+    }
+
     // Restart.
     let mut restart = restart.lock().expect("restart mutex");
     if let Some(restart) = restart.take() {
+        save_bandwidth(&bandwidth, logger);
         restart_process(restart, logger);
     }
+
+    if let Some(exit) = exit {
+        exit
+    } else if update_required {
+        Exit::UpdateRequired
+    } else if given_back > 0 {
+        Exit::Aborted
+    } else {
+        Exit::Drained
+    }
+}
+
+/// Decides how many engine processes to run on the given cores, and fixes
+/// the number of threads for each of them in the options.
+fn engine_layout(cores: usize, assets: &Assets, opt: &mut EngineOpt, logger: &Logger) -> usize {
+    let threads = match opt.threads_per_worker.unwrap_or_default() {
+        ThreadsPerWorker::Number(n) => min(usize::from(n), cores),
+        ThreadsPerWorker::Auto if opt.fake_engine.is_some() => 1,
+        ThreadsPerWorker::Auto => {
+            logger.info("Benchmarking threads per worker ...");
+            assets.threads_per_worker(cores, logger)
+        }
+    };
+    let workers = max(1, cores / threads);
+    if threads > 1 {
+        logger.info(&format!("Workers: {} with {} threads each", workers, threads));
+    }
+    opt.threads_per_worker = NonZeroUsize::new(threads).map(ThreadsPerWorker::Number);
+    workers
+}
+
+/// Spawns workers. Workers handle engine processes and send their results
+/// to tx, thereby requesting more work.
+fn spawn_workers(workers: usize, assets: Assets, opt: EngineOpt, health: Arc<Health>, webhook: WebhookStub, logger: &Logger, join_handles: &mut Vec<JoinHandle<()>>) -> mpsc::Receiver<Pull> {
+    let assets = Arc::new(assets);
+    let hang_timeout = opt.hang_timeout.unwrap_or(Duration::from_secs(60));
+    let engine_retries = opt.engine_retries.unwrap_or(2);
+    let multipv = opt.multipv;
+    let max_depth = opt.max_depth;
+    let uci_options = (opt.uci_option, opt.uci_option_move, opt.uci_option_analysis);
+    let fake_engine = opt.fake_engine.map(Duration::from_millis);
+    let sandbox = !opt.no_engine_sandbox && sandbox::SUPPORTED;
+    let mate_early_stop = !opt.no_mate_early_stop;
+    if !opt.no_engine_sandbox && !sandbox::SUPPORTED && fake_engine.is_none() {
+        logger.debug("Engine sandbox is not supported on this platform");
+    }
+    // Resolved by engine_layout().
+    let threads = match opt.threads_per_worker {
+        Some(ThreadsPerWorker::Number(n)) => usize::from(n),
+        _ => 1,
+    };
+    let memory_limit = opt.max_memory.map(|mib| mib * 1024 * 1024 / workers as u64);
+    if let Some(limit) = memory_limit {
+        let hash = stockfish::hash_for_memory_limit(limit);
+        logger.info(&format!("Memory limit: {} MiB per engine (hash: {} MiB)", limit / (1024 * 1024), hash));
+        if limit <= stockfish::ENGINE_OVERHEAD {
+            logger.warn("Memory limit is too small for the number of cores. Engines will likely crash. Reduce --cores or raise --max-memory.");
+        }
+    }
+    let (tx, rx) = mpsc::channel::<Pull>(workers);
+    for i in 0..workers {
+        let logger = logger.clone();
+        let assets = assets.clone();
+        let health = health.clone();
+        let webhook = webhook.clone();
+        let uci_options = uci_options.clone();
+        let tx = tx.clone();
+        join_handles.push(tokio::spawn(async move {
+            logger.debug(&format!("Started worker {}.", i));
+            health.worker_started();
+
+            let mut job: Option<Position> = None;
+            let mut engine = ByEngineFlavor {
+                official: None,
+                multi_variant: None,
+            };
+            let mut engine_backoff = RandomizedBackoff::default();
+
+            loop {
+                // Skip positions of batches that are no longer needed.
+                let response = if let Some(mut job) = job.take().filter(|job| !job.cancellation.is_cancelled()) {
+                    // Apply local MultiPV, unless requested by the server,
+                    // and the stricter depth limit.
+                    if let Work::Analysis { multipv: ref mut m, depth: ref mut d, .. } = job.work {
+                        if m.is_none() {
+                            *m = multipv;
+                        }
+                        *d = match (*d, max_depth) {
+                            (Some(d), Some(max_depth)) => Some(min(d, max_depth)),
+                            (d, max_depth) => d.or(max_depth),
+                        };
+                    }
+
+                    // Ensure engine process is ready.
+                    let flavor = job.flavor;
+                    let context = ProgressAt::from(&job);
+                    health.worker_busy(i, Some(context.to_string()));
+                    let (mut sf, join_handle) = if let Some((sf, join_handle)) = engine.get_mut(flavor).take() {
+                        (sf, join_handle)
+                    } else {
+                        // Backoff before starting engine.
+                        let backoff = engine_backoff.next();
+                        if backoff >= Duration::from_secs(5) {
+                            logger.info(&format!("Waiting {:?} before attempting to start engine", backoff));
+                        } else {
+                            logger.debug(&format!("Waiting {:?} before attempting to start engine", backoff));
+                        }
+                        tokio::select! {
+                            _ = tx.closed() => break,
+                            _ = time::sleep(engine_backoff.next()) => (),
+                        }
+
+                        // Start engine and spawn actor.
+                        let (sf, sf_actor) = stockfish::channel(assets.stockfish.get(flavor).clone(), StockfishInit {
+                            nnue: assets.nnue.clone(),
+                            hash: memory_limit.map(stockfish::hash_for_memory_limit),
+                            threads,
+                            memory_limit,
+                            fake: fake_engine,
+                            sandbox,
+                            mate_early_stop,
+                            options: uci_options.0.clone(),
+                            move_options: uci_options.1.clone(),
+                            analysis_options: uci_options.2.clone(),
+                        }, i, logger.clone());
+                        let join_handle = tokio::spawn(async move {
+                            sf_actor.run().await;
+                        });
+                        (sf, join_handle)
+                    };
+
+                    // Heuristic for timeout, based on fixed communication
+                    // cost and nodes.
+                    let timeout = match job.work.movetime() {
+                        Some(movetime) => Duration::from_secs(4) + movetime,
+                        None => {
+                            let nodes = job.work.node_limit().unwrap_or_default().get(flavor.eval_flavor());
+                            Duration::from_secs(4 + nodes / 250_000)
+                        }
+                    };
+
+                    // Keep a copy to retry if the engine hangs or crashes.
+                    let batch_id = job.work.id();
+                    let position_id = job.position_id;
+                    let retry = if job.attempts < engine_retries {
+                        Some(Position {
+                            attempts: job.attempts + 1,
+                            ..job.clone()
+                        })
+                    } else {
+                        None
+                    };
+                    let silence = sf.silence();
+                    let stderr = sf.stderr();
+
+                    // Analyse or play.
+                    tokio::select! {
+                        _ = tx.closed() => {
+                            logger.debug(&format!("Worker {} shutting down engine early", i));
+                            drop(sf);
+                            join_handle.await.expect("join");
+                            break;
+                        }
+                        _ = time::sleep(timeout) => {
+                            logger.warn(&format!("Engine timed out in worker {}. If this happens frequently it is better to stop and defer to clients with better hardware. Context: {}", i, context));
+                            drop(sf);
+                            join_handle.await.expect("join");
+                            break;
+                        }
+                        _ = silence.exceeds(hang_timeout) => {
+                            logger.warn(&format!("Engine in worker {} produced no output for {:?}. Restarting it. Context: {}", i, hang_timeout, context));
+                            drop(sf);
+                            join_handle.await.expect("join");
+                            Some(Err(PositionFailed {
+                                batch_id,
+                                position_id,
+                                retry,
+                            }))
+                        }
+                        res = sf.go(job) => {
+                            match res {
+                                Ok(res) => {
+                                    *engine.get_mut(flavor) = Some((sf, join_handle));
+                                    engine_backoff.reset();
+                                    health.engine_started();
+                                    Some(Ok(res))
+                                }
+                                Err(failed) => {
+                                    drop(sf);
+                                    logger.warn(&format!("Worker {} waiting for engine to shut down after error. Context: {}", i, context));
+                                    join_handle.await.expect("join");
+                                    let lines = stderr.lines();
+                                    if !lines.is_empty() {
+                                        logger.warn(&format!("Last engine stderr of worker {}:\n{}", i, lines.join("\n")));
+                                    }
+                                    if retry.is_none() {
+                                        logger.warn(&format!("Giving up on position after {} engine failures. Context: {}", engine_retries + 1, context));
+                                        health.engine_failed();
+                                        webhook.notify(Event::EngineCrashes {
+                                            worker: i,
+                                            failures: engine_retries + 1,
+                                        });
+                                    }
+                                    Some(Err(PositionFailed {
+                                        retry,
+                                        ..failed
+                                    }))
+                                },
+                            }
+                        }
+                    }
+                } else {
+                    None
+                };
+
+                health.worker_busy(i, None);
+                let (callback, waiter) = oneshot::channel();
+
+                if tx.send(Pull { worker: Some(i), response, callback }).await.is_err() {
+                    logger.debug(&format!("Worker {} was about to send result, but shutting down", i));
+                    break;
+                }
+
+                tokio::select! {
+                    _ = tx.closed() => break,
+                    res = waiter => {
+                        match res {
+                            Ok(next_job) => job = Some(next_job),
+                            Err(_) => break,
+                        }
+                    }
+                }
+            }
+
+            if let Some((sf, join_handle)) = engine.get_mut(EngineFlavor::Official).take() {
+                logger.debug(&format!("Worker {} waiting for standard engine to shut down", i));
+                drop(sf);
+                join_handle.await.expect("join");
+            }
+
+            if let Some((sf, join_handle)) = engine.get_mut(EngineFlavor::MultiVariant).take() {
+                logger.debug(&format!("Worker {} waiting for multi-variant engine to shut down", i));
+                drop(sf);
+                join_handle.await.expect("join");
+            }
+
+            logger.debug(&format!("Stopped worker {}", i));
+            health.worker_stopped();
+            drop(tx);
+        }));
+    }
+    rx
+}
+
+async fn run_dev_server(opt: Opt, logger: &Logger) -> Exit {
+    let addr = opt.listen.unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], 9670)));
+    if let Err(err) = dev_server::serve(addr, opt.dev_server, logger.clone()).await {
+        logger.error(&format!("Dev server failed: {}", err));
+    }
+    Exit::Drained
+}
+
+async fn run_worker(mut opt: Opt, logger: &Logger) -> Exit {
+    logger.headline("Checking configuration ...");
+
+    let (coordinator, secret) = match (opt.cluster.coordinator, opt.cluster.cluster_secret) {
+        (Some(coordinator), Some(secret)) => (coordinator, secret),
+        _ => {
+            logger.error("Worker mode requires --coordinator and --cluster-secret");
+            return Exit::Drained;
+        }
+    };
+    logger.info(&format!("Coordinator: {}", coordinator));
+
+    let cpu = Cpu::detect();
+    logger.info(&format!("CPU features: {:?}", cpu));
+
+    let assets = match Assets::prepare(cpu, logger) {
+        Ok(assets) => assets,
+        Err(err) => {
+            logger.error(&format!("Failed to prepare bundled stockfish: {}", err));
+            return Exit::EngineFailed;
+        }
+    };
+    logger.info(&format!("Engine: {} (for GPLv3, run: {} license)", assets.sf_name, env::args().next().unwrap_or_else(|| "./fishnet".to_owned())));
+
+    let cores = usize::from(opt.cores.unwrap_or(Cores::Auto));
+    logger.info(&format!("Cores: {}", cores));
+    let workers = engine_layout(cores, &assets, &mut opt.engine, logger);
+
+    // Install handler for SIGTERM.
+    #[cfg(unix)]
+    let mut sig_term = signal::unix::signal(signal::unix::SignalKind::terminate()).expect("install handler for sigterm");
+    #[cfg(windows)]
+    let mut sig_term = signal::windows::ctrl_break().expect("install handler for ctrl+break");
+
+    // Install handler for SIGINT.
+    #[cfg(unix)]
+    let mut sig_int = signal::unix::signal(signal::unix::SignalKind::interrupt()).expect("install handler for sigint");
+    #[cfg(windows)]
+    let mut sig_int = signal::windows::ctrl_c().expect("install handler for ctrl+c");
+
+    // To wait for workers and cluster actor before shutdown.
+    let mut join_handles = Vec::new();
+
+    logger.headline("Running (press Ctrl + C to stop) ...");
+
+    // Spawn cluster actor, taking the place of the queue.
+    let mut cluster = {
+        let (cluster, cluster_actor) = cluster::channel(coordinator, secret, workers, logger.clone());
+        join_handles.push(tokio::spawn(async move {
+            cluster_actor.run().await;
+        }));
+        cluster
+    };
+
+    let mut rx = spawn_workers(workers, assets, opt.engine, Arc::new(Health::new(workers)), WebhookStub::default(), logger, &mut join_handles);
+
+    // Main loop. Positions handed out by the coordinator are lost when the
+    // connection closes, so there is no graceful shutdown.
+    loop {
+        tokio::select! {
+            res = sig_int.recv() => {
+                res.expect("sigint handler installed");
+                logger.clear_echo();
+                logger.fishnet_info("Stopping now.");
+                rx.close();
+            }
+            res = sig_term.recv() => {
+                res.expect("sigterm handler installed");
+                logger.fishnet_info("Stopping now.");
+                rx.close();
+            }
+            res = rx.recv() => {
+                if let Some(res) = res {
+                    cluster.pull(res);
+                } else {
+                    logger.debug("About to exit.");
+                    break;
+                }
+            }
+        }
+    }
+
+    // Disconnect from coordinator.
+    drop(cluster);
+
+    // Wait for all workers.
+    for join_handle in join_handles.into_iter() {
+        join_handle.await.expect("join");
+    }
+
+    Exit::Drained
+}
+
+/// Stands in for signals that do not exist on this platform.
+#[cfg(windows)]
+struct NoSignal;
+
+#[cfg(windows)]
+impl NoSignal {
+    async fn recv(&mut self) -> Option<()> {
+        std::future::pending().await
+    }
 }
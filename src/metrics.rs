@@ -0,0 +1,267 @@
+use std::fmt::Write as _;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt as _, AsyncRead, AsyncWrite, AsyncWriteExt as _, BufReader};
+use tokio::net::TcpListener;
+use crate::configure::ListenAddr;
+use crate::logger::Logger;
+use crate::queue::QueueStub;
+use crate::web::{self, History};
+
+/// The main loop ticks at least every two minutes.
+const STALL_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Shared view on the health of the client, for liveness and readiness
+/// probes.
+pub struct Health {
+    key_accepted: AtomicBool,
+    engine_started: AtomicBool,
+    engine_failed: AtomicBool,
+    workers: AtomicUsize,
+    expected_workers: usize,
+    tick: Mutex<Instant>,
+    activity: Mutex<Vec<Option<String>>>,
+}
+
+impl Health {
+    pub fn new(expected_workers: usize) -> Health {
+        Health {
+            key_accepted: AtomicBool::new(false),
+            engine_started: AtomicBool::new(false),
+            engine_failed: AtomicBool::new(false),
+            workers: AtomicUsize::new(0),
+            expected_workers,
+            tick: Mutex::new(Instant::now()),
+            activity: Mutex::new(vec![None; expected_workers]),
+        }
+    }
+
+    pub fn key_accepted(&self) {
+        self.key_accepted.store(true, Ordering::Relaxed);
+    }
+
+    pub fn engine_started(&self) {
+        self.engine_started.store(true, Ordering::Relaxed);
+    }
+
+    /// Called when giving up on a position. Only counts as a failure to
+    /// start if no engine ever worked.
+    pub fn engine_failed(&self) {
+        if !self.engine_started.load(Ordering::Relaxed) {
+            self.engine_failed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub fn has_engine_failed(&self) -> bool {
+        self.engine_failed.load(Ordering::Relaxed)
+    }
+
+    pub fn worker_started(&self) {
+        self.workers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn worker_stopped(&self) {
+        self.workers.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Records what a worker is analysing, if anything.
+    pub fn worker_busy(&self, worker: usize, context: Option<String>) {
+        let mut activity = self.activity.lock().expect("health activity");
+        if activity.len() <= worker {
+            activity.resize(worker + 1, None);
+        }
+        activity[worker] = context;
+    }
+
+    pub fn worker_activity(&self) -> Vec<Option<String>> {
+        self.activity.lock().expect("health activity").clone()
+    }
+
+    /// Called from the main loop, which also waits for the queue.
+    pub fn tick(&self) {
+        *self.tick.lock().expect("health tick") = Instant::now();
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.key_accepted.load(Ordering::Relaxed) && self.engine_started.load(Ordering::Relaxed)
+    }
+
+    pub fn is_live(&self) -> bool {
+        self.tick.lock().expect("health tick").elapsed() < STALL_TIMEOUT &&
+            self.workers.load(Ordering::Relaxed) >= self.expected_workers
+    }
+}
+
+pub async fn listen(addr: &ListenAddr, socket_mode: u32, health: Arc<Health>, queue: QueueStub, logger: Logger) -> io::Result<()> {
+    let history = Arc::new(History::default());
+    tokio::spawn(web::record_history(history.clone(), queue.clone()));
+    match addr {
+        ListenAddr::Tcp(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            logger.info(&format!("Metrics on http://{} (/metrics, /healthz, /readyz, /debug/state, dashboard on /)", addr));
+            loop {
+                let (stream, peer) = listener.accept().await?;
+                spawn_connection(stream, peer.to_string(), health.clone(), history.clone(), queue.clone(), logger.clone());
+            }
+        }
+        #[cfg(unix)]
+        ListenAddr::Unix(path) => {
+            use std::fs;
+            use std::os::unix::fs::PermissionsExt as _;
+
+            // Remove a stale socket left behind by a previous run.
+            match fs::remove_file(path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => (),
+            }
+            let listener = tokio::net::UnixListener::bind(path)?;
+            fs::set_permissions(path, fs::Permissions::from_mode(socket_mode))?;
+            logger.info(&format!("Metrics on {} (/metrics, /healthz, /readyz, /debug/state, dashboard on /)", path.display()));
+            loop {
+                let (stream, _) = listener.accept().await?;
+                spawn_connection(stream, path.display().to_string(), health.clone(), history.clone(), queue.clone(), logger.clone());
+            }
+        }
+        #[cfg(not(unix))]
+        ListenAddr::Unix(_) => {
+            let _ = socket_mode;
+            Err(io::Error::new(io::ErrorKind::Other, "unix sockets are not supported on this platform"))
+        }
+    }
+}
+
+fn spawn_connection<S>(stream: S, peer: String, health: Arc<Health>, history: Arc<History>, queue: QueueStub, logger: Logger)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        if let Err(err) = handle_connection(stream, health, history, queue, &logger).await {
+            logger.debug(&format!("Metrics connection from {} failed: {}", peer, err));
+        }
+    });
+}
+
+async fn handle_connection<S>(stream: S, health: Arc<Health>, history: Arc<History>, queue: QueueStub, logger: &Logger) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut stream = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await?;
+    let path = request_line.split_whitespace().nth(1).unwrap_or("").to_owned();
+
+    // Only the headers of the WebSocket handshake are of interest.
+    let (mut websocket_key, mut origin, mut host) = (None, None, None);
+    loop {
+        let mut header = String::new();
+        if stream.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some(colon) = header.find(':') {
+            let (name, value) = (header[..colon].trim(), header[colon + 1..].trim().to_owned());
+            if name.eq_ignore_ascii_case("sec-websocket-key") {
+                websocket_key = Some(value);
+            } else if name.eq_ignore_ascii_case("origin") {
+                origin = Some(value);
+            } else if name.eq_ignore_ascii_case("host") {
+                host = Some(value);
+            }
+        }
+    }
+
+    match (path.as_str(), websocket_key) {
+        ("/", _) => {
+            let stream = stream.get_mut();
+            stream.write_all(format!("HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", web::INDEX.len(), web::INDEX).as_bytes()).await?;
+            return stream.shutdown().await;
+        }
+        ("/ws", Some(key)) if web::same_origin(origin.as_deref(), host.as_deref()) => {
+            return web::websocket(stream, &key, health, history, queue, logger.clone()).await;
+        }
+        _ => (),
+    }
+
+    let (status, body) = match path.as_str() {
+        "/ws" => ("403 Forbidden", "forbidden\n".to_owned()),
+        "/healthz" if health.is_live() => ("200 OK", "ok\n".to_owned()),
+        "/healthz" => ("503 Service Unavailable", "stalled\n".to_owned()),
+        "/readyz" if health.is_ready() => ("200 OK", "ok\n".to_owned()),
+        "/readyz" => ("503 Service Unavailable", "not ready\n".to_owned()),
+        "/metrics" => ("200 OK", metrics(&queue, logger).await),
+        "/debug/state" => ("200 OK", state_dump(&health, &queue).await),
+        _ => ("404 Not Found", "not found\n".to_owned()),
+    };
+
+    let stream = stream.get_mut();
+    stream.write_all(format!("HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body).as_bytes()).await?;
+    stream.shutdown().await
+}
+
+async fn metrics(queue: &QueueStub, logger: &Logger) -> String {
+    let stats = queue.stats().await;
+    let mut metrics = format!(concat!(
+        "# TYPE fishnet_batches_total counter\n",
+        "fishnet_batches_total {}\n",
+        "# TYPE fishnet_positions_total counter\n",
+        "fishnet_positions_total {}\n",
+        "# TYPE fishnet_nodes_total counter\n",
+        "fishnet_nodes_total {}\n",
+        "# TYPE fishnet_nnue_nps gauge\n",
+        "fishnet_nnue_nps {}\n",
+        "# TYPE fishnet_engine_crashes_total counter\n",
+        "fishnet_engine_crashes_total {}\n",
+        "# TYPE fishnet_bytes_sent_total counter\n",
+        "fishnet_bytes_sent_total {}\n",
+        "# TYPE fishnet_bytes_received_total counter\n",
+        "fishnet_bytes_received_total {}\n",
+        "# TYPE fishnet_errors_total counter\n",
+        "fishnet_errors_total {}\n",
+    ), stats.total_batches, stats.total_positions, stats.total_nodes, stats.nnue_nps.nps(), stats.engine_crashes, stats.bytes_sent, stats.bytes_received, logger.error_count());
+
+    if !stats.round_trips.is_empty() {
+        metrics.push_str("# TYPE fishnet_api_round_trip_seconds summary\n");
+    }
+    for round_trips in &stats.round_trips {
+        for (quantile, duration) in &[("0.5", round_trips.p50), ("0.9", round_trips.p90), ("0.99", round_trips.p99)] {
+            writeln!(metrics, "fishnet_api_round_trip_seconds{{call=\"{}\",quantile=\"{}\"}} {}", round_trips.kind, quantile, duration.as_secs_f64()).ok();
+        }
+        writeln!(metrics, "fishnet_api_round_trip_seconds_sum{{call=\"{}\"}} {}", round_trips.kind, round_trips.sum.as_secs_f64()).ok();
+        writeln!(metrics, "fishnet_api_round_trip_seconds_count{{call=\"{}\"}} {}", round_trips.kind, round_trips.count).ok();
+    }
+    metrics
+}
+
+/// Diagnostic snapshot for when the client seems stuck. Written on SIGQUIT
+/// and served on /debug/state.
+pub async fn state_dump(health: &Health, queue: &QueueStub) -> String {
+    let dashboard = queue.dashboard().await;
+    let mut dump = String::new();
+    writeln!(dump, "fishnet/{} state: {}", env!("CARGO_PKG_VERSION"), dashboard.activity()).ok();
+    writeln!(dump, "Incoming positions: {}", dashboard.incoming).ok();
+    match queue.api_unhealthy_since() {
+        Some(since) => writeln!(dump, "API: unhealthy since {}s ago", since.elapsed().as_secs()).ok(),
+        None => writeln!(dump, "API: healthy").ok(),
+    };
+
+    writeln!(dump, "Pending batches: {}", dashboard.pending.len()).ok();
+    for batch in &dashboard.pending {
+        let done = batch.positions.iter().filter(|&&done| done).count();
+        let bitmap: String = batch.positions.iter().map(|&done| if done { '#' } else { '.' }).collect();
+        writeln!(dump, "  {} for {}s, {}/{} [{}]", batch.at, batch.started_at.elapsed().as_secs(), done, batch.positions.len(), bitmap).ok();
+    }
+
+    writeln!(dump, "Workers:").ok();
+    for (i, activity) in health.worker_activity().into_iter().enumerate() {
+        writeln!(dump, "  #{}: {}", i, activity.as_deref().unwrap_or("idle")).ok();
+    }
+
+    writeln!(dump, "Last API requests:").ok();
+    for latency in queue.api_latencies() {
+        let status = latency.status.map_or("failed".to_owned(), |s| s.as_u16().to_string());
+        writeln!(dump, "  {}: {} in {}ms, {}s ago", latency.kind, status, latency.duration.as_millis(), latency.at.elapsed().as_secs()).ok();
+    }
+    dump
+}
@@ -0,0 +1,196 @@
+//! Certificate pinning for private endpoints. With pins configured, the
+//! endpoint is trusted if and only if its certificate or public key has one
+//! of the pinned SHA-256 fingerprints, so that servers with self-signed
+//! certificates can be used without disabling verification.
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::Arc;
+use sha2::{Digest as _, Sha256};
+use crate::api::{read_pem, ClientError};
+use crate::configure::ClientOpt;
+
+/// SHA-256 fingerprint of a certificate or of its public key.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Pin([u8; 32]);
+
+impl Pin {
+    /// Whether this is the fingerprint of the DER encoded certificate, or of
+    /// the public key within it.
+    fn matches(&self, cert: &[u8]) -> bool {
+        if Sha256::digest(cert)[..] == self.0 {
+            return true;
+        }
+        match subject_public_key_info(cert) {
+            Some(spki) => Sha256::digest(spki)[..] == self.0,
+            None => false,
+        }
+    }
+}
+
+impl FromStr for Pin {
+    type Err = &'static str;
+
+    /// Accepts the hex fingerprint, as printed by
+    /// `openssl x509 -fingerprint -sha256`, or the base64 encoded digest with
+    /// an optional `sha256//` prefix, as used by curl `--pinnedpubkey`.
+    fn from_str(s: &str) -> Result<Pin, &'static str> {
+        let s = s.trim();
+        let digest = if let Some(b64) = s.strip_prefix("sha256//") {
+            base64::decode(b64).map_err(|_| "invalid base64 fingerprint")?
+        } else {
+            let hex: String = s.chars().filter(|&c| c != ':').collect();
+            match hex::decode(&hex) {
+                Ok(digest) => digest,
+                Err(_) => base64::decode(s).map_err(|_| "expected hex or base64 sha256 fingerprint")?,
+            }
+        };
+        let mut pin = [0; 32];
+        if digest.len() != pin.len() {
+            return Err("expected 32 byte sha256 fingerprint");
+        }
+        pin.copy_from_slice(&digest);
+        Ok(Pin(pin))
+    }
+}
+
+impl fmt::Display for Pin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+struct Element<'a> {
+    tag: u8,
+    /// The complete element, including tag and length.
+    raw: &'a [u8],
+    contents: &'a [u8],
+    /// Data after the element.
+    rest: &'a [u8],
+}
+
+/// Splits the next DER element off `data`.
+fn der_element(data: &[u8]) -> Option<Element<'_>> {
+    let tag = *data.first()?;
+    let first = *data.get(1)?;
+    let (len, header) = if first < 0x80 {
+        (usize::from(first), 2)
+    } else {
+        let num = usize::from(first & 0x7f);
+        if num == 0 || num > 4 {
+            return None;
+        }
+        let len = data.get(2..2 + num)?.iter().fold(0, |len, &b| len << 8 | usize::from(b));
+        (len, 2 + num)
+    };
+    let raw = data.get(..header.checked_add(len)?)?;
+    Some(Element {
+        tag,
+        raw,
+        contents: &raw[header..],
+        rest: &data[raw.len()..],
+    })
+}
+
+/// Finds the DER encoded SubjectPublicKeyInfo in a certificate.
+fn subject_public_key_info(cert: &[u8]) -> Option<&[u8]> {
+    const SEQUENCE: u8 = 0x30;
+    const VERSION: u8 = 0xa0;
+
+    let cert = der_element(cert).filter(|e| e.tag == SEQUENCE)?;
+    let tbs = der_element(cert.contents).filter(|e| e.tag == SEQUENCE)?;
+    let mut fields = tbs.contents;
+    if fields.first() == Some(&VERSION) {
+        fields = der_element(fields)?.rest;
+    }
+    // Skip serial number, signature algorithm, issuer, validity and subject.
+    for _ in 0..5 {
+        fields = der_element(fields)?.rest;
+    }
+    der_element(fields).filter(|e| e.tag == SEQUENCE).map(|spki| spki.raw)
+}
+
+struct PinnedCertVerifier {
+    pins: Vec<Pin>,
+}
+
+impl rustls::ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(&self, _roots: &rustls::RootCertStore, presented_certs: &[rustls::Certificate], _dns_name: webpki::DNSNameRef<'_>, _ocsp_response: &[u8]) -> Result<rustls::ServerCertVerified, rustls::TLSError> {
+        // The pin replaces checking the chain and the name. The handshake
+        // still proves possession of the private key.
+        let leaf = presented_certs.first().ok_or(rustls::TLSError::NoCertificatesPresented)?;
+        if self.pins.iter().any(|pin| pin.matches(&leaf.0)) {
+            Ok(rustls::ServerCertVerified::assertion())
+        } else {
+            Err(rustls::TLSError::General("server certificate does not match any pinned fingerprint".to_owned()))
+        }
+    }
+}
+
+/// TLS configuration that only trusts the pinned certificates, and presents
+/// the configured client certificate, if any.
+pub fn tls_config(opt: &ClientOpt) -> Result<rustls::ClientConfig, ClientError> {
+    let mut config = rustls::ClientConfig::new();
+    config.alpn_protocols = if opt.http2_prior_knowledge {
+        vec![b"h2".to_vec()]
+    } else {
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    };
+    config.dangerous().set_certificate_verifier(Arc::new(PinnedCertVerifier {
+        pins: opt.pin_sha256.clone(),
+    }));
+    if let (Some(ref client_cert), Some(ref client_key)) = (&opt.client_cert, &opt.client_key) {
+        let certs = rustls::internal::pemfile::certs(&mut io::Cursor::new(read_pem(client_cert)?))
+            .ok()
+            .filter(|certs| !certs.is_empty())
+            .ok_or_else(|| ClientError::Invalid(client_cert.clone(), "invalid client certificate"))?;
+        let key = read_pem(client_key)?;
+        let key = rustls::internal::pemfile::pkcs8_private_keys(&mut io::Cursor::new(&key)).ok()
+            .filter(|keys| !keys.is_empty())
+            .or_else(|| rustls::internal::pemfile::rsa_private_keys(&mut io::Cursor::new(&key)).ok())
+            .and_then(|keys| keys.into_iter().next())
+            .ok_or_else(|| ClientError::Invalid(client_key.clone(), "invalid client key"))?;
+        config.set_single_client_cert(certs, key).map_err(|_| ClientError::Invalid(client_key.clone(), "client key does not match certificate"))?;
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pin_from_str() {
+        let hex: Pin = "AB:".repeat(31).chars().chain("CD".chars()).collect::<String>().parse().expect("hex pin");
+        assert_eq!(hex.0[0], 0xab);
+        assert_eq!(hex.0[31], 0xcd);
+        let b64: Pin = format!("sha256//{}", base64::encode(hex.0)).parse().expect("base64 pin");
+        assert_eq!(b64, hex);
+        assert_eq!(hex.to_string().parse::<Pin>(), Ok(hex));
+        assert!("abcd".parse::<Pin>().is_err());
+    }
+
+    #[test]
+    fn test_subject_public_key_info() {
+        // Certificate with the fields reduced to a minimum, but the
+        // structure of a real one.
+        let spki = [0x30, 0x03, 0x02, 0x01, 0x2a];
+        let mut tbs = vec![
+            0xa0, 0x03, 0x02, 0x01, 0x02, // version
+            0x02, 0x01, 0x01, // serial number
+            0x30, 0x00, // signature algorithm
+            0x30, 0x00, // issuer
+            0x30, 0x00, // validity
+            0x30, 0x00, // subject
+        ];
+        tbs.extend_from_slice(&spki);
+        let mut cert = vec![0x30, 0x81, tbs.len() as u8 + 2, 0x30, tbs.len() as u8];
+        cert.extend_from_slice(&tbs);
+        assert_eq!(subject_public_key_info(&cert), Some(&spki[..]));
+
+        let pin = Pin(Sha256::digest(&spki).into());
+        assert!(pin.matches(&cert));
+        assert!(!pin.matches(&cert[..cert.len() - 1]));
+    }
+}
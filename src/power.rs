@@ -0,0 +1,135 @@
+use std::time::{Duration, Instant};
+use crate::logger::Logger;
+
+/// Check the power source at most this often.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Copy, Clone)]
+pub struct PowerStatus {
+    pub on_battery: bool,
+    /// Remaining charge in percent, if known.
+    pub charge: Option<u8>,
+}
+
+/// Reads the current power source. Returns `None` if it can not be
+/// determined, for example on machines without a battery.
+#[cfg(target_os = "linux")]
+pub fn status() -> Option<PowerStatus> {
+    // The same source upower uses.
+    use std::fs;
+    let mut found = false;
+    let mut on_battery = false;
+    let mut charge: Option<u8> = None;
+    for entry in fs::read_dir("/sys/class/power_supply").ok()? {
+        let path = entry.ok()?.path();
+        let read = |name: &str| fs::read_to_string(path.join(name)).map(|s| s.trim().to_owned()).ok();
+        if read("type").as_deref() != Some("Battery") || read("scope").as_deref() == Some("Device") {
+            continue; // Ignore AC adapters and batteries of mice, keyboards, ...
+        }
+        found = true;
+        on_battery |= read("status").as_deref() == Some("Discharging");
+        if let Some(capacity) = read("capacity").and_then(|c| c.parse().ok()) {
+            charge = Some(charge.map_or(capacity, |c| c.min(capacity)));
+        }
+    }
+    if found {
+        Some(PowerStatus { on_battery, charge })
+    } else {
+        None
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub fn status() -> Option<PowerStatus> {
+    // pmset reports what IOKit knows about power sources, for example:
+    // Now drawing from 'Battery Power'
+    //  -InternalBattery-0 (id=4653155)	85%; discharging; 4:12 remaining present: true
+    let output = std::process::Command::new("pmset").args(&["-g", "batt"]).output().ok()?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    let charge = output.lines()
+        .filter(|line| line.contains("InternalBattery"))
+        .find_map(|line| line.split('\t').nth(1)?.split('%').next()?.trim().parse().ok());
+    charge.map(|charge| PowerStatus {
+        on_battery: output.contains("'Battery Power'"),
+        charge: Some(charge),
+    })
+}
+
+#[cfg(windows)]
+pub fn status() -> Option<PowerStatus> {
+    // https://docs.microsoft.com/en-us/windows/win32/api/winbase/ns-winbase-system_power_status
+    #[repr(C)]
+    #[derive(Default)]
+    struct SystemPowerStatus {
+        ac_line_status: u8,
+        battery_flag: u8,
+        battery_life_percent: u8,
+        system_status_flag: u8,
+        battery_life_time: u32,
+        battery_full_life_time: u32,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetSystemPowerStatus(status: *mut SystemPowerStatus) -> i32;
+    }
+
+    let mut status = SystemPowerStatus::default();
+    // Safety: The struct matches SYSTEM_POWER_STATUS and is only written to.
+    if unsafe { GetSystemPowerStatus(&mut status) } == 0 || status.battery_flag & 128 != 0 {
+        return None; // Failed or no system battery.
+    }
+    Some(PowerStatus {
+        on_battery: status.ac_line_status == 0,
+        charge: Some(status.battery_life_percent).filter(|&p| p <= 100),
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+pub fn status() -> Option<PowerStatus> {
+    None
+}
+
+/// Decides whether to pause acquiring new batches, because the machine is
+/// running on battery power.
+pub struct BatteryPause {
+    /// Keep going on battery, as long as the charge is at least this
+    /// percentage.
+    threshold: Option<u8>,
+    checked_at: Option<Instant>,
+    paused: bool,
+    logger: Logger,
+}
+
+impl BatteryPause {
+    pub fn new(threshold: Option<u8>, logger: Logger) -> BatteryPause {
+        BatteryPause {
+            threshold,
+            checked_at: None,
+            paused: false,
+            logger,
+        }
+    }
+
+    pub async fn paused(&mut self) -> bool {
+        if self.checked_at.is_some_and(|t| t.elapsed() < CHECK_INTERVAL) {
+            return self.paused;
+        }
+        self.checked_at = Some(Instant::now());
+
+        let status = tokio::task::spawn_blocking(status).await.ok().flatten();
+        let paused = status.is_some_and(|s| s.on_battery && match (self.threshold, s.charge) {
+            (Some(threshold), Some(charge)) => charge < threshold,
+            _ => true,
+        });
+
+        if paused && !self.paused {
+            let charge = status.and_then(|s| s.charge).map_or("unknown".to_owned(), |c| format!("{}%", c));
+            self.logger.info(&format!("Running on battery (charge: {}). Pausing until plugged in.", charge));
+        } else if !paused && self.paused {
+            self.logger.info("Resuming after battery pause.");
+        }
+        self.paused = paused;
+        paused
+    }
+}
@@ -0,0 +1,81 @@
+use std::time::Duration;
+use reqwest::StatusCode;
+use tokio::sync::mpsc;
+use tokio::time;
+use tokio_compat_02::FutureExt as _;
+use crate::configure::Cloud;
+use crate::logger::Logger;
+
+/// EC2 announces reclaiming spot instances two minutes in advance, GCP only
+/// 30 seconds.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+const EC2_TOKEN: &str = "http://169.254.169.254/latest/api/token";
+const EC2_INSTANCE_ACTION: &str = "http://169.254.169.254/latest/meta-data/spot/instance-action";
+const GCP_PREEMPTED: &str = "http://metadata.google.internal/computeMetadata/v1/instance/preempted";
+
+/// Polls the instance metadata service of the cloud provider. The receiver
+/// gets a message once the instance is about to be reclaimed.
+pub fn spawn(cloud: Cloud, logger: Logger) -> mpsc::Receiver<()> {
+    let (tx, rx) = mpsc::channel(1);
+    let logger = logger.module("preemption");
+    // The metadata service is link-local, so never go through a proxy.
+    let client = reqwest::Client::builder()
+        .no_proxy()
+        .timeout(Duration::from_secs(2))
+        .build()
+        .expect("metadata client");
+    tokio::spawn(async move {
+        let mut reachable = true;
+        loop {
+            tokio::select! {
+                _ = tx.closed() => break,
+                _ = time::sleep(POLL_INTERVAL) => (),
+            }
+            match preempted(cloud, &client).await {
+                Ok(false) => reachable = true,
+                Ok(true) => {
+                    tx.send(()).await.ok();
+                    break;
+                }
+                Err(err) => {
+                    // Only log once, in case this is not actually running
+                    // on the configured cloud.
+                    if reachable {
+                        logger.warn(&format!("Failed to query {} metadata for preemption notices: {}", cloud, err));
+                    }
+                    reachable = false;
+                }
+            }
+        }
+    });
+    rx
+}
+
+async fn preempted(cloud: Cloud, client: &reqwest::Client) -> reqwest::Result<bool> {
+    match cloud {
+        Cloud::Ec2 => {
+            // Prefer IMDSv2, but fall back to IMDSv1 if tokens are not
+            // available.
+            let token = match client.put(EC2_TOKEN).header("X-aws-ec2-metadata-token-ttl-seconds", "60").send().compat().await {
+                Ok(res) if res.status().is_success() => Some(res.text().compat().await?),
+                _ => None,
+            };
+            let mut req = client.get(EC2_INSTANCE_ACTION);
+            if let Some(token) = token {
+                req = req.header("X-aws-ec2-metadata-token", token);
+            }
+            let res = req.send().compat().await?;
+            // Not found until an interruption is scheduled.
+            if res.status() == StatusCode::NOT_FOUND {
+                return Ok(false);
+            }
+            res.error_for_status()?;
+            Ok(true)
+        }
+        Cloud::Gcp => {
+            let res = client.get(GCP_PREEMPTED).header("Metadata-Flavor", "Google").send().compat().await?.error_for_status()?;
+            Ok(res.text().compat().await?.trim().eq_ignore_ascii_case("true"))
+        }
+    }
+}
@@ -3,65 +3,112 @@ use std::convert::TryInto;
 use std::collections::{VecDeque, HashMap};
 use std::collections::hash_map::Entry;
 use std::fmt;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex as StdMutex};
 use std::time::{Duration, Instant};
 use shakmaty::uci::Uci;
-use shakmaty::fen::Fen;
+use shakmaty::fen::{self, Fen};
 use shakmaty::variants::VariantPosition;
-use shakmaty::{Setup as _, Position as _, MaterialSide, Material};
+use shakmaty::{Setup as _, Position as _, MaterialSide, Material, Outcome};
 use url::Url;
-use tokio::sync::{mpsc, oneshot, Mutex, Notify};
+use tokio::sync::{mpsc, oneshot, watch, Mutex, Notify};
 use tokio::time;
+use crate::archive::{self, Archive};
 use crate::assets::{EngineFlavor, EvalFlavor};
-use crate::api::{AcquireQuery, AcquireResponseBody, Acquired, AnalysisPart, ApiStub, BatchId, Work, LichessVariant, nnue_to_classical};
-use crate::configure::{BacklogOpt, Endpoint};
-use crate::ipc::{Position, PositionResponse, PositionFailed, PositionId, Pull};
+use crate::cache::{EvalCache, EvalKey};
+use crate::api::{AcquireQuery, AcquireResponseBody, Acquired, AnalysisPart, AnalysisStatus, ApiStub, BatchId, Bound, BoundedScore, Latency, RoundTrips, EngineInfo, Work, LichessVariant, NodeLimit, Score, Wdl, nnue_to_classical};
+use crate::configure::{BacklogOpt, Endpoint, Route, Schedule, WorkType};
+use crate::ipc::{Moves, Origin, Position, PositionResponse, PositionFailed, PositionId, Pull};
 use crate::logger::{Logger, ProgressAt, QueueStatusBar};
-use crate::util::{NevermindExt as _, RandomizedBackoff};
-
-pub fn channel(endpoint: Endpoint, opt: BacklogOpt, cores: usize, api: ApiStub, logger: Logger) -> (QueueStub, QueueActor) {
-    let state = Arc::new(Mutex::new(QueueState::new(cores, logger.clone())));
-    let (tx, rx) = mpsc::unbounded_channel();
+use crate::metrics::Health;
+use crate::idle::{self, Activity, IdlePause};
+use crate::power::{self, BatteryPause};
+use crate::serve::AnalyseRequest;
+use crate::util::{Cancellation, NevermindExt as _, RandomizedBackoff};
+
+pub fn channel(endpoint: Endpoint, opt: BacklogOpt, cores: usize, acquire: bool, health: Arc<Health>, api: ApiStub, logger: Logger) -> (QueueStub, QueueActor) {
+    let batch_timeout = opt.batch_timeout.unwrap_or(Duration::from_secs(10 * 60));
+    let progress_interval = opt.progress_interval.unwrap_or(Duration::from_secs(5));
+    let max_batches = opt.max_batches.unwrap_or(1);
+    let schedule = opt.schedule.unwrap_or_default();
+    let logger = logger.module("queue");
+    let cache = opt.eval_cache.filter(|&n| n > 0).map(EvalCache::new);
+    let mut state = QueueState::new(cores, batch_timeout, progress_interval, max_batches, schedule, cache, logger.clone());
+    state.archive = opt.archive.clone().map(|path| {
+        logger.info(&format!("Archive: {}", path.display()));
+        Archive::open(path, opt.archive_max_size.unwrap_or(archive::DEFAULT_MAX_SIZE)).expect("open archive")
+    });
+    let stats = state.stats.clone();
+    let state = Arc::new(Mutex::new(state));
+    // Each worker has at most one pull in flight. Leave some room for
+    // notifications.
+    let (tx, rx) = mpsc::channel(cores + 2);
+    let (pulls_tx, pulls_rx) = mpsc::channel(cores + 2);
     let interrupt = Arc::new(Notify::new());
-    (QueueStub::new(tx, interrupt.clone(), state.clone(), api.clone()), QueueActor::new(rx, interrupt, state, endpoint, opt, api, logger))
+    let mut actor = QueueActor::new(rx, interrupt.clone(), state.clone(), endpoint, opt, api.clone(), logger);
+    actor.stats = stats.clone();
+    actor.acquire = acquire;
+    actor.health = health;
+    actor.dispatcher = Some(Dispatcher {
+        rx: pulls_rx,
+        tx: tx.clone(),
+        queue: QueueStub::new(tx.clone(), None, interrupt.clone(), state.clone(), stats.clone(), api.clone()),
+    });
+    (QueueStub::new(tx, Some(pulls_tx), interrupt, state, stats, api), actor)
 }
 
 #[derive(Clone)]
 pub struct QueueStub {
-    tx: Option<mpsc::UnboundedSender<QueueMessage>>,
+    tx: Option<mpsc::Sender<QueueMessage>>,
+    /// To the dispatcher. `None` for the stub of the dispatcher itself, so
+    /// that it stops once all other stubs are gone.
+    pulls: Option<mpsc::Sender<Dispatch>>,
     interrupt: Arc<Notify>,
     state: Arc<Mutex<QueueState>>,
+    /// Kept apart from the state, so that recording and reading statistics
+    /// never waits for the queue.
+    stats: Arc<StdMutex<StatsRecorder>>,
     api: ApiStub,
 }
 
 impl QueueStub {
-    fn new(tx: mpsc::UnboundedSender<QueueMessage>, interrupt: Arc<Notify>, state: Arc<Mutex<QueueState>>, api: ApiStub) -> QueueStub {
+    fn new(tx: mpsc::Sender<QueueMessage>, pulls: Option<mpsc::Sender<Dispatch>>, interrupt: Arc<Notify>, state: Arc<Mutex<QueueState>>, stats: Arc<StdMutex<StatsRecorder>>, api: ApiStub) -> QueueStub {
         QueueStub {
             tx: Some(tx),
+            pulls,
             interrupt,
             state,
+            stats,
             api,
         }
     }
 
+    /// Hot path, called for every completed position. Never waits for the
+    /// state lock. The dispatcher applies the response and hands out the
+    /// next position.
     pub async fn pull(&mut self, pull: Pull) {
-        let mut state = self.state.lock().await;
-        let (response, callback) = pull.split();
-        if let Some(response) = response {
-            state.handle_position_response(self.clone(), response);
+        if let (Some(worker), Some(response)) = (pull.worker, &pull.response) {
+            self.stats.lock().expect("stats mutex").record_worker(worker, response);
         }
-        if let Err(callback) = state.try_pull(callback) {
-            if let Some(ref mut tx) = self.tx {
-                tx.send(QueueMessage::Pull {
-                    callback,
-                }).nevermind("queue dropped");
+        if let Some(ref mut pulls) = self.pulls {
+            pulls.send(Dispatch::Pull(Box::new(pull))).await.nevermind("queue dropped");
+        }
+    }
+
+    /// Waits until the dispatcher has applied all responses so far.
+    async fn flush(&mut self) {
+        if let Some(ref mut pulls) = self.pulls {
+            let (callback, flushed) = oneshot::channel();
+            if pulls.send(Dispatch::Flush(callback)).await.is_ok() {
+                flushed.await.nevermind("dispatcher dropped");
             }
         }
     }
 
     fn move_submitted(&mut self) {
-        if let Some(ref tx) = self.tx {
-            tx.send(QueueMessage::MoveSubmitted).nevermind("too late");
+        if let Some(ref mut tx) = self.tx {
+            // If the channel is full, the actor will pick up the submission
+            // when it gets to the next pull.
+            tx.try_send(QueueMessage::MoveSubmitted).nevermind("queue busy");
 
             // Skip the queue backoff.
             self.interrupt.notify_one();
@@ -75,40 +122,249 @@ impl QueueStub {
         self.interrupt.notify_one();
     }
 
-    pub async fn shutdown(mut self) {
+    /// Reports progress of all pending batches right away, for example
+    /// before giving them back on short notice.
+    pub async fn report_progress(&mut self) {
+        let mut state = self.state.lock().await;
+        state.report_progress(&mut self.api);
+    }
+
+    /// Since when the server has had no work for this client, if it has
+    /// none right now.
+    pub async fn no_work_since(&self) -> Option<Instant> {
+        let state = self.state.lock().await;
+        state.no_work_since
+    }
+
+    /// Gives back pending batches. Returns how many.
+    pub async fn shutdown(mut self) -> usize {
         self.shutdown_soon().await;
+        self.flush().await;
 
+        let mut queue = self.clone();
         let mut state = self.state.lock().await;
-        for (k, _) in state.pending.drain() {
-            self.api.abort(k);
+        let batch_ids: Vec<BatchId> = state.pending.keys().copied().collect();
+        for &batch_id in &batch_ids {
+            state.give_back(&mut queue.api, batch_id);
         }
+        batch_ids.len()
+    }
+
+    /// Analyses a game for a local client, ahead of any work from the
+    /// server. Results are streamed as they complete.
+    pub async fn analyse_local(&mut self, req: AnalyseRequest) -> Option<mpsc::UnboundedReceiver<PositionResponse>> {
+        let mut state = self.state.lock().await;
+        if state.shutdown_soon {
+            return None;
+        }
+
+        state.local_batches += 1;
+        let batch_id: BatchId = format!("local{}", state.local_batches).parse().expect("local batch id");
+        let body = AcquireResponseBody {
+            work: Work::Analysis {
+                id: batch_id,
+                nodes: req.nodes.map(NodeLimit::from_nnue),
+                multipv: req.multipv,
+                movetime: req.movetime.map(Duration::from_millis),
+                depth: req.depth,
+            },
+            game_id: None,
+            position: req.fen.unwrap_or_default(),
+            variant: req.variant,
+            moves: req.moves,
+            skip_positions: Vec::new(),
+            timeout: None,
+        };
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        if let Ok(incoming) = IncomingBatch::from_acquired(Endpoint::default(), body, true, None) {
+            state.local.insert(batch_id, LocalSink {
+                tx,
+                sent: Vec::new(),
+            });
+            match state.add_incoming_batch(incoming) {
+                Some(completed) => {
+                    if let Some(sink) = state.local.remove(&batch_id) {
+                        for pos in completed.positions {
+                            if let Skip::Present(res) = pos {
+                                sink.tx.send(res).nevermind("local client gone");
+                            }
+                        }
+                    }
+                }
+                None => state.forward_local(batch_id),
+            }
+            self.interrupt.notify_one();
+        }
+        Some(rx)
+    }
+
+    /// Drops a batch that the server no longer needs, and stops analysing
+    /// its positions.
+    pub async fn cancel(&mut self, batch_id: BatchId) {
+        let mut state = self.state.lock().await;
+        if let Some(pending) = state.pending.remove(&batch_id) {
+            pending.cancellation.cancel();
+            state.incoming.retain(|p| p.work.id() != batch_id);
+            state.logger.warn(&format!("Batch {} is no longer needed by the server. Stopped analysing it.", batch_id));
+        }
+    }
+
+    pub async fn expire(&mut self) {
+        let mut state = self.state.lock().await;
+        state.expire(self.clone());
     }
 
     pub async fn stats(&self) -> StatsRecorder {
+        let mut stats = self.stats.lock().expect("stats mutex").clone();
+        stats.bytes_sent = self.api.bandwidth().sent();
+        stats.bytes_received = self.api.bandwidth().received();
+        stats.round_trips = self.api.round_trips();
+        stats
+    }
+
+    /// Batches from the server that are in progress.
+    pub async fn pending_batches(&self) -> Vec<BatchId> {
+        let state = self.state.lock().await;
+        state.pending.keys().filter(|batch_id| !state.local.contains_key(batch_id)).copied().collect()
+    }
+
+    pub fn api_latencies(&self) -> Vec<Latency> {
+        self.api.latencies()
+    }
+
+    pub fn api_unhealthy_since(&self) -> Option<time::Instant> {
+        self.api.unhealthy_since()
+    }
+
+    pub async fn slow_workers(&self) -> Vec<(usize, u32, u32)> {
+        self.stats.lock().expect("stats mutex").slow_workers()
+    }
+
+    pub async fn dashboard(&self) -> Dashboard {
+        let state = self.state.lock().await;
+        let mut pending: Vec<BatchProgress> = state.pending.values().map(|p| BatchProgress {
+            at: ProgressAt {
+                batch_id: p.work.id(),
+                batch_url: p.url.clone(),
+                position_id: None,
+            },
+            positions: p.positions.iter().map(Option::is_some).collect(),
+            started_at: p.started_at,
+        }).collect();
+        pending.sort_by_key(|p| p.started_at);
+        Dashboard {
+            pending,
+            incoming: state.incoming.len(),
+            waiting: state.waiting,
+            stats: self.stats.lock().expect("stats mutex").clone(),
+        }
+    }
+
+    pub async fn update_required(&self) -> bool {
         let state = self.state.lock().await;
-        state.stats.clone()
+        state.update_required
     }
 }
 
 struct QueueState {
     shutdown_soon: bool,
+    update_required: bool,
+    user_active: bool,
     cores: usize,
+    batch_timeout: Duration,
+    progress_interval: Duration,
+    max_batches: usize,
+    schedule: Schedule,
+    prefetching: bool,
     incoming: VecDeque<Position>,
+    last_pulled: Option<BatchId>,
+    /// Position that each local worker analysed last.
+    affinity: HashMap<usize, (BatchId, PositionId)>,
     pending: HashMap<BatchId, PendingBatch>,
     move_submissions: VecDeque<CompletedBatch>,
-    stats: StatsRecorder,
+    stats: Arc<StdMutex<StatsRecorder>>,
+    cache: Option<EvalCache>,
+    archive: Option<Archive>,
+    no_work_since: Option<Instant>,
+    local: HashMap<BatchId, LocalSink>,
+    local_batches: u64,
+    waiting: Option<Waiting>,
     logger: Logger,
 }
 
+/// Why the queue is not currently acquiring work, and until when.
+#[derive(Debug, Copy, Clone)]
+pub enum Waiting {
+    Backlog(Instant),
+    Backoff(Instant),
+    RateLimited(Instant),
+    OnBattery,
+    UserActive,
+    BandwidthCap,
+    LongPoll,
+}
+
+/// Snapshot of the queue for the dashboard.
+pub struct Dashboard {
+    pub pending: Vec<BatchProgress>,
+    pub incoming: usize,
+    pub waiting: Option<Waiting>,
+    pub stats: StatsRecorder,
+}
+
+impl Dashboard {
+    pub fn activity(&self) -> String {
+        let now = Instant::now();
+        match self.waiting {
+            None => "working".to_owned(),
+            Some(Waiting::Backlog(until)) => format!("idle for {}s (backlog)", until.saturating_duration_since(now).as_secs()),
+            Some(Waiting::Backoff(until)) => format!("no job, backing off {}s", until.saturating_duration_since(now).as_secs()),
+            Some(Waiting::RateLimited(until)) => format!("server busy, retrying in {}s", until.saturating_duration_since(now).as_secs()),
+            Some(Waiting::OnBattery) => "paused on battery".to_owned(),
+            Some(Waiting::UserActive) => "paused while user is active".to_owned(),
+            Some(Waiting::BandwidthCap) => "monthly bandwidth cap reached".to_owned(),
+            Some(Waiting::LongPoll) => "waiting for a job".to_owned(),
+        }
+    }
+}
+
+pub struct BatchProgress {
+    pub at: ProgressAt,
+    /// Whether each position is done.
+    pub positions: Vec<bool>,
+    pub started_at: Instant,
+}
+
+struct LocalSink {
+    tx: mpsc::UnboundedSender<PositionResponse>,
+    sent: Vec<bool>,
+}
+
 impl QueueState {
-    fn new(cores: usize, logger: Logger) -> QueueState {
+    fn new(cores: usize, batch_timeout: Duration, progress_interval: Duration, max_batches: usize, schedule: Schedule, cache: Option<EvalCache>, logger: Logger) -> QueueState {
         QueueState {
             shutdown_soon: false,
+            update_required: false,
+            user_active: false,
             cores,
+            batch_timeout,
+            progress_interval,
+            max_batches,
+            schedule,
+            prefetching: false,
             incoming: VecDeque::new(),
+            last_pulled: None,
+            affinity: HashMap::new(),
             pending: HashMap::new(),
             move_submissions: VecDeque::new(),
-            stats: StatsRecorder::new(),
+            stats: Arc::new(StdMutex::new(StatsRecorder::new())),
+            cache,
+            archive: None,
+            no_work_since: None,
+            local: HashMap::new(),
+            local_batches: 0,
+            waiting: None,
             logger,
         }
     }
@@ -120,34 +376,109 @@ impl QueueState {
         }
     }
 
-    fn add_incoming_batch(&mut self, batch: IncomingBatch) {
-        match self.pending.entry(batch.work.id()) {
-            Entry::Occupied(entry) => self.logger.error(&format!("Dropping duplicate incoming batch {}", entry.key())),
+    fn add_incoming_batch(&mut self, batch: IncomingBatch) -> Option<CompletedBatch> {
+        // User-requested positions go ahead of system positions, but keep
+        // their order among themselves.
+        let pending = &self.pending;
+        let insert_at = if batch.user {
+            self.incoming.iter()
+                .position(|pos| pending.get(&pos.work.id()).is_some_and(|p| !p.user))
+                .unwrap_or(self.incoming.len())
+        } else {
+            self.incoming.len()
+        };
+
+        let timeout = batch.timeout.map_or(self.batch_timeout, |t| min(t, self.batch_timeout));
+
+        let batch_id = batch.work.id();
+        match self.pending.entry(batch_id) {
+            Entry::Occupied(entry) => {
+                self.logger.error(&format!("Dropping duplicate incoming batch {}", entry.key()));
+                None
+            }
             Entry::Vacant(entry) => {
                 let progress_at = ProgressAt::from(&batch);
 
-                // Reversal only for cosmetics when displaying progress.
+                // Going backwards, so that incoming positions end up in
+                // ascending ply order.
                 let mut positions = Vec::with_capacity(batch.positions.len());
+                let mut cached = Vec::new();
+                let mut cloud = batch.cloud;
+                let mut trivial = batch.trivial;
+                let mut forced = HashMap::new();
                 for pos in batch.positions.into_iter().rev() {
                     positions.insert(0, match pos {
+                        Skip::Present(pos) if batch.duplicates.contains_key(&pos.position_id.0) => None,
+                        Skip::Present(pos) if trivial.contains_key(&pos.position_id.0) => {
+                            match trivial.remove(&pos.position_id.0) {
+                                Some(Trivial::Over(score)) => cached.push(trivial_response(pos, score, None)),
+                                Some(Trivial::Forced(uci)) if pos.work.is_analysis() => {
+                                    // Follows from the next position.
+                                    forced.insert(pos.position_id.0, uci);
+                                }
+                                Some(Trivial::Forced(uci)) => cached.push(trivial_response(pos, Score::Cp(0), Some(uci))),
+                                None => (),
+                            }
+                            None
+                        }
+                        Skip::Present(pos) if cloud.contains_key(&pos.position_id.0) => {
+                            cached.extend(cloud.remove(&pos.position_id.0));
+                            None
+                        }
                         Skip::Present(pos) => {
-                            self.incoming.push_back(pos);
+                            let hit = match (&mut self.cache, eval_key(&batch.work, batch.flavor, batch.variant, &batch.epds, pos.position_id)) {
+                                (Some(cache), Some(key)) => cache.get(&key),
+                                _ => None,
+                            };
+                            match hit {
+                                Some(res) => cached.push(PositionResponse {
+                                    work: pos.work,
+                                    position_id: pos.position_id,
+                                    url: pos.url,
+                                    origin: Origin::Reused,
+                                    ..res
+                                }),
+                                None => self.incoming.insert(insert_at, pos),
+                            }
                             None
                         }
                         Skip::Skip => Some(Skip::Skip),
                     });
                 }
 
-                entry.insert(PendingBatch {
+                let started_at = Instant::now();
+                let pending = entry.insert(PendingBatch {
                     work: batch.work,
+                    user: batch.user,
+                    duplicates: batch.duplicates,
+                    forced,
+                    epds: batch.epds,
                     flavor: batch.flavor,
                     variant: batch.variant,
+                    nodes_multiplier: batch.nodes_multiplier,
                     url: batch.url,
                     positions,
-                    started_at: Instant::now(),
+                    cancellation: batch.cancellation,
+                    started_at,
+                    reported_at: started_at,
+                    deadline: started_at + timeout,
                 });
 
+                if let Some(ref cache) = self.cache {
+                    self.logger.debug(&format!("Found {} positions of batch {} in cache (hit rate {:.0}%)", cached.len(), batch_id, cache.hit_rate() * 100.0));
+                }
+                if !cached.is_empty() {
+                    for res in cached {
+                        pending.resolve(res);
+                    }
+                }
+
+                if pending.pending() == 0 {
+                    return self.pending.remove(&batch_id).and_then(|p| p.try_into_completed().ok());
+                }
+
                 self.logger.progress(self.status_bar(), progress_at);
+                None
             }
         }
     }
@@ -158,23 +489,200 @@ impl QueueState {
                 let progress_at = ProgressAt::from(&res);
                 let batch_id = res.work.id();
                 if let Some(pending) = self.pending.get_mut(&batch_id) {
-                    if let Some(pos) = pending.positions.get_mut(res.position_id.0) {
-                        *pos = Some(Skip::Present(res));
+                    if let Some(ref mut cache) = self.cache {
+                        if let Some(key) = eval_key(&pending.work, pending.flavor, pending.variant, &pending.epds, res.position_id) {
+                            cache.insert(key, res.clone());
+                        }
                     }
+                    pending.resolve(res);
                 }
+                self.forward_local(batch_id);
                 self.logger.progress(self.status_bar(), progress_at);
                 self.maybe_finished(queue, batch_id);
             }
-            Err(failed) => {
-                self.pending.remove(&failed.batch_id);
-                self.incoming.retain(|p| p.work.id() != failed.batch_id);
-                queue.api.abort(failed.batch_id);
+            Err(PositionFailed { batch_id, retry: Some(position), .. }) if self.pending.contains_key(&batch_id) => {
+                self.incoming.push_front(position);
             }
+            Err(failed) => self.give_back(&mut queue.api, failed.batch_id),
+        }
+    }
+
+    fn expire(&mut self, mut queue: QueueStub) {
+        let now = Instant::now();
+        let expired: Vec<BatchId> = self.pending.values()
+            .filter(|p| p.deadline <= now)
+            .map(|p| p.work.id())
+            .collect();
+
+        for batch_id in expired {
+            self.logger.warn(&format!("Batch {} exceeded its deadline. Giving it back.", batch_id));
+            self.give_back(&mut queue.api, batch_id);
         }
+
+        self.shed_excess(&mut queue.api);
     }
 
-    fn try_pull(&mut self, callback: oneshot::Sender<Position>) -> Result<(), oneshot::Sender<Position>> {
-        if let Some(position) = self.incoming.pop_front() {
+    fn shed_excess(&mut self, api: &mut ApiStub) {
+        // Do not act on the initial guess.
+        let nps = match self.stats.lock().expect("stats mutex").total_nps() {
+            Some(nps) => max(1, nps),
+            None => return,
+        };
+
+        // Assume batches are worked off in order of their deadlines. Give
+        // back those that would be delivered late at the current speed.
+        let mut batches: Vec<(Instant, BatchId, u64)> = self.pending.values()
+            .filter(|p| p.work.is_analysis())
+            .map(|p| (p.deadline, p.work.id(), p.pending() as u64 * match p.work.movetime() {
+                Some(movetime) => movetime.as_millis() as u64 * nps / 1000,
+                None => p.work.node_limit().unwrap_or_default().get(EvalFlavor::Nnue),
+            }))
+            .collect();
+        batches.sort_by_key(|&(deadline, _, _)| deadline);
+
+        let now = Instant::now();
+        let mut total_nodes = 0;
+        for (deadline, batch_id, nodes) in batches {
+            let estimated = now + Duration::from_millis((total_nodes + nodes) * 1000 / nps);
+            if estimated > deadline {
+                self.logger.warn(&format!("Batch {} would finish {:?} late at {} knps. Giving it back.", batch_id, estimated - deadline, nps / 1000));
+                self.give_back(api, batch_id);
+            } else {
+                total_nodes += nodes;
+            }
+        }
+    }
+
+    /// Submits the progress of all batches from the server, regardless of
+    /// the progress interval.
+    fn report_progress(&mut self, api: &mut ApiStub) {
+        for (batch_id, pending) in &mut self.pending {
+            if self.local.contains_key(batch_id) {
+                continue;
+            }
+            let progress_report = pending.progress_report();
+            if progress_report.iter().any(Option::is_some) {
+                pending.reported_at = Instant::now();
+                api.submit_progress(*batch_id, pending.flavor.eval_flavor(), pending.engine(), pending.nodes_multiplier, progress_report);
+            }
+        }
+    }
+
+    /// Gives back all batches from the server, but keeps those of local
+    /// clients.
+    fn give_back_all(&mut self, api: &mut ApiStub) {
+        let batch_ids: Vec<BatchId> = self.pending.keys().filter(|batch_id| !self.local.contains_key(batch_id)).copied().collect();
+        if !batch_ids.is_empty() {
+            self.logger.info(&format!("Giving back {} batches.", batch_ids.len()));
+        }
+        for batch_id in batch_ids {
+            self.give_back(api, batch_id);
+        }
+    }
+
+    fn give_back(&mut self, api: &mut ApiStub, batch_id: BatchId) {
+        // Already completed or given back, for example when several
+        // positions of the batch failed.
+        let pending = match self.pending.remove(&batch_id) {
+            Some(pending) => pending,
+            None => return,
+        };
+        // Stop searches of other positions of the batch in flight.
+        pending.cancellation.cancel();
+        self.incoming.retain(|p| p.work.id() != batch_id);
+        if self.local.remove(&batch_id).is_none() {
+            api.abort(batch_id);
+        }
+    }
+
+    fn forward_local(&mut self, batch_id: BatchId) {
+        if let (Some(sink), Some(pending)) = (self.local.get_mut(&batch_id), self.pending.get(&batch_id)) {
+            sink.sent.resize(pending.positions.len(), false);
+            for (sent, pos) in sink.sent.iter_mut().zip(&pending.positions) {
+                if let Some(Skip::Present(res)) = pos {
+                    if !*sent {
+                        *sent = true;
+                        sink.tx.send(res.clone()).nevermind("local client gone");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Takes positions from pending batches in turn, so that several
+    /// batches make progress at the same time and none starves, or from the
+    /// batch closest to completion, depending on the schedule. Positions of
+    /// user batches still go first.
+    ///
+    /// Local workers continue with the next ply of the game they analysed
+    /// last, so that the engine can keep its hash table. Games that another
+    /// worker is busy with are only split when there is nothing else to
+    /// do, and then from the middle of the remaining positions.
+    fn next_position(&mut self, worker: Option<usize>) -> Option<Position> {
+        let pending = &self.pending;
+        let is_user = |pos: &Position| pending.get(&pos.work.id()).is_some_and(|p| p.user);
+        let user = is_user(self.incoming.front()?);
+
+        let continued = worker
+            .and_then(|w| self.affinity.get(&w))
+            .and_then(|&(batch_id, last)| self.next_ply(batch_id, last))
+            .filter(|&i| is_user(&self.incoming[i]) == user);
+
+        let index = match continued {
+            Some(index) => index,
+            None => {
+                let mut batches: Vec<BatchId> = Vec::new();
+                for pos in &self.incoming {
+                    if is_user(pos) == user && !batches.contains(&pos.work.id()) {
+                        batches.push(pos.work.id());
+                    }
+                }
+
+                let claimed = |batch_id: &BatchId| self.affinity.iter().any(|(&w, &(b, last))| {
+                    Some(w) != worker && b == *batch_id && self.next_ply(b, last).is_some()
+                });
+                let unclaimed: Vec<BatchId> = batches.iter().copied().filter(|b| !claimed(b)).collect();
+                let split = unclaimed.is_empty();
+                if !split {
+                    batches = unclaimed;
+                }
+
+                let batch_id = match self.schedule {
+                    Schedule::RoundRobin => match self.last_pulled.and_then(|last| batches.iter().position(|&b| b == last)) {
+                        Some(i) => batches[(i + 1) % batches.len()],
+                        None => batches[0],
+                    },
+                    Schedule::FewestRemaining => {
+                        // Positions that are already being analysed count as
+                        // remaining, too.
+                        *batches.iter().min_by_key(|b| pending.get(b).map_or(usize::MAX, |p| p.pending()))?
+                    }
+                };
+                self.last_pulled = Some(batch_id);
+
+                let mut indexes: Vec<usize> = (0..self.incoming.len()).filter(|&i| self.incoming[i].work.id() == batch_id).collect();
+                indexes.sort_by_key(|&i| self.incoming[i].position_id.0);
+                *indexes.get(if split { indexes.len() / 2 } else { 0 })?
+            }
+        };
+
+        let position = self.incoming.remove(index)?;
+        if let Some(worker) = worker {
+            self.affinity.insert(worker, (position.work.id(), position.position_id));
+        }
+        Some(position)
+    }
+
+    /// Index of the incoming position of the batch that comes next after
+    /// the given one.
+    fn next_ply(&self, batch_id: BatchId, last: PositionId) -> Option<usize> {
+        (0..self.incoming.len())
+            .filter(|&i| self.incoming[i].work.id() == batch_id && self.incoming[i].position_id.0 > last.0)
+            .min_by_key(|&i| self.incoming[i].position_id.0)
+    }
+
+    fn try_pull(&mut self, worker: Option<usize>, callback: oneshot::Sender<Position>) -> Result<(), oneshot::Sender<Position>> {
+        if let Some(position) = self.next_position(worker) {
             if let Err(err) = callback.send(position) {
                 self.incoming.push_front(err);
             }
@@ -184,6 +692,30 @@ impl QueueState {
         }
     }
 
+    /// Running low on positions, so that some workers would go idle while
+    /// waiting for the next batch to be acquired.
+    fn wants_more(&self) -> bool {
+        let local = &self.local;
+        !self.shutdown_soon &&
+            self.waiting.is_none() &&
+            self.incoming.len() < self.cores &&
+            self.pending.keys().filter(|batch_id| !local.contains_key(batch_id)).count() < self.max_batches
+    }
+
+    /// Submits completed analysis, and keeps a copy in the archive.
+    fn submit_analysis(&mut self, api: &mut ApiStub, completed: CompletedBatch) {
+        let batch_id = completed.work.id();
+        let (flavor, engine, nodes_multiplier) = (completed.flavor.eval_flavor(), completed.engine(), completed.nodes_multiplier);
+        let (url, variant) = (completed.url.clone(), completed.variant);
+        let analysis = completed.into_analysis();
+        if let Some(ref mut archive) = self.archive {
+            if let Err(err) = archive.record(batch_id, url.as_ref(), variant, &analysis) {
+                self.logger.error(&format!("Failed to archive analysis for {}: {}", batch_id, err));
+            }
+        }
+        api.submit_analysis(batch_id, flavor, engine, nodes_multiplier, analysis);
+    }
+
     fn maybe_finished(&mut self, mut queue: QueueStub, batch: BatchId) {
         if let Some(pending) = self.pending.remove(&batch) {
             match pending.try_into_completed() {
@@ -196,7 +728,7 @@ impl QueueState {
                     extra.push(match completed.nps() {
                         Some(nps) => {
                             let nnue_nps = if completed.flavor.eval_flavor() == EvalFlavor::Nnue { Some(nps) } else { None };
-                            self.stats.record_batch(completed.total_positions(), completed.total_nodes(), nnue_nps);
+                            self.stats.lock().expect("stats mutex").record_batch(completed.total_positions(), completed.total_nodes(), nnue_nps);
                             format!("{} knps", nps / 1000)
                         }
                         None => "? nps".to_owned(),
@@ -207,8 +739,10 @@ impl QueueState {
                     };
                     match completed.work {
                         Work::Analysis { id, .. } => {
-                            self.logger.info(&log);
-                            queue.api.submit_analysis(id, completed.flavor.eval_flavor(), completed.into_analysis());
+                            self.logger.notice(&log);
+                            if self.local.remove(&id).is_none() {
+                                self.submit_analysis(&mut queue.api, completed);
+                            }
                         }
                         Work::Move { .. } => {
                             self.logger.debug(&log);
@@ -217,13 +751,22 @@ impl QueueState {
                         }
                     }
                 }
-                Err(pending) => {
-                    let progress_report = pending.progress_report();
-                    if progress_report.iter().filter(|p| p.is_some()).count() % (self.cores * 2) == 0 {
-                        queue.api.submit_analysis(pending.work.id(), pending.flavor.eval_flavor(), progress_report);
+                Err(mut pending) => {
+                    if !self.local.contains_key(&batch) && pending.reported_at.elapsed() >= self.progress_interval {
+                        let progress_report = pending.progress_report();
+                        if progress_report.iter().any(Option::is_some) {
+                            pending.reported_at = Instant::now();
+                            let batch_id = pending.work.id();
+                            let gone = queue.api.submit_progress(batch_id, pending.flavor.eval_flavor(), pending.engine(), pending.nodes_multiplier, progress_report);
+                            tokio::spawn(async move {
+                                if gone.await.is_ok() {
+                                    queue.cancel(batch_id).await;
+                                }
+                            });
+                        }
                     }
 
-                    self.pending.insert(pending.work.id(), pending);
+                    self.pending.insert(pending.work.id(), *pending);
                 }
             }
         }
@@ -233,83 +776,302 @@ impl QueueState {
 #[derive(Debug)]
 enum QueueMessage {
     Pull {
+        worker: Option<usize>,
         callback: oneshot::Sender<Position>,
     },
     MoveSubmitted,
+    Prefetch,
 }
 
 pub struct QueueActor {
-    rx: mpsc::UnboundedReceiver<QueueMessage>,
+    rx: mpsc::Receiver<QueueMessage>,
     interrupt: Arc<Notify>,
     state: Arc<Mutex<QueueState>>,
+    stats: Arc<StdMutex<StatsRecorder>>,
     api: ApiStub,
     endpoint: Endpoint,
     opt: BacklogOpt,
+    acquire: bool,
+    health: Arc<Health>,
     backoff: RandomizedBackoff,
+    user_streak: u32,
+    battery_pause: Option<BatteryPause>,
+    idle_pause: Option<IdlePause>,
+    capped: bool,
+    /// Batches acquired so far, for --max-batches-then-exit.
+    acquired: u64,
+    /// Variants to acquire, if not all.
+    variants: Option<Vec<LichessVariant>>,
+    /// Queue status pushed by the server, if subscribed.
+    status_events: Option<watch::Receiver<Option<AnalysisStatus>>>,
+    dispatcher: Option<Dispatcher>,
     logger: Logger,
 }
 
 impl QueueActor {
-    fn new(rx: mpsc::UnboundedReceiver<QueueMessage>, interrupt: Arc<Notify>, state: Arc<Mutex<QueueState>>, endpoint: Endpoint, opt: BacklogOpt, api: ApiStub, logger: Logger) -> QueueActor {
+    fn new(rx: mpsc::Receiver<QueueMessage>, interrupt: Arc<Notify>, state: Arc<Mutex<QueueState>>, endpoint: Endpoint, opt: BacklogOpt, api: ApiStub, logger: Logger) -> QueueActor {
+        let battery_pause = if opt.pause_on_battery {
+            Some(BatteryPause::new(opt.battery_threshold, logger.clone()))
+        } else {
+            None
+        };
+        let idle_pause = opt.only_when_idle.map(|minutes| IdlePause::new(Duration::from_secs(u64::from(minutes) * 60), logger.clone()));
+        let variants = if opt.variants.is_empty() { None } else { Some(opt.variants.clone()) };
         QueueActor {
             rx,
             interrupt,
             state,
+            stats: Arc::new(StdMutex::new(StatsRecorder::new())),
             api,
             endpoint,
             opt,
+            acquire: true,
+            health: Arc::new(Health::new(0)),
             backoff: RandomizedBackoff::default(),
+            user_streak: 0,
+            battery_pause,
+            idle_pause,
+            capped: false,
+            acquired: 0,
+            variants,
+            status_events: None,
+            dispatcher: None,
             logger,
         }
     }
 
-    pub async fn run(self) {
+    /// Tells the server to only hand out these variants, when the engines
+    /// do not support all of them.
+    pub fn set_variants(&mut self, variants: &[LichessVariant]) {
+        self.variants = Some(variants.iter().copied().filter(|v| self.opt.variants.is_empty() || self.opt.variants.contains(v)).collect());
+    }
+
+    /// Takes the queue status from the subscription while it is connected,
+    /// and reacts to changes right away.
+    pub fn set_status_events(&mut self, status_events: watch::Receiver<Option<AnalysisStatus>>) {
+        self.status_events = Some(status_events);
+    }
+
+    async fn status(&mut self) -> Option<AnalysisStatus> {
+        match self.status_events.as_ref().and_then(|rx| rx.borrow().clone()) {
+            Some(status) => Some(status),
+            None => self.api.status().await,
+        }
+    }
+
+    fn acquire_query(&self, slow: bool) -> AcquireQuery {
+        AcquireQuery {
+            slow,
+            variants: self.variants.as_ref().map(|vs| vs.iter().map(|v| v.key()).collect::<Vec<_>>().join(",")),
+            only: self.opt.only.map(|w| w.to_string()),
+            wait: self.opt.long_poll.map(|d| d.as_secs()),
+        }
+    }
+
+    /// Whether the batch passes --only, --variants and --max-positions. The
+    /// server might not know about these filters, so they are checked again.
+    fn wanted(&self, body: &AcquireResponseBody) -> bool {
+        let unwanted_work = matches!((self.opt.only, &body.work), (Some(WorkType::Analysis), Work::Move { .. }) | (Some(WorkType::Move), Work::Analysis { .. }));
+        let too_long = match (&body.work, self.opt.max_positions) {
+            (Work::Analysis { .. }, Some(max)) => body.moves.len() + 1 > max,
+            _ => false,
+        };
+        !unwanted_work && !too_long && (self.opt.variants.is_empty() || self.opt.variants.contains(&body.variant))
+    }
+
+    async fn on_battery(&mut self) -> bool {
+        match self.battery_pause {
+            Some(ref mut battery_pause) => battery_pause.paused().await,
+            None => false,
+        }
+    }
+
+    fn over_bandwidth_cap(&mut self) -> bool {
+        let capped = self.api.bandwidth().cap_exceeded();
+        if capped && !self.capped {
+            self.logger.warn("Monthly bandwidth cap reached. Not acquiring new batches until next month.");
+        }
+        self.capped = capped;
+        capped
+    }
+
+    /// Whether to hold off, because the user is using the machine.
+    async fn user_active(&mut self) -> bool {
+        let activity = match self.idle_pause {
+            Some(ref mut idle_pause) => idle_pause.activity().await,
+            None => return false,
+        };
+        let mut state = self.state.lock().await;
+        state.user_active = activity != Activity::Idle;
+        if activity == Activity::Returned && self.opt.abort_when_active {
+            // Stops searches in flight, so that the machine is responsive
+            // right away.
+            state.give_back_all(&mut self.api);
+        }
+        state.user_active
+    }
+
+    pub async fn run(mut self) {
         self.logger.debug("Queue actor started");
-        self.run_inner().await;
+        match self.dispatcher.take() {
+            Some(dispatcher) => {
+                tokio::join!(dispatcher.run(), self.run_inner());
+            }
+            None => self.run_inner().await,
+        }
+    }
+
+    async fn set_waiting(&self, waiting: Option<Waiting>) {
+        let mut state = self.state.lock().await;
+        state.waiting = waiting;
     }
 
     pub async fn backlog_wait_time(&mut self) -> (Duration, AcquireQuery) {
         let sec = Duration::from_secs(1);
-        let min_user_backlog = {
-            let state = self.state.lock().await;
-            state.stats.min_user_backlog()
-        };
+        let min_user_backlog = self.stats.lock().expect("stats mutex").min_user_backlog();
         let user_backlog = max(min_user_backlog, self.opt.user.map(Duration::from).unwrap_or_default());
         let system_backlog = self.opt.system.map(Duration::from).unwrap_or_default();
 
-        if user_backlog >= sec || system_backlog >= sec {
-            if let Some(status) = self.api.status().await {
+        if user_backlog >= sec || system_backlog >= sec || self.opt.user_ratio.is_some() {
+            if let Some(status) = self.status().await {
                 let user_wait = user_backlog.checked_sub(status.user.oldest).unwrap_or_default();
                 let system_wait = system_backlog.checked_sub(status.system.oldest).unwrap_or_default();
                 self.logger.debug(&format!("User wait: {:?} due to {:?} for oldest {:?}, system wait: {:?} due to {:?} for oldest {:?}",
                        user_wait, user_backlog, status.user.oldest,
                        system_wait, system_backlog, status.system.oldest));
                 let slow = user_wait >= system_wait + sec;
-                (min(user_wait, system_wait), AcquireQuery { slow })
+                let mixed = status.user.queued > 0 && status.system.queued > 0 && system_wait < sec;
+                let slow = match self.opt.user_ratio {
+                    Some(ratio) if !slow && mixed => {
+                        // Take one system batch after every ratio user batches.
+                        self.user_streak += 1;
+                        if self.user_streak > ratio {
+                            self.user_streak = 0;
+                            self.logger.debug(&format!("Both backlogs populated. Taking a system batch after {} user batches.", ratio));
+                            true
+                        } else {
+                            false
+                        }
+                    }
+                    _ => slow,
+                };
+                (min(user_wait, system_wait), self.acquire_query(slow))
             } else {
                 self.logger.debug("Queue status not available. Will not delay acquire.");
                 let slow = user_backlog >= system_backlog + sec;
-                (Duration::default(), AcquireQuery { slow })
+                (Duration::default(), self.acquire_query(slow))
             }
         } else {
-            (Duration::default(), AcquireQuery { slow: false })
+            (Duration::default(), self.acquire_query(false))
         }
     }
 
-    async fn handle_acquired_response_body(&mut self, body: AcquireResponseBody) {
-        match IncomingBatch::from_acquired(self.endpoint.clone(), body) {
-            Ok(incoming) => {
+    async fn handle_acquired_response_body(&mut self, body: AcquireResponseBody, user: bool) {
+        if !self.wanted(&body) {
+            self.logger.debug(&format!("Giving back filtered batch {} ({}, {} plies).", body.work.id(), body.variant.key(), body.moves.len()));
+            self.api.abort(body.work.id());
+            return;
+        }
+
+        let variant = body.variant;
+        self.acquired += 1;
+        self.state.lock().await.no_work_since = None;
+        if self.opt.max_batches_then_exit.is_some_and(|limit| self.acquired >= limit) {
+            let mut state = self.state.lock().await;
+            if !state.shutdown_soon {
+                self.logger.info(&format!("Acquired {} batches. Stopping after finishing them.", self.acquired));
+                state.shutdown_soon = true;
+            }
+        }
+
+        match IncomingBatch::from_acquired(self.endpoint.clone(), body, user, self.opt.nodes_multiplier(variant)) {
+            Ok(mut incoming) => {
+                if self.opt.cloud_eval {
+                    self.prefill_from_cloud(&mut incoming).await;
+                }
+
                 let mut state = self.state.lock().await;
-                state.add_incoming_batch(incoming);
+                if let Some(completed) = state.add_incoming_batch(incoming) {
+                    let batch_id = completed.work.id();
+                    self.logger.notice(&format!("Completed batch {} from cache.", batch_id));
+                    state.stats.lock().expect("stats mutex").record_batch(completed.total_positions(), completed.total_nodes(), None);
+                    state.submit_analysis(&mut self.api, completed);
+                }
             }
             Err(completed) => {
                 let batch_id = completed.work.id();
                 self.logger.warn(&format!("Completed empty batch {}.", batch_id));
-                self.api.submit_analysis(batch_id, completed.flavor.eval_flavor(), completed.into_analysis());
+                let mut state = self.state.lock().await;
+                state.stats.lock().expect("stats mutex").record_batch(completed.total_positions(), completed.total_nodes(), None);
+                state.submit_analysis(&mut self.api, *completed);
             }
         }
     }
 
+    /// Looks up positions from the start of the game in the cloud eval
+    /// database, until the first one that is missing or too shallow.
+    async fn prefill_from_cloud(&mut self, batch: &mut IncomingBatch) {
+        if !batch.work.is_analysis() || batch.variant != LichessVariant::Standard {
+            return;
+        }
+
+        let min_depth = self.opt.cloud_eval_depth.unwrap_or(30);
+        for pos in &batch.positions {
+            let pos = match pos {
+                Skip::Present(pos) if !batch.duplicates.contains_key(&pos.position_id.0) => pos,
+                _ => continue,
+            };
+            if pos.chess960 {
+                break;
+            }
+            let epd = match batch.epds.get(pos.position_id.0).cloned().flatten() {
+                Some(epd) => epd,
+                None => break,
+            };
+            let eval = match self.api.cloud_eval(format!("{} 0 1", epd)).await {
+                Some(eval) if eval.depth >= min_depth => eval,
+                _ => break,
+            };
+            let pv = match eval.pvs.into_iter().next() {
+                Some(pv) => pv,
+                None => break,
+            };
+
+            // Cloud evals are from the point of view of white.
+            let sign = if epd.split(' ').nth(1) == Some("b") { -1 } else { 1 };
+            let score = match (pv.cp, pv.mate) {
+                (Some(cp), _) => Score::Cp(sign * cp),
+                (None, Some(mate)) => Score::Mate(sign * mate),
+                (None, None) => break,
+            };
+
+            batch.cloud.insert(pos.position_id.0, PositionResponse {
+                work: pos.work.clone(),
+                position_id: pos.position_id,
+                url: pos.url.clone(),
+                score,
+                bound: None,
+                wdl: Wdl::from_score(score, pos.ply()),
+                best_move: pv.moves.first().cloned(),
+                pv: pv.moves,
+                depth: eval.depth,
+                nodes: eval.knodes * 1000,
+                time: Duration::default(),
+                nps: None,
+                seldepth: None,
+                hashfull: None,
+                tbhits: None,
+                secondary: Vec::new(),
+                engine: None,
+                origin: Origin::CloudEval,
+            });
+        }
+
+        if !batch.cloud.is_empty() {
+            self.logger.debug(&format!("Found {} positions of batch {} in cloud eval", batch.cloud.len(), batch.work.id()));
+        }
+    }
+
     async fn handle_move_submissions(&mut self) {
         loop {
             let next = {
@@ -326,7 +1088,7 @@ impl QueueActor {
 
             if let Some(completed) = next {
                 if let Some(Acquired::Accepted(body)) = self.api.submit_move_and_acquire(completed.work.id(), completed.into_best_move()).await {
-                    self.handle_acquired_response_body(body).await;
+                    self.handle_acquired_response_body(body, true).await;
                 }
             } else {
                 break;
@@ -334,16 +1096,72 @@ impl QueueActor {
         }
     }
 
+    /// Acquires the next batch ahead of time, but only if it is available
+    /// right away. Otherwise the next pull will wait for it as usual.
+    async fn prefetch(&mut self) -> bool {
+        if !self.acquire || self.on_battery().await || self.user_active().await || self.over_bandwidth_cap() {
+            return false;
+        }
+
+        let (wait, mut query) = self.backlog_wait_time().await;
+        if wait >= Duration::from_secs(1) {
+            return false;
+        }
+        query.wait = None;
+
+        let user = !query.slow;
+        match self.api.acquire(query).await {
+            Some(Acquired::Accepted(body)) => {
+                self.logger.debug("Prefetched next batch");
+                self.health.key_accepted();
+                self.backoff.reset();
+                self.handle_acquired_response_body(body, user).await;
+                true
+            }
+            Some(Acquired::BadRequest) => {
+                self.logger.error("Client update might be required. Stopping queue");
+                let mut state = self.state.lock().await;
+                state.shutdown_soon = true;
+                state.update_required = true;
+                false
+            }
+            Some(Acquired::NoContent) | Some(Acquired::RateLimited(_)) | None => false,
+        }
+    }
+
     async fn run_inner(mut self) {
-        while let Some(msg) = self.rx.recv().await {
+        loop {
+            let msg = tokio::select! {
+                msg = self.rx.recv() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                _ = time::sleep(idle::CHECK_INTERVAL), if self.idle_pause.is_some() => {
+                    // Also notice the user coming back while there is
+                    // still work.
+                    self.user_active().await;
+                    continue;
+                }
+            };
+
             match msg {
-                QueueMessage::Pull { mut callback } => {
+                QueueMessage::Pull { worker, mut callback } => {
                     loop {
                         self.handle_move_submissions().await;
 
+                        if self.user_active().await {
+                            self.set_waiting(Some(Waiting::UserActive)).await;
+                            tokio::select! {
+                                _ = callback.closed() => break,
+                                _ = time::sleep(idle::CHECK_INTERVAL) => (),
+                            }
+                            self.set_waiting(None).await;
+                            continue;
+                        }
+
                         {
                             let mut state = self.state.lock().await;
-                            callback = match state.try_pull(callback) {
+                            callback = match state.try_pull(worker, callback) {
                                 Ok(()) => break,
                                 Err(not_done) => not_done,
                             };
@@ -353,6 +1171,36 @@ impl QueueActor {
                             }
                         }
 
+                        // Only serving local clients. Wait for their work.
+                        if !self.acquire {
+                            tokio::select! {
+                                _ = callback.closed() => break,
+                                _ = self.interrupt.notified() => continue,
+                            }
+                        }
+
+                        if self.over_bandwidth_cap() {
+                            self.set_waiting(Some(Waiting::BandwidthCap)).await;
+                            tokio::select! {
+                                _ = callback.closed() => break,
+                                _ = self.interrupt.notified() => (),
+                                _ = time::sleep(Duration::from_secs(60 * 60)) => (),
+                            }
+                            self.set_waiting(None).await;
+                            continue;
+                        }
+
+                        if self.on_battery().await {
+                            self.set_waiting(Some(Waiting::OnBattery)).await;
+                            tokio::select! {
+                                _ = callback.closed() => break,
+                                _ = self.interrupt.notified() => (),
+                                _ = time::sleep(power::CHECK_INTERVAL) => (),
+                            }
+                            self.set_waiting(None).await;
+                            continue;
+                        }
+
                         let (wait, query) = tokio::select! {
                             _ = callback.closed() => break,
                             res = self.backlog_wait_time() => res,
@@ -364,42 +1212,191 @@ impl QueueActor {
                             self.logger.debug(&format!("Going idle for {:?}.", wait));
                         }
 
+                        if wait >= Duration::from_secs(1) {
+                            self.set_waiting(Some(Waiting::Backlog(Instant::now() + wait))).await;
+                        }
                         tokio::select! {
                             _ = callback.closed() => break,
-                            _ = self.interrupt.notified() => continue,
-                            _ = time::sleep(wait) => (),
+                            _ = self.interrupt.notified() => {
+                                self.set_waiting(None).await;
+                                continue;
+                            }
+                            _ = status_changed(&mut self.status_events) => {
+                                self.set_waiting(None).await;
+                                continue;
+                            }
+                            _ = time::sleep(wait) => self.set_waiting(None).await,
                         }
 
-                        match self.api.acquire(query).await {
+                        let user = !query.slow;
+                        let long_poll = query.wait.map(Duration::from_secs);
+                        if long_poll.is_some() {
+                            self.set_waiting(Some(Waiting::LongPoll)).await;
+                        }
+                        let started_at = Instant::now();
+                        // The API actor gives back batches that arrive after
+                        // the request was dropped.
+                        let acquired = tokio::select! {
+                            _ = callback.closed() => break,
+                            _ = self.interrupt.notified() => {
+                                self.set_waiting(None).await;
+                                continue;
+                            }
+                            acquired = self.api.acquire(query) => acquired,
+                        };
+                        if long_poll.is_some() {
+                            self.set_waiting(None).await;
+                        }
+                        match acquired {
+                            Some(Acquired::Accepted(body)) if !self.wanted(&body) => {
+                                // Do not keep acquiring the same batch again
+                                // if the server ignores the filters.
+                                self.handle_acquired_response_body(body, user).await;
+                                let backoff = self.backoff.next();
+                                self.set_waiting(Some(Waiting::Backoff(Instant::now() + backoff))).await;
+                                tokio::select! {
+                                    _ = callback.closed() => break,
+                                    _ = self.interrupt.notified() => (),
+                                    _ = time::sleep(backoff) => (),
+                                }
+                                self.set_waiting(None).await;
+                            }
                             Some(Acquired::Accepted(body)) => {
+                                self.health.key_accepted();
                                 self.backoff.reset();
-                                self.handle_acquired_response_body(body).await;
+                                self.handle_acquired_response_body(body, user).await;
                             }
                             Some(Acquired::NoContent) => {
+                                self.health.key_accepted();
+                                self.state.lock().await.no_work_since.get_or_insert_with(Instant::now);
+                                // The server already held the request, so ask
+                                // again right away. Back off as usual if it
+                                // answered early, because then it does not
+                                // support long polling.
+                                if let Some(long_poll) = long_poll {
+                                    if started_at.elapsed() >= long_poll / 2 {
+                                        self.backoff.reset();
+                                        continue;
+                                    }
+                                }
                                 let backoff = self.backoff.next();
                                 self.logger.debug(&format!("No job received. Backing off {:?}.", backoff));
+                                self.set_waiting(Some(Waiting::Backoff(Instant::now() + backoff))).await;
                                 tokio::select! {
                                     _ = callback.closed() => break,
                                     _ = self.interrupt.notified() => (),
                                     _ = time::sleep(backoff) => (),
                                 }
+                                self.set_waiting(None).await;
                             }
                             Some(Acquired::BadRequest) => {
                                 self.logger.error("Client update might be required. Stopping queue");
                                 let mut state = self.state.lock().await;
                                 state.shutdown_soon = true;
+                                state.update_required = true;
                             },
+                            Some(Acquired::RateLimited(retry_after)) => {
+                                self.logger.warn(&format!("Server is busy. Retrying after {:?}.", retry_after));
+                                self.set_waiting(Some(Waiting::RateLimited(Instant::now() + retry_after))).await;
+                                tokio::select! {
+                                    _ = callback.closed() => break,
+                                    _ = time::sleep(retry_after) => (),
+                                }
+                                self.set_waiting(None).await;
+                            }
                             None => (),
                         }
                     }
+                    self.set_waiting(None).await;
                 }
                 QueueMessage::MoveSubmitted => self.handle_move_submissions().await,
+                QueueMessage::Prefetch => {
+                    // Fill up to the maximum number of batches at once, as
+                    // long as workers would otherwise run out of work.
+                    while self.prefetch().await && self.state.lock().await.wants_more() {}
+                    self.state.lock().await.prefetching = false;
+                }
             }
         }
 
     }
 }
 
+/// Applies completed positions and hands out the next ones, so that workers
+/// never wait for the state lock themselves. Completions that arrive at the
+/// same time are applied under a single lock.
+enum Dispatch {
+    Pull(Box<Pull>),
+    Flush(oneshot::Sender<()>),
+}
+
+struct Dispatcher {
+    rx: mpsc::Receiver<Dispatch>,
+    tx: mpsc::Sender<QueueMessage>,
+    queue: QueueStub,
+}
+
+impl Dispatcher {
+    async fn run(mut self) {
+        while let Some(msg) = self.rx.recv().await {
+            let mut pulls = Vec::new();
+            let mut flushed = Vec::new();
+            let mut next = Some(msg);
+            while let Some(msg) = next {
+                match msg {
+                    Dispatch::Pull(pull) => pulls.push(*pull),
+                    Dispatch::Flush(callback) => flushed.push(callback),
+                }
+                next = self.rx.try_recv().ok();
+            }
+            self.dispatch(pulls).await;
+            for callback in flushed {
+                callback.send(()).nevermind("flush dropped");
+            }
+        }
+    }
+
+    async fn dispatch(&mut self, pulls: Vec<Pull>) {
+        if pulls.is_empty() {
+            return;
+        }
+        let mut waiting = Vec::new();
+        {
+            let mut state = self.queue.state.lock().await;
+            for pull in pulls {
+                let worker = pull.worker;
+                let (response, callback) = pull.split();
+                if let Some(response) = response {
+                    state.handle_position_response(self.queue.clone(), response);
+                }
+                let pulled = if state.user_active {
+                    Err(callback)
+                } else {
+                    state.try_pull(worker, callback)
+                };
+                if let Err(callback) = pulled {
+                    waiting.push((worker, callback));
+                }
+            }
+            if waiting.is_empty() && !state.prefetching && state.wants_more() {
+                // If the actor is busy, the next pull will try again.
+                state.prefetching = self.tx.try_send(QueueMessage::Prefetch).is_ok();
+            }
+        }
+
+        // The actor needs the state to make progress, so it is released
+        // before waiting for room in the channel. While the actor is
+        // stalled, this holds up the main loop and, in turn, the workers,
+        // rather than piling up messages.
+        for (worker, callback) in waiting {
+            self.tx.send(QueueMessage::Pull {
+                worker,
+                callback,
+            }).await.nevermind("queue dropped");
+        }
+    }
+}
+
 impl Drop for QueueActor {
     fn drop(&mut self) {
         self.logger.debug("Queue actor exited");
@@ -421,12 +1418,32 @@ impl<T> Skip<T> {
 #[derive(Debug, Clone)]
 pub struct IncomingBatch {
     work: Work,
+    user: bool,
+    timeout: Option<Duration>,
+    duplicates: HashMap<usize, usize>,
+    trivial: HashMap<usize, Trivial>,
+    epds: Vec<Option<String>>,
+    cloud: HashMap<usize, PositionResponse>,
     flavor: EngineFlavor,
     variant: LichessVariant,
+    /// Node limits were scaled by this factor.
+    nodes_multiplier: Option<f64>,
     positions: Vec<Skip<Position>>,
+    cancellation: Cancellation,
     url: Option<Url>,
 }
 
+/// Resolves when the subscribed queue status changes. Never resolves
+/// without a subscription.
+async fn status_changed(status_events: &mut Option<watch::Receiver<Option<AnalysisStatus>>>) {
+    if let Some(rx) = status_events {
+        if rx.changed().await.is_ok() {
+            return;
+        }
+    }
+    std::future::pending().await
+}
+
 fn is_standard_material_side(side: &MaterialSide) -> bool {
     side.pawns <= 8 &&
     side.knights <= 2 &&
@@ -477,94 +1494,297 @@ fn rewrite_moves(variant: LichessVariant, pos: &Fen, moves: Vec<Uci>) -> (bool,
     (chess960, rewritten)
 }
 
+/// EPD of each position in the game, or `None` after an illegal move.
+fn position_epds(variant: LichessVariant, fen: &Fen, moves: &[Uci]) -> Vec<Option<String>> {
+    let mut epds = vec![None; moves.len() + 1];
+
+    let mut pos = match VariantPosition::from_setup(variant.into(), fen) {
+        Ok(pos) => pos,
+        Err(_) => return epds,
+    };
+
+    epds[0] = Some(fen::epd(&pos));
+    for (i, uci) in moves.iter().enumerate() {
+        match uci.to_move(&pos) {
+            Ok(m) => pos.play_unchecked(&m),
+            Err(_) => break,
+        }
+        epds[i + 1] = Some(fen::epd(&pos));
+    }
+
+    epds
+}
+
+/// Maps each position that occurs again later in the game (by EPD) to its
+/// first occurrence, so that it needs to be analysed only once.
+fn find_duplicates(epds: &[Option<String>], positions: &[Skip<Position>]) -> HashMap<usize, usize> {
+    let mut duplicates = HashMap::new();
+    let mut seen = HashMap::new();
+    for (i, (epd, pos)) in epds.iter().zip(positions).enumerate() {
+        if let (Some(epd), Skip::Present(_)) = (epd, pos) {
+            match seen.entry(epd) {
+                Entry::Occupied(entry) => {
+                    duplicates.insert(i, *entry.get());
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(i);
+                }
+            }
+        }
+    }
+    duplicates
+}
+
+/// Position that can be analysed without an engine.
+#[derive(Debug, Clone)]
+enum Trivial {
+    /// The only legal move. The analysis follows from the position after
+    /// the move.
+    Forced(Uci),
+    /// The game is over, with this score for the side to move.
+    Over(Score),
+}
+
+/// Finds positions with a single legal move, and positions where the game
+/// is already over.
+fn trivial_positions(variant: LichessVariant, fen: &Fen, moves: &[Uci], chess960: bool) -> Vec<Option<Trivial>> {
+    let mut trivial = vec![None; moves.len() + 1];
+
+    let mut pos = match VariantPosition::from_setup(variant.into(), fen) {
+        Ok(pos) => pos,
+        Err(_) => return trivial,
+    };
+
+    for (i, uci) in moves.iter().map(Some).chain(Some(None)).enumerate() {
+        let legals = pos.legal_moves();
+        trivial[i] = match legals.len() {
+            0 => match pos.outcome() {
+                Some(Outcome::Decisive { winner }) if winner != pos.turn() => Some(Trivial::Over(Score::Mate(0))),
+                Some(Outcome::Draw) => Some(Trivial::Over(Score::Cp(0))),
+                _ => None,
+            },
+            1 => Some(Trivial::Forced(if chess960 {
+                Uci::from_chess960(&legals[0])
+            } else {
+                Uci::from_move(&pos, &legals[0])
+            })),
+            _ => None,
+        };
+        match uci.map(|uci| uci.to_move(&pos)) {
+            Some(Ok(m)) => pos.play_unchecked(&m),
+            _ => break,
+        }
+    }
+
+    trivial
+}
+
+/// Response for a position where the game is over, or with the only legal
+/// move when playing.
+fn trivial_response(pos: Position, score: Score, best_move: Option<Uci>) -> PositionResponse {
+    PositionResponse {
+        wdl: match score {
+            Score::Cp(0) if best_move.is_none() => Wdl { win: 0, draw: 1000, loss: 0 },
+            _ => Wdl::from_score(score, pos.ply()),
+        },
+        work: pos.work,
+        position_id: pos.position_id,
+        url: pos.url,
+        score,
+        bound: None,
+        pv: best_move.iter().cloned().collect(),
+        best_move,
+        depth: 0,
+        seldepth: None,
+        nodes: 0,
+        time: Duration::default(),
+        nps: None,
+        hashfull: None,
+        tbhits: None,
+        secondary: Vec::new(),
+        engine: None,
+        origin: Origin::Trivial,
+    }
+}
+
+/// Analysis of a position with a single legal move, from the analysis of
+/// the position after the move.
+fn forced_response(position_id: PositionId, uci: Uci, next: &PositionResponse) -> PositionResponse {
+    PositionResponse {
+        position_id,
+        url: next.url.clone().map(|mut url| {
+            url.set_fragment(Some(&position_id.0.to_string()));
+            url
+        }),
+        score: match next.score {
+            Score::Cp(cp) => Score::Cp(-cp),
+            Score::Mate(mate) if mate > 0 => Score::Mate(-mate),
+            Score::Mate(mate) => Score::Mate(1 - mate),
+        },
+        bound: next.bound.map(|bound| match bound {
+            Bound::Lower => Bound::Upper,
+            Bound::Upper => Bound::Lower,
+        }),
+        wdl: Wdl {
+            win: next.wdl.loss,
+            draw: next.wdl.draw,
+            loss: next.wdl.win,
+        },
+        best_move: Some(uci.clone()),
+        pv: Some(uci).into_iter().chain(next.pv.iter().cloned()).collect(),
+        time: Duration::default(),
+        nps: None,
+        secondary: Vec::new(),
+        origin: Origin::Trivial,
+        ..next.clone()
+    }
+}
+
+fn eval_key(work: &Work, flavor: EngineFlavor, variant: LichessVariant, epds: &[Option<String>], position_id: PositionId) -> Option<EvalKey> {
+    match work {
+        // Results of time limited searches depend on the hardware.
+        Work::Analysis { movetime: Some(_), .. } => None,
+        Work::Analysis { .. } => epds.get(position_id.0).cloned().flatten().map(|epd| EvalKey {
+            variant,
+            epd,
+            nodes: work.node_limit().unwrap_or_default().get(flavor.eval_flavor()),
+            multipv: work.multipv(),
+            depth: match *work {
+                Work::Analysis { depth, .. } => depth,
+                Work::Move { .. } => None,
+            },
+        }),
+        Work::Move { .. } => None,
+    }
+}
+
 impl IncomingBatch {
-    fn from_acquired(endpoint: Endpoint, body: AcquireResponseBody) -> Result<IncomingBatch, CompletedBatch> {
+    fn from_acquired(endpoint: Endpoint, mut body: AcquireResponseBody, user: bool, nodes_multiplier: Option<f64>) -> Result<IncomingBatch, Box<CompletedBatch>> {
+        let nodes_multiplier = match (&mut body.work, nodes_multiplier) {
+            (Work::Analysis { nodes: Some(ref mut nodes), .. }, Some(factor)) => {
+                *nodes = nodes.scaled(factor);
+                Some(factor)
+            }
+            _ => None,
+        };
+
         let flavor = engine_flavor(&body);
         let (chess960, body_moves) = rewrite_moves(body.variant, &body.position, body.moves);
 
-        let url = body.game_id.as_ref().map(|g| {
-            let mut url = endpoint.url.clone();
-            url.set_path(g);
-            url
-        });
+        let url = body.game_id.as_ref().map(|g| endpoint.route_with(Route::Game, g));
 
-        Ok(IncomingBatch {
-            work: body.work.clone(),
-            url: url.clone(),
-            flavor,
-            variant: body.variant,
-            positions: match body.work {
-                Work::Move { .. } => {
-                    vec![Skip::Present(Position {
-                        work: body.work,
-                        url,
-                        flavor,
-                        position_id: PositionId(0),
-                        variant: body.variant,
-                        chess960,
-                        fen: body.position,
-                        moves: body_moves,
-                    })]
+        let work = body.work.clone();
+        let cancellation = Cancellation::default();
+        let mut duplicates = HashMap::new();
+        let mut epds = Vec::new();
+        let mut trivial = HashMap::new();
+
+        let positions = match body.work {
+            Work::Move { .. } => {
+                if let Some(Some(Trivial::Forced(uci))) = trivial_positions(body.variant, &body.position, &body_moves, chess960).pop() {
+                    trivial.insert(0, Trivial::Forced(uci));
                 }
-                Work::Analysis { .. } => {
-                    let mut moves = Vec::new();
-                    let mut positions = vec![Skip::Present(Position {
+                vec![Skip::Present(Position {
+                    work: body.work,
+                    url: url.clone(),
+                    flavor,
+                    position_id: PositionId(0),
+                    variant: body.variant,
+                    chess960,
+                    fen: body.position,
+                    moves: Moves::from(body_moves),
+                    attempts: 0,
+                    cancellation: cancellation.clone(),
+                })]
+            }
+            Work::Analysis { .. } => {
+                let game = Moves::from(body_moves);
+                let mut positions = vec![Skip::Present(Position {
+                    work: body.work.clone(),
+                    url: url.clone().map(|mut url| {
+                        url.set_fragment(Some("0"));
+                        url
+                    }),
+                    flavor,
+                    position_id: PositionId(0),
+                    variant: body.variant,
+                    chess960,
+                    fen: body.position.clone(),
+                    moves: game.prefix(0),
+                    attempts: 0,
+                    cancellation: cancellation.clone(),
+                })];
+
+                for i in 0..game.len() {
+                    positions.push(Skip::Present(Position {
                         work: body.work.clone(),
                         url: url.clone().map(|mut url| {
-                            url.set_fragment(Some("0"));
+                            url.set_fragment(Some(&(1 + i).to_string()));
                             url
                         }),
                         flavor,
-                        position_id: PositionId(0),
+                        position_id: PositionId(1 + i),
                         variant: body.variant,
                         chess960,
                         fen: body.position.clone(),
-                        moves: moves.clone(),
-                    })];
-
-                    for (i, m) in body_moves.into_iter().enumerate() {
-                        let mut url = endpoint.url.clone();
-                        moves.push(m);
-                        positions.push(Skip::Present(Position {
-                            work: body.work.clone(),
-                            url: body.game_id.as_ref().map(|g| {
-                                url.set_path(g);
-                                url.set_fragment(Some(&(1 + i).to_string()));
-                                url
-                            }),
-                            flavor,
-                            position_id: PositionId(1 + i),
-                            variant: body.variant,
-                            chess960,
-                            fen: body.position.clone(),
-                            moves: moves.clone(),
-                        }));
-                    }
+                        moves: game.prefix(1 + i),
+                        attempts: 0,
+                        cancellation: cancellation.clone(),
+                    }));
+                }
 
-                    for skip in body.skip_positions.into_iter() {
-                        if let Some(pos) = positions.get_mut(skip) {
-                            *pos = Skip::Skip;
-                        }
+                for skip in body.skip_positions.into_iter() {
+                    if let Some(pos) = positions.get_mut(skip) {
+                        *pos = Skip::Skip;
                     }
+                }
 
-                    // Edge case: Batch is immediately completed, because all
-                    // positions are skipped.
-                    if positions.iter().all(Skip::is_skipped) {
-                        let now = Instant::now();
-                        return Err(CompletedBatch {
-                            work: body.work,
-                            url,
-                            flavor,
-                            variant: body.variant,
-                            positions: positions.into_iter().map(|_| Skip::Skip).collect(),
-                            started_at: now,
-                            completed_at: now,
-                        });
-                    }
+                // Edge case: Batch is immediately completed, because all
+                // positions are skipped.
+                if positions.iter().all(Skip::is_skipped) {
+                    let now = Instant::now();
+                    return Err(Box::new(CompletedBatch {
+                        work: body.work,
+                        url,
+                        flavor,
+                        variant: body.variant,
+                        nodes_multiplier,
+                        positions: positions.into_iter().map(|_| Skip::Skip).collect(),
+                        started_at: now,
+                        completed_at: now,
+                    }));
+                }
 
-                    positions
+                epds = position_epds(body.variant, &body.position, &game);
+                duplicates = find_duplicates(&epds, &positions);
+                for (i, t) in trivial_positions(body.variant, &body.position, &game, chess960).into_iter().enumerate() {
+                    match t {
+                        // Forced moves need the position after the move.
+                        Some(Trivial::Forced(_)) if !matches!(positions.get(i + 1), Some(Skip::Present(_))) => (),
+                        Some(t) if matches!(positions.get(i), Some(Skip::Present(_))) => {
+                            trivial.insert(i, t);
+                        }
+                        _ => (),
+                    }
                 }
+                positions
             }
+        };
+
+        Ok(IncomingBatch {
+            work,
+            user,
+            timeout: body.timeout,
+            duplicates,
+            trivial,
+            epds,
+            cloud: HashMap::new(),
+            cancellation,
+            url,
+            flavor,
+            variant: body.variant,
+            nodes_multiplier,
+            positions,
         })
     }
 }
@@ -582,27 +1802,74 @@ impl From<&IncomingBatch> for ProgressAt {
 #[derive(Debug, Clone)]
 struct PendingBatch {
     work: Work,
+    user: bool,
+    duplicates: HashMap<usize, usize>,
+    /// Positions with a single legal move, resolved together with the
+    /// position after the move.
+    forced: HashMap<usize, Uci>,
+    epds: Vec<Option<String>>,
     url: Option<Url>,
     flavor: EngineFlavor,
     variant: LichessVariant,
+    nodes_multiplier: Option<f64>,
     positions: Vec<Option<Skip<PositionResponse>>>,
+    cancellation: Cancellation,
     started_at: Instant,
+    reported_at: Instant,
+    deadline: Instant,
 }
 
 impl PendingBatch {
-    fn try_into_completed(self) -> Result<CompletedBatch, PendingBatch> {
-        match self.positions.clone().into_iter().collect() {
-            Some(positions) => Ok(CompletedBatch {
-                work: self.work,
-                url: self.url,
-                flavor: self.flavor,
-                variant: self.variant,
-                positions,
-                started_at: self.started_at,
-                completed_at: Instant::now(),
-            }),
-            None => Err(self),
+    fn resolve(&mut self, res: PositionResponse) {
+        let mut resolved = vec![res];
+        while let Some(res) = resolved.pop() {
+            let i = res.position_id.0;
+            for (&duplicate, &original) in &self.duplicates {
+                if original == i {
+                    resolved.push(PositionResponse {
+                        position_id: PositionId(duplicate),
+                        origin: Origin::Reused,
+                        ..res.clone()
+                    });
+                }
+            }
+            if let Some(prev) = i.checked_sub(1) {
+                if let (Some(uci), Some(None)) = (self.forced.get(&prev), self.positions.get(prev)) {
+                    resolved.push(forced_response(PositionId(prev), uci.clone(), &res));
+                }
+            }
+            if let Some(pos) = self.positions.get_mut(i) {
+                *pos = Some(Skip::Present(res));
+            }
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.positions.iter().all(Option::is_some)
+    }
+
+    fn try_into_completed(self) -> Result<CompletedBatch, Box<PendingBatch>> {
+        // Check first, so that positions are only moved once complete.
+        if !self.is_complete() {
+            return Err(Box::new(self));
         }
+        Ok(CompletedBatch {
+            work: self.work,
+            url: self.url,
+            flavor: self.flavor,
+            variant: self.variant,
+            nodes_multiplier: self.nodes_multiplier,
+            positions: self.positions.into_iter().flatten().collect(),
+            started_at: self.started_at,
+            completed_at: Instant::now(),
+        })
+    }
+
+    fn engine(&self) -> Option<EngineInfo> {
+        self.positions.iter().find_map(|p| match p {
+            Some(Skip::Present(res)) => res.engine.as_deref().cloned(),
+            _ => None,
+        })
     }
 
     fn progress_report(&self) -> Vec<Option<AnalysisPart>> {
@@ -612,10 +1879,18 @@ impl PendingBatch {
             Some(Skip::Present(pos)) if i > 0 => Some(AnalysisPart::Complete {
                 pv: pos.pv.clone(),
                 depth: pos.depth,
-                score: pos.score,
+                score: BoundedScore {
+                    score: pos.score,
+                    bound: pos.bound,
+                },
                 time: pos.time.as_millis() as u64,
                 nodes: pos.nodes,
                 nps: pos.nps,
+                seldepth: pos.seldepth,
+                hashfull: pos.hashfull,
+                tbhits: pos.tbhits,
+                secondary: pos.secondary.clone(),
+                wdl: pos.wdl,
             }),
             _ => None,
         }).collect()
@@ -631,12 +1906,20 @@ pub struct CompletedBatch {
     url: Option<Url>,
     flavor: EngineFlavor,
     variant: LichessVariant,
+    nodes_multiplier: Option<f64>,
     positions: Vec<Skip<PositionResponse>>,
     started_at: Instant,
     completed_at: Instant,
 }
 
 impl CompletedBatch {
+    fn engine(&self) -> Option<EngineInfo> {
+        self.positions.iter().find_map(|p| match p {
+            Skip::Present(res) => res.engine.as_deref().cloned(),
+            Skip::Skip => None,
+        })
+    }
+
     fn into_analysis(self) -> Vec<Option<AnalysisPart>> {
         let lila_updated = matches!(self.work, Work::Analysis { nodes: Some(_), .. });
         let flavor = self.flavor.eval_flavor();
@@ -649,7 +1932,10 @@ impl CompletedBatch {
                 Skip::Present(pos) => AnalysisPart::Complete {
                     pv: pos.pv,
                     depth: pos.depth,
-                    score: pos.score,
+                    score: BoundedScore {
+                        score: pos.score,
+                        bound: pos.bound,
+                    },
                     time: pos.time.as_millis() as u64,
                     nodes: match flavor {
                         EvalFlavor::Nnue if !lila_updated => {
@@ -663,6 +1949,11 @@ impl CompletedBatch {
                         _ => pos.nodes,
                     },
                     nps: pos.nps,
+                    seldepth: pos.seldepth,
+                    hashfull: pos.hashfull,
+                    tbhits: pos.tbhits,
+                    secondary: pos.secondary,
+                    wdl: pos.wdl,
                 },
             })
         }).collect()
@@ -677,15 +1968,15 @@ impl CompletedBatch {
 
     fn total_positions(&self) -> u64 {
         self.positions.iter().map(|p| match p {
-            Skip::Skip => 0,
-            Skip::Present(_) => 1,
+            Skip::Present(pos) if pos.origin == Origin::Engine => 1,
+            _ => 0,
         }).sum()
     }
 
     fn total_nodes(&self) -> u64 {
         self.positions.iter().map(|p| match p {
-            Skip::Skip => 0,
-            Skip::Present(pos) => pos.nodes,
+            Skip::Present(pos) if pos.origin == Origin::Engine => pos.nodes,
+            _ => 0,
         }).sum()
     }
 
@@ -702,6 +1993,12 @@ pub struct StatsRecorder {
     pub total_positions: u64,
     pub total_nodes: u64,
     pub nnue_nps: NpsRecorder,
+    pub workers: Vec<WorkerStats>,
+    pub engine_crashes: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// API round trip times per kind of request.
+    pub round_trips: Vec<RoundTrips>,
 }
 
 impl StatsRecorder {
@@ -711,9 +2008,73 @@ impl StatsRecorder {
             total_positions: 0,
             total_nodes: 0,
             nnue_nps: NpsRecorder::new(),
+            workers: Vec::new(),
+            engine_crashes: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            round_trips: Vec::new(),
+        }
+    }
+
+    fn record_worker(&mut self, worker: usize, res: &Result<PositionResponse, PositionFailed>) {
+        if self.workers.len() <= worker {
+            self.workers.resize_with(worker + 1, WorkerStats::new);
+        }
+        let stats = &mut self.workers[worker];
+        match res {
+            Ok(res) if res.origin == Origin::Engine => {
+                stats.positions += 1;
+                if let Some(nps) = res.nps {
+                    stats.nps.record(nps);
+                }
+            }
+            Ok(_) => (),
+            Err(_) => {
+                stats.errors += 1;
+                self.engine_crashes += 1;
+            }
         }
     }
 
+    /// Workers that have been much slower than their peers for several
+    /// consecutive checks, with their nps and the median nps. Reports each
+    /// slow streak once.
+    fn slow_workers(&mut self) -> Vec<(usize, u32, u32)> {
+        let mut known: Vec<u32> = self.workers.iter()
+            .filter(|w| w.nps.uncertainty <= 0.4)
+            .map(|w| w.nps.nps)
+            .collect();
+        if known.len() < 2 {
+            return Vec::new();
+        }
+        known.sort_unstable();
+        let median = known[known.len() / 2];
+
+        let mut slow = Vec::new();
+        for (i, worker) in self.workers.iter_mut().enumerate() {
+            if worker.nps.uncertainty <= 0.4 && worker.nps.nps < median / 2 {
+                worker.slow_streak += 1;
+                if worker.slow_streak == 3 {
+                    slow.push((i, worker.nps.nps, median));
+                }
+            } else {
+                worker.slow_streak = 0;
+            }
+        }
+        slow
+    }
+
+    /// Combined speed of the workers that analysed positions, once all of
+    /// them are measured. The nps of a single batch would understate it when
+    /// several batches share the workers.
+    fn total_nps(&self) -> Option<u64> {
+        let measured: Vec<&NpsRecorder> = self.workers.iter().filter(|w| w.positions > 0).map(|w| &w.nps).collect();
+        if measured.is_empty() || measured.iter().any(|nps| nps.uncertainty > 0.4) {
+            return None;
+        }
+        Some(measured.iter().map(|nps| u64::from(nps.nps)).sum())
+    }
+
     fn record_batch(&mut self, positions: u64, nodes: u64, nnue_nps: Option<u32>) {
         self.total_batches += 1;
         self.total_positions += positions;
@@ -738,6 +2099,25 @@ impl StatsRecorder {
     }
 }
 
+#[derive(Clone)]
+pub struct WorkerStats {
+    pub positions: u64,
+    pub errors: u64,
+    pub nps: NpsRecorder,
+    slow_streak: u32,
+}
+
+impl WorkerStats {
+    fn new() -> WorkerStats {
+        WorkerStats {
+            positions: 0,
+            errors: 0,
+            nps: NpsRecorder::new(),
+            slow_streak: 0,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct NpsRecorder {
     nps: u32,
@@ -752,6 +2132,10 @@ impl NpsRecorder {
         }
     }
 
+    pub fn nps(&self) -> u32 {
+        self.nps
+    }
+
     fn record(&mut self, nps: u32) {
         let alpha = 0.9;
         self.uncertainty *= alpha;
@@ -774,3 +2158,397 @@ impl fmt::Display for NpsRecorder {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use tokio::sync::mpsc::UnboundedReceiver;
+    use crate::api::mock::{self, Call};
+    use crate::configure::Verbose;
+    use crate::sim::{analysis, analysis_of, batch_id, engine_response, spawn_queue};
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    async fn pull(queue: &mut QueueStub, response: Option<PositionResponse>) -> Position {
+        let (callback, position) = oneshot::channel();
+        queue.pull(Pull {
+            worker: Some(0),
+            response: response.map(Ok),
+            callback,
+        }).await;
+        time::timeout(TIMEOUT, position).await.expect("position in time").expect("position")
+    }
+
+    async fn expect_call<F>(calls: &mut UnboundedReceiver<Call>, pred: F) -> Call
+        where F: Fn(&Call) -> bool,
+    {
+        loop {
+            let call = time::timeout(TIMEOUT, calls.recv()).await.expect("call in time").expect("mock alive");
+            if pred(&call) {
+                return call;
+            }
+        }
+    }
+    #[tokio::test]
+    async fn test_acquire_analyse_submit() {
+        let (api, mut mock) = mock::channel();
+        mock.acquire(analysis(batch_id("aaaaaaaa")));
+        let mut calls = mock.spawn();
+        let (mut queue, _actor) = spawn_queue(&[], api);
+
+        let position = pull(&mut queue, None).await;
+        assert_eq!(position.work.id(), batch_id("aaaaaaaa"));
+
+        let (callback, _next) = oneshot::channel();
+        queue.pull(Pull {
+            worker: Some(0),
+            response: Some(Ok(engine_response(position))),
+            callback,
+        }).await;
+
+        match expect_call(&mut calls, |c| matches!(c, Call::SubmitAnalysis { partial: false, .. })).await {
+            Call::SubmitAnalysis { batch_id: submitted, analysis, .. } => {
+                assert_eq!(submitted, batch_id("aaaaaaaa"));
+                assert_eq!(analysis.len(), 1);
+                assert!(matches!(analysis[0], Some(AnalysisPart::Complete { depth: 20, .. })));
+            }
+            call => panic!("unexpected call: {:?}", call),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_gives_back_pending() {
+        let (api, mut mock) = mock::channel();
+        mock.acquire(analysis(batch_id("bbbbbbbb")));
+        let mut calls = mock.spawn();
+        let (mut queue, _actor) = spawn_queue(&[], api);
+
+        pull(&mut queue, None).await;
+        assert_eq!(queue.pending_batches().await, vec![batch_id("bbbbbbbb")]);
+
+        assert_eq!(queue.shutdown().await, 1);
+        match expect_call(&mut calls, |c| matches!(c, Call::Abort(_))).await {
+            Call::Abort(aborted) => assert_eq!(aborted, batch_id("bbbbbbbb")),
+            call => panic!("unexpected call: {:?}", call),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unwanted_work_given_back() {
+        let (api, mut mock) = mock::channel();
+        mock.acquire(analysis(batch_id("cccccccc")));
+        let mut calls = mock.spawn();
+        let (mut queue, _actor) = spawn_queue(&["--only", "move", "--variants", "standard,crazyhouse"], api);
+
+        let (callback, _position) = oneshot::channel();
+        queue.pull(Pull {
+            worker: Some(0),
+            response: None,
+            callback,
+        }).await;
+
+        match expect_call(&mut calls, |c| matches!(c, Call::Acquire(_))).await {
+            Call::Acquire(query) => {
+                assert_eq!(query.only.as_deref(), Some("move"));
+                assert_eq!(query.variants.as_deref(), Some("standard,crazyhouse"));
+            }
+            call => panic!("unexpected call: {:?}", call),
+        }
+        match expect_call(&mut calls, |c| matches!(c, Call::Abort(_))).await {
+            Call::Abort(aborted) => assert_eq!(aborted, batch_id("cccccccc")),
+            call => panic!("unexpected call: {:?}", call),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_long_batch_given_back() {
+        let (api, mut mock) = mock::channel();
+        mock.acquire(analysis_of(batch_id("dddddddd"), &["e2e4", "e7e5", "g1f3"]));
+        mock.acquire(analysis_of(batch_id("eeeeeeee"), &["e2e4", "e7e5"]));
+        let mut calls = mock.spawn();
+        let (mut queue, _actor) = spawn_queue(&["--max-positions", "3"], api);
+
+        let position = pull(&mut queue, None).await;
+        assert_eq!(position.work.id(), batch_id("eeeeeeee"));
+        match expect_call(&mut calls, |c| matches!(c, Call::Abort(_))).await {
+            Call::Abort(aborted) => assert_eq!(aborted, batch_id("dddddddd")),
+            call => panic!("unexpected call: {:?}", call),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_long_poll_acquire() {
+        let (api, mut mock) = mock::channel();
+        mock.acquire(analysis(batch_id("ffffffff")));
+        let mut calls = mock.spawn();
+        let (mut queue, _actor) = spawn_queue(&["--long-poll", "60s"], api);
+
+        let position = pull(&mut queue, None).await;
+        assert_eq!(position.work.id(), batch_id("ffffffff"));
+        match expect_call(&mut calls, |c| matches!(c, Call::Acquire(_))).await {
+            Call::Acquire(query) => assert_eq!(query.wait, Some(60)),
+            call => panic!("unexpected call: {:?}", call),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bad_request_requires_update() {
+        let (api, mut mock) = mock::channel();
+        mock.acquire(Acquired::BadRequest);
+        let mut calls = mock.spawn();
+        let (mut queue, _actor) = spawn_queue(&[], api);
+
+        let (callback, _position) = oneshot::channel();
+        queue.pull(Pull {
+            worker: Some(0),
+            response: None,
+            callback,
+        }).await;
+
+        expect_call(&mut calls, |c| matches!(c, Call::Acquire(_))).await;
+        time::timeout(TIMEOUT, async {
+            while !queue.update_required().await {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("update required in time");
+    }
+
+    /// Game with a transposition back to the starting position, so that
+    /// batches can contain duplicates.
+    const MOVES: [&str; 8] = ["g1f3", "g8f6", "f3g1", "f6g8", "e2e4", "e7e5", "g1f3", "b8c6"];
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Acquire { moves: usize, skip: Vec<usize>, user: bool },
+        Pull,
+        Respond(usize),
+        Fail { index: usize, retry: bool },
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            1 => (0..=MOVES.len(), vec(0..=MOVES.len() + 1, 0..4), any::<bool>()).prop_map(|(moves, skip, user)| Op::Acquire { moves, skip, user }),
+            3 => Just(Op::Pull),
+            3 => any::<usize>().prop_map(Op::Respond),
+            1 => any::<(usize, bool)>().prop_map(|(index, retry)| Op::Fail { index, retry }),
+        ]
+    }
+
+    fn incoming_batch(id: BatchId, moves: usize, skip: Vec<usize>, user: bool) -> Result<IncomingBatch, Box<CompletedBatch>> {
+        IncomingBatch::from_acquired(Endpoint::default(), AcquireResponseBody {
+            work: Work::Analysis {
+                id,
+                nodes: None,
+                multipv: None,
+                movetime: None,
+                depth: None,
+            },
+            game_id: None,
+            position: Default::default(),
+            variant: LichessVariant::Standard,
+            moves: MOVES[..moves].iter().map(|m| m.parse().expect("uci")).collect(),
+            skip_positions: skip,
+            timeout: None,
+        }, user, None)
+    }
+
+    /// Checks invariants that must hold between any two operations.
+    fn check_invariants(state: &QueueState, in_flight: &[Position], last_pending: &mut HashMap<BatchId, usize>) {
+        for pos in state.incoming.iter().chain(in_flight) {
+            if let Some(pending) = state.pending.get(&pos.work.id()) {
+                assert!(pos.position_id.0 < pending.positions.len(), "position {} of batch {} in bounds", pos.position_id.0, pos.work.id());
+            } else {
+                assert!(!state.incoming.iter().any(|p| p.work.id() == pos.work.id()), "no incoming positions of batch {} that is gone", pos.work.id());
+            }
+        }
+        for (batch_id, pending) in &state.pending {
+            let remaining = pending.pending();
+            if let Some(&last) = last_pending.get(batch_id) {
+                assert!(remaining <= last, "pending positions of batch {} went up from {} to {}", batch_id, last, remaining);
+            }
+            last_pending.insert(*batch_id, remaining);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_queue_state_invariants(ops in vec(op(), 1..60)) {
+            let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().expect("runtime");
+            rt.block_on(async move {
+                let (api, mock) = mock::channel();
+                let mut calls = mock.spawn();
+                let logger = Logger::new(Verbose::default(), false);
+                let state = QueueState::new(1, Duration::from_secs(60 * 60), Duration::from_secs(60 * 60), 2, Schedule::default(), None, logger);
+                let stats = state.stats.clone();
+                let state = Arc::new(Mutex::new(state));
+                let (tx, _rx) = mpsc::channel(1);
+                let queue = QueueStub::new(tx, None, Arc::new(Notify::new()), state.clone(), stats, api);
+
+                let mut acquired = Vec::new();
+                let mut in_flight: Vec<Position> = Vec::new();
+                let mut last_pending = HashMap::new();
+                {
+                    let mut state = state.lock().await;
+                    for op in ops {
+                        match op {
+                            Op::Acquire { moves, skip, user } => {
+                                let id = batch_id(&format!("{:08}", acquired.len()));
+                                if let Ok(batch) = incoming_batch(id, moves, skip, user) {
+                                    acquired.push(id);
+                                    assert!(state.add_incoming_batch(batch).is_none(), "no cache, so nothing completed right away");
+                                }
+                            }
+                            Op::Pull => in_flight.extend(state.next_position(Some(0))),
+                            Op::Respond(_) | Op::Fail { .. } if in_flight.is_empty() => (),
+                            Op::Respond(index) => {
+                                let pos = in_flight.remove(index % in_flight.len());
+                                state.handle_position_response(queue.clone(), Ok(engine_response(pos)));
+                            }
+                            Op::Fail { index, retry } => {
+                                let pos = in_flight.remove(index % in_flight.len());
+                                state.handle_position_response(queue.clone(), Err(PositionFailed {
+                                    batch_id: pos.work.id(),
+                                    position_id: pos.position_id,
+                                    retry: if retry { Some(pos) } else { None },
+                                }));
+                            }
+                        }
+                        check_invariants(&state, &in_flight, &mut last_pending);
+                    }
+
+                    // Work off everything that is left.
+                    loop {
+                        if let Some(pos) = in_flight.pop() {
+                            state.handle_position_response(queue.clone(), Ok(engine_response(pos)));
+                        } else if let Some(pos) = state.next_position(None) {
+                            in_flight.push(pos);
+                        } else {
+                            break;
+                        }
+                        check_invariants(&state, &in_flight, &mut last_pending);
+                    }
+                    assert!(state.pending.is_empty(), "all batches done");
+                }
+
+                drop(queue);
+                drop(state);
+                let mut finished: HashMap<BatchId, usize> = HashMap::new();
+                while let Some(call) = calls.recv().await {
+                    match call {
+                        Call::SubmitAnalysis { batch_id, partial: false, .. } | Call::Abort(batch_id) => {
+                            *finished.entry(batch_id).or_default() += 1;
+                        }
+                        _ => (),
+                    }
+                }
+                for batch_id in acquired {
+                    assert_eq!(finished.get(&batch_id).copied().unwrap_or(0), 1, "batch {} submitted or aborted exactly once", batch_id);
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn test_workers_continue_with_their_game() {
+        let logger = Logger::new(Verbose::default(), false);
+        let mut state = QueueState::new(2, Duration::from_secs(60 * 60), Duration::from_secs(60 * 60), 2, Schedule::default(), None, logger);
+        for id in &["aaaaaaaa", "bbbbbbbb"] {
+            if let Ok(batch) = incoming_batch(batch_id(id), 0, Vec::new(), false) {
+                assert!(state.add_incoming_batch(batch).is_none());
+            }
+        }
+        // More plies of the same games.
+        let first: Vec<Position> = state.incoming.iter().cloned().collect();
+        for ply in 1..5 {
+            for pos in &first {
+                state.incoming.push_back(Position {
+                    position_id: PositionId(ply),
+                    ..pos.clone()
+                });
+            }
+        }
+
+        // Each worker takes its own game, in ascending ply order.
+        let mut last: HashMap<usize, Position> = HashMap::new();
+        for _ in 0..3 {
+            for worker in 0..2 {
+                let pos = state.next_position(Some(worker)).expect("position");
+                if let Some(prev) = last.get(&worker) {
+                    assert_eq!(pos.work.id(), prev.work.id());
+                    assert!(pos.position_id.0 > prev.position_id.0);
+                }
+                last.insert(worker, pos);
+            }
+        }
+        assert_ne!(last[&0].work.id(), last[&1].work.id());
+
+        // Split a game only when there is nothing else to do.
+        let pos = state.next_position(Some(2)).expect("position");
+        assert!(pos.position_id.0 > last[&0].position_id.0 || pos.position_id.0 > last[&1].position_id.0);
+    }
+
+    #[test]
+    fn test_shed_excess_with_concurrent_batches() {
+        let logger = Logger::new(Verbose::default(), false);
+        let mut state = QueueState::new(2, Duration::from_secs(60 * 60), Duration::from_secs(60 * 60), 2, Schedule::default(), None, logger);
+        let (mut api, _mock) = mock::channel();
+        for id in &["aaaaaaaa", "bbbbbbbb"] {
+            if let Ok(batch) = incoming_batch(batch_id(id), 0, Vec::new(), false) {
+                assert!(state.add_incoming_batch(batch).is_none());
+            }
+        }
+        {
+            // Each batch got half of the two workers.
+            let mut stats = state.stats.lock().expect("stats mutex");
+            stats.nnue_nps = NpsRecorder { nps: 1_500_000, uncertainty: 0.0 };
+            for _ in 0..2 {
+                stats.workers.push(WorkerStats {
+                    positions: 20,
+                    nps: NpsRecorder { nps: 1_500_000, uncertainty: 0.0 },
+                    ..WorkerStats::new()
+                });
+            }
+        }
+
+        // One position of 2_250_000 nodes each, done in 1.5s together.
+        let set_deadlines = |state: &mut QueueState, after: Duration| {
+            for pending in state.pending.values_mut() {
+                pending.deadline = Instant::now() + after;
+            }
+        };
+        set_deadlines(&mut state, Duration::from_secs(2));
+        state.shed_excess(&mut api);
+        assert_eq!(state.pending.len(), 2);
+
+        set_deadlines(&mut state, Duration::from_secs(1));
+        state.shed_excess(&mut api);
+        assert_eq!(state.pending.len(), 1);
+    }
+
+    #[test]
+    fn test_forced_response() {
+        let logger = Logger::new(Verbose::default(), false);
+        let mut state = QueueState::new(1, Duration::from_secs(60 * 60), Duration::from_secs(60 * 60), 2, Schedule::default(), None, logger);
+        if let Ok(batch) = incoming_batch(batch_id("aaaaaaaa"), 0, Vec::new(), false) {
+            assert!(state.add_incoming_batch(batch).is_none());
+        }
+        let next = engine_response(state.next_position(None).expect("position"));
+        let uci: Uci = "e2e4".parse().expect("uci");
+
+        for &(next_score, score) in &[(Score::Cp(35), Score::Cp(-35)), (Score::Mate(2), Score::Mate(-2)), (Score::Mate(-1), Score::Mate(2)), (Score::Mate(0), Score::Mate(1))] {
+            let res = forced_response(PositionId(7), uci.clone(), &PositionResponse {
+                score: next_score,
+                bound: Some(Bound::Lower),
+                ..next.clone()
+            });
+            assert_eq!(res.position_id.0, 7);
+            assert_eq!(format!("{:?}", res.score), format!("{:?}", score));
+            assert_eq!(res.bound, Some(Bound::Upper));
+            assert_eq!(res.best_move, Some(uci.clone()));
+            assert_eq!(res.pv.len(), next.pv.len() + 1);
+            assert_eq!(res.origin, Origin::Trivial);
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! Restricts engine processes. The engine only needs stdin, stdout and
+//! reading its own files, so it is denied network access and a few system
+//! calls that are only useful for escaping or attacking the host.
+
+use tokio::process::Command;
+
+/// Whether engine processes can be sandboxed on this platform.
+pub const SUPPORTED: bool = cfg!(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")));
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod seccomp {
+    use std::io;
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    /// System calls of the x32 ABI have this bit set.
+    #[cfg(target_arch = "x86_64")]
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+    const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+
+    /// BPF_LD | BPF_W | BPF_ABS
+    const BPF_LD_W_ABS: u16 = 0x20;
+    /// BPF_JMP | BPF_JEQ | BPF_K
+    const BPF_JMP_JEQ_K: u16 = 0x15;
+    /// BPF_JMP | BPF_JGE | BPF_K
+    #[cfg(target_arch = "x86_64")]
+    const BPF_JMP_JGE_K: u16 = 0x35;
+    /// BPF_RET | BPF_K
+    const BPF_RET_K: u16 = 0x06;
+
+    /// Offsets in struct seccomp_data.
+    const OFFSET_NR: u32 = 0;
+    const OFFSET_ARCH: u32 = 4;
+
+    const DENIED: &[libc::c_long] = &[
+        // Network.
+        libc::SYS_socket,
+        libc::SYS_socketpair,
+        libc::SYS_connect,
+        libc::SYS_bind,
+        libc::SYS_listen,
+        libc::SYS_accept,
+        libc::SYS_accept4,
+        // Other processes.
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        // Namespaces and mounts.
+        libc::SYS_setns,
+        libc::SYS_unshare,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        // Kernel attack surface.
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_userfaultfd,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_kexec_load,
+        libc::SYS_io_uring_setup,
+        libc::SYS_io_uring_enter,
+        libc::SYS_io_uring_register,
+    ];
+
+    fn stmt(code: u16, k: u32) -> libc::sock_filter {
+        libc::sock_filter { code, jt: 0, jf: 0, k }
+    }
+
+    fn jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter { code, jt, jf, k }
+    }
+
+    /// Builds the filter ahead of time, because allocating is not allowed
+    /// between fork and exec. Denied system calls fail with EPERM.
+    pub fn filter() -> Vec<libc::sock_filter> {
+        let deny = SECCOMP_RET_ERRNO | libc::EPERM as u32;
+        let mut checks = Vec::new();
+        #[cfg(target_arch = "x86_64")]
+        checks.push((BPF_JMP_JGE_K, X32_SYSCALL_BIT));
+        checks.extend(DENIED.iter().map(|&nr| (BPF_JMP_JEQ_K, nr as u32)));
+
+        let mut filter = vec![
+            stmt(BPF_LD_W_ABS, OFFSET_ARCH),
+            jump(BPF_JMP_JEQ_K, AUDIT_ARCH, 1, 0),
+            stmt(BPF_RET_K, deny),
+            stmt(BPF_LD_W_ABS, OFFSET_NR),
+        ];
+        for (i, &(code, k)) in checks.iter().enumerate() {
+            // Skip the remaining checks and the allow to get to the deny.
+            filter.push(jump(code, k, (checks.len() - i) as u8, 0));
+        }
+        filter.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+        filter.push(stmt(BPF_RET_K, deny));
+        filter
+    }
+
+    /// Runs in the forked child, so only async-signal-safe calls.
+    pub fn install(filter: &mut [libc::sock_filter]) -> io::Result<()> {
+        unsafe {
+            // Safety: Only passes pointers to live memory to the kernel.
+
+            // Best effort: A new user and network namespace without any
+            // interfaces. Often not permitted in containers, where the
+            // filter still denies creating sockets.
+            libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNET);
+
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let prog = libc::sock_fprog {
+                len: filter.len() as libc::c_ushort,
+                filter: filter.as_mut_ptr(),
+            };
+            if libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &prog as *const libc::sock_fprog) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub fn sandbox(command: &mut Command, enabled: bool) -> &mut Command {
+    if !enabled {
+        return command;
+    }
+    let mut filter = seccomp::filter();
+    unsafe {
+        // Safety: The closure is run in a fork, and only makes system calls
+        // on memory that was allocated before.
+        command.pre_exec(move || seccomp::install(&mut filter))
+    }
+}
+
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+pub fn sandbox(command: &mut Command, _enabled: bool) -> &mut Command {
+    // Not supported. Engines run with the privileges of fishnet. On Windows,
+    // this would require running the engine in an AppContainer, which is
+    // not implemented.
+    command
+}
+
+#[cfg(all(test, target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sandbox_runs_command() {
+        let output = sandbox(Command::new("sh").arg("-c").arg("echo ok"), true).output().await.expect("run sandboxed");
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "ok\n");
+    }
+
+    #[test]
+    fn test_filter_denies_syscalls() {
+        // The filter only applies to the thread that installs it, and
+        // threads spawned from there.
+        std::thread::spawn(|| {
+            seccomp::install(&mut seccomp::filter()).expect("install filter");
+
+            let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0) };
+            assert_eq!(fd, -1);
+            assert_eq!(std::io::Error::last_os_error().raw_os_error(), Some(libc::EPERM));
+
+            let ret = unsafe { libc::syscall(libc::SYS_io_uring_setup, 1, std::ptr::null_mut::<libc::c_void>()) };
+            assert_eq!(ret, -1);
+            assert_eq!(std::io::Error::last_os_error().raw_os_error(), Some(libc::EPERM));
+
+            let pid = unsafe { libc::getpid() };
+            assert!(pid > 0);
+        }).join().expect("filtered thread");
+    }
+}
@@ -0,0 +1,146 @@
+use std::io;
+use std::net::SocketAddr;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr, SpaceSeparator, StringWithSeparator};
+use shakmaty::fen::Fen;
+use shakmaty::uci::Uci;
+use tokio::io::{AsyncBufReadExt as _, AsyncReadExt as _, AsyncWriteExt as _, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use crate::api::{Bound, LichessVariant, PvLine, Score, Wdl};
+use crate::logger::Logger;
+use crate::queue::QueueStub;
+
+/// Largest accepted request body. A 600 ply game is about 3 KiB of moves.
+const MAX_BODY: usize = 64 * 1024;
+
+#[serde_as]
+#[derive(Debug, Deserialize)]
+pub struct AnalyseRequest {
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub fen: Option<Fen>,
+    #[serde_as(as = "StringWithSeparator::<SpaceSeparator, Uci>")]
+    #[serde(default)]
+    pub moves: Vec<Uci>,
+    #[serde(default)]
+    pub variant: LichessVariant,
+    #[serde(default)]
+    pub nodes: Option<u64>,
+    #[serde(default)]
+    pub multipv: Option<u32>,
+    /// Milliseconds per position, instead of nodes.
+    #[serde(default)]
+    pub movetime: Option<u64>,
+    #[serde(default)]
+    pub depth: Option<u32>,
+}
+
+#[serde_as]
+#[derive(Debug, Serialize)]
+struct PlyResult {
+    ply: usize,
+    score: Score,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bound: Option<Bound>,
+    wdl: Wdl,
+    depth: u32,
+    nodes: u64,
+    time: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nps: Option<u32>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    best_move: Option<Uci>,
+    #[serde_as(as = "StringWithSeparator::<SpaceSeparator, Uci>")]
+    pv: Vec<Uci>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    secondary: Vec<PvLine>,
+}
+
+pub async fn serve(addr: SocketAddr, queue: QueueStub, logger: Logger) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    logger.info(&format!("Listening on http://{} (POST /analyse)", addr));
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let queue = queue.clone();
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_connection(stream, queue, &logger).await {
+                logger.debug(&format!("Connection from {} failed: {}", peer, err));
+            }
+        });
+    }
+}
+
+async fn handle_connection(stream: TcpStream, mut queue: QueueStub, logger: &Logger) -> io::Result<()> {
+    let mut stream = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await?;
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        if stream.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+            break;
+        }
+        let mut parts = header.splitn(2, ':');
+        if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(usize::MAX);
+            }
+        }
+    }
+
+    match (method, path) {
+        ("POST", "/analyse") => (),
+        (_, "/analyse") => return respond(stream.get_mut(), "405 Method Not Allowed", "only POST is supported\n").await,
+        _ => return respond(stream.get_mut(), "404 Not Found", "not found\n").await,
+    }
+
+    if content_length > MAX_BODY {
+        return respond(stream.get_mut(), "413 Payload Too Large", "request body too large\n").await;
+    }
+    let mut body = vec![0; content_length];
+    stream.read_exact(&mut body).await?;
+
+    let req: AnalyseRequest = match serde_json::from_slice(&body) {
+        Ok(req) => req,
+        Err(err) => return respond(stream.get_mut(), "400 Bad Request", &format!("{}\n", err)).await,
+    };
+
+    let plies = req.moves.len() + 1;
+    let mut results = match queue.analyse_local(req).await {
+        Some(results) => results,
+        None => return respond(stream.get_mut(), "503 Service Unavailable", "shutting down\n").await,
+    };
+    logger.debug(&format!("Analysing {} positions for local client", plies));
+
+    let stream = stream.get_mut();
+    stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nConnection: close\r\n\r\n").await?;
+    while let Some(res) = results.recv().await {
+        let mut line = serde_json::to_vec(&PlyResult {
+            ply: res.position_id.0,
+            score: res.score,
+            bound: res.bound,
+            wdl: res.wdl,
+            depth: res.depth,
+            nodes: res.nodes,
+            time: res.time.as_millis() as u64,
+            nps: res.nps,
+            best_move: res.best_move,
+            pv: res.pv,
+            secondary: res.secondary,
+        }).expect("serialize ply result");
+        line.push(b'\n');
+        stream.write_all(&line).await?;
+    }
+    stream.shutdown().await
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    stream.write_all(format!("HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body).as_bytes()).await?;
+    stream.shutdown().await
+}
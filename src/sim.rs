@@ -0,0 +1,255 @@
+//! Deterministic simulations of the queue against a scripted API and a fake
+//! engine. Time is paused, so waits and backoffs pass instantly, but in the
+//! same order as in production.
+
+use std::sync::Arc;
+use std::time::Duration;
+use structopt::StructOpt as _;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+use crate::api::{AcquireResponseBody, Acquired, ApiStub, BatchId, LichessVariant, Score, Wdl, Work, mock::{self, Call}};
+use crate::configure::{BacklogOpt, Endpoint, Verbose};
+use crate::ipc::{Origin, Position, PositionResponse, Pull};
+use crate::logger::Logger;
+use crate::metrics::Health;
+use crate::queue::{self, QueueStub, Waiting};
+
+/// Generous, because it is virtual.
+const TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub fn batch_id(id: &str) -> BatchId {
+    id.parse().expect("batch id")
+}
+
+/// Analysis of the starting position only.
+pub fn analysis(id: BatchId) -> Acquired {
+    analysis_of(id, &[])
+}
+
+/// Analysis of the game with the given moves from the starting position.
+pub fn analysis_of(id: BatchId, moves: &[&str]) -> Acquired {
+    Acquired::Accepted(AcquireResponseBody {
+        work: Work::Analysis {
+            id,
+            nodes: None,
+            multipv: None,
+            movetime: None,
+            depth: None,
+        },
+        game_id: None,
+        position: Default::default(),
+        variant: LichessVariant::Standard,
+        moves: moves.iter().map(|m| m.parse().expect("uci")).collect(),
+        skip_positions: Vec::new(),
+        timeout: None,
+    })
+}
+
+pub fn engine_response(position: Position) -> PositionResponse {
+    PositionResponse {
+        wdl: Wdl::from_score(Score::Cp(20), position.ply()),
+        work: position.work,
+        position_id: position.position_id,
+        url: position.url,
+        score: Score::Cp(20),
+        bound: None,
+        best_move: None,
+        pv: Vec::new(),
+        depth: 20,
+        nodes: 2_000_000,
+        time: Duration::from_secs(1),
+        nps: Some(2_000_000),
+        seldepth: None,
+        hashfull: None,
+        tbhits: None,
+        secondary: Vec::new(),
+        engine: None,
+        origin: Origin::Engine,
+    }
+}
+
+pub fn spawn_queue(args: &[&str], api: ApiStub) -> (QueueStub, JoinHandle<()>) {
+    let opt = BacklogOpt::from_iter(Some("fishnet").into_iter().chain(args.iter().copied()));
+    let logger = Logger::new(Verbose::default(), false);
+    let (queue, actor) = queue::channel(Endpoint::default(), opt, 1, true, Arc::new(Health::new(1)), api, logger);
+    (queue, tokio::spawn(actor.run()))
+}
+
+/// A queue with its actor, talking to a scripted API, in paused time.
+pub struct Sim {
+    pub queue: QueueStub,
+    pub actor: JoinHandle<()>,
+    calls: mpsc::UnboundedReceiver<Call>,
+    started_at: Instant,
+}
+
+impl Sim {
+    /// Must be called from a test runtime. Acquire requests are answered
+    /// from `script`, then with no content.
+    pub fn start(args: &[&str], script: Vec<Acquired>) -> Sim {
+        time::pause();
+        let (api, mut mock) = mock::channel();
+        for acquired in script {
+            mock.acquire(acquired);
+        }
+        let calls = mock.spawn();
+        let (queue, actor) = spawn_queue(args, api);
+        Sim {
+            queue,
+            actor,
+            calls,
+            started_at: Instant::now(),
+        }
+    }
+
+    /// Virtual time since the start of the simulation.
+    pub fn elapsed(&self) -> Duration {
+        Instant::now() - self.started_at
+    }
+
+    /// Next request to the API, and when it was made.
+    pub async fn next_call(&mut self) -> (Duration, Call) {
+        let call = time::timeout(TIMEOUT, self.calls.recv()).await.expect("call in time").expect("mock alive");
+        (self.elapsed(), call)
+    }
+
+    /// Next request to the API that matches `pred`, skipping others.
+    pub async fn expect_call<F>(&mut self, pred: F) -> (Duration, Call)
+        where F: Fn(&Call) -> bool,
+    {
+        loop {
+            let (at, call) = self.next_call().await;
+            if pred(&call) {
+                return (at, call);
+            }
+        }
+    }
+
+    /// All requests to the API until the given virtual time.
+    pub async fn calls_until(&mut self, until: Duration) -> Vec<(Duration, Call)> {
+        let mut calls = Vec::new();
+        while let Ok(Some(call)) = time::timeout_at(self.started_at + until, self.calls.recv()).await {
+            calls.push((self.elapsed(), call));
+        }
+        calls
+    }
+
+    /// Pulls for a worker that is not done with anything yet.
+    pub async fn pull(&mut self) -> oneshot::Receiver<Position> {
+        let (callback, position) = oneshot::channel();
+        self.queue.pull(Pull {
+            worker: Some(0),
+            response: None,
+            callback,
+        }).await;
+        position
+    }
+
+    /// Backoff that the queue announced after a request without work.
+    pub async fn backoff(&self) -> Duration {
+        loop {
+            if let Some(Waiting::Backoff(until)) = self.queue.dashboard().await.waiting {
+                return until.saturating_duration_since(std::time::Instant::now());
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Analyses every position it gets in `think` time, until the queue
+    /// stops handing out work.
+    pub fn spawn_engine(&self, think: Duration) -> JoinHandle<usize> {
+        let mut queue = self.queue.clone();
+        tokio::spawn(async move {
+            let mut analysed = 0;
+            let mut response = None;
+            loop {
+                let (callback, position) = oneshot::channel();
+                queue.pull(Pull {
+                    worker: Some(0),
+                    response: response.take().map(Ok),
+                    callback,
+                }).await;
+                let position = match position.await {
+                    Ok(position) => position,
+                    Err(_) => break analysed,
+                };
+                time::sleep(think).await;
+                analysed += 1;
+                response = Some(engine_response(position));
+            }
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_backoff_grows_without_work() {
+    let mut sim = Sim::start(&[], Vec::new());
+    let _position = sim.pull().await;
+
+    let mut backoffs = Vec::new();
+    while sim.elapsed() < Duration::from_secs(10 * 60) {
+        sim.expect_call(|c| matches!(c, Call::Acquire(_))).await;
+        backoffs.push(sim.backoff().await);
+    }
+
+    assert!(backoffs.len() > 2, "kept polling: {:?}", backoffs);
+    assert!(backoffs[0] < Duration::from_secs(2), "starts short: {:?}", backoffs);
+    for pair in backoffs.windows(2) {
+        // Allow for the real time that passes between reading them.
+        assert!(pair[1] + Duration::from_millis(100) >= pair[0], "backoff never shrinks: {:?}", backoffs);
+    }
+    assert!(backoffs.iter().all(|&b| b <= Duration::from_secs(30)), "backoff is capped: {:?}", backoffs);
+    assert!(backoffs[backoffs.len() - 1] >= Duration::from_secs(15), "backoff grows to the cap: {:?}", backoffs);
+}
+
+#[tokio::test]
+async fn test_waits_for_backlog() {
+    let mut sim = Sim::start(&["--user-backlog", "60s", "--system-backlog", "120s"], vec![analysis(batch_id("aaaaaaaa"))]);
+    let _position = sim.pull().await;
+
+    let (status_at, _) = sim.expect_call(|c| matches!(c, Call::Status)).await;
+    let (acquire_at, _) = sim.expect_call(|c| matches!(c, Call::Acquire(_))).await;
+    assert!(acquire_at - status_at >= Duration::from_secs(60), "waited {:?}", acquire_at - status_at);
+}
+
+#[tokio::test]
+async fn test_shutdown_during_backoff() {
+    let mut sim = Sim::start(&[], Vec::new());
+    let position = sim.pull().await;
+    sim.expect_call(|c| matches!(c, Call::Acquire(_))).await;
+    sim.expect_call(|c| matches!(c, Call::Acquire(_))).await;
+
+    // The actor is sleeping now. Shutting down must not wait for it.
+    let shutdown_at = sim.elapsed();
+    assert_eq!(sim.queue.clone().shutdown().await, 0);
+    assert!(position.await.is_err(), "no work after shutdown");
+    assert!(sim.elapsed() - shutdown_at < Duration::from_secs(1));
+
+    let Sim { queue, actor, mut calls, .. } = sim;
+    drop(queue);
+    time::timeout(Duration::from_secs(1), actor).await.expect("actor stopped in time").expect("actor");
+    // The mock stops once the actor is gone.
+    while let Some(call) = calls.recv().await {
+        assert!(!matches!(call, Call::Acquire(_)), "no acquire after shutdown");
+    }
+}
+
+#[tokio::test]
+async fn test_duplicate_batch_analysed_once() {
+    let id = batch_id("aaaaaaaa");
+    // The second position is pulled right away, so that the duplicate is
+    // prefetched while the first batch is still pending.
+    let mut sim = Sim::start(&["--max-batches", "2"], vec![analysis_of(id, &["e2e4"]), analysis_of(id, &["e2e4"])]);
+    let engine = sim.spawn_engine(Duration::from_secs(5));
+
+    let submitted: Vec<Call> = sim.calls_until(Duration::from_secs(10 * 60)).await.into_iter()
+        .map(|(_, call)| call)
+        .filter(|call| matches!(call, Call::SubmitAnalysis { partial: false, .. }))
+        .collect();
+    assert_eq!(submitted.len(), 1, "submitted once: {:?}", submitted);
+
+    sim.queue.clone().shutdown().await;
+    drop(sim);
+    assert_eq!(engine.await.expect("engine"), 2);
+}
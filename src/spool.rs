@@ -0,0 +1,55 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write as _};
+use std::path::PathBuf;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use crate::api::BatchId;
+
+/// Directory of completed analysis that could not be submitted yet. Each
+/// batch is stored as a JSON file named after its id.
+#[derive(Debug, Clone)]
+pub struct Spool {
+    dir: PathBuf,
+}
+
+impl Spool {
+    pub fn new(dir: PathBuf) -> io::Result<Spool> {
+        fs::create_dir_all(&dir)?;
+        Ok(Spool { dir })
+    }
+
+    fn path(&self, batch_id: BatchId) -> PathBuf {
+        self.dir.join(format!("{}.json", batch_id))
+    }
+
+    pub fn store<T: Serialize>(&self, batch_id: BatchId, body: &T) -> io::Result<()> {
+        // Write to a temporary file first, so that an interrupted write
+        // never leaves a truncated entry behind.
+        let tmp = self.dir.join(format!("{}.json.tmp", batch_id));
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer(&mut writer, body)?;
+        writer.flush()?;
+        fs::rename(tmp, self.path(batch_id))
+    }
+
+    pub fn load(&self, batch_id: BatchId) -> io::Result<Map<String, Value>> {
+        Ok(serde_json::from_slice(&fs::read(self.path(batch_id))?)?)
+    }
+
+    pub fn remove(&self, batch_id: BatchId) -> io::Result<()> {
+        fs::remove_file(self.path(batch_id))
+    }
+
+    pub fn batch_ids(&self) -> io::Result<Vec<BatchId>> {
+        let mut batch_ids = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                if let Some(batch_id) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok()) {
+                    batch_ids.push(batch_id);
+                }
+            }
+        }
+        Ok(batch_ids)
+    }
+}
@@ -1,43 +1,163 @@
+use std::cmp::max;
+use std::convert::TryFrom;
+use std::fmt;
 use std::io;
-use std::time::Duration;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::process::Stdio;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use tokio::sync::{mpsc, oneshot};
-use tokio::process::{Command, ChildStdin, ChildStdout};
-use tokio::io::{BufWriter, AsyncWriteExt as _, BufReader, AsyncBufReadExt as _, Lines};
-use shakmaty::variants::Variant;
-use crate::api::{Score, Work};
-use crate::ipc::{Position, PositionResponse, PositionFailed};
-use crate::assets::EngineFlavor;
-use crate::logger::Logger;
+use tokio::time;
+use tokio::process::{Command, ChildStdin, ChildStdout, ChildStderr};
+use tokio::io::{AsyncRead, BufWriter, AsyncReadExt as _, AsyncWriteExt as _, BufReader, AsyncBufReadExt as _};
+use shakmaty::uci::Uci;
+use shakmaty::variants::{Variant, VariantPosition};
+use shakmaty::Position as _;
+use crate::{chaos, sandbox};
+use crate::api::{BatchId, Bound, EngineInfo, PvLine, Score, Wdl, Work};
+use crate::ipc::{Origin, Position, PositionResponse, PositionFailed};
+use crate::assets::{EngineFlavor, EvalFlavor};
+use crate::configure::UciOption;
+use crate::logger::{Level, Logger};
 use crate::util::NevermindExt as _;
 
-pub fn channel(exe: PathBuf, init: StockfishInit, logger: Logger) -> (StockfishStub, StockfishActor) {
+/// Number of stderr lines to keep for error reports.
+const STDERR_TAIL: usize = 20;
+
+/// Stderr lines to log per engine and minute.
+const STDERR_LINES_PER_MINUTE: u32 = 10;
+
+/// Memory an engine needs besides its hash table, most of it for the NNUE
+/// network.
+pub const ENGINE_OVERHEAD: u64 = 64 * 1024 * 1024;
+
+/// Hash table size in MiB, unless a memory limit requires less.
+const DEFAULT_HASH: u64 = 16;
+
+/// Stop analysing once the engine reports the same mate in at most this
+/// many moves for `MATE_EARLY_STOP_DEPTHS` depths in a row.
+const MATE_EARLY_STOP_MOVES: i64 = 8;
+const MATE_EARLY_STOP_DEPTHS: u32 = 5;
+
+/// Largest hash table in MiB that fits into the given memory limit in bytes.
+pub fn hash_for_memory_limit(limit: u64) -> u32 {
+    (limit.saturating_sub(ENGINE_OVERHEAD) / (1024 * 1024)).clamp(1, DEFAULT_HASH) as u32
+}
+
+pub fn channel(exe: PathBuf, init: StockfishInit, worker: usize, logger: Logger) -> (StockfishStub, StockfishActor) {
     let (tx, rx) = mpsc::channel(1);
-    (StockfishStub { tx }, StockfishActor { rx, exe, init: Some(init), logger })
+    let silence = Silence(Arc::new(Mutex::new(Instant::now())));
+    let stderr = StderrTail(Arc::new(Mutex::new(VecDeque::new())));
+    (StockfishStub {
+        tx,
+        silence: silence.clone(),
+        stderr: stderr.clone(),
+    }, StockfishActor {
+        rx,
+        exe,
+        init: Some(init),
+        move_options: Vec::new(),
+        analysis_options: Vec::new(),
+        profile: None,
+        mate_early_stop: false,
+        last_batch: None,
+        engine: None,
+        worker,
+        silence,
+        stderr,
+        logger: logger.module("uci"),
+    })
 }
 
 pub struct StockfishStub {
     tx: mpsc::Sender<StockfishMessage>,
+    silence: Silence,
+    stderr: StderrTail,
 }
 
 impl StockfishStub {
     pub async fn go(&mut self, position: Position) -> Result<PositionResponse, PositionFailed> {
         let (callback, response) = oneshot::channel();
         let batch_id = position.work.id();
+        let position_id = position.position_id;
+        self.silence.reset();
         self.tx.send(StockfishMessage::Go { position, callback }).await.map_err(|_| PositionFailed {
             batch_id,
+            position_id,
+            retry: None,
         })?;
         response.await.map_err(|_| PositionFailed {
             batch_id,
+            position_id,
+            retry: None,
         })
     }
+
+    pub fn silence(&self) -> Silence {
+        self.silence.clone()
+    }
+
+    pub fn stderr(&self) -> StderrTail {
+        self.stderr.clone()
+    }
+}
+
+/// Last lines the engine wrote to stderr.
+#[derive(Clone)]
+pub struct StderrTail(Arc<Mutex<VecDeque<String>>>);
+
+impl StderrTail {
+    fn push(&self, line: String) {
+        let mut lines = self.0.lock().expect("stderr tail");
+        if lines.len() >= STDERR_TAIL {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        self.0.lock().expect("stderr tail").iter().cloned().collect()
+    }
+}
+
+/// Time of the last engine output.
+#[derive(Clone)]
+pub struct Silence(Arc<Mutex<Instant>>);
+
+impl Silence {
+    fn reset(&self) {
+        *self.0.lock().expect("silence") = Instant::now();
+    }
+
+    /// Resolves once the engine has been silent for the given duration.
+    pub async fn exceeds(self, timeout: Duration) {
+        loop {
+            let elapsed = self.0.lock().expect("silence").elapsed();
+            if elapsed >= timeout {
+                break;
+            }
+            time::sleep(timeout - elapsed).await;
+        }
+    }
 }
 
 pub struct StockfishActor {
     rx: mpsc::Receiver<StockfishMessage>,
     exe: PathBuf,
     init: Option<StockfishInit>,
+    move_options: Vec<UciOption>,
+    analysis_options: Vec<UciOption>,
+    profile: Option<Profile>,
+    mate_early_stop: bool,
+    /// Batch of the previous position. The hash table is kept for the
+    /// next position of the same game.
+    last_batch: Option<BatchId>,
+    engine: Option<Arc<EngineInfo>>,
+    worker: usize,
+    silence: Silence,
+    stderr: StderrTail,
     logger: Logger,
 }
 
@@ -51,44 +171,159 @@ enum StockfishMessage {
 
 pub struct StockfishInit {
     pub nnue: String,
+    /// Hash table size in MiB.
+    pub hash: Option<u32>,
+    /// Number of search threads.
+    pub threads: usize,
+    /// Memory limit for the engine process in bytes.
+    pub memory_limit: Option<u64>,
+    /// Do not start the engine, but respond with synthetic analysis after
+    /// this delay.
+    pub fake: Option<Duration>,
+    /// Deny the engine process network access and dangerous system calls.
+    pub sandbox: bool,
+    /// Stop analysing once the engine keeps finding the same short mate.
+    pub mate_early_stop: bool,
+    /// Set after the standard options.
+    pub options: Vec<UciOption>,
+    /// Set whenever the engine switches to playing moves.
+    pub move_options: Vec<UciOption>,
+    /// Set whenever the engine switches to analysis.
+    pub analysis_options: Vec<UciOption>,
+}
+
+/// Kind of work the engine options are currently set up for.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Profile {
+    Move,
+    Analysis,
 }
 
 struct Stdin {
     inner: BufWriter<ChildStdin>,
+    worker: usize,
+    logger: Logger,
 }
 
 impl Stdin {
-    fn new(inner: ChildStdin) -> Stdin {
+    fn new(inner: ChildStdin, worker: usize, logger: Logger) -> Stdin {
         Stdin {
             inner: BufWriter::new(inner),
+            worker,
+            logger,
         }
     }
 
+    /// Buffers the line. Call `flush` before waiting for a response.
     async fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.logger.enabled(Level::Trace) {
+            self.logger.trace(&format!("Worker {} << {}", self.worker, line));
+        }
         self.inner.write_all(line.as_bytes()).await?;
-        self.inner.write_all(b"\n").await?;
-        self.inner.flush().await?;
-        Ok(())
+        self.inner.write_all(b"\n").await
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush().await
     }
 }
 
-struct Stdout {
-    inner: Lines<BufReader<ChildStdout>>,
+/// Engine output is read in chunks of this size, usually many lines at
+/// once while searching.
+const READ_CHUNK: usize = 16 * 1024;
+
+/// Longer lines are skipped. Even a long principal variation is only a few
+/// KiB.
+const MAX_LINE: usize = 64 * 1024;
+
+/// Splits engine output into lines, reusing one buffer instead of
+/// allocating for each line.
+struct Stdout<R = ChildStdout> {
+    inner: R,
+    buf: Vec<u8>,
+    /// Length of the line returned last, including the line terminator.
+    /// It is removed from the buffer on the next read.
+    consumed: usize,
+    /// Bytes at the start of the buffer already known not to contain a
+    /// line terminator.
+    scanned: usize,
+    /// Skipping the rest of an overlong line.
+    discarding: bool,
+    silence: Silence,
+    worker: usize,
+    logger: Logger,
 }
 
-impl Stdout {
-    fn new(inner: ChildStdout) -> Stdout {
+impl<R: AsyncRead + Unpin> Stdout<R> {
+    fn new(inner: R, silence: Silence, worker: usize, logger: Logger) -> Stdout<R> {
         Stdout {
-            inner: BufReader::new(inner).lines(),
+            inner,
+            buf: Vec::with_capacity(READ_CHUNK),
+            consumed: 0,
+            scanned: 0,
+            discarding: false,
+            silence,
+            worker,
+            logger,
         }
     }
 
-    async fn read_line(&mut self) -> io::Result<String> {
-        if let Some(line) = self.inner.next_line().await? {
-            Ok(line)
-        } else {
-            Err(io::ErrorKind::UnexpectedEof.into())
+    async fn read_line(&mut self) -> io::Result<&str> {
+        self.buf.drain(..self.consumed);
+        self.consumed = 0;
+
+        let end = loop {
+            if let Some(pos) = self.buf[self.scanned..].iter().position(|&b| b == b'\n') {
+                let end = self.scanned + pos;
+                self.scanned = 0;
+                if self.discarding {
+                    self.discarding = false;
+                    self.buf.drain(..=end);
+                    continue;
+                }
+                self.consumed = end + 1;
+                break end;
+            }
+            self.scanned = self.buf.len();
+
+            if self.buf.len() > MAX_LINE {
+                if !self.discarding {
+                    self.logger.warn(&format!("Worker {}: Skipping engine output line longer than {} bytes", self.worker, MAX_LINE));
+                }
+                self.discarding = true;
+                self.buf.clear();
+                self.scanned = 0;
+            }
+
+            let len = self.buf.len();
+            self.buf.resize(len + READ_CHUNK, 0);
+            let n = match self.inner.read(&mut self.buf[len..]).await {
+                Ok(n) => n,
+                Err(err) => {
+                    self.buf.truncate(len);
+                    return Err(err);
+                }
+            };
+            self.buf.truncate(len + n);
+            match n {
+                0 if self.buf.is_empty() || self.discarding => return Err(io::ErrorKind::UnexpectedEof.into()),
+                0 => {
+                    // Last line without terminator.
+                    self.consumed = self.buf.len();
+                    self.scanned = 0;
+                    break self.buf.len();
+                }
+                _ => self.silence.reset(),
+            }
+        };
+
+        let line = &self.buf[..end];
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let line = std::str::from_utf8(line).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        if self.logger.enabled(Level::Trace) {
+            self.logger.trace(&format!("Worker {} >> {}", self.worker, line));
         }
+        Ok(line)
     }
 }
 
@@ -104,19 +339,171 @@ impl From<io::Error> for EngineError {
     }
 }
 
+async fn capture_stderr(stderr: ChildStderr, tail: StderrTail, worker: usize, pid: u32, logger: Logger) {
+    let mut lines = BufReader::new(stderr).lines();
+    let mut window = Instant::now();
+    let mut logged = 0;
+    let mut suppressed = 0;
+    while let Ok(Some(line)) = lines.next_line().await {
+        if window.elapsed() >= Duration::from_secs(60) {
+            if suppressed > 0 {
+                logger.warn(&format!("Worker {}: Suppressed {} lines of engine stderr", worker, suppressed));
+            }
+            window = Instant::now();
+            logged = 0;
+            suppressed = 0;
+        }
+        if logged < STDERR_LINES_PER_MINUTE {
+            logged += 1;
+            logger.warn(&format!("Worker {}: Stockfish process {}: {}", worker, pid, line));
+        } else {
+            suppressed += 1;
+        }
+        tail.push(line);
+    }
+}
+
 #[cfg(unix)]
 fn new_process_group(command: &mut Command) -> &mut Command {
+    #[cfg(target_os = "linux")]
+    let parent = std::process::id() as libc::pid_t;
+
     // Stop SIGINT from propagating to child process.
     unsafe {
         // Safety: The closure is run in a fork, and is not allowed to break
         // invariants by using raw handles.
-        command.pre_exec(|| {
+        command.pre_exec(move || {
             libc::setpgid(0, 0);
+
+            // Kill the engine when fishnet exits without cleaning up, for
+            // example after a panic. Strictly, the signal is sent when the
+            // spawning thread exits, so engines must be spawned from a
+            // thread that lives as long as the process (the runtime of
+            // main). If the parent is already gone, do not even start.
+            #[cfg(target_os = "linux")]
+            {
+                if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                if libc::getppid() != parent {
+                    return Err(io::Error::other("parent exited"));
+                }
+            }
+
             Ok(())
         })
     }
 }
 
+/// Kills the process group of the engine, including anything it spawned,
+/// when the actor is done with it.
+#[cfg(unix)]
+struct ProcessGroup(u32);
+
+#[cfg(unix)]
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        // Safety: Only signals the group that was created for the engine.
+        unsafe {
+            libc::kill(-(self.0 as libc::pid_t), libc::SIGKILL);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn kill_on_exit(pid: u32) -> io::Result<ProcessGroup> {
+    // Exiting is covered by PR_SET_PDEATHSIG on Linux.
+    Ok(ProcessGroup(pid))
+}
+
+#[cfg(windows)]
+fn kill_on_exit(pid: u32) -> io::Result<()> {
+    use std::ptr;
+    use std::sync::Once;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    type Handle = *mut std::ffi::c_void;
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct BasicLimitInformation {
+        per_process_user_time_limit: i64,
+        per_job_user_time_limit: i64,
+        limit_flags: u32,
+        minimum_working_set_size: usize,
+        maximum_working_set_size: usize,
+        active_process_limit: u32,
+        affinity: usize,
+        priority_class: u32,
+        scheduling_class: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct ExtendedLimitInformation {
+        basic_limit_information: BasicLimitInformation,
+        io_info: [u64; 6],
+        process_memory_limit: usize,
+        job_memory_limit: usize,
+        peak_process_memory_used: usize,
+        peak_job_memory_used: usize,
+    }
+
+    const JOB_OBJECT_EXTENDED_LIMIT_INFORMATION: i32 = 9;
+    const JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE: u32 = 0x2000;
+    const PROCESS_SET_QUOTA: u32 = 0x0100;
+    const PROCESS_TERMINATE: u32 = 0x0001;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn CreateJobObjectW(attributes: *mut std::ffi::c_void, name: *const u16) -> Handle;
+        fn SetInformationJobObject(job: Handle, class: i32, info: *mut std::ffi::c_void, len: u32) -> i32;
+        fn AssignProcessToJobObject(job: Handle, process: Handle) -> i32;
+        fn OpenProcess(access: u32, inherit: i32, pid: u32) -> Handle;
+        fn CloseHandle(handle: Handle) -> i32;
+    }
+
+    // All engines are in one job that is never closed explicitly. Windows
+    // closes it when fishnet exits, for whatever reason, and then kills
+    // every process in it.
+    static INIT: Once = Once::new();
+    static JOB: AtomicUsize = AtomicUsize::new(0);
+    INIT.call_once(|| {
+        // Safety: The struct matches JOBOBJECT_EXTENDED_LIMIT_INFORMATION.
+        unsafe {
+            let job = CreateJobObjectW(ptr::null_mut(), ptr::null());
+            if !job.is_null() {
+                let mut info = ExtendedLimitInformation::default();
+                info.basic_limit_information.limit_flags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+                if SetInformationJobObject(job, JOB_OBJECT_EXTENDED_LIMIT_INFORMATION, &mut info as *mut _ as *mut _, std::mem::size_of::<ExtendedLimitInformation>() as u32) != 0 {
+                    JOB.store(job as usize, Ordering::SeqCst);
+                } else {
+                    CloseHandle(job);
+                }
+            }
+        }
+    });
+    let job = JOB.load(Ordering::SeqCst) as Handle;
+    if job.is_null() {
+        return Err(io::Error::new(io::ErrorKind::Other, "failed to create job object"));
+    }
+
+    // Safety: The process handle is closed right after use.
+    unsafe {
+        let process = OpenProcess(PROCESS_SET_QUOTA | PROCESS_TERMINATE, 0, pid);
+        if process.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let res = if AssignProcessToJobObject(job, process) != 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        };
+        CloseHandle(process);
+        res
+    }
+}
+
 #[cfg(windows)]
 fn new_process_group(command: &mut Command) -> &mut Command {
     // Stop CTRL+C from propagating to child process:
@@ -125,6 +512,35 @@ fn new_process_group(command: &mut Command) -> &mut Command {
     command.creation_flags(create_new_process_group)
 }
 
+#[cfg(unix)]
+fn limit_memory(command: &mut Command, limit: Option<u64>) -> &mut Command {
+    match limit {
+        Some(limit) => unsafe {
+            // Safety: The closure is run in a fork, and only calls
+            // setrlimit, which is async-signal-safe.
+            command.pre_exec(move || {
+                // RLIMIT_DATA rather than RLIMIT_AS, because malloc reserves
+                // a lot of address space that is never used.
+                let rlimit = libc::rlimit {
+                    rlim_cur: limit as libc::rlim_t,
+                    rlim_max: limit as libc::rlim_t,
+                };
+                if libc::setrlimit(libc::RLIMIT_DATA, &rlimit) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            })
+        },
+        None => command,
+    }
+}
+
+#[cfg(windows)]
+fn limit_memory(command: &mut Command, _limit: Option<u64>) -> &mut Command {
+    // Not enforced. Only the hash table is shrunk.
+    command
+}
+
 impl StockfishActor {
     pub async fn run(self) {
         let logger = self.logger.clone();
@@ -134,15 +550,29 @@ impl StockfishActor {
     }
 
     async fn run_inner(mut self) -> Result<(), EngineError> {
-        let mut child = new_process_group(
+        if let Some(latency) = self.init.as_ref().and_then(|init| init.fake) {
+            return self.run_fake(latency).await;
+        }
+
+        let memory_limit = self.init.as_ref().and_then(|init| init.memory_limit);
+        let sandbox = self.init.as_ref().is_some_and(|init| init.sandbox);
+        let mut child = sandbox::sandbox(limit_memory(new_process_group(
             Command::new(&self.exe)
                 .stdout(Stdio::piped())
                 .stdin(Stdio::piped())
-                .kill_on_drop(true)).spawn()?;
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)), memory_limit), sandbox).spawn()?;
 
         let pid = child.id().expect("pid");
-        let mut stdout = Stdout::new(child.stdout.take().ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "stdout closed"))?);
-        let mut stdin = Stdin::new(child.stdin.take().ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "stdin closed"))?);
+        let _kill_on_exit = kill_on_exit(pid).map_err(|err| {
+            self.logger.warn(&format!("Stockfish process {} may outlive fishnet: {}", pid, err));
+        });
+        let mut stdout = Stdout::new(child.stdout.take().ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "stdout closed"))?, self.silence.clone(), self.worker, self.logger.clone());
+        let mut stdin = Stdin::new(child.stdin.take().ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "stdin closed"))?, self.worker, self.logger.clone());
+
+        if let Some(stderr) = child.stderr.take() {
+            tokio::spawn(capture_stderr(stderr, self.stderr.clone(), self.worker, pid, self.logger.clone()));
+        }
 
         loop {
             tokio::select! {
@@ -170,6 +600,21 @@ impl StockfishActor {
         Ok(())
     }
 
+    /// Answers every position after a delay, without an engine process.
+    async fn run_fake(mut self, latency: Duration) -> Result<(), EngineError> {
+        while let Some(msg) = self.rx.recv().await {
+            let StockfishMessage::Go { mut callback, position } = msg;
+            tokio::select! {
+                _ = callback.closed() => (),
+                _ = time::sleep(latency) => {
+                    self.silence.reset();
+                    callback.send(fake_response(position, latency)).nevermind("go receiver dropped");
+                }
+            }
+        }
+        Ok(())
+    }
+
     async fn handle_message(&mut self, stdout: &mut Stdout, stdin: &mut Stdin, msg: StockfishMessage) -> Result<(), EngineError> {
         match msg {
             StockfishMessage::Go { mut callback, position } => {
@@ -185,32 +630,66 @@ impl StockfishActor {
     }
 
     async fn go(&mut self, stdout: &mut Stdout, stdin: &mut Stdin, position: Position) -> io::Result<PositionResponse> {
+        if chaos::strike() {
+            return Err(io::Error::other("chaos: simulated engine crash"));
+        }
+
         // Set global options (once).
         if let Some(init) = self.init.take() {
-            stdout.read_line().await?; // discard preample
-            stdin.write_line(&format!("setoption name EvalFile value {}", init.nnue)).await?;
+            let preamble = stdout.read_line().await?;
+            self.engine = Some(Arc::new(EngineInfo {
+                name: preamble.split(" by ").next().unwrap_or_default().trim().to_owned(),
+                nnue: match position.flavor.eval_flavor() {
+                    EvalFlavor::Nnue => Path::new(&init.nnue).file_stem().and_then(|s| s.to_str()).map(ToOwned::to_owned),
+                    EvalFlavor::Classical => None,
+                },
+            }));
+            // Engines for variants bring their own evaluation.
+            if position.flavor.eval_flavor() == EvalFlavor::Nnue {
+                stdin.write_line(&format!("setoption name EvalFile value {}", init.nnue)).await?;
+            }
             stdin.write_line("setoption name Analysis Contempt value Off").await?;
+            stdin.write_line("setoption name UCI_ShowWDL value true").await?;
+            if let Some(hash) = init.hash {
+                stdin.write_line(&format!("setoption name Hash value {}", hash)).await?;
+            }
+            if init.threads > 1 {
+                stdin.write_line(&format!("setoption name Threads value {}", init.threads)).await?;
+            }
+            for option in &init.options {
+                stdin.write_line(&format!("setoption name {} value {}", option.name, option.value)).await?;
+            }
+            self.move_options = init.move_options;
+            self.analysis_options = init.analysis_options;
+            self.mate_early_stop = init.mate_early_stop;
         }
 
-        // Clear hash.
-        stdin.write_line("ucinewgame").await?;
+        // Switch options when the kind of work changes.
+        let profile = if position.work.is_analysis() { Profile::Analysis } else { Profile::Move };
+        if self.profile != Some(profile) {
+            let options = match profile {
+                Profile::Move => &self.move_options,
+                Profile::Analysis => &self.analysis_options,
+            };
+            for option in options {
+                stdin.write_line(&format!("setoption name {} value {}", option.name, option.value)).await?;
+            }
+            self.profile = Some(profile);
+        }
+
+        // Clear hash, unless continuing with the same game.
+        let batch_id = position.work.id();
+        if self.last_batch != Some(batch_id) {
+            stdin.write_line("ucinewgame").await?;
+            self.last_batch = Some(batch_id);
+        }
 
         // Set UCI_Chess960.
         stdin.write_line(&format!("setoption name UCI_Chess960 value {}", position.chess960)).await?;
 
         // Set UCI_Variant.
         if position.flavor == EngineFlavor::MultiVariant {
-            let uci_variant = match position.variant.into() {
-                Variant::Chess => "chess",
-                Variant::Giveaway => "giveaway",
-                Variant::Atomic => "atomic",
-                Variant::ThreeCheck => "3check",
-                Variant::KingOfTheHill =>  "kingofthehill",
-                Variant::RacingKings => "racingkings",
-                Variant::Horde => "horde",
-                Variant::Crazyhouse => "crazyhouse",
-            };
-            stdin.write_line(&format!("setoption name UCI_Variant value {}", uci_variant)).await?;
+            stdin.write_line(&format!("setoption name UCI_Variant value {}", uci_variant(position.variant.into()))).await?;
         }
 
         // Setup position.
@@ -221,6 +700,7 @@ impl StockfishActor {
         let go = match &position.work {
             Work::Move { level, clock, .. } => {
                 stdin.write_line("setoption name UCI_AnalyseMode value false").await?;
+                stdin.write_line("setoption name MultiPV value 1").await?;
                 stdin.write_line("setoption name UCI_LimitStrength value true").await?;
                 stdin.write_line(&format!("setoption name UCI_Elo value {}", level.elo())).await?;
 
@@ -241,82 +721,451 @@ impl StockfishActor {
 
                 go
             }
-            Work::Analysis { nodes, .. } => {
+            Work::Analysis { nodes, movetime, depth, .. } => {
                 stdin.write_line("setoption name UCI_AnalyseMode value true").await?;
+                stdin.write_line(&format!("setoption name MultiPV value {}", position.work.multipv())).await?;
                 stdin.write_line("setoption name UCI_LimitStrength value false").await?;
-                vec!["go".to_owned(), "nodes".to_owned(), nodes.unwrap_or_default().get(position.flavor.eval_flavor()).to_string()]
+                let mut go = match movetime {
+                    Some(movetime) => vec!["go".to_owned(), "movetime".to_owned(), movetime.as_millis().to_string()],
+                    None => vec!["go".to_owned(), "nodes".to_owned(), nodes.unwrap_or_default().get(position.flavor.eval_flavor()).to_string()],
+                };
+                if let Some(depth) = depth {
+                    go.extend_from_slice(&["depth".to_owned(), depth.to_string()]);
+                }
+                go
             }
         };
         stdin.write_line(&go.join(" ")).await?;
+        stdin.flush().await?;
 
-        // Process response.
+        // Process response. Stop early if the batch is no longer needed.
+        let cancellation = position.cancellation.clone();
+        let mut stopped = false;
         let mut score = None;
+        let mut bound = None;
+        let mut wdl = None;
         let mut depth = None;
+        let mut seldepth = None;
         let mut pv = Vec::new();
         let mut time = Duration::default();
         let mut nodes = 0;
         let mut nps = None;
+        let mut hashfull = None;
+        let mut tbhits = None;
+        let mut secondary: Vec<Option<PvLine>> = Vec::new();
+
+        // Secondary lines would be cut short, too.
+        let mate_early_stop = self.mate_early_stop && position.work.is_analysis() && position.work.multipv() <= 1;
+        let mut mate_streak: Option<(i64, u32, u32)> = None;
+        let mut stopped_on_mate = false;
 
         loop {
-            let line = stdout.read_line().await?;
-            let mut parts = line.split(' ');
-            match parts.next() {
-                Some("bestmove") => {
+            let garbled;
+            let line = if chaos::strike() {
+                garbled = chaos::garbled_uci_line();
+                &garbled
+            } else {
+                tokio::select! {
+                    line = stdout.read_line() => line?,
+                    _ = cancellation.cancelled(), if !stopped => {
+                        stdin.write_line("stop").await?;
+                        stdin.flush().await?;
+                        stopped = true;
+                        continue;
+                    }
+                }
+            };
+            let (output, warnings) = parse_search_output(line);
+            if !warnings.is_empty() {
+                let message = format!("Worker {}: Ignored parts of engine output {:?}: {}", self.worker, line, warnings.iter().map(|w| w.to_string()).collect::<Vec<_>>().join(", "));
+                // Unknown tokens are expected from engines with extensions.
+                if warnings.iter().all(|w| matches!(w, UciWarning::UnknownToken(_))) {
+                    self.logger.debug(&message);
+                } else {
+                    self.logger.warn(&message);
+                }
+            }
+            match output {
+                SearchOutput::BestMove(best_move) => {
+                    if stopped {
+                        // The result will be discarded anyway.
+                        score = score.or(Some(Score::Cp(0)));
+                        depth = depth.or(Some(0));
+                    }
+                    let score = score.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing score"))?;
                     return Ok(PositionResponse {
+                        wdl: wdl.unwrap_or_else(|| Wdl::from_score(score, position.ply())),
                         work: position.work,
                         position_id: position.position_id,
                         url: position.url,
-                        best_move: parts.next().and_then(|m| m.parse().ok()),
-                        score: score.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing score"))?,
+                        best_move,
+                        score,
+                        bound,
                         depth: depth.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing depth"))?,
+                        seldepth,
                         pv,
                         time,
                         nodes,
                         nps,
+                        hashfull,
+                        tbhits,
+                        secondary: secondary.into_iter().flatten().collect(),
+                        engine: self.engine.clone(),
+                        origin: Origin::Engine,
                     });
                 }
-                Some("info") => {
-                    while let Some(part) = parts.next() {
-                        match part {
-                            "depth" => {
-                                depth = Some(
-                                    parts.next()
-                                        .and_then(|t| t.parse().ok())
-                                        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "expected depth"))?);
-                            }
-                            "nodes" => {
-                                nodes = parts.next()
-                                    .and_then(|t| t.parse().ok())
-                                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "expected nodes"))?;
-                            }
-                            "time" => {
-                                time = parts.next()
-                                    .and_then(|t| t.parse().ok())
-                                    .map(Duration::from_millis)
-                                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "expected time"))?;
-                            }
-                            "nps" => {
-                                nps = parts.next().and_then(|n| n.parse().ok());
+                SearchOutput::Info(info) => {
+                    if let Some(ref string) = info.string {
+                        self.logger.debug(&format!("Worker {}: info string {}", self.worker, string));
+                    }
+                    nodes = info.nodes.unwrap_or(nodes);
+                    time = info.time.unwrap_or(time);
+                    nps = info.nps.or(nps);
+                    hashfull = info.hashfull.or(hashfull);
+                    tbhits = info.tbhits.or(tbhits);
+
+                    let multipv = info.multipv.unwrap_or(1);
+                    if multipv <= 1 {
+                        depth = info.depth.or(depth);
+                        seldepth = info.seldepth.or(seldepth);
+                        // Bounds are reported while the engine researches
+                        // with a wider window. Prefer the last exact score
+                        // and its line.
+                        if info.score.is_some() && (info.bound.is_none() || score.is_none() || bound.is_some()) {
+                            score = info.score;
+                            bound = info.bound;
+                            wdl = info.wdl;
+                            pv = info.pv.unwrap_or(pv);
+                        }
+
+                        // Count the depths in a row with the same exact
+                        // short mate.
+                        if let (true, Some(Score::Mate(mate)), None, Some(d)) = (mate_early_stop, info.score, info.bound, info.depth) {
+                            mate_streak = match mate_streak {
+                                _ if mate.abs() > MATE_EARLY_STOP_MOVES => None,
+                                Some((m, last, streak)) if m == mate && d > last => Some((m, d, streak + 1)),
+                                Some((m, last, streak)) if m == mate => Some((m, last, streak)),
+                                _ => Some((mate, d, 1)),
+                            };
+                            if !stopped && !stopped_on_mate && mate_streak.is_some_and(|(_, _, streak)| streak >= MATE_EARLY_STOP_DEPTHS) {
+                                self.logger.debug(&format!("Worker {}: Stopping early on mate {} at depth {}", self.worker, mate, d));
+                                stdin.write_line("stop").await?;
+                                stdin.flush().await?;
+                                stopped_on_mate = true;
                             }
-                            "score" => {
-                                score = match parts.next() {
-                                    Some("cp") => parts.next().and_then(|cp| cp.parse().ok()).map(Score::Cp),
-                                    Some("mate") => parts.next().and_then(|mate| mate.parse().ok()).map(Score::Mate),
-                                    _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "expected cp or mate")),
-                                }
+                        } else if info.score.is_some() && info.bound.is_none() {
+                            mate_streak = None;
+                        }
+                    } else if multipv > position.work.multipv() as usize {
+                        // Not requested, and possibly absurdly large.
+                        self.logger.warn(&format!("Worker {}: Ignored unexpected engine output {:?}", self.worker, line));
+                    } else if let (Some(score), Some(pv)) = (info.score, info.pv) {
+                        if secondary.len() < multipv - 1 {
+                            secondary.resize(multipv - 1, None);
+                        }
+                        secondary[multipv - 2] = Some(PvLine { score, pv });
+                    }
+                }
+                SearchOutput::Unexpected => self.logger.warn(&format!("Worker {}: Ignored unexpected engine output {:?}", self.worker, line)),
+            }
+        }
+    }
+}
+
+/// Name of the variant for the `UCI_Variant` option, as understood by
+/// multi-variant Stockfish and Fairy-Stockfish.
+pub fn uci_variant(variant: Variant) -> &'static str {
+    match variant {
+        Variant::Chess => "chess",
+        Variant::Giveaway => "giveaway",
+        Variant::Atomic => "atomic",
+        Variant::ThreeCheck => "3check",
+        Variant::KingOfTheHill =>  "kingofthehill",
+        Variant::RacingKings => "racingkings",
+        Variant::Horde => "horde",
+        Variant::Crazyhouse => "crazyhouse",
+    }
+}
+
+/// Plays the first legal move, with a plausible looking search behind it.
+fn fake_response(position: Position, latency: Duration) -> PositionResponse {
+    let best_move = VariantPosition::from_setup(position.variant.into(), &position.fen).ok().and_then(|mut pos| {
+        for uci in position.moves.iter() {
+            let m = uci.to_move(&pos).ok()?;
+            pos.play_unchecked(&m);
+        }
+        pos.legal_moves().first().map(|m| if position.chess960 {
+            Uci::from_chess960(m)
+        } else {
+            Uci::from_move(&pos, m)
+        })
+    });
+    let nodes = match position.work {
+        Work::Analysis { .. } => position.work.node_limit().unwrap_or_default().get(position.flavor.eval_flavor()),
+        Work::Move { .. } => 0,
+    };
+    PositionResponse {
+        wdl: Wdl::from_score(Score::Cp(0), position.ply()),
+        depth: match position.work {
+            Work::Analysis { depth, .. } => depth.unwrap_or(20),
+            Work::Move { level, .. } => level.depth(),
+        },
+        work: position.work,
+        position_id: position.position_id,
+        url: position.url,
+        score: Score::Cp(0),
+        bound: None,
+        pv: best_move.iter().cloned().collect(),
+        best_move,
+        nodes,
+        time: latency,
+        nps: u32::try_from(nodes * 1000 / max(1, latency.as_millis() as u64)).ok(),
+        seldepth: None,
+        hashfull: None,
+        tbhits: None,
+        secondary: Vec::new(),
+        engine: None,
+        origin: Origin::Engine,
+    }
+}
+
+/// Line of engine output during a search.
+#[derive(Debug)]
+pub enum SearchOutput {
+    Info(Info),
+    BestMove(Option<Uci>),
+    Unexpected,
+}
+
+/// Fields of an `info` line. Fields that were not given are `None`.
+#[derive(Debug, Default)]
+pub struct Info {
+    pub multipv: Option<usize>,
+    pub depth: Option<u32>,
+    pub seldepth: Option<u32>,
+    pub nodes: Option<u64>,
+    pub time: Option<Duration>,
+    pub nps: Option<u32>,
+    pub hashfull: Option<u32>,
+    pub tbhits: Option<u64>,
+    pub score: Option<Score>,
+    pub bound: Option<Bound>,
+    pub wdl: Option<Wdl>,
+    pub pv: Option<Vec<Uci>>,
+    /// Free-form text after `info string`.
+    pub string: Option<String>,
+}
+
+/// Part of a line of engine output that was ignored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UciWarning {
+    /// A field ended before its value.
+    MissingValue { field: &'static str },
+    /// The value of a field could not be parsed.
+    InvalidValue { field: &'static str, value: String },
+    /// A token that is not part of the protocol, possibly an extension of
+    /// a third-party engine.
+    UnknownToken(String),
+}
+
+impl fmt::Display for UciWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UciWarning::MissingValue { field } => write!(f, "missing {}", field),
+            UciWarning::InvalidValue { field, value } => write!(f, "invalid {} {:?}", field, value),
+            UciWarning::UnknownToken(token) => write!(f, "unknown token {:?}", token),
+        }
+    }
+}
+
+/// Fields of `info` lines that are ignored, with the number of values
+/// they take.
+const SKIPPED_INFO_FIELDS: &[(&str, usize)] = &[
+    ("currmove", 1),
+    ("currmovenumber", 1),
+    ("sbhits", 1),
+    ("cpuload", 1),
+];
+
+/// Parses a line of engine output after `go`. Fields may come in any
+/// order. Anything that can not be understood is skipped and reported, so
+/// that third-party engines with extensions still work. Never panics,
+/// whatever the engine writes.
+pub fn parse_search_output(line: &str) -> (SearchOutput, Vec<UciWarning>) {
+    let mut warnings = Vec::new();
+    let mut parts = line.split_whitespace().peekable();
+    let output = match parts.next() {
+        Some("bestmove") => SearchOutput::BestMove(match parts.next() {
+            Some("(none)") | Some("0000") => None,
+            Some(m) => match m.parse() {
+                Ok(m) => Some(m),
+                Err(_) => {
+                    warnings.push(UciWarning::InvalidValue { field: "bestmove", value: m.to_owned() });
+                    None
+                }
+            },
+            None => {
+                warnings.push(UciWarning::MissingValue { field: "bestmove" });
+                None
+            }
+        }),
+        Some("info") => {
+            let mut info = Info::default();
+            while let Some(part) = parts.next() {
+                match part {
+                    "multipv" => info.multipv = parse_value(&mut parts, "multipv", &mut warnings),
+                    "depth" => info.depth = parse_value(&mut parts, "depth", &mut warnings),
+                    "seldepth" => info.seldepth = parse_value(&mut parts, "seldepth", &mut warnings),
+                    "hashfull" => info.hashfull = parse_value(&mut parts, "hashfull", &mut warnings),
+                    "tbhits" => info.tbhits = parse_value(&mut parts, "tbhits", &mut warnings),
+                    "nodes" => info.nodes = parse_value(&mut parts, "nodes", &mut warnings),
+                    "time" => info.time = parse_value(&mut parts, "time", &mut warnings).map(Duration::from_millis),
+                    "nps" => info.nps = parse_value(&mut parts, "nps", &mut warnings),
+                    "score" => {
+                        info.score = match parts.next() {
+                            Some("cp") => parse_value(&mut parts, "score cp", &mut warnings).map(Score::Cp),
+                            Some("mate") => parse_value(&mut parts, "score mate", &mut warnings).map(Score::Mate),
+                            Some(kind) => {
+                                warnings.push(UciWarning::InvalidValue { field: "score", value: kind.to_owned() });
+                                None
                             }
-                            "pv" => {
-                                pv.clear();
-                                while let Some(part) = parts.next() {
-                                    pv.push(part.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid pv"))?);
-                                }
+                            None => {
+                                warnings.push(UciWarning::MissingValue { field: "score" });
+                                None
                             }
-                            _ => (),
+                        };
+                        match parts.peek() {
+                            Some(&"lowerbound") => info.bound = Some(Bound::Lower),
+                            Some(&"upperbound") => info.bound = Some(Bound::Upper),
+                            _ => continue,
                         }
+                        parts.next();
                     }
+                    "wdl" => {
+                        let win = parse_value(&mut parts, "wdl", &mut warnings);
+                        let draw = parse_value(&mut parts, "wdl", &mut warnings);
+                        let loss = parse_value(&mut parts, "wdl", &mut warnings);
+                        info.wdl = match (win, draw, loss) {
+                            (Some(win), Some(draw), Some(loss)) => Some(Wdl { win, draw, loss }),
+                            _ => None,
+                        };
+                    }
+                    "pv" => {
+                        // Usually last, but the moves end at the first token
+                        // that is not a move.
+                        let mut moves = Vec::new();
+                        while let Some(m) = parts.peek().and_then(|m| m.parse().ok()) {
+                            parts.next();
+                            moves.push(m);
+                        }
+                        info.pv = Some(moves);
+                    }
+                    "string" => {
+                        // The rest of the line, with the original spacing.
+                        let rest = parts.next().map_or("", |first| {
+                            let offset = first.as_ptr() as usize - line.as_ptr() as usize;
+                            &line[offset..]
+                        });
+                        info.string = Some(rest.to_owned());
+                        break;
+                    }
+                    _ => match SKIPPED_INFO_FIELDS.iter().find(|&&(field, _)| field == part) {
+                        Some(&(_, values)) => {
+                            for _ in 0..values {
+                                parts.next();
+                            }
+                        }
+                        None => warnings.push(UciWarning::UnknownToken(part.to_owned())),
+                    },
                 }
-                _ => self.logger.warn(&format!("Unexpected engine output: {}", line)),
             }
+            SearchOutput::Info(info)
         }
+        _ => SearchOutput::Unexpected,
+    };
+    (output, warnings)
+}
+
+fn parse_value<'a, T, I>(parts: &mut I, field: &'static str, warnings: &mut Vec<UciWarning>) -> Option<T>
+    where T: FromStr,
+          I: Iterator<Item = &'a str>,
+{
+    match parts.next() {
+        Some(value) => match value.parse() {
+            Ok(value) => Some(value),
+            Err(_) => {
+                warnings.push(UciWarning::InvalidValue { field, value: value.to_owned() });
+                None
+            }
+        },
+        None => {
+            warnings.push(UciWarning::MissingValue { field });
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configure::Verbose;
+
+    #[tokio::test]
+    async fn test_stdout_framing() {
+        let long = "x".repeat(MAX_LINE + READ_CHUNK);
+        let output = format!("uciok\r\ninfo depth 1\n{}\n\nbestmove e2e4", long);
+        let silence = Silence(Arc::new(Mutex::new(Instant::now())));
+        let mut stdout = Stdout::new(output.as_bytes(), silence, 0, Logger::new(Verbose::default(), false));
+        assert_eq!(stdout.read_line().await.expect("line"), "uciok");
+        assert_eq!(stdout.read_line().await.expect("line"), "info depth 1");
+        assert_eq!(stdout.read_line().await.expect("line"), "");
+        assert_eq!(stdout.read_line().await.expect("line"), "bestmove e2e4");
+        assert_eq!(stdout.read_line().await.expect_err("eof").kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_parse_search_output() {
+        let (output, warnings) = parse_search_output("info depth 24 seldepth 30 multipv 1 score cp -37 lowerbound wdl 30 900 70 nodes 1234 nps 56 hashfull 3 tbhits 0 time 22 pv e2e4 e7e5");
+        assert!(warnings.is_empty());
+        match output {
+            SearchOutput::Info(info) => {
+                assert_eq!(info.depth, Some(24));
+                assert_eq!(info.seldepth, Some(30));
+                assert_eq!(info.hashfull, Some(3));
+                assert_eq!(info.tbhits, Some(0));
+                assert_eq!(info.multipv, Some(1));
+                assert!(matches!(info.score, Some(Score::Cp(-37))));
+                assert_eq!(info.bound, Some(Bound::Lower));
+                assert_eq!(info.wdl, Some(Wdl { win: 30, draw: 900, loss: 70 }));
+                assert_eq!(info.nodes, Some(1234));
+                assert_eq!(info.time, Some(Duration::from_millis(22)));
+                assert_eq!(info.pv.map(|pv| pv.len()), Some(2));
+            }
+            _ => panic!("expected info"),
+        }
+
+        // Out of order, with extensions and garbage.
+        let (output, warnings) = parse_search_output("info  pv e2e4 frobnicate depth x nodes");
+        assert_eq!(warnings, vec![
+            UciWarning::UnknownToken("frobnicate".to_owned()),
+            UciWarning::InvalidValue { field: "depth", value: "x".to_owned() },
+            UciWarning::MissingValue { field: "nodes" },
+        ]);
+        match output {
+            SearchOutput::Info(info) => {
+                assert_eq!(info.pv.map(|pv| pv.len()), Some(1));
+                assert_eq!(info.depth, None);
+            }
+            _ => panic!("expected info"),
+        }
+
+        match parse_search_output("info string NNUE evaluation  enabled").0 {
+            SearchOutput::Info(info) => assert_eq!(info.string.as_deref(), Some("NNUE evaluation  enabled")),
+            _ => panic!("expected info"),
+        }
+
+        assert!(matches!(parse_search_output("bestmove (none)"), (SearchOutput::BestMove(None), ref w) if w.is_empty()));
+        assert!(matches!(parse_search_output("bestmove e2e4 ponder e7e5").0, SearchOutput::BestMove(Some(_))));
+        assert!(matches!(parse_search_output("readyok").0, SearchOutput::Unexpected));
     }
 }
@@ -1,8 +1,9 @@
 use std::env;
 use std::fs;
+use std::path::Path;
 use atty::Stream;
 use shell_escape::escape;
-use crate::configure::{Opt, Key};
+use crate::configure::{ListenAddr, Opt};
 
 pub fn systemd_system(opt: Opt) {
     let exe = exec_start(&opt);
@@ -28,6 +29,8 @@ pub fn systemd_system(opt: Opt) {
     }
     println!("NoNewPrivileges=true");
     println!("Restart=on-failure");
+    println!("SuccessExitStatus=2 6");
+    println!("RestartPreventExitStatus=1 3 4");
     println!();
     println!("[Install]");
     println!("WantedBy=multi-user.target");
@@ -64,6 +67,8 @@ pub fn systemd_user(opt: Opt) {
         println!("ProtectSystem=full");
     }
     println!("Restart=on-failure");
+    println!("SuccessExitStatus=2 6");
+    println!("RestartPreventExitStatus=1 3 4");
     println!();
     println!("[Install]");
     println!("WantedBy=default.target");
@@ -85,6 +90,12 @@ fn exec_start(opt: &Opt) -> String {
     if opt.verbose.level > 0 {
         builder.push(format!("-{}", "v".repeat(opt.verbose.level)));
     }
+    if opt.verbose.quiet {
+        builder.push("--quiet".to_owned());
+    }
+    if let Some(ref log_filter) = opt.verbose.log_filter {
+        builder.push(format!("--log-filter {}", escape(log_filter.to_string().into())));
+    }
     if opt.auto_update {
         builder.push("--auto-update".to_owned());
     }
@@ -98,18 +109,148 @@ fn exec_start(opt: &Opt) -> String {
             .expect("printable config path").to_owned();
         builder.push(escape(canonical.into()).into_owned());
     }
-    if let Some(Key(ref key)) = opt.key {
+    for key in &opt.key {
         builder.push("--key".to_owned());
-        builder.push(escape(key.into()).into_owned());
+        builder.push(escape(key.expose().into()).into_owned());
+    }
+    if let Some(ref key_store) = opt.key_store {
+        builder.push("--key-store".to_owned());
+        builder.push(key_store.to_string());
+    }
+    if let Some(ref key_file) = opt.key_file {
+        assert!(key_file != Path::new("-"), "reading keys from stdin is not supported for services");
+        let canonical = fs::canonicalize(key_file)
+            .expect("canonicalize key file path")
+            .to_str()
+            .expect("printable key file path").to_owned();
+        builder.push("--key-file".to_owned());
+        builder.push(escape(canonical.into()).into_owned());
     }
     if let Some(ref endpoint) = opt.endpoint {
         builder.push("--endpoint".to_owned());
         builder.push(escape(endpoint.to_string().into()).into_owned());
     }
+    for route in &opt.route {
+        builder.push("--route".to_owned());
+        builder.push(escape(route.to_string().into()).into_owned());
+    }
+    if let Some(ref proxy) = opt.client.proxy {
+        builder.push("--proxy".to_owned());
+        builder.push(escape(proxy.url.to_string().into()).into_owned());
+    }
+    if let Some(ref pool_idle_timeout) = opt.client.pool_idle_timeout {
+        builder.push("--pool-idle-timeout".to_owned());
+        builder.push(format!("{}s", pool_idle_timeout.as_secs()));
+    }
+    for (flag, timeout) in &[
+        ("--acquire-timeout", opt.client.acquire_timeout),
+        ("--submit-timeout", opt.client.submit_timeout),
+        ("--abort-timeout", opt.client.abort_timeout),
+        ("--status-timeout", opt.client.status_timeout),
+    ] {
+        if let Some(timeout) = timeout {
+            builder.push((*flag).to_owned());
+            builder.push(format!("{}s", timeout.as_secs()));
+        }
+    }
+    if let Some(ref pool_max_idle) = opt.client.pool_max_idle {
+        builder.push("--pool-max-idle".to_owned());
+        builder.push(pool_max_idle.to_string());
+    }
+    if opt.client.http2_prior_knowledge {
+        builder.push("--http2-prior-knowledge".to_owned());
+    }
+    if let Some(ref spool_dir) = opt.client.spool_dir {
+        builder.push("--spool-dir".to_owned());
+        builder.push(escape(absolute(spool_dir).into()).into_owned());
+    }
+    if let Some(ref trace_api) = opt.client.trace_api {
+        builder.push("--trace-api".to_owned());
+        builder.push(escape(absolute(trace_api).into()).into_owned());
+    }
+    if let Some(ref cacert) = opt.client.cacert {
+        builder.push("--cacert".to_owned());
+        builder.push(escape(absolute(cacert).into()).into_owned());
+    }
+    if let (Some(ref client_cert), Some(ref client_key)) = (&opt.client.client_cert, &opt.client.client_key) {
+        builder.push("--client-cert".to_owned());
+        builder.push(escape(absolute(client_cert).into()).into_owned());
+        builder.push("--client-key".to_owned());
+        builder.push(escape(absolute(client_key).into()).into_owned());
+    }
+    for pin in &opt.client.pin_sha256 {
+        builder.push("--pin-sha256".to_owned());
+        builder.push(pin.to_string());
+    }
+    if let Some(ref instance_label) = opt.client.instance_label {
+        builder.push("--instance-label".to_owned());
+        builder.push(instance_label.clone());
+    }
+    if let Some(ref bandwidth_cap) = opt.client.bandwidth_cap {
+        builder.push("--bandwidth-cap".to_owned());
+        builder.push(bandwidth_cap.to_string());
+    }
+    match opt.metrics_listen {
+        Some(ListenAddr::Tcp(ref addr)) => {
+            builder.push("--metrics-listen".to_owned());
+            builder.push(addr.to_string());
+        }
+        Some(ListenAddr::Unix(ref path)) => {
+            builder.push("--metrics-listen".to_owned());
+            builder.push(escape(format!("unix:{}", absolute(path)).into()).into_owned());
+        }
+        None => (),
+    }
+    if let Some(ref metrics_socket_mode) = opt.metrics_socket_mode {
+        builder.push("--metrics-socket-mode".to_owned());
+        builder.push(format!("{:o}", metrics_socket_mode));
+    }
+    if let Some(ref webhook) = opt.webhook {
+        builder.push("--webhook".to_owned());
+        builder.push(escape(webhook.to_string().into()).into_owned());
+    }
+    if let Some(ref webhook_format) = opt.webhook_format {
+        builder.push("--webhook-format".to_owned());
+        builder.push(webhook_format.to_string());
+    }
+    if let Some(ref crash_report_url) = opt.crash_report_url {
+        builder.push("--crash-report-url".to_owned());
+        builder.push(escape(crash_report_url.to_string().into()).into_owned());
+    }
+    if let Some(ref scale_down_after) = opt.scale_down_after {
+        builder.push("--scale-down-after".to_owned());
+        builder.push(format!("{}s", scale_down_after.as_secs()));
+    }
+    if opt.scale_down_exit {
+        builder.push("--scale-down-exit".to_owned());
+    }
+    if let Some(ref preemption_notice) = opt.preemption_notice {
+        builder.push("--preemption-notice".to_owned());
+        builder.push(preemption_notice.to_string());
+    }
+    if let Some(ref preemption_action) = opt.preemption_action {
+        builder.push("--preemption-action".to_owned());
+        builder.push(preemption_action.to_string());
+    }
+    if let Some(ref coordinator_listen) = opt.cluster.coordinator_listen {
+        builder.push("--coordinator-listen".to_owned());
+        builder.push(coordinator_listen.to_string());
+    }
+    if let Some(ref coordinator) = opt.cluster.coordinator {
+        builder.push("--coordinator".to_owned());
+        builder.push(escape(coordinator.into()).into_owned());
+    }
+    if let Some(ref cluster_secret) = opt.cluster.cluster_secret {
+        builder.push("--cluster-secret".to_owned());
+        builder.push(escape(cluster_secret.expose().into()).into_owned());
+    }
     if let Some(ref cores) = opt.cores {
         builder.push("--cores".to_owned());
         builder.push(escape(cores.to_string().into()).into_owned());
     }
+    if opt.exclusive {
+        builder.push("--exclusive".to_owned());
+    }
     if let Some(ref user_backlog) = opt.backlog.user {
         builder.push("--user-backlog".to_owned());
         builder.push(escape(user_backlog.to_string().into()).into_owned());
@@ -118,6 +259,141 @@ fn exec_start(opt: &Opt) -> String {
         builder.push("--system_backlog".to_owned());
         builder.push(escape(system_backlog.to_string().into()).into_owned());
     }
-    builder.push("run".to_owned());
+    if let Some(ref user_ratio) = opt.backlog.user_ratio {
+        builder.push("--user-ratio".to_owned());
+        builder.push(user_ratio.to_string());
+    }
+    if let Some(ref long_poll) = opt.backlog.long_poll {
+        builder.push("--long-poll".to_owned());
+        builder.push(format!("{}s", long_poll.as_secs()));
+    }
+    if opt.backlog.status_events {
+        builder.push("--status-events".to_owned());
+    }
+    if let Some(ref batch_timeout) = opt.backlog.batch_timeout {
+        builder.push("--batch-timeout".to_owned());
+        builder.push(format!("{}s", batch_timeout.as_secs()));
+    }
+    if let Some(ref progress_interval) = opt.backlog.progress_interval {
+        builder.push("--progress-interval".to_owned());
+        builder.push(format!("{}s", progress_interval.as_secs()));
+    }
+    if let Some(ref max_batches) = opt.backlog.max_batches {
+        builder.push("--max-batches".to_owned());
+        builder.push(max_batches.to_string());
+    }
+    if let Some(ref schedule) = opt.backlog.schedule {
+        builder.push("--schedule".to_owned());
+        builder.push(schedule.to_string());
+    }
+    if let Some(ref only) = opt.backlog.only {
+        builder.push("--only".to_owned());
+        builder.push(only.to_string());
+    }
+    if !opt.backlog.variants.is_empty() {
+        builder.push("--variants".to_owned());
+        builder.push(opt.backlog.variants.iter().map(|v| v.key()).collect::<Vec<_>>().join(","));
+    }
+    if let Some(ref max_positions) = opt.backlog.max_positions {
+        builder.push("--max-positions".to_owned());
+        builder.push(max_positions.to_string());
+    }
+    if let Some(ref nodes_multiplier) = opt.backlog.nodes_multiplier {
+        builder.push("--nodes-multiplier".to_owned());
+        builder.push(nodes_multiplier.to_string());
+    }
+    for multiplier in &opt.backlog.variant_nodes_multiplier {
+        builder.push("--variant-nodes-multiplier".to_owned());
+        builder.push(multiplier.to_string());
+    }
+    if let Some(ref eval_cache) = opt.backlog.eval_cache {
+        builder.push("--eval-cache".to_owned());
+        builder.push(eval_cache.to_string());
+    }
+    if let Some(ref archive) = opt.backlog.archive {
+        builder.push("--archive".to_owned());
+        builder.push(escape(absolute(archive).into()).into_owned());
+    }
+    if let Some(ref archive_max_size) = opt.backlog.archive_max_size {
+        builder.push("--archive-max-size".to_owned());
+        builder.push(archive_max_size.to_string());
+    }
+    if opt.backlog.cloud_eval {
+        builder.push("--cloud-eval".to_owned());
+    }
+    if let Some(ref cloud_eval_depth) = opt.backlog.cloud_eval_depth {
+        builder.push("--cloud-eval-depth".to_owned());
+        builder.push(cloud_eval_depth.to_string());
+    }
+    if opt.backlog.pause_on_battery {
+        builder.push("--pause-on-battery".to_owned());
+    }
+    if let Some(ref battery_threshold) = opt.backlog.battery_threshold {
+        builder.push("--battery-threshold".to_owned());
+        builder.push(battery_threshold.to_string());
+    }
+    if let Some(ref only_when_idle) = opt.backlog.only_when_idle {
+        builder.push("--only-when-idle".to_owned());
+        builder.push(only_when_idle.to_string());
+    }
+    if opt.backlog.abort_when_active {
+        builder.push("--abort-when-active".to_owned());
+    }
+    if let Some(ref hang_timeout) = opt.engine.hang_timeout {
+        builder.push("--hang-timeout".to_owned());
+        builder.push(format!("{}s", hang_timeout.as_secs()));
+    }
+    if let Some(ref engine_retries) = opt.engine.engine_retries {
+        builder.push("--engine-retries".to_owned());
+        builder.push(engine_retries.to_string());
+    }
+    if let Some(ref multipv) = opt.engine.multipv {
+        builder.push("--multipv".to_owned());
+        builder.push(multipv.to_string());
+    }
+    if let Some(ref max_depth) = opt.engine.max_depth {
+        builder.push("--max-depth".to_owned());
+        builder.push(max_depth.to_string());
+    }
+    if let Some(ref max_memory) = opt.engine.max_memory {
+        builder.push("--max-memory".to_owned());
+        builder.push(max_memory.to_string());
+    }
+    if let Some(ref threads_per_worker) = opt.engine.threads_per_worker {
+        builder.push("--threads-per-worker".to_owned());
+        builder.push(threads_per_worker.to_string());
+    }
+    if let Some(ref variant_engine) = opt.engine.variant_engine {
+        builder.push("--variant-engine".to_owned());
+        builder.push(variant_engine.to_string());
+    }
+    if opt.engine.no_engine_sandbox {
+        builder.push("--no-engine-sandbox".to_owned());
+    }
+    if opt.engine.no_mate_early_stop {
+        builder.push("--no-mate-early-stop".to_owned());
+    }
+    for uci_option in &opt.engine.uci_option {
+        builder.push("--uci-option".to_owned());
+        builder.push(escape(uci_option.to_string().into()).into_owned());
+    }
+    for uci_option in &opt.engine.uci_option_move {
+        builder.push("--uci-option-move".to_owned());
+        builder.push(escape(uci_option.to_string().into()).into_owned());
+    }
+    for uci_option in &opt.engine.uci_option_analysis {
+        builder.push("--uci-option-analysis".to_owned());
+        builder.push(escape(uci_option.to_string().into()).into_owned());
+    }
+    builder.push(if opt.cluster.coordinator.is_some() { "worker" } else { "run" }.to_owned());
     builder.join(" ")
 }
+
+/// The service runs in /tmp, so paths must not be relative.
+fn absolute(path: &Path) -> String {
+    env::current_dir()
+        .expect("current directory")
+        .join(path)
+        .to_str()
+        .expect("printable path").to_owned()
+}
@@ -0,0 +1,92 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write as _};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use reqwest::{Method, StatusCode};
+use url::Url;
+use crate::configure::{redacted_url, Key};
+
+/// Bodies are cut off after this many bytes.
+const MAX_BODY: usize = 2000;
+
+/// Request to the endpoint and its outcome, as seen by the API actor.
+pub struct Exchange<'a> {
+    pub method: &'a Method,
+    pub url: &'a Url,
+    pub request: Option<&'a [u8]>,
+    /// Status and body of the response, or the error if there was none.
+    pub response: Result<(StatusCode, &'a [u8]), String>,
+    pub latency: Duration,
+}
+
+/// Appends every request to the endpoint and its response to a file, for
+/// attaching to bug reports. Keys are masked.
+pub struct ApiTrace {
+    file: Mutex<File>,
+}
+
+impl ApiTrace {
+    pub fn open(path: &Path) -> io::Result<ApiTrace> {
+        Ok(ApiTrace {
+            file: Mutex::new(OpenOptions::new().create(true).append(true).open(path)?),
+        })
+    }
+
+    pub fn record(&self, exchange: &Exchange<'_>, keys: &[Key]) {
+        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        let mut entry = format!("[{}.{:03}] {} {}", timestamp.as_secs(), timestamp.subsec_millis(), exchange.method, redacted_url(exchange.url));
+        match exchange.response {
+            Ok((status, _)) => entry.push_str(&format!(" -> {} in {}ms\n", status, exchange.latency.as_millis())),
+            Err(ref err) => entry.push_str(&format!(" -> failed in {}ms: {}\n", exchange.latency.as_millis(), err)),
+        }
+        if let Some(request) = exchange.request.filter(|b| !b.is_empty()) {
+            entry.push_str(&format!("> {}\n", excerpt(request, keys)));
+        }
+        if let Ok((_, response)) = exchange.response {
+            if !response.is_empty() {
+                entry.push_str(&format!("< {}\n", excerpt(response, keys)));
+            }
+        }
+
+        // Tracing is best effort.
+        let mut file = self.file.lock().expect("api trace");
+        file.write_all(entry.as_bytes()).ok();
+    }
+}
+
+fn excerpt(body: &[u8], keys: &[Key]) -> String {
+    let text = match std::str::from_utf8(body) {
+        Ok(text) => text,
+        Err(_) => return format!("({} bytes of binary data)", body.len()),
+    };
+    // Mask before cutting off, so that no part of a key is left at the end.
+    let mut text = text.to_owned();
+    for key in keys {
+        text = text.replace(key.expose(), "***");
+    }
+    let excerpt = if text.len() > MAX_BODY {
+        let mut end = MAX_BODY;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        format!("{} ... ({} bytes)", &text[..end], body.len())
+    } else {
+        text
+    };
+    excerpt.replace('\n', " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_excerpt_masks_key_at_cut() {
+        let key: Key = "abcdefgh".parse().expect("key");
+        let body = format!("{}abcdefgh{}", "x".repeat(MAX_BODY - 4), "y".repeat(10));
+        let excerpt = excerpt(body.as_bytes(), &[key]);
+        assert!(!excerpt.contains("abcd"), "{}", excerpt);
+        assert!(excerpt.ends_with(&format!("... ({} bytes)", body.len())));
+    }
+}
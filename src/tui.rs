@@ -0,0 +1,181 @@
+use std::collections::VecDeque;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use ratatui::{DefaultTerminal, Frame};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Color, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, Paragraph, Row, Sparkline, Table};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time;
+use crate::logger::Logger;
+use crate::metrics::Health;
+use crate::queue::{Dashboard, QueueStub};
+
+/// Seconds of nps history for the sparkline.
+const NPS_HISTORY: usize = 120;
+
+/// Full-screen dashboard. Owns the terminal until stopped.
+pub struct Tui {
+    stop: oneshot::Sender<()>,
+    join_handle: JoinHandle<()>,
+}
+
+impl Tui {
+    /// Takes over the terminal. Pressing q or ^C sends an interrupt, which
+    /// should be handled like SIGINT.
+    pub fn spawn(cores: usize, queue: QueueStub, health: Arc<Health>, logger: Logger) -> io::Result<(Tui, mpsc::UnboundedReceiver<()>)> {
+        let terminal = ratatui::try_init()?;
+        logger.capture();
+
+        let (interrupt_tx, interrupt_rx) = mpsc::unbounded_channel();
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let join_handle = tokio::spawn(run(terminal, cores, queue, health, logger, interrupt_tx, stop_rx));
+        Ok((Tui {
+            stop: stop_tx,
+            join_handle,
+        }, interrupt_rx))
+    }
+
+    /// Restores the terminal and prints log lines normally again.
+    pub async fn stop(self) {
+        drop(self.stop);
+        self.join_handle.await.expect("join");
+    }
+}
+
+async fn run(mut terminal: DefaultTerminal, cores: usize, queue: QueueStub, health: Arc<Health>, logger: Logger, interrupt: mpsc::UnboundedSender<()>, mut stop: oneshot::Receiver<()>) {
+    // Reading terminal events blocks, so do it on a separate thread.
+    let done = Arc::new(AtomicBool::new(false));
+    let input = {
+        let done = done.clone();
+        thread::spawn(move || {
+            while !done.load(Ordering::Relaxed) {
+                if let Ok(true) = event::poll(Duration::from_millis(100)) {
+                    if let Ok(Event::Key(key)) = event::read() {
+                        let ctrl_c = key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c');
+                        if key.kind == KeyEventKind::Press && (ctrl_c || key.code == KeyCode::Char('q')) {
+                            interrupt.send(()).ok();
+                        }
+                    }
+                }
+            }
+        })
+    };
+
+    let mut nps = VecDeque::with_capacity(NPS_HISTORY);
+    let mut sampled: Option<Instant> = None;
+    let mut interval = time::interval(Duration::from_millis(250));
+    let mut result = Ok(());
+    loop {
+        tokio::select! {
+            _ = &mut stop => break,
+            _ = interval.tick() => (),
+        }
+
+        let dashboard = queue.dashboard().await;
+        if sampled.is_none_or(|s| s.elapsed() >= Duration::from_secs(1)) {
+            sampled = Some(Instant::now());
+            if nps.len() >= NPS_HISTORY {
+                nps.pop_front();
+            }
+            nps.push_back(u64::from(dashboard.stats.nnue_nps.nps()));
+        }
+        let activity = health.worker_activity();
+        let log = logger.recent_lines();
+
+        if let Err(err) = terminal.draw(|frame| draw(frame, cores, &dashboard, &activity, &nps, &log)) {
+            result = Err(err);
+            break;
+        }
+    }
+
+    done.store(true, Ordering::Relaxed);
+    input.join().ok();
+    ratatui::restore();
+    logger.release();
+    if let Err(err) = result {
+        logger.error(&format!("Dashboard failed: {}", err));
+    }
+}
+
+fn draw(frame: &mut Frame, cores: usize, dashboard: &Dashboard, activity: &[Option<String>], nps: &VecDeque<u64>, log: &[String]) {
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(3),
+            Constraint::Min(8),
+            Constraint::Length(5),
+            Constraint::Length(10),
+        ])
+        .split(frame.area());
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+        .split(rows[1]);
+
+    let stats = &dashboard.stats;
+    frame.render_widget(Paragraph::new(format!(
+        "fishnet/{}: {} cores, {}, {} batches, {} positions, {} queued (q to stop)",
+        env!("CARGO_PKG_VERSION"), cores, dashboard.activity(),
+        stats.total_batches, stats.total_positions, dashboard.incoming,
+    )).block(Block::default().borders(Borders::ALL).title("fishnet")), rows[0]);
+
+    let workers = activity.iter().enumerate().map(|(i, context)| {
+        let (nps, positions, errors) = stats.workers.get(i).map_or_else(
+            || ("?".to_owned(), 0, 0),
+            |w| (format!("{} knps", w.nps.nps() / 1000), w.positions, w.errors));
+        Row::new(vec![
+            i.to_string(),
+            context.clone().unwrap_or_else(|| "idle".to_owned()),
+            nps,
+            positions.to_string(),
+            errors.to_string(),
+        ])
+    });
+    frame.render_widget(Table::new(workers, [
+        Constraint::Length(3),
+        Constraint::Min(20),
+        Constraint::Length(10),
+        Constraint::Length(8),
+        Constraint::Length(6),
+    ]).header(Row::new(vec!["#", "analysing", "nps", "done", "errors"]).style(Style::default().fg(Color::Yellow)))
+      .block(Block::default().borders(Borders::ALL).title("Workers")), columns[0]);
+
+    // One symbol per position of each pending batch.
+    let batches: Vec<Line> = dashboard.pending.iter().flat_map(|batch| {
+        let bar: String = batch.positions.iter().map(|&done| if done { '#' } else { '.' }).collect();
+        let done = batch.positions.iter().filter(|&&done| done).count();
+        vec![
+            Line::from(format!("{} ({}/{})", batch.at, done, batch.positions.len())),
+            Line::from(bar),
+        ]
+    }).collect();
+    frame.render_widget(Paragraph::new(batches)
+        .block(Block::default().borders(Borders::ALL).title("Pending batches")), columns[1]);
+
+    let history: Vec<u64> = nps.iter().copied().collect();
+    frame.render_widget(Sparkline::default()
+        .data(&history)
+        .style(Style::default().fg(Color::Green))
+        .block(Block::default().borders(Borders::ALL).title(format!("nnue nps: {}", stats.nnue_nps))), rows[2]);
+
+    let visible = usize::from(rows[3].height.saturating_sub(2));
+    let lines: Vec<Line> = log.iter().skip(log.len().saturating_sub(visible)).map(|line| {
+        let style = if line.starts_with("E: ") {
+            Style::default().fg(Color::Red)
+        } else if line.starts_with("W: ") {
+            Style::default().fg(Color::Yellow)
+        } else {
+            Style::default()
+        };
+        Line::styled(line.clone(), style)
+    }).collect();
+    frame.render_widget(Paragraph::new(lines)
+        .block(Block::default().borders(Borders::ALL).title("Log")), rows[3]);
+}
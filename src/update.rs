@@ -0,0 +1,232 @@
+use std::fmt;
+use std::io;
+use std::env;
+use std::fs;
+use std::error::Error;
+use std::path::Path;
+use std::time::Duration;
+use serde::Deserialize;
+use ed25519_dalek::{PublicKey, Signature};
+use reqwest::StatusCode;
+use reqwest::header::RANGE;
+use sha2::{Digest as _, Sha256};
+use tokio::time;
+use tokio_compat_02::FutureExt as _;
+use crate::bandwidth::{Bandwidth, HEADER_OVERHEAD};
+use crate::logger::Logger;
+use crate::util::RandomizedBackoff;
+
+/// Hex encoded Ed25519 public key of the release signing key. Each release
+/// binary is accompanied by a detached signature with the suffix `.sig`.
+/// The bundled engines and networks are embedded in the binary, so they are
+/// covered by the same signature.
+const RELEASE_PUBLIC_KEY: Option<&str> = option_env!("FISHNET_RELEASE_PUBLIC_KEY");
+
+const LATEST_RELEASE: &str = "https://api.github.com/repos/niklasf/fishnet/releases/latest";
+
+#[derive(Debug)]
+pub enum UpdateStatus {
+    UpToDate(String),
+    Updated(String),
+}
+
+#[derive(Debug)]
+pub enum UpdateError {
+    Network(reqwest::Error),
+    Io(io::Error),
+    SelfUpdate(self_update::errors::Error),
+    NoAsset(String),
+    NoSignature(String),
+    NoPublicKey,
+    BadSignature(String),
+    BadChecksum(String),
+    BadTag(String),
+}
+
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpdateError::Network(err) => write!(f, "network error: {}", err),
+            UpdateError::Io(err) => write!(f, "io error: {}", err),
+            UpdateError::SelfUpdate(err) => write!(f, "failed to replace binary: {}", err),
+            UpdateError::NoAsset(name) => write!(f, "latest release has no binary {}", name),
+            UpdateError::NoSignature(name) => write!(f, "latest release has no signature for {}", name),
+            UpdateError::NoPublicKey => f.write_str("this build does not embed a release signing key, refusing to install unverified update"),
+            UpdateError::BadSignature(name) => write!(f, "signature verification failed for {}, refusing to install", name),
+            UpdateError::BadChecksum(name) => write!(f, "download of {} is corrupted", name),
+            UpdateError::BadTag(tag) => write!(f, "latest release has an invalid tag {:?}", tag),
+        }
+    }
+}
+
+impl Error for UpdateError {}
+
+impl From<reqwest::Error> for UpdateError {
+    fn from(err: reqwest::Error) -> UpdateError {
+        UpdateError::Network(err)
+    }
+}
+
+impl From<io::Error> for UpdateError {
+    fn from(err: io::Error) -> UpdateError {
+        UpdateError::Io(err)
+    }
+}
+
+impl From<self_update::errors::Error> for UpdateError {
+    fn from(err: self_update::errors::Error) -> UpdateError {
+        UpdateError::SelfUpdate(err)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Release {
+    pub tag_name: String,
+    pub assets: Vec<ReleaseAsset>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ReleaseAsset {
+    pub name: String,
+    browser_download_url: String,
+    #[serde(default)]
+    size: u64,
+    /// For example sha256:abcd..., only provided for recent releases.
+    #[serde(default)]
+    pub digest: Option<String>,
+}
+
+impl ReleaseAsset {
+    /// Catches truncated or corrupted downloads early, with a clearer error
+    /// than the signature check.
+    fn check(&self, data: &[u8]) -> Result<(), UpdateError> {
+        if self.size != 0 && self.size != data.len() as u64 {
+            return Err(UpdateError::BadChecksum(self.name.clone()));
+        }
+        if let Some(expected) = self.digest.as_ref().and_then(|d| d.strip_prefix("sha256:")) {
+            if !hex::encode(Sha256::digest(data)).eq_ignore_ascii_case(expected) {
+                return Err(UpdateError::BadChecksum(self.name.clone()));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn asset_name() -> String {
+    format!("fishnet-{}{}", self_update::get_target(), env::consts::EXE_SUFFIX)
+}
+
+pub(crate) fn verify(name: &str, data: &[u8], signature: &[u8]) -> Result<(), UpdateError> {
+    let public_key = RELEASE_PUBLIC_KEY
+        .and_then(|key| hex::decode(key.trim()).ok())
+        .and_then(|key| PublicKey::from_bytes(&key).ok())
+        .ok_or(UpdateError::NoPublicKey)?;
+    let signature = Signature::from_bytes(signature).map_err(|_| UpdateError::BadSignature(name.to_owned()))?;
+    public_key.verify_strict(data, &signature).map_err(|_| UpdateError::BadSignature(name.to_owned()))
+}
+
+/// Fetches release metadata, counting the body as actually received.
+pub(crate) async fn latest_release(client: &reqwest::Client, bandwidth: &Bandwidth, url: &str) -> Result<Release, UpdateError> {
+    bandwidth.record_sent(HEADER_OVERHEAD);
+    let res = client.get(url).send().await?.error_for_status()?;
+    let body = res.bytes().await?;
+    bandwidth.record_received(HEADER_OVERHEAD + body.len() as u64);
+    serde_json::from_slice(&body).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err).into())
+}
+
+/// Attempts per download. Interrupted downloads continue where they left
+/// off, if the server supports range requests.
+const DOWNLOAD_ATTEMPTS: u32 = 5;
+
+pub(crate) async fn download(client: &reqwest::Client, bandwidth: &Bandwidth, asset: &ReleaseAsset, logger: &Logger) -> Result<Vec<u8>, UpdateError> {
+    let mut data = Vec::new();
+    let mut backoff = RandomizedBackoff::default();
+    let mut attempt = 1;
+    loop {
+        match download_range(client, bandwidth, &asset.browser_download_url, &mut data).await {
+            Ok(()) => break,
+            Err(err) if attempt < DOWNLOAD_ATTEMPTS && err.status().is_none_or(|s| s.is_server_error()) => {
+                let backoff = backoff.next();
+                logger.warn(&format!("Download of {} interrupted after {} bytes: {}. Resuming in {:?}.", asset.name, data.len(), err, backoff));
+                time::sleep(backoff).await;
+                attempt += 1;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    asset.check(&data)?;
+    Ok(data)
+}
+
+async fn download_range(client: &reqwest::Client, bandwidth: &Bandwidth, url: &str, data: &mut Vec<u8>) -> reqwest::Result<()> {
+    let mut req = client.get(url).timeout(Duration::from_secs(5 * 60));
+    if !data.is_empty() {
+        req = req.header(RANGE, format!("bytes={}-", data.len()));
+    }
+    bandwidth.record_sent(HEADER_OVERHEAD);
+    let res = req.send().await?;
+    bandwidth.record_received(HEADER_OVERHEAD);
+    if res.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        // Already complete.
+        return Ok(());
+    }
+    let mut res = res.error_for_status()?;
+    if res.status() != StatusCode::PARTIAL_CONTENT {
+        // Range not supported. Start over.
+        data.clear();
+    }
+    while let Some(chunk) = res.chunk().await? {
+        bandwidth.record_received(chunk.len() as u64);
+        data.extend_from_slice(&chunk);
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+pub(crate) fn set_executable(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt as _;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn set_executable(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+pub async fn auto_update(verbose: bool, client: &reqwest::Client, bandwidth: &Bandwidth, logger: &Logger) -> Result<UpdateStatus, UpdateError> {
+    if verbose {
+        logger.headline("Updating ...");
+    }
+    logger.fishnet_info("Checking for updates (--auto-update) ...");
+    auto_update_inner(client, bandwidth, logger).compat().await
+}
+
+async fn auto_update_inner(client: &reqwest::Client, bandwidth: &Bandwidth, logger: &Logger) -> Result<UpdateStatus, UpdateError> {
+    let release = latest_release(client, bandwidth, LATEST_RELEASE).await?;
+    let version = release.tag_name.trim_start_matches('v').to_owned();
+    if !self_update::version::bump_is_greater(env!("CARGO_PKG_VERSION"), &version)? {
+        return Ok(UpdateStatus::UpToDate(env!("CARGO_PKG_VERSION").to_owned()));
+    }
+
+    let name = asset_name();
+    let sig_name = format!("{}.sig", name);
+    let asset = release.assets.iter().find(|a| a.name == name).ok_or_else(|| UpdateError::NoAsset(name.clone()))?;
+    let sig_asset = release.assets.iter().find(|a| a.name == sig_name).ok_or_else(|| UpdateError::NoSignature(name.clone()))?;
+
+    logger.fishnet_info(&format!("Downloading {} {} ...", name, version));
+    let data = download(client, bandwidth, asset, logger).await?;
+    let signature = download(client, bandwidth, sig_asset, logger).await?;
+    verify(&name, &data, &signature)?;
+    logger.debug(&format!("Verified signature of {}", name));
+
+    let current_exe = env::current_exe()?;
+    let tmp_dir = tempfile::Builder::new().prefix("fishnet-update-").tempdir_in(current_exe.parent().unwrap_or_else(|| Path::new(".")))?;
+    let new_exe = tmp_dir.path().join(&name);
+    fs::write(&new_exe, &data)?;
+    set_executable(&new_exe)?;
+    self_update::Move::from_source(&new_exe)
+        .replace_using_temp(&tmp_dir.path().join("replaced"))
+        .to_dest(&current_exe)?;
+
+    Ok(UpdateStatus::Updated(version))
+}
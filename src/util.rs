@@ -1,6 +1,11 @@
 use std::cmp::min;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
 use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
 
 #[derive(Debug, Default)]
 pub struct RandomizedBackoff {
@@ -20,6 +25,79 @@ impl RandomizedBackoff {
     }
 }
 
+/// Lets work be stopped early, for example when the server no longer needs
+/// a batch. Clones share the same state.
+#[derive(Debug, Clone)]
+pub struct Cancellation {
+    tx: Arc<watch::Sender<bool>>,
+    rx: watch::Receiver<bool>,
+}
+
+impl Default for Cancellation {
+    fn default() -> Cancellation {
+        let (tx, rx) = watch::channel(false);
+        Cancellation {
+            tx: Arc::new(tx),
+            rx,
+        }
+    }
+}
+
+impl Cancellation {
+    pub fn cancel(&self) {
+        self.tx.send(true).nevermind("nobody is listening");
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    /// Resolves once cancelled.
+    pub async fn cancelled(&self) {
+        let mut rx = self.rx.clone();
+        while !*rx.borrow() {
+            rx.changed().await.expect("cancellation sender alive");
+        }
+    }
+}
+
+/// Holds a value that must not show up in logs or crash reports, like keys
+/// and shared secrets. Debug and Display are masked; use `expose()` where the
+/// actual value is needed.
+#[derive(Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub fn new(value: T) -> Secret<T> {
+        Secret(value)
+    }
+
+    pub fn expose(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(***)")
+    }
+}
+
+impl<T> fmt::Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("***")
+    }
+}
+
+impl<T: FromStr> FromStr for Secret<T> {
+    type Err = T::Err;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Secret)
+    }
+}
+
 pub trait NevermindExt: Sized {
     fn nevermind(self, _msg: &str) {}
 }
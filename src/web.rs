@@ -0,0 +1,309 @@
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use serde::Serialize;
+use sha1::{Digest as _, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _, BufReader};
+use tokio::sync::mpsc;
+use tokio::time;
+use crate::logger::Logger;
+use crate::metrics::Health;
+use crate::queue::QueueStub;
+
+/// Stats samples to keep, one every `SAMPLE_INTERVAL` (one hour).
+const HISTORY: usize = 360;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Log lines to include in each update.
+const LOG_LINES: usize = 50;
+
+/// Browsers send small control frames only.
+const MAX_FRAME: u64 = 64 * 1024;
+
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+#[derive(Debug, Clone, Serialize)]
+struct Sample {
+    positions: u64,
+    nps: u32,
+}
+
+/// Stats history, recorded even while nobody is watching.
+#[derive(Default)]
+pub struct History(Mutex<VecDeque<Sample>>);
+
+pub async fn record_history(history: Arc<History>, queue: QueueStub) {
+    let mut interval = time::interval(SAMPLE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let stats = queue.stats().await;
+        let mut samples = history.0.lock().expect("history");
+        if samples.len() >= HISTORY {
+            samples.pop_front();
+        }
+        samples.push_back(Sample {
+            positions: stats.total_positions,
+            nps: stats.nnue_nps.nps(),
+        });
+    }
+}
+
+#[derive(Serialize)]
+struct Update {
+    version: &'static str,
+    activity: String,
+    incoming: usize,
+    batches: u64,
+    positions: u64,
+    nodes: u64,
+    nps: u32,
+    workers: Vec<WorkerUpdate>,
+    pending: Vec<BatchUpdate>,
+    history: Vec<Sample>,
+    log: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct WorkerUpdate {
+    analysing: Option<String>,
+    nps: u32,
+    positions: u64,
+    errors: u64,
+}
+
+#[derive(Serialize)]
+struct BatchUpdate {
+    batch: String,
+    done: usize,
+    total: usize,
+}
+
+async fn update(health: &Health, history: &History, queue: &QueueStub, logger: &Logger) -> Update {
+    let dashboard = queue.dashboard().await;
+    let stats = &dashboard.stats;
+    let log = logger.recent_lines();
+    Update {
+        version: env!("CARGO_PKG_VERSION"),
+        activity: dashboard.activity(),
+        incoming: dashboard.incoming,
+        batches: stats.total_batches,
+        positions: stats.total_positions,
+        nodes: stats.total_nodes,
+        nps: stats.nnue_nps.nps(),
+        workers: health.worker_activity().into_iter().enumerate().map(|(i, analysing)| {
+            let worker = stats.workers.get(i);
+            WorkerUpdate {
+                analysing,
+                nps: worker.map_or(0, |w| w.nps.nps()),
+                positions: worker.map_or(0, |w| w.positions),
+                errors: worker.map_or(0, |w| w.errors),
+            }
+        }).collect(),
+        pending: dashboard.pending.iter().map(|batch| BatchUpdate {
+            batch: batch.at.to_string(),
+            done: batch.positions.iter().filter(|&&done| done).count(),
+            total: batch.positions.len(),
+        }).collect(),
+        history: history.0.lock().expect("history").iter().cloned().collect(),
+        log: log[log.len().saturating_sub(LOG_LINES)..].to_vec(),
+    }
+}
+
+/// Whether a WebSocket handshake comes from the dashboard itself. Browsers
+/// allow any web page to connect to local WebSockets, and send its origin
+/// along. Clients other than browsers send none.
+pub fn same_origin(origin: Option<&str>, host: Option<&str>) -> bool {
+    let origin = match origin {
+        Some(origin) => origin,
+        None => return true,
+    };
+    let authority = match origin.strip_prefix("http://").or_else(|| origin.strip_prefix("https://")) {
+        Some(authority) => authority,
+        None => return false,
+    };
+    matches!(host, Some(host) if authority.eq_ignore_ascii_case(host))
+}
+
+/// Completes the WebSocket handshake and pushes an update every two
+/// seconds, until the browser goes away.
+pub async fn websocket<S>(stream: BufReader<S>, key: &str, health: Arc<Health>, history: Arc<History>, queue: QueueStub, logger: Logger) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let accept = base64::encode(Sha1::digest(format!("{}{}", key, WEBSOCKET_GUID).as_bytes()));
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    writer.write_all(format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n", accept).as_bytes()).await?;
+
+    // Read frames on a separate task, so that a partially received frame
+    // is never dropped.
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok(frame) = read_frame(&mut reader).await {
+            if tx.send(frame).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut interval = time::interval(Duration::from_secs(2));
+    loop {
+        tokio::select! {
+            frame = rx.recv() => match frame {
+                Some((OPCODE_PING, payload)) => write_frame(&mut writer, OPCODE_PONG, &payload).await?,
+                Some((OPCODE_CLOSE, _)) | None => break,
+                Some(_) => (),
+            },
+            _ = interval.tick() => {
+                let update = update(&health, &history, &queue, &logger).await;
+                write_frame(&mut writer, OPCODE_TEXT, &serde_json::to_vec(&update)?).await?;
+            }
+        }
+    }
+
+    write_frame(&mut writer, OPCODE_CLOSE, &[]).await?;
+    writer.shutdown().await
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0; 2];
+    reader.read_exact(&mut header).await?;
+    let opcode = header[0] & 0x0f;
+    let len = match header[1] & 0x7f {
+        126 => {
+            let mut len = [0; 2];
+            reader.read_exact(&mut len).await?;
+            u64::from(u16::from_be_bytes(len))
+        }
+        127 => {
+            let mut len = [0; 8];
+            reader.read_exact(&mut len).await?;
+            u64::from_be_bytes(len)
+        }
+        len => u64::from(len),
+    };
+    if len > MAX_FRAME {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "websocket frame too large"));
+    }
+
+    let mut mask = [0; 4];
+    if header[1] & 0x80 != 0 {
+        reader.read_exact(&mut mask).await?;
+    }
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload).await?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok((opcode, payload))
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= 0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame).await
+}
+
+pub const INDEX: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>fishnet</title>
+<style>
+body { font-family: sans-serif; margin: 2em; color: #333; }
+table { border-collapse: collapse; margin-bottom: 1em; }
+td, th { padding: 0.2em 0.8em; text-align: left; }
+th { border-bottom: 1px solid #ccc; }
+pre { background: #f4f4f4; padding: 1em; overflow-x: auto; }
+#status.offline { color: #c33; }
+</style>
+</head>
+<body>
+<h1>fishnet <span id="version"></span></h1>
+<p id="status">Connecting ...</p>
+<svg id="history" width="720" height="80"></svg>
+<h2>Workers</h2>
+<table id="workers"></table>
+<h2>Pending batches</h2>
+<table id="pending"></table>
+<h2>Log</h2>
+<pre id="log"></pre>
+<script>
+function row(cells, tag) {
+  var tr = document.createElement('tr');
+  cells.forEach(function (cell) {
+    var td = document.createElement(tag || 'td');
+    td.textContent = cell;
+    tr.appendChild(td);
+  });
+  return tr;
+}
+function table(id, head, rows) {
+  var el = document.getElementById(id);
+  el.innerHTML = '';
+  el.appendChild(row(head, 'th'));
+  rows.forEach(function (cells) { el.appendChild(row(cells)); });
+}
+function render(u) {
+  document.getElementById('version').textContent = u.version;
+  document.getElementById('status').className = '';
+  document.getElementById('status').textContent = u.activity + ', ' + u.batches + ' batches, ' + u.positions + ' positions, ' + Math.round(u.nps / 1000) + ' knps (nnue), ' + u.incoming + ' queued';
+  var max = Math.max.apply(null, u.history.map(function (s) { return s.nps; }).concat([1]));
+  document.getElementById('history').innerHTML = '<polyline fill="none" stroke="#393" points="' + u.history.map(function (s, i) {
+    return (i * 2) + ',' + (80 - 78 * s.nps / max);
+  }).join(' ') + '"/>';
+  table('workers', ['#', 'analysing', 'knps', 'positions', 'errors'], u.workers.map(function (w, i) {
+    return [i, w.analysing || 'idle', Math.round(w.nps / 1000), w.positions, w.errors];
+  }));
+  table('pending', ['batch', 'progress'], u.pending.map(function (b) {
+    return [b.batch, b.done + '/' + b.total];
+  }));
+  document.getElementById('log').textContent = u.log.join('\n');
+}
+function connect() {
+  var ws = new WebSocket((location.protocol === 'https:' ? 'wss://' : 'ws://') + location.host + '/ws');
+  ws.onmessage = function (e) { render(JSON.parse(e.data)); };
+  ws.onclose = function () {
+    document.getElementById('status').className = 'offline';
+    document.getElementById('status').textContent = 'Disconnected. Reconnecting ...';
+    setTimeout(connect, 5000);
+  };
+}
+connect();
+</script>
+</body>
+</html>
+"##;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_same_origin() {
+        assert!(same_origin(None, Some("127.0.0.1:9670")));
+        assert!(same_origin(Some("http://127.0.0.1:9670"), Some("127.0.0.1:9670")));
+        assert!(same_origin(Some("http://Fishnet.local:9670"), Some("fishnet.local:9670")));
+        assert!(!same_origin(Some("https://example.com"), Some("127.0.0.1:9670")));
+        assert!(!same_origin(Some("http://127.0.0.1:8080"), Some("127.0.0.1:9670")));
+        assert!(!same_origin(Some("null"), Some("127.0.0.1:9670")));
+        assert!(!same_origin(Some("http://127.0.0.1:9670"), None));
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+use serde_json::json;
+use tokio::sync::mpsc;
+use tokio_compat_02::FutureExt as _;
+use url::Url;
+use crate::configure::WebhookFormat;
+use crate::logger::Logger;
+
+/// Do not repeat the same kind of event more often than this.
+const REPEAT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    KeyRejected {
+        key: String,
+    },
+    UpdateRequired,
+    EngineCrashes {
+        worker: usize,
+        failures: u32,
+    },
+    Idle {
+        duration: Duration,
+    },
+    NoWork {
+        duration: Duration,
+    },
+}
+
+impl Event {
+    fn kind(&self) -> &'static str {
+        match self {
+            Event::KeyRejected { .. } => "key_rejected",
+            Event::UpdateRequired => "update_required",
+            Event::EngineCrashes { .. } => "engine_crashes",
+            Event::Idle { .. } => "idle",
+            Event::NoWork { .. } => "no_work",
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::KeyRejected { key } => write!(f, "Key {} was rejected by the server", key),
+            Event::UpdateRequired => f.write_str("Server requires a newer fishnet"),
+            Event::EngineCrashes { worker, failures } => write!(f, "Engine of worker {} failed {} times in a row", worker, failures),
+            Event::Idle { duration } => write!(f, "No positions analysed for {} minutes", duration.as_secs() / 60),
+            Event::NoWork { duration } => write!(f, "No work available for {} minutes. Ready to scale down", duration.as_secs() / 60),
+        }
+    }
+}
+
+/// Sends events to the webhook, if one is configured.
+#[derive(Clone, Default)]
+pub struct WebhookStub {
+    tx: Option<mpsc::UnboundedSender<Event>>,
+}
+
+impl WebhookStub {
+    pub fn notify(&self, event: Event) {
+        if let Some(ref tx) = self.tx {
+            tx.send(event).ok();
+        }
+    }
+}
+
+pub fn spawn(url: Option<Url>, format: WebhookFormat, client: reqwest::Client, logger: Logger) -> WebhookStub {
+    let url = match url {
+        Some(url) => url,
+        None => return WebhookStub::default(),
+    };
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(WebhookActor {
+        rx,
+        url,
+        format,
+        client,
+        sent: HashMap::new(),
+        logger: logger.module("webhook"),
+    }.run());
+    WebhookStub {
+        tx: Some(tx),
+    }
+}
+
+struct WebhookActor {
+    rx: mpsc::UnboundedReceiver<Event>,
+    url: Url,
+    format: WebhookFormat,
+    client: reqwest::Client,
+    sent: HashMap<&'static str, Instant>,
+    logger: Logger,
+}
+
+impl WebhookActor {
+    async fn run(mut self) {
+        while let Some(event) = self.rx.recv().await {
+            if self.sent.get(event.kind()).is_some_and(|t| t.elapsed() < REPEAT_INTERVAL) {
+                self.logger.debug(&format!("Not repeating webhook for {}", event.kind()));
+                continue;
+            }
+            self.sent.insert(event.kind(), Instant::now());
+
+            if let Err(err) = self.send(&event).compat().await {
+                self.logger.warn(&format!("Failed to send webhook for {}: {}", event.kind(), err));
+            }
+        }
+    }
+
+    async fn send(&self, event: &Event) -> reqwest::Result<()> {
+        let message = format!("fishnet/{}: {}", env!("CARGO_PKG_VERSION"), event);
+        let body = match self.format {
+            WebhookFormat::Json => json!({
+                "event": event.kind(),
+                "message": message,
+                "version": env!("CARGO_PKG_VERSION"),
+            }),
+            WebhookFormat::Discord => json!({ "content": message }),
+            WebhookFormat::Slack => json!({ "text": message }),
+        };
+        self.client.post(self.url.clone()).json(&body).send().await?.error_for_status()?;
+        Ok(())
+    }
+}